@@ -1,6 +1,10 @@
 use criterion::{criterion_group, criterion_main, Criterion};
 
+use memobuild::hasher::file_hasher::{hash_dir, hash_dir_files_with};
+use memobuild::hasher::{IgnoreRules, StatCache};
 use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
 use tempfile::tempdir;
 
 fn bench_hashing(c: &mut Criterion) {
@@ -26,5 +30,56 @@ fn bench_hashing(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, bench_hashing);
+/// A context of `dirs` x `files` 16 KB files, last modified an hour ago so that the stat
+/// cache does not treat them as racily clean.
+fn make_context(root: &Path, dirs: usize, files: usize) {
+    let content = vec![b'x'; 16 * 1024];
+    let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+    for d in 0..dirs {
+        let dir = root.join(format!("pkg_{}", d));
+        fs::create_dir_all(&dir).unwrap();
+        for f in 0..files {
+            let path = dir.join(format!("mod_{}.rs", f));
+            fs::write(&path, &content).unwrap();
+            fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(an_hour_ago)
+                .unwrap();
+        }
+    }
+}
+
+/// Hashing an unchanged context, as a no-op build does: every file read and hashed, versus
+/// only stat'ed against a warm stat cache.
+fn bench_noop_hashing(c: &mut Criterion) {
+    let dir = tempdir().unwrap();
+    let path = dir.path();
+    make_context(path, 20, 100);
+    let ignore = IgnoreRules::empty();
+
+    let mut group = c.benchmark_group("no-op context hashing (2000 files)");
+    group.bench_function("full rehash", |b| {
+        b.iter(|| hash_dir(path, &ignore).unwrap())
+    });
+
+    let cache_dir = tempdir().unwrap();
+    let cache_file = cache_dir.path().join("stat-cache.json");
+    let stat_cache = StatCache::load_from(&cache_file).unwrap();
+    hash_dir_files_with(path, &ignore, Some(&stat_cache)).unwrap();
+    stat_cache.save().unwrap();
+    group.bench_function("stat cache", |b| {
+        b.iter(|| hash_dir_files_with(path, &ignore, Some(&stat_cache)).unwrap())
+    });
+    group.bench_function("stat cache, loaded from disk", |b| {
+        b.iter(|| {
+            let stat_cache = StatCache::load_from(&cache_file).unwrap();
+            hash_dir_files_with(path, &ignore, Some(&stat_cache)).unwrap()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_hashing, bench_noop_hashing);
 criterion_main!(benches);
@@ -49,7 +49,7 @@ RUN npm run build
 
     // Find COPY package.json node
     let copy_package_idx = graph.nodes.iter()
-        .position(|n| matches!(&n.kind, memobuild::graph::NodeKind::Copy { src, .. } if src.to_string_lossy() == "package.json"))
+        .position(|n| matches!(&n.kind, memobuild::graph::NodeKind::Copy { sources, .. } if sources.iter().any(|s| s.to_string_lossy() == "package.json")))
         .expect("Should find COPY package.json node");
 
     // Find RUN npm install node
@@ -57,7 +57,8 @@ RUN npm run build
         .nodes
         .iter()
         .position(|n| {
-            matches!(&n.kind, memobuild::graph::NodeKind::Run) && n.content.contains("npm install")
+            matches!(&n.kind, memobuild::graph::NodeKind::Run { .. })
+                && n.content.contains("npm install")
         })
         .expect("Should find RUN npm install node");
 
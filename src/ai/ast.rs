@@ -3,7 +3,10 @@ use std::collections::HashSet;
 use std::fs;
 use std::path::{Path, PathBuf};
 
-pub struct AstAnalyzer;
+pub struct AstAnalyzer {
+    /// Report what was found on stdout
+    pub verbose: bool,
+}
 
 impl Default for AstAnalyzer {
     fn default() -> Self {
@@ -13,16 +16,36 @@ impl Default for AstAnalyzer {
 
 impl AstAnalyzer {
     pub fn new() -> Self {
-        Self
+        Self { verbose: true }
+    }
+
+    fn report(&self, src: &Path, deps: &[PathBuf]) {
+        if !self.verbose {
+            return;
+        }
+        println!(
+            "      🟢 Found {} hidden dependencies in {:?}",
+            deps.len(),
+            src
+        );
+        for dep in deps {
+            println!("         └─ {}", dep.display());
+        }
     }
 
     pub fn analyze_dependencies(&self, graph: &mut BuildGraph, context_dir: &Path) {
-        println!("   🔍 Performing AST-based dependency detection...");
+        if self.verbose {
+            println!("   🔍 Performing AST-based dependency detection...");
+        }
 
         let mut extra_deps = Vec::new();
 
         for node in &graph.nodes {
-            if let NodeKind::Copy { src, .. } = &node.kind {
+            let sources: &[PathBuf] = match &node.kind {
+                NodeKind::Copy { sources, .. } => sources,
+                _ => &[],
+            };
+            for src in sources {
                 let full_src = context_dir.join(src);
                 if full_src.exists() && full_src.is_file() {
                     if let Some(ext) = full_src.extension() {
@@ -31,28 +54,14 @@ impl AstAnalyzer {
                             Some("js") | Some("ts") | Some("jsx") | Some("tsx") => {
                                 let deps = self.find_js_dependencies(&path);
                                 if !deps.is_empty() {
-                                    println!(
-                                        "      🟢 Found {} hidden dependencies in {:?}",
-                                        deps.len(),
-                                        src
-                                    );
-                                    for dep in &deps {
-                                        println!("         └─ {}", dep.display());
-                                    }
+                                    self.report(src, &deps);
                                     extra_deps.push((node.id, deps));
                                 }
                             }
                             Some("rs") => {
                                 let deps = self.find_rust_dependencies(&path);
                                 if !deps.is_empty() {
-                                    println!(
-                                        "      🟢 Found {} hidden dependencies in {:?}",
-                                        deps.len(),
-                                        src
-                                    );
-                                    for dep in &deps {
-                                        println!("         └─ {}", dep.display());
-                                    }
+                                    self.report(src, &deps);
                                     extra_deps.push((node.id, deps));
                                 }
                             }
@@ -26,6 +26,14 @@ impl AiLayer {
         }
     }
 
+    /// Whether the analysis is reported on stdout. Commands whose output is the graph itself
+    /// turn this off.
+    pub fn with_verbose(mut self, verbose: bool) -> Self {
+        self.ast_analyzer.verbose = verbose;
+        self.optimizer.verbose = verbose;
+        self
+    }
+
     /// Run AI-powered analysis on the build graph
     pub fn analyze(
         &self,
@@ -33,7 +41,10 @@ impl AiLayer {
         env_fp: &EnvFingerprint,
         context_dir: &std::path::Path,
     ) {
-        println!("🤖 AI Layer: Analyzing build graph...");
+        let verbose = self.ast_analyzer.verbose;
+        if verbose {
+            println!("🤖 AI Layer: Analyzing build graph...");
+        }
 
         // 1. AST-based dependency detection
         self.ast_analyzer.analyze_dependencies(graph, context_dir);
@@ -41,7 +52,9 @@ impl AiLayer {
         // 2. Build optimization
         self.optimizer.optimize_graph(graph, env_fp);
 
-        // 3. CI Pipeline advice
-        self.ci_advisor.analyze_ci_context();
+        // 3. CI Pipeline advice, which leaves the graph alone
+        if verbose {
+            self.ci_advisor.analyze_ci_context();
+        }
     }
 }
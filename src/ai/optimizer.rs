@@ -1,7 +1,10 @@
 use crate::env::EnvFingerprint;
 use crate::graph::{BuildGraph, NodeKind};
 
-pub struct BuildOptimizer;
+pub struct BuildOptimizer {
+    /// Report the optimizations on stdout
+    pub verbose: bool,
+}
 
 impl Default for BuildOptimizer {
     fn default() -> Self {
@@ -11,21 +14,25 @@ impl Default for BuildOptimizer {
 
 impl BuildOptimizer {
     pub fn new() -> Self {
-        Self
+        Self { verbose: true }
     }
 
     pub fn optimize_graph(&self, graph: &mut BuildGraph, _env_fp: &EnvFingerprint) {
-        println!("   🧠 Applying ML-based build optimization...");
+        if self.verbose {
+            println!("   🧠 Applying ML-based build optimization...");
+        }
 
         for node in &mut graph.nodes {
             // Heuristic: RUN nodes that appear to be independent or CPU-bound
             // should be prioritized and marked as parallelizable.
-            if let NodeKind::Run = &node.kind {
+            if let NodeKind::Run { .. } = &node.kind {
                 if node.content.contains("test") || node.content.contains("build") {
-                    println!(
-                        "      ⚡ Optimizing node {}: '{}' - Setting high priority",
-                        node.id, node.name
-                    );
+                    if self.verbose {
+                        println!(
+                            "      ⚡ Optimizing node {}: '{}' - Setting high priority",
+                            node.id, node.name
+                        );
+                    }
                     node.metadata.priority = 10;
                     node.metadata.parallelizable = true;
                 }
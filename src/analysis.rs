@@ -0,0 +1,136 @@
+//! Critical-path analysis of an executed build graph (`memobuild analyze`).
+//!
+//! Node durations come from `NodeMetadata::execution_time_ms`; nodes without one count as
+//! instantaneous. With unlimited workers a build can finish no sooner than its longest
+//! dependency chain, so that chain shows which steps to split or reorder, and the slack of
+//! the other nodes shows how much they could slow down without delaying the build.
+
+use crate::graph::BuildGraph;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+/// Schedule of one node when every node starts as soon as its dependencies finish.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NodeTiming {
+    pub id: usize,
+    pub label: String,
+    pub stage: usize,
+    pub duration_ms: u64,
+    pub earliest_start_ms: u64,
+    pub latest_start_ms: u64,
+    /// How long the node can be delayed without delaying the build
+    pub slack_ms: u64,
+    pub critical: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BuildAnalysis {
+    /// Sum of all node durations: the build time with no parallelism at all
+    pub total_work_ms: u64,
+    /// Length of the longest dependency chain: the build time with unlimited parallelism
+    pub critical_path_ms: u64,
+    /// Node ids of the longest chain, dependencies first
+    pub critical_path: Vec<usize>,
+    /// `total_work_ms / critical_path_ms`: the average number of nodes that can run at once
+    pub parallelism: f64,
+    /// Measured duration of the build, when known
+    pub wall_time_ms: Option<u64>,
+    /// `wall_time_ms / critical_path_ms`: how much faster the build could finish with
+    /// unlimited parallelism
+    pub potential_speedup: Option<f64>,
+    pub nodes: Vec<NodeTiming>,
+}
+
+impl BuildAnalysis {
+    /// Analyze `graph`, which must pass [`BuildGraph::validate`].
+    pub fn from_graph(graph: &BuildGraph) -> Result<Self> {
+        graph.validate().ensure_valid()?;
+
+        let len = graph.nodes.len();
+        let duration: Vec<u64> = graph
+            .nodes
+            .iter()
+            .map(|n| n.metadata.execution_time_ms.unwrap_or(0))
+            .collect();
+        let order = graph.topological_order();
+
+        let mut earliest_finish = vec![0u64; len];
+        for &id in &order {
+            let start = graph.nodes[id]
+                .deps
+                .iter()
+                .map(|&d| earliest_finish[d])
+                .max()
+                .unwrap_or(0);
+            earliest_finish[id] = start + duration[id];
+        }
+        let critical_path_ms = earliest_finish.iter().copied().max().unwrap_or(0);
+
+        let mut latest_finish = vec![critical_path_ms; len];
+        for &id in order.iter().rev() {
+            let latest_start = latest_finish[id] - duration[id];
+            for &dep in &graph.nodes[id].deps {
+                latest_finish[dep] = latest_finish[dep].min(latest_start);
+            }
+        }
+
+        // Walk back from the node that finishes last along the dependency that finishes last
+        let mut critical_path = Vec::new();
+        let mut current = (0..len).max_by_key(|&i| earliest_finish[i]);
+        while let Some(id) = current {
+            critical_path.push(id);
+            current = graph.nodes[id]
+                .deps
+                .iter()
+                .copied()
+                .max_by_key(|&d| earliest_finish[d]);
+        }
+        critical_path.reverse();
+
+        let nodes = graph
+            .nodes
+            .iter()
+            .enumerate()
+            .map(|(id, node)| {
+                let earliest_start = earliest_finish[id] - duration[id];
+                let latest_start = latest_finish[id] - duration[id];
+                NodeTiming {
+                    id,
+                    label: crate::visualize::label(node),
+                    stage: node.metadata.stage,
+                    duration_ms: duration[id],
+                    earliest_start_ms: earliest_start,
+                    latest_start_ms: latest_start,
+                    slack_ms: latest_start - earliest_start,
+                    critical: critical_path.contains(&id),
+                }
+            })
+            .collect();
+
+        let total_work_ms = duration.iter().sum();
+        Ok(BuildAnalysis {
+            total_work_ms,
+            critical_path_ms,
+            critical_path,
+            parallelism: ratio(total_work_ms, critical_path_ms),
+            wall_time_ms: None,
+            potential_speedup: None,
+            nodes,
+        })
+    }
+
+    /// Record the measured build duration, which the potential speedup is relative to.
+    pub fn with_wall_time(mut self, wall_time_ms: u64) -> Self {
+        self.wall_time_ms = Some(wall_time_ms);
+        self.potential_speedup = Some(ratio(wall_time_ms, self.critical_path_ms));
+        self
+    }
+}
+
+fn ratio(a: u64, b: u64) -> f64 {
+    if b == 0 {
+        1.0
+    } else {
+        a as f64 / b as f64
+    }
+}
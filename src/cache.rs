@@ -7,7 +7,7 @@ pub mod metadata;
 pub mod utils;
 
 pub use local::LocalCache;
-pub use hybrid::HybridCache;
+pub use hybrid::{CacheLocation, HybridCache};
 pub use metadata::{DatabaseStats, PostgresMetadataStore, ReplicatedMetadataStore};
 pub use remote::{RemoteCache, RemoteCacheEntry};
 pub use http::HttpRemoteCache;
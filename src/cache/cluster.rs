@@ -471,9 +471,15 @@ impl RemoteCache for DistributedCache {
         self.local_cache.report_dag(dag).await
     }
 
-    async fn report_analytics(&self, dirty: u32, cached: u32, duration_ms: u64) -> Result<()> {
+    async fn report_analytics(
+        &self,
+        dirty: u32,
+        cached: u32,
+        duration_ms: u64,
+        analysis: Option<&crate::analysis::BuildAnalysis>,
+    ) -> Result<()> {
         self.local_cache
-            .report_analytics(dirty, cached, duration_ms)
+            .report_analytics(dirty, cached, duration_ms, analysis)
             .await
     }
 }
\ No newline at end of file
@@ -3,6 +3,15 @@ use crate::cache::local::LocalCache;
 use anyhow::Result;
 use std::sync::Arc;
 
+/// Where an artifact can be fetched from without rebuilding it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheLocation {
+    Local,
+    Remote,
+    Missing,
+}
+
 pub struct HybridCache {
     pub local: LocalCache,
     pub remote: Option<Arc<dyn RemoteCache>>,
@@ -20,6 +29,19 @@ impl HybridCache {
         Self::new(remote)
     }
 
+    /// Check the local cache, then the remote one, without downloading anything.
+    pub async fn locate(&self, key: &str) -> Result<CacheLocation> {
+        if self.local.exists(key) {
+            return Ok(CacheLocation::Local);
+        }
+        if let Some(ref remote) = self.remote {
+            if remote.get_node_layers(key).await?.is_some() || remote.has(key).await? {
+                return Ok(CacheLocation::Remote);
+            }
+        }
+        Ok(CacheLocation::Missing)
+    }
+
     pub async fn get_artifact(&self, key: &str) -> Result<Option<Vec<u8>>> {
         // 1. Try local
         if let Some(data) = self.local.get_data(key)? {
@@ -85,9 +107,24 @@ impl HybridCache {
         Ok(())
     }
 
-    pub async fn report_analytics(&self, dirty: u32, cached: u32, duration_ms: u64) -> Result<()> {
+    /// Send the executed graph to the remote cache server, which derives its build-time
+    /// analysis from it.
+    pub async fn report_dag(&self, dag: &crate::graph::BuildGraph) -> Result<()> {
+        if let Some(ref remote) = self.remote {
+            remote.report_dag(dag).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn report_analytics(
+        &self,
+        dirty: u32,
+        cached: u32,
+        duration_ms: u64,
+        analysis: Option<&crate::analysis::BuildAnalysis>,
+    ) -> Result<()> {
         if let Some(ref remote) = self.remote {
-            remote.report_analytics(dirty, cached, duration_ms).await?;
+            remote.report_analytics(dirty, cached, duration_ms, analysis).await?;
         }
         Ok(())
     }
@@ -21,7 +21,11 @@ pub struct LocalCache {
 
 impl LocalCache {
     pub fn new() -> Result<Self> {
-        let cache_dir = Self::get_cache_dir()?;
+        Self::in_dir(Self::get_cache_dir()?)
+    }
+
+    /// Cache kept in `cache_dir` rather than the one [`LocalCache::get_cache_dir`] names.
+    pub fn in_dir(cache_dir: PathBuf) -> Result<Self> {
         fs::create_dir_all(&cache_dir)?;
 
         let index_path = cache_dir.join("index.json");
@@ -34,7 +38,7 @@ impl LocalCache {
         })
     }
 
-    fn get_cache_dir() -> Result<PathBuf> {
+    pub fn get_cache_dir() -> Result<PathBuf> {
         if let Ok(dir) = std::env::var("MEMOBUILD_CACHE_DIR") {
             return Ok(PathBuf::from(dir));
         }
@@ -30,5 +30,11 @@ pub trait RemoteCache: Send + Sync {
 
     async fn report_build_event(&self, event: BuildEvent) -> Result<()>;
     async fn report_dag(&self, dag: &BuildGraph) -> Result<()>;
-    async fn report_analytics(&self, dirty: u32, cached: u32, duration_ms: u64) -> Result<()>;
+    async fn report_analytics(
+        &self,
+        dirty: u32,
+        cached: u32,
+        duration_ms: u64,
+        analysis: Option<&crate::analysis::BuildAnalysis>,
+    ) -> Result<()>;
 }
\ No newline at end of file
@@ -154,7 +154,13 @@ impl RemoteCache for RedisCache {
         Ok(())
     }
 
-    async fn report_analytics(&self, _dirty: u32, _cached: u32, _duration_ms: u64) -> Result<()> {
+    async fn report_analytics(
+        &self,
+        _dirty: u32,
+        _cached: u32,
+        _duration_ms: u64,
+        _analysis: Option<&crate::analysis::BuildAnalysis>,
+    ) -> Result<()> {
         Ok(())
     }
 }
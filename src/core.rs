@@ -1,17 +1,263 @@
 use crate::env::EnvFingerprint;
-use crate::graph::BuildGraph;
+use crate::docker::parser::is_url;
+use crate::graph::{BuildGraph, Node, NodeKind, RunMount};
+use crate::hasher::file_hasher::{combine_file_hashes, hash_dir_files_with, hash_path_with};
+use crate::hasher::StatCache;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
 
-#[allow(dead_code)]
-pub fn detect_changes(graph: &mut BuildGraph) {
-    for node in &mut graph.nodes {
-        node.dirty = true;
+/// Inputs of a single node as observed during change detection.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct NodeInputs {
+    /// Instruction text of the node
+    pub content: String,
+    /// Environment the node runs with, including build args in scope
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Hash of every source path the node reads, keyed by path relative to the context
+    pub sources: BTreeMap<String, String>,
+    /// Hash of every file under those sources, keyed by path relative to the context, so
+    /// that a changed source can be traced to the files that changed
+    #[serde(default)]
+    pub files: BTreeMap<String, String>,
+}
+
+/// Record of the inputs seen by the last successful build of a context directory.
+/// Stored under the local cache dir so every subcommand compares against the same state.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ContextState {
+    pub context: PathBuf,
+    pub nodes: BTreeMap<String, NodeInputs>,
+}
+
+impl ContextState {
+    /// Location of the state file for a context directory.
+    pub fn state_path(context_dir: &Path) -> Result<PathBuf> {
+        let canonical = context_dir
+            .canonicalize()
+            .unwrap_or_else(|_| context_dir.to_path_buf());
+        let key = blake3::hash(canonical.to_string_lossy().as_bytes())
+            .to_hex()
+            .to_string();
+        Ok(crate::cache::LocalCache::get_cache_dir()?
+            .join("contexts")
+            .join(format!("{}.json", key)))
+    }
+
+    /// Load the previous build state, returning an empty state if none was recorded.
+    pub fn load(context_dir: &Path) -> Result<Self> {
+        Self::load_from(&Self::state_path(context_dir)?)
+    }
+
+    pub fn load_from(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read build state at {}", path.display()))?;
+        Ok(serde_json::from_str(&content).unwrap_or_default())
+    }
+
+    /// Persist this state as the reference for the next build of the same context.
+    pub fn save(&self) -> Result<()> {
+        self.save_to(&Self::state_path(&self.context)?)
+    }
+
+    pub fn save_to(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
     }
 }
 
-#[allow(dead_code)]
+/// Stable identity of each node across builds: its instruction text, suffixed with
+/// the occurrence index when the same instruction appears more than once.
+pub fn node_state_keys(graph: &BuildGraph) -> Vec<String> {
+    let mut seen: HashMap<&str, usize> = HashMap::new();
+    graph
+        .nodes
+        .iter()
+        .map(|node| {
+            let count = seen.entry(node.content.as_str()).or_insert(0);
+            *count += 1;
+            if *count == 1 {
+                node.content.clone()
+            } else {
+                format!("{}#{}", node.content, count)
+            }
+        })
+        .collect()
+}
+
+/// Context paths a node reads: every local COPY/ADD source with wildcards expanded, the
+/// context bind mounts of a RUN, or the node's `source_path` for other kinds, plus any extra paths found by analysis.
+pub fn node_source_paths(node: &Node, context_dir: &Path) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = match &node.kind {
+        NodeKind::Copy {
+            sources,
+            from: None,
+            ..
+        } if node.source_path.is_some() => sources
+            .iter()
+            .flat_map(|src| resolve_source(context_dir, &src.to_string_lossy()))
+            .collect(),
+        NodeKind::Add { sources, .. } => sources
+            .iter()
+            .filter(|src| !is_url(src))
+            .flat_map(|src| resolve_source(context_dir, src))
+            .collect(),
+        // Bind mounts of the build context read it like a COPY
+        NodeKind::Run { mounts } => mounts
+            .iter()
+            .filter_map(|mount| match mount {
+                RunMount::Bind {
+                    source, from: None, ..
+                } => Some(resolve_source(context_dir, source.as_deref().unwrap_or("."))),
+                _ => None,
+            })
+            .flatten()
+            .collect(),
+        _ => node.source_path.iter().cloned().collect(),
+    };
+    paths.extend(node.metadata.extra_source_paths.iter().cloned());
+    paths
+}
+
+/// Expand wildcards in a COPY/ADD source. A pattern without matches is kept as is, so that
+/// matching files appearing later still change the node's inputs.
+pub fn resolve_source(context_dir: &Path, src: &str) -> Vec<PathBuf> {
+    let path = if src == "." {
+        context_dir.to_path_buf()
+    } else {
+        context_dir.join(src)
+    };
+    if !src.contains(['*', '?', '[']) {
+        return vec![path];
+    }
+
+    let mut matches: Vec<PathBuf> = glob::glob(&path.to_string_lossy())
+        .map(|paths| paths.filter_map(|p| p.ok()).collect())
+        .unwrap_or_default();
+    matches.sort();
+    if matches.is_empty() {
+        vec![path]
+    } else {
+        matches
+    }
+}
+
+/// Hash the filesystem inputs of every node and mark as dirty those whose inputs differ
+/// from the previous build recorded for `context_dir`.
+///
+/// Returns the current state, which callers save once the build has succeeded.
+pub fn detect_changes(graph: &mut BuildGraph, context_dir: &Path) -> Result<ContextState> {
+    let previous = ContextState::load(context_dir)?;
+    let stat_cache = StatCache::open(context_dir)?;
+    let current = detect_changes_with(graph, context_dir, &previous, &stat_cache)?;
+    if let Err(e) = stat_cache.save() {
+        eprintln!("⚠️  Failed to save the file hash cache: {:#}", e);
+    }
+    Ok(current)
+}
+
+/// Same as [`detect_changes`] but compares against an explicit previous state, hashing
+/// every source file.
+pub fn detect_changes_against(
+    graph: &mut BuildGraph,
+    context_dir: &Path,
+    previous: &ContextState,
+) -> Result<ContextState> {
+    detect_changes_with(graph, context_dir, previous, &StatCache::in_memory())
+}
+
+/// Same as [`detect_changes_against`], reusing the hashes of files `stat_cache` knows to
+/// be unchanged.
+pub fn detect_changes_with(
+    graph: &mut BuildGraph,
+    context_dir: &Path,
+    previous: &ContextState,
+    stat_cache: &StatCache,
+) -> Result<ContextState> {
+    let ignore = graph.ignore_rules(context_dir);
+    let keys = node_state_keys(graph);
+    let mut current = ContextState {
+        context: context_dir.to_path_buf(),
+        nodes: BTreeMap::new(),
+    };
+
+    for (node, key) in graph.nodes.iter_mut().zip(keys) {
+        let excludes = match &node.kind {
+            NodeKind::Copy { options, .. } | NodeKind::Add { options, .. } => {
+                options.exclude.as_slice()
+            }
+            _ => &[],
+        };
+
+        let mut sources = BTreeMap::new();
+        let mut files = BTreeMap::new();
+        for path in node_source_paths(node, context_dir) {
+            let rel_path = path.strip_prefix(context_dir).unwrap_or(&path);
+            let rel = if rel_path.as_os_str().is_empty() {
+                ".".to_string()
+            } else {
+                rel_path.to_string_lossy().to_string()
+            };
+            let hash = if path.is_dir() {
+                // .dockerignore patterns are relative to the context, --exclude ones to the source
+                let mut dir_ignore = ignore.relative_to(rel_path);
+                dir_ignore.add_patterns(excludes);
+                let dir_files = hash_dir_files_with(&path, &dir_ignore, Some(stat_cache))
+                    .with_context(|| format!("Failed to hash source {}", path.display()))?;
+                for (file, hash) in &dir_files {
+                    files.insert(
+                        rel_path.join(file).to_string_lossy().to_string(),
+                        hash.clone(),
+                    );
+                }
+                combine_file_hashes(&dir_files)
+            } else {
+                let hash = hash_path_with(&path, &ignore, Some(stat_cache))
+                    .with_context(|| format!("Failed to hash source {}", path.display()))?;
+                if path.is_file() {
+                    files.insert(rel.clone(), hash.clone());
+                }
+                hash
+            };
+            sources.insert(rel, hash);
+        }
+
+        if !sources.is_empty() {
+            let mut hasher = blake3::Hasher::new();
+            for (rel, hash) in &sources {
+                hasher.update(rel.as_bytes());
+                hasher.update(hash.as_bytes());
+            }
+            node.metadata.source_content_hash = Some(hasher.finalize().to_hex().to_string());
+        }
+
+        let inputs = NodeInputs {
+            content: node.content.clone(),
+            env: node.env.clone().into_iter().collect(),
+            sources,
+            files,
+        };
+        node.dirty = previous.nodes.get(&key) != Some(&inputs);
+        current.nodes.insert(key, inputs);
+    }
+
+    Ok(current)
+}
+
+/// Mark every node that depends on a dirty node dirty as well. Nodes are visited in
+/// topological order, so this holds whatever order the graph stores them in.
 pub fn propagate_dirty(graph: &mut BuildGraph) {
     let node_count = graph.nodes.len();
-    for i in 0..node_count {
+    for i in graph.topological_order() {
         if !graph.nodes[i].dirty {
             for dep in &graph.nodes[i].deps {
                 if *dep < node_count && graph.nodes[*dep].dirty {
@@ -23,13 +269,22 @@ pub fn propagate_dirty(graph: &mut BuildGraph) {
     }
 }
 
-#[allow(dead_code)]
-pub fn compute_composite_hashes(graph: &mut BuildGraph, _env_fp: &EnvFingerprint) {
-    for node in &mut graph.nodes {
-        use blake3::Hasher;
-        let mut hasher = Hasher::new();
-        hasher.update(node.content.as_bytes());
-        node.hash = hasher.finalize().to_hex().to_string();
+/// Compute each node's cache key in topological order, Merkle style: the key covers the
+/// instruction, its env, the hash of its filesystem sources, the keys of its dependencies
+/// and the environment fingerprint. Changing any input therefore changes the key of the
+/// node and of everything downstream of it, and nothing else.
+pub fn compute_composite_hashes(graph: &mut BuildGraph, env_fp: &EnvFingerprint) {
+    for node_id in graph.topological_order() {
+        let dep_hashes: Vec<String> = graph.nodes[node_id]
+            .deps
+            .iter()
+            .filter(|&&dep| dep < graph.nodes.len())
+            .map(|&dep| graph.nodes[dep].hash.clone())
+            .collect();
+
+        // The key already covers the node's `source_content_hash`
+        let hash = graph.nodes[node_id].compute_node_key(&dep_hashes, None, Some(env_fp));
+        graph.nodes[node_id].hash = hash;
     }
 }
 
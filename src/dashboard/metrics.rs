@@ -42,6 +42,11 @@ pub enum BuildEvent {
         cache_hits: usize,
         executed_nodes: usize,
     },
+    /// `build --watch` saw changes in the context and is about to rebuild
+    RebuildTriggered {
+        changed_paths: Vec<String>,
+        dirty_nodes: usize,
+    },
 }
 
 pub trait BuildObserver: Send + Sync {
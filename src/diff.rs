@@ -0,0 +1,485 @@
+//! `memobuild diff`: compare two recorded build graphs node by node and name the inputs of
+//! [`Node::compute_node_key`] that differ, to explain unexpected rebuilds and cache misses
+//! between machines.
+
+use crate::env::EnvFingerprint;
+use crate::graph::{BuildGraph, Node};
+use crate::history::{BuildHistory, BuildRecord};
+use crate::visualize::{kind_name, label};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::Path;
+
+/// A recorded build: its executed graph and, when known, the environment it ran in.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BuildSnapshot {
+    pub graph: BuildGraph,
+    #[serde(default)]
+    pub env: Option<EnvFingerprint>,
+}
+
+impl BuildSnapshot {
+    /// Load a build by reference:
+    /// - `last`: the most recent build of `context_dir` in the local history
+    /// - `http(s)://<server>`: the DAG the cache server received through `POST /dag`
+    /// - an existing file: a JSON build record or serialized `BuildGraph`
+    /// - anything else: the id, or a unique prefix of it, of a build in the local history
+    pub async fn load(reference: &str, context_dir: &Path) -> Result<Self> {
+        Self::load_from(&BuildHistory::open_default()?, reference, context_dir).await
+    }
+
+    /// [`BuildSnapshot::load`] against a specific history.
+    pub async fn load_from(
+        history: &BuildHistory,
+        reference: &str,
+        context_dir: &Path,
+    ) -> Result<Self> {
+        let snapshot = Self::load_unchecked(history, reference, context_dir).await?;
+        snapshot
+            .graph
+            .validate()
+            .ensure_valid()
+            .with_context(|| format!("Build '{}' has an invalid graph", reference))?;
+        Ok(snapshot)
+    }
+
+    async fn load_unchecked(
+        history: &BuildHistory,
+        reference: &str,
+        context_dir: &Path,
+    ) -> Result<Self> {
+        if reference == "last" {
+            return Ok(history.resolve(reference, context_dir)?.into());
+        }
+
+        if reference.starts_with("http://") || reference.starts_with("https://") {
+            let url = if reference.trim_end_matches('/').ends_with("/dag") {
+                reference.to_string()
+            } else {
+                format!("{}/dag", reference.trim_end_matches('/'))
+            };
+            let graph: BuildGraph = reqwest::get(&url)
+                .await?
+                .error_for_status()
+                .with_context(|| format!("No DAG available at {}", url))?
+                .json()
+                .await
+                .with_context(|| format!("Invalid DAG from {}", url))?;
+            return Ok(BuildSnapshot { graph, env: None });
+        }
+
+        if !Path::new(reference).is_file() {
+            return Ok(history.resolve(reference, context_dir)?.into());
+        }
+        let content = std::fs::read_to_string(reference)
+            .with_context(|| format!("Failed to read build '{}'", reference))?;
+        if let Ok(snapshot) = serde_json::from_str::<BuildSnapshot>(&content) {
+            return Ok(snapshot);
+        }
+        let graph: BuildGraph = serde_json::from_str(&content)
+            .with_context(|| format!("{} is neither a build record nor a graph", reference))?;
+        Ok(BuildSnapshot { graph, env: None })
+    }
+}
+
+impl From<BuildRecord> for BuildSnapshot {
+    fn from(record: BuildRecord) -> Self {
+        BuildSnapshot {
+            graph: record.graph,
+            env: Some(record.env),
+        }
+    }
+}
+
+/// One input of a node's cache key that differs between the two builds.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "input", rename_all = "snake_case")]
+pub enum KeyChange {
+    /// Instruction kind or text
+    Instruction { before: String, after: String },
+    /// A variable in the node's env (ARG/ENV values a RUN sees)
+    Env {
+        key: String,
+        before: Option<String>,
+        after: Option<String>,
+    },
+    /// Content hash of COPY/ADD sources in the build context
+    SourceHash {
+        before: Option<String>,
+        after: Option<String>,
+    },
+    /// An upstream node's key changed
+    Dependency { id: usize, label: String },
+    /// The node depends on different nodes
+    Dependencies {
+        before: Vec<String>,
+        after: Vec<String>,
+    },
+    /// Scheduling settings hashed into the key (parallelizable, priority, shell, network)
+    Setting {
+        name: String,
+        before: String,
+        after: String,
+    },
+    /// The environment fingerprint differs (see [`GraphDiff::env`])
+    EnvFingerprint,
+    /// The key differs, but the recorded inputs do not say why (e.g. no fingerprint was
+    /// recorded for one of the builds)
+    Unexplained,
+}
+
+impl std::fmt::Display for KeyChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyChange::Instruction { before, after } => {
+                write!(f, "instruction: {:?} → {:?}", before, after)
+            }
+            KeyChange::Env { key, before, after } => write!(
+                f,
+                "env {}: {} → {}",
+                key,
+                show(before.as_deref()),
+                show(after.as_deref())
+            ),
+            KeyChange::SourceHash { before, after } => write!(
+                f,
+                "source files: {} → {}",
+                short(before.as_deref()),
+                short(after.as_deref())
+            ),
+            KeyChange::Dependency { id, label } => {
+                write!(f, "dependency [{}] {} changed", id, label)
+            }
+            KeyChange::Dependencies { before, after } => {
+                write!(f, "dependencies: {:?} → {:?}", before, after)
+            }
+            KeyChange::Setting {
+                name,
+                before,
+                after,
+            } => write!(f, "{}: {} → {}", name, before, after),
+            KeyChange::EnvFingerprint => write!(f, "environment fingerprint changed"),
+            KeyChange::Unexplained => write!(f, "key changed for a reason not recorded"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NodeStatus {
+    Unchanged,
+    Changed,
+    Added,
+    Removed,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeDiff {
+    pub status: NodeStatus,
+    /// Node id in the first build
+    pub before: Option<usize>,
+    /// Node id in the second build
+    pub after: Option<usize>,
+    pub label: String,
+    pub changes: Vec<KeyChange>,
+}
+
+/// A field of [`EnvFingerprint`] that differs, e.g. `toolchain.rustc` or `os`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EnvChange {
+    pub field: String,
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GraphDiff {
+    pub env: Vec<EnvChange>,
+    pub nodes: Vec<NodeDiff>,
+}
+
+impl GraphDiff {
+    pub fn count(&self, status: NodeStatus) -> usize {
+        self.nodes.iter().filter(|n| n.status == status).count()
+    }
+}
+
+/// Compare build `a` with build `b`. Nodes are paired by stage and instruction text first,
+/// then by their position among nodes of the same kind in the stage, so edited
+/// instructions show up as changed rather than as a removal plus an addition.
+pub fn diff_builds(a: &BuildSnapshot, b: &BuildSnapshot) -> GraphDiff {
+    let env = match (&a.env, &b.env) {
+        (Some(before), Some(after)) => diff_fingerprints(before, after),
+        _ => Vec::new(),
+    };
+    let fingerprint_changed = !env.is_empty();
+
+    let pairs = pair_nodes(&a.graph, &b.graph);
+    let to_a: HashMap<usize, usize> = pairs.iter().map(|&(ia, ib)| (ib, ia)).collect();
+    let paired_a: BTreeSet<usize> = pairs.iter().map(|&(ia, _)| ia).collect();
+
+    let mut nodes = Vec::new();
+    for node_b in &b.graph.nodes {
+        let Some(&ia) = to_a.get(&node_b.id) else {
+            nodes.push(NodeDiff {
+                status: NodeStatus::Added,
+                before: None,
+                after: Some(node_b.id),
+                label: label(node_b),
+                changes: Vec::new(),
+            });
+            continue;
+        };
+        let node_a = &a.graph.nodes[ia];
+        let mut changes = Vec::new();
+        if node_a.hash != node_b.hash || node_a.hash.is_empty() {
+            changes = key_changes(node_a, node_b, &a.graph, &b.graph, &to_a);
+            if node_a.hash != node_b.hash && changes.is_empty() {
+                changes.push(if fingerprint_changed {
+                    KeyChange::EnvFingerprint
+                } else {
+                    KeyChange::Unexplained
+                });
+            }
+        }
+        nodes.push(NodeDiff {
+            status: if changes.is_empty() {
+                NodeStatus::Unchanged
+            } else {
+                NodeStatus::Changed
+            },
+            before: Some(ia),
+            after: Some(node_b.id),
+            label: label(node_b),
+            changes,
+        });
+    }
+    for node_a in &a.graph.nodes {
+        if !paired_a.contains(&node_a.id) {
+            nodes.push(NodeDiff {
+                status: NodeStatus::Removed,
+                before: Some(node_a.id),
+                after: None,
+                label: label(node_a),
+                changes: Vec::new(),
+            });
+        }
+    }
+
+    GraphDiff { env, nodes }
+}
+
+/// The inputs of `compute_node_key` that differ between two paired nodes.
+fn key_changes(
+    a: &Node,
+    b: &Node,
+    graph_a: &BuildGraph,
+    graph_b: &BuildGraph,
+    to_a: &HashMap<usize, usize>,
+) -> Vec<KeyChange> {
+    let mut changes = Vec::new();
+
+    if a.content != b.content || kind_name(&a.kind) != kind_name(&b.kind) {
+        changes.push(KeyChange::Instruction {
+            before: label(a),
+            after: label(b),
+        });
+    } else if format!("{:?}", a.kind) != format!("{:?}", b.kind) {
+        // Same text, different parsed form (e.g. a WORKDIR-resolved destination)
+        changes.push(KeyChange::Instruction {
+            before: format!("{:?}", a.kind),
+            after: format!("{:?}", b.kind),
+        });
+    }
+
+    let keys: BTreeSet<&String> = a.env.keys().chain(b.env.keys()).collect();
+    for key in keys {
+        let (before, after) = (a.env.get(key), b.env.get(key));
+        if before != after {
+            changes.push(KeyChange::Env {
+                key: key.clone(),
+                before: before.cloned(),
+                after: after.cloned(),
+            });
+        }
+    }
+
+    if a.metadata.source_content_hash != b.metadata.source_content_hash {
+        changes.push(KeyChange::SourceHash {
+            before: a.metadata.source_content_hash.clone(),
+            after: b.metadata.source_content_hash.clone(),
+        });
+    }
+
+    let deps_b_in_a: Vec<Option<usize>> = b.deps.iter().map(|d| to_a.get(d).copied()).collect();
+    let same_deps = deps_b_in_a.len() == a.deps.len()
+        && deps_b_in_a
+            .iter()
+            .all(|d| d.is_some_and(|d| a.deps.contains(&d)));
+    if same_deps {
+        for (&db, da) in b.deps.iter().zip(&deps_b_in_a) {
+            let (Some(dep_a), Some(dep_b)) =
+                (da.and_then(|d| graph_a.nodes.get(d)), graph_b.nodes.get(db))
+            else {
+                continue;
+            };
+            if dep_a.hash != dep_b.hash {
+                changes.push(KeyChange::Dependency {
+                    id: db,
+                    label: label(dep_b),
+                });
+            }
+        }
+    } else {
+        let labels = |graph: &BuildGraph, deps: &[usize]| -> Vec<String> {
+            deps.iter()
+                .filter_map(|&d| graph.nodes.get(d))
+                .map(label)
+                .collect()
+        };
+        changes.push(KeyChange::Dependencies {
+            before: labels(graph_a, &a.deps),
+            after: labels(graph_b, &b.deps),
+        });
+    }
+
+    for (name, before, after) in [
+        (
+            "parallelizable",
+            a.metadata.parallelizable.to_string(),
+            b.metadata.parallelizable.to_string(),
+        ),
+        (
+            "priority",
+            a.metadata.priority.to_string(),
+            b.metadata.priority.to_string(),
+        ),
+        (
+            "shell",
+            format!("{:?}", a.metadata.shell),
+            format!("{:?}", b.metadata.shell),
+        ),
+        (
+            "network",
+            network(a.metadata.network.as_ref()),
+            network(b.metadata.network.as_ref()),
+        ),
+    ] {
+        if before != after {
+            changes.push(KeyChange::Setting {
+                name: name.to_string(),
+                before,
+                after,
+            });
+        }
+    }
+
+    changes
+}
+
+/// Pair node ids of `a` and `b`: same stage and instruction text first, then same stage,
+/// kind and ordinal among that kind.
+fn pair_nodes(a: &BuildGraph, b: &BuildGraph) -> Vec<(usize, usize)> {
+    let mut pairs = Vec::new();
+    let mut used_a = vec![false; a.nodes.len()];
+    let mut used_b = vec![false; b.nodes.len()];
+
+    for node_b in &b.nodes {
+        if let Some(node_a) = a.nodes.iter().find(|n| {
+            !used_a[n.id]
+                && n.metadata.stage == node_b.metadata.stage
+                && n.content == node_b.content
+                && kind_name(&n.kind) == kind_name(&node_b.kind)
+        }) {
+            used_a[node_a.id] = true;
+            used_b[node_b.id] = true;
+            pairs.push((node_a.id, node_b.id));
+        }
+    }
+
+    let ordinal = |graph: &BuildGraph, node: &Node| {
+        graph
+            .nodes
+            .iter()
+            .take_while(|n| n.id != node.id)
+            .filter(|n| {
+                n.metadata.stage == node.metadata.stage
+                    && kind_name(&n.kind) == kind_name(&node.kind)
+            })
+            .count()
+    };
+    for node_b in b.nodes.iter().filter(|n| !used_b[n.id]) {
+        let position = ordinal(b, node_b);
+        if let Some(node_a) = a.nodes.iter().find(|n| {
+            !used_a[n.id]
+                && n.metadata.stage == node_b.metadata.stage
+                && kind_name(&n.kind) == kind_name(&node_b.kind)
+                && ordinal(a, n) == position
+        }) {
+            used_a[node_a.id] = true;
+            pairs.push((node_a.id, node_b.id));
+        }
+    }
+
+    pairs
+}
+
+/// Fields of two environment fingerprints that differ.
+pub fn diff_fingerprints(a: &EnvFingerprint, b: &EnvFingerprint) -> Vec<EnvChange> {
+    let mut changes = Vec::new();
+    for (field, before, after) in [("os", &a.os, &b.os), ("arch", &a.arch, &b.arch)] {
+        if before != after {
+            changes.push(EnvChange {
+                field: field.to_string(),
+                before: Some(before.clone()),
+                after: Some(after.clone()),
+            });
+        }
+    }
+    for (section, before, after) in [
+        ("env_vars", &a.env_vars, &b.env_vars),
+        ("toolchain", &a.toolchain, &b.toolchain),
+    ] {
+        changes.extend(diff_maps(section, before, after));
+    }
+    changes
+}
+
+fn diff_maps(
+    section: &str,
+    a: &BTreeMap<String, String>,
+    b: &BTreeMap<String, String>,
+) -> Vec<EnvChange> {
+    let keys: BTreeSet<&String> = a.keys().chain(b.keys()).collect();
+    keys.into_iter()
+        .filter(|k| a.get(*k) != b.get(*k))
+        .map(|k| EnvChange {
+            field: format!("{}.{}", section, k),
+            before: a.get(k).cloned(),
+            after: b.get(k).cloned(),
+        })
+        .collect()
+}
+
+fn show(value: Option<&str>) -> String {
+    match value {
+        Some(v) => format!("{:?}", v),
+        None => "(unset)".to_string(),
+    }
+}
+
+/// A network policy as `--network` spells it
+fn network(policy: Option<&crate::sandbox::NetworkPolicy>) -> String {
+    match policy {
+        Some(policy) => policy.to_string(),
+        None => "(default)".to_string(),
+    }
+}
+
+fn short(hash: Option<&str>) -> String {
+    match hash {
+        Some(h) => h.chars().take(12).collect(),
+        None => "(none)".to_string(),
+    }
+}
@@ -1,45 +1,159 @@
-use crate::docker::parser::Instruction;
-use crate::graph::{BuildGraph, Node, NodeMetadata};
+use crate::docker::expand::{expand, expand_word};
+use crate::docker::parser::{default_shell, is_url, Instruction};
+use crate::graph::{BuildGraph, CopyOptions, Node, NodeMetadata, RunMount};
+use crate::sandbox::{NetworkPolicy, ResourceLimits};
 use std::collections::HashMap;
 use std::path::PathBuf;
 
 /// Convert a flat list of Dockerfile instructions into a dependency graph.
 /// Supports DAG construction with conditional branching and smart dependency tracking.
 ///
-/// Key features:
-/// - COPY nodes create dependencies on source files
-/// - RUN commands depend on preceding COPY operations for their sources
-/// - Multi-stage builds and conditional branching support
+/// Dependencies follow data flow rather than instruction order:
+/// - RUN (and RUN_EXTEND, HOOK, unknown instructions) reads and may change the whole
+///   filesystem, so it depends on everything written since the previous RUN, and on the
+///   ENV, ARG, WORKDIR, USER and SHELL nodes in effect
+/// - COPY, ADD, GIT and COPY_EXTEND only add files: they depend on the previous RUN (or the
+///   FROM), so consecutive copies are independent of each other. `--link` copies depend on
+///   nothing in their stage
+/// - ENV, ARG, WORKDIR, LABEL, CMD and other metadata-only instructions depend on the
+///   stage's FROM alone and never serialize filesystem work
+/// - Multi-stage builds: each FROM starts a new stage; `FROM <stage>` depends on the final
+///   nodes of that stage, `COPY --from=<stage>` and `RUN --mount=from=<stage>` on its
+///   final filesystem
 /// - Content-addressed identities for incremental builds
 pub fn build_graph_from_instructions(
     instructions: Vec<Instruction>,
     project_root: PathBuf,
+) -> BuildGraph {
+    build_graph_with_args(instructions, project_root, &HashMap::new())
+}
+
+/// Same as [`build_graph_from_instructions`], with `--build-arg` values.
+///
+/// `ARG`s declared before the first FROM are global and only visible to FROM lines until a
+/// stage redeclares them; `ARG`s inside a stage are visible until the next FROM. `$VAR`
+/// references in FROM, COPY, ADD, WORKDIR, ENV, USER, EXPOSE, LABEL, VOLUME, STOPSIGNAL, GIT
+/// and COPY_EXTEND arguments are expanded here, while RUN commands keep their text and
+/// receive the ARG and ENV values in scope through `Node::env`, as Docker does. Either way
+/// the values reach the cache key of the nodes that can observe them, and only those.
+pub fn build_graph_with_args(
+    instructions: Vec<Instruction>,
+    project_root: PathBuf,
+    build_args: &HashMap<String, String>,
 ) -> BuildGraph {
     let mut nodes: Vec<Node> = Vec::new();
-    let mut copy_sources: HashMap<String, usize> = HashMap::new(); // Track COPY operations by source
-    let mut env_vars: HashMap<String, String> = HashMap::new(); // Track environment variables
-    let mut _workdir: Option<String> = None; // Track current working directory
+    let mut global_args: HashMap<String, String> = HashMap::new(); // ARGs declared before any FROM
+    let mut stage_args: HashMap<String, String> = HashMap::new(); // ARGs declared in the current stage
+    let mut stage_states: Vec<StageState> = Vec::new(); // ENV and SHELL in effect in each stage
+    let mut stages: Vec<(Option<String>, Vec<usize>)> = Vec::new(); // (stage name, filesystem)
+    let mut arg_nodes: HashMap<String, usize> = HashMap::new(); // ARG nodes of the current stage
+    let mut stage_from: Option<usize> = None; // FROM node of the current stage
+    let mut barrier: Option<usize> = None; // Last node that may have changed any file
+    let mut filesystem: Vec<usize> = Vec::new(); // Barrier plus the files written since
+    let mut pending_limits: Option<ResourceLimits> = None; // Set by `# memobuild:limits`
+    let mut pending_network: Option<NetworkPolicy> = None; // Set by `# memobuild:network`
 
-    for (i, instr) in instructions.iter().enumerate() {
+    for instr in instructions.iter() {
+        // Directives produce no node; they apply to the next instruction
+        match instr {
+            Instruction::Limits(limits) => {
+                pending_limits = Some(limits.clone());
+                continue;
+            }
+            Instruction::Network(policy) => {
+                pending_network = Some(policy.clone());
+                continue;
+            }
+            _ => {}
+        }
+        let id = nodes.len();
         let name = format!("{:?}", instr);
         let mut env = std::collections::HashMap::new();
         let mut metadata = NodeMetadata::default();
 
+        if stages.is_empty() {
+            if let Instruction::Arg(arg, default) = instr {
+                // Global ARGs only parameterize FROM lines; they do not produce nodes
+                let value = build_args
+                    .get(arg)
+                    .cloned()
+                    .or_else(|| default.as_deref().map(|d| expand_word(d, &global_args)));
+                if let Some(value) = value {
+                    global_args.insert(arg.clone(), value);
+                }
+                continue;
+            }
+        }
+
+        if let Instruction::From(img, stage_name) = instr {
+            // A new stage starts: nothing chains across the FROM boundary
+            stage_args.clear();
+            arg_nodes.clear();
+            let img = expand(img, &global_args);
+            let inherited = match resolve_stage(&stages, &img) {
+                Some(stage) => stage_states[stage].inherit(),
+                None => StageState {
+                    base_image: (!img.eq_ignore_ascii_case("scratch")).then_some(img),
+                    ..Default::default()
+                },
+            };
+            stage_states.push(inherited);
+            stages.push((stage_name.clone(), vec![id]));
+        }
+        metadata.limits = pending_limits.take().unwrap_or_default();
+        metadata.network = pending_network.take();
+        metadata.stage = stages.len().saturating_sub(1);
+        metadata.stage_name = stages.last().and_then(|(name, _)| name.clone());
+
+        // Variables visible to this instruction: ENV takes precedence over ARG
+        let mut scope = stage_args.clone();
+        if let Some(state) = stage_states.last() {
+            scope.extend(state.env.clone());
+            metadata.shell = state.shell.clone();
+            metadata.workdir = state.workdir.clone();
+            metadata.base_image = state.base_image.clone();
+        }
+
+        let role = Role::of(instr);
+        let base_deps: Vec<usize> = match role {
+            Role::Barrier => {
+                let mut deps = filesystem.clone();
+                if let Some(state) = stage_states.last() {
+                    deps.extend(state.config_nodes());
+                }
+                deps.extend(arg_nodes.values().copied());
+                deps.sort_unstable();
+                deps
+            }
+            Role::Writer if is_linked(instr) => Vec::new(),
+            Role::Writer => barrier.into_iter().collect(),
+            Role::Metadata => stage_from.into_iter().collect(),
+        };
+
         let (content, source_path, kind, deps, _parallelizable) = match instr {
-            Instruction::From(img) => {
-                // FROM nodes have no dependencies (base image)
+            Instruction::From(img, _) => {
+                let img = expand(img, &global_args);
+                // FROM nodes have no dependencies unless they build on an earlier stage, whose
+                // filesystem and configuration they inherit
+                let deps = match resolve_stage(&stages[..stages.len() - 1], &img) {
+                    Some(stage) => stage_sinks(&nodes, stage),
+                    None => vec![],
+                };
                 (
                     format!("FROM {}", img),
                     None,
                     crate::graph::NodeKind::From,
-                    vec![],
+                    deps,
                     true, // FROM can be parallelized if multiple base images
                 )
             }
             Instruction::Workdir(dir) => {
-                _workdir = Some(dir.clone());
-                // WORKDIR depends on previous operations that might affect the filesystem
-                let deps = if i > 0 { vec![i - 1] } else { vec![] };
+                let dir = expand(dir, &scope);
+                if let Some(state) = stage_states.last_mut() {
+                    state.workdir = resolve_dst(&state.workdir, &dir);
+                    state.workdir_node = Some(id);
+                }
+                let deps = base_deps.clone();
                 metadata.parallelizable = true; // WORKDIR operations can be parallelized if independent
                 (
                     format!("WORKDIR {}", dir),
@@ -49,45 +163,154 @@ pub fn build_graph_from_instructions(
                     true,
                 )
             }
-            Instruction::Copy(src, dst) => {
-                let path = if src == "." {
-                    // Fix 3: COPY . . → hash entire project root
-                    project_root.clone()
+            Instruction::Copy(spec) => {
+                let sources: Vec<String> = spec.sources.iter().map(|s| expand(s, &scope)).collect();
+                let dst = expand(&spec.dst, &scope);
+                let workdir = stage_states
+                    .last()
+                    .map(|s| s.workdir.as_str())
+                    .unwrap_or("/");
+                let from = spec.from.as_deref().map(|f| expand(f, &scope));
+                let options = expand_options(&spec.options, &scope);
+                let mut deps = base_deps.clone();
+                let stage = from
+                    .as_deref()
+                    .and_then(|f| resolve_stage(&stages[..stages.len().saturating_sub(1)], f));
+
+                let path = if from.is_some() {
+                    // Sources come from another stage or image, not the build context
+                    None
                 } else {
-                    project_root.join(src)
+                    sources.first().map(|src| context_path(&project_root, src))
                 };
 
-                // Track this COPY operation for potential RUN dependencies
-                copy_sources.insert(src.clone(), i);
-
-                // COPY depends on previous filesystem operations
-                let deps = if i > 0 { vec![i - 1] } else { vec![] };
+                if let Some(stage) = stage {
+                    // COPY --from=<stage> needs that stage's final filesystem
+                    deps.extend(stages[stage].1.iter().copied());
+                    metadata.tags.push("copy-from".to_string());
+                }
 
                 metadata.parallelizable = true; // COPY operations can be parallelized
                 metadata.tags.push("copy".to_string());
+                if options.link {
+                    metadata.tags.push("link".to_string());
+                }
 
                 (
-                    format!("COPY {} {}", src, dst),
-                    Some(path),
+                    copy_content("COPY", from.as_deref(), None, &options, &sources, &dst),
+                    path,
                     crate::graph::NodeKind::Copy {
-                        src: PathBuf::from(src),
-                        dst: PathBuf::from(dst),
+                        sources: sources.iter().map(PathBuf::from).collect(),
+                        dst: PathBuf::from(resolve_dst(workdir, &dst)),
+                        from,
+                        options,
+                    },
+                    deps,
+                    true,
+                )
+            }
+            Instruction::Add(spec) => {
+                let sources: Vec<String> = spec.sources.iter().map(|s| expand(s, &scope)).collect();
+                let dst = expand(&spec.dst, &scope);
+                let workdir = stage_states
+                    .last()
+                    .map(|s| s.workdir.as_str())
+                    .unwrap_or("/");
+                let options = expand_options(&spec.options, &scope);
+                let deps = base_deps.clone();
+                metadata.parallelizable = true;
+                metadata.tags.push("add".to_string());
+                if options.link {
+                    metadata.tags.push("link".to_string());
+                }
+
+                let local: Vec<&String> = sources.iter().filter(|s| !is_url(s)).collect();
+                if local.len() < sources.len() {
+                    metadata.tags.push("remote".to_string());
+                }
+
+                (
+                    copy_content(
+                        "ADD",
+                        None,
+                        spec.checksum.as_deref(),
+                        &options,
+                        &sources,
+                        &dst,
+                    ),
+                    local.first().map(|src| context_path(&project_root, src)),
+                    crate::graph::NodeKind::Add {
+                        sources,
+                        dst: PathBuf::from(resolve_dst(workdir, &dst)),
+                        checksum: spec.checksum.clone(),
+                        options,
                     },
                     deps,
                     true,
                 )
             }
-            Instruction::Run(cmd) => {
-                // Analyze RUN command to determine dependencies
-                let mut deps = if i > 0 { vec![i - 1] } else { vec![] };
+            Instruction::CopyHeredoc(heredocs, dst, options) => {
+                // Inline files: the body is part of the instruction, so it is hashed with it
+                let dst = expand(dst, &scope);
+                let workdir = stage_states
+                    .last()
+                    .map(|s| s.workdir.as_str())
+                    .unwrap_or("/");
+                let options = expand_options(options, &scope);
+                let deps = base_deps.clone();
+                metadata.parallelizable = true;
+                metadata.tags.push("copy".to_string());
 
-                // Check if RUN command references files that were copied
-                for (src_path, copy_idx) in &copy_sources {
-                    if (cmd.contains(src_path) || cmd.contains(&format!("./{}", src_path)))
-                        && !deps.contains(copy_idx)
-                    {
-                        deps.push(*copy_idx);
+                let names: Vec<String> = heredocs.iter().map(|h| format!("<<{}", h.name)).collect();
+                let mut content = copy_content("COPY", None, None, &options, &names, &dst);
+                for doc in heredocs {
+                    content.push('\n');
+                    if doc.expand {
+                        content.push_str(&expand(&doc.content, &scope));
+                    } else {
+                        content.push_str(&doc.content);
                     }
+                    content.push_str(&doc.name);
+                }
+
+                (
+                    content,
+                    None,
+                    crate::graph::NodeKind::Copy {
+                        sources: names.iter().map(PathBuf::from).collect(),
+                        dst: PathBuf::from(resolve_dst(workdir, &dst)),
+                        from: None,
+                        options,
+                    },
+                    deps,
+                    true,
+                )
+            }
+            Instruction::Run(cmd, mounts) => {
+                let mut deps = base_deps.clone();
+                let workdir = stage_states
+                    .last()
+                    .map(|s| s.workdir.as_str())
+                    .unwrap_or("/");
+                let mounts: Vec<RunMount> = mounts
+                    .iter()
+                    .map(|m| expand_mount(m, &scope, workdir))
+                    .collect();
+
+                for mount in &mounts {
+                    if let RunMount::Bind { from: Some(f), .. } = mount {
+                        // A bind mount of another stage needs that stage's final filesystem
+                        if let Some(stage) =
+                            resolve_stage(&stages[..stages.len().saturating_sub(1)], f)
+                        {
+                            deps.extend(stages[stage].1.iter().copied());
+                        }
+                    }
+                }
+                deps.sort_unstable();
+                deps.dedup();
+                if !mounts.is_empty() {
+                    metadata.tags.push("mount".to_string());
                 }
 
                 // RUN commands that don't modify shared state can be parallelized
@@ -95,21 +318,28 @@ pub fn build_graph_from_instructions(
                     !cmd.contains("rm") && !cmd.contains("mv") && !cmd.contains("chmod");
                 metadata.parallelizable = is_parallelizable;
                 metadata.tags.push("run".to_string());
+                // The shell expands variables at run time, so it gets them through its env
+                env = scope.clone();
 
                 (
                     cmd.clone(),
                     None,
-                    crate::graph::NodeKind::Run,
+                    crate::graph::NodeKind::Run { mounts },
                     deps,
                     is_parallelizable,
                 )
             }
             Instruction::Env(key, value) => {
+                let key = &expand_word(key, &scope);
+                let value = expand_word(value, &scope);
                 env.insert(key.clone(), value.clone());
-                env_vars.insert(key.clone(), value.clone());
+                if let Some(state) = stage_states.last_mut() {
+                    state.env.insert(key.clone(), value.clone());
+                    state.env_nodes.insert(key.clone(), id);
+                }
 
                 // ENV operations can be parallelized if they don't conflict
-                let deps = if i > 0 { vec![i - 1] } else { vec![] };
+                let deps = base_deps.clone();
                 metadata.parallelizable = true;
                 metadata.tags.push("env".to_string());
 
@@ -121,21 +351,212 @@ pub fn build_graph_from_instructions(
                     true,
                 )
             }
+            Instruction::Arg(arg, default) => {
+                // A build arg wins over the stage default, which wins over the global one
+                let value = build_args
+                    .get(arg)
+                    .cloned()
+                    .or_else(|| default.as_deref().map(|d| expand_word(d, &scope)))
+                    .or_else(|| global_args.get(arg).cloned());
+                if let Some(value) = value {
+                    stage_args.insert(arg.clone(), value);
+                }
+                arg_nodes.insert(arg.clone(), id);
+
+                // The value is not part of the ARG node itself: like Docker, a changed build
+                // arg only invalidates the instructions that use it
+                let deps = base_deps.clone();
+                metadata.parallelizable = true;
+                metadata.tags.push("arg".to_string());
+
+                let content = match default {
+                    Some(d) => format!("ARG {}={}", arg, d),
+                    None => format!("ARG {}", arg),
+                };
+                (content, None, crate::graph::NodeKind::Arg, deps, true)
+            }
             Instruction::Cmd(cmd) => {
-                let deps = if i > 0 { vec![i - 1] } else { vec![] };
+                let deps = base_deps.clone();
                 metadata.parallelizable = true;
                 metadata.tags.push("cmd".to_string());
+                let shell = stage_states.last().cloned().unwrap_or_default().shell();
 
                 (
                     format!("CMD {}", cmd),
                     None,
-                    crate::graph::NodeKind::Cmd,
+                    crate::graph::NodeKind::Cmd {
+                        command: cmd.argv(&shell),
+                    },
+                    deps,
+                    true,
+                )
+            }
+            Instruction::Entrypoint(cmd) => {
+                let deps = base_deps.clone();
+                metadata.parallelizable = true;
+                metadata.tags.push("entrypoint".to_string());
+                let shell = stage_states.last().cloned().unwrap_or_default().shell();
+
+                (
+                    format!("ENTRYPOINT {}", cmd),
+                    None,
+                    crate::graph::NodeKind::Entrypoint {
+                        command: cmd.argv(&shell),
+                    },
+                    deps,
+                    true,
+                )
+            }
+            Instruction::User(user) => {
+                let user = expand(user, &scope);
+                if let Some(state) = stage_states.last_mut() {
+                    state.user_node = Some(id);
+                }
+                let deps = base_deps.clone();
+                metadata.parallelizable = true;
+                metadata.tags.push("user".to_string());
+
+                (
+                    format!("USER {}", user),
+                    None,
+                    crate::graph::NodeKind::User { user },
+                    deps,
+                    true,
+                )
+            }
+            Instruction::Expose(ports) => {
+                let ports: Vec<String> = ports.iter().map(|p| expand(p, &scope)).collect();
+                let deps = base_deps.clone();
+                metadata.parallelizable = true;
+                metadata.tags.push("expose".to_string());
+
+                (
+                    format!("EXPOSE {}", ports.join(" ")),
+                    None,
+                    crate::graph::NodeKind::Expose { ports },
+                    deps,
+                    true,
+                )
+            }
+            Instruction::Label(labels) => {
+                let labels: Vec<(String, String)> = labels
+                    .iter()
+                    .map(|(k, v)| (expand_word(k, &scope), expand_word(v, &scope)))
+                    .collect();
+                let deps = base_deps.clone();
+                metadata.parallelizable = true;
+                metadata.tags.push("label".to_string());
+
+                let pairs: Vec<String> = labels
+                    .iter()
+                    .map(|(k, v)| format!("{:?}={:?}", k, v))
+                    .collect();
+                (
+                    format!("LABEL {}", pairs.join(" ")),
+                    None,
+                    crate::graph::NodeKind::Label { labels },
+                    deps,
+                    true,
+                )
+            }
+            Instruction::Volume(paths) => {
+                let paths: Vec<String> = paths.iter().map(|p| expand_word(p, &scope)).collect();
+                let deps = base_deps.clone();
+                metadata.parallelizable = true;
+                metadata.tags.push("volume".to_string());
+
+                (
+                    format!("VOLUME {}", paths.join(" ")),
+                    None,
+                    crate::graph::NodeKind::Volume { paths },
+                    deps,
+                    true,
+                )
+            }
+            Instruction::Healthcheck(config) => {
+                let deps = base_deps.clone();
+                metadata.parallelizable = true;
+                metadata.tags.push("healthcheck".to_string());
+
+                let mut content = String::from("HEALTHCHECK");
+                for (flag, value) in [
+                    ("interval", &config.interval),
+                    ("timeout", &config.timeout),
+                    ("start-period", &config.start_period),
+                    ("start-interval", &config.start_interval),
+                ] {
+                    if let Some(value) = value {
+                        content.push_str(&format!(" --{}={}", flag, value));
+                    }
+                }
+                if let Some(retries) = config.retries {
+                    content.push_str(&format!(" --retries={}", retries));
+                }
+                content.push_str(&format!(" {:?}", config.test));
+
+                (
+                    content,
+                    None,
+                    crate::graph::NodeKind::Healthcheck {
+                        config: config.clone(),
+                    },
+                    deps,
+                    true,
+                )
+            }
+            Instruction::Shell(shell) => {
+                if let Some(state) = stage_states.last_mut() {
+                    state.shell = shell.clone();
+                    state.shell_node = Some(id);
+                }
+                let deps = base_deps.clone();
+                metadata.parallelizable = true;
+                metadata.tags.push("shell".to_string());
+
+                (
+                    format!("SHELL {:?}", shell),
+                    None,
+                    crate::graph::NodeKind::Shell {
+                        shell: shell.clone(),
+                    },
+                    deps,
+                    true,
+                )
+            }
+            Instruction::Stopsignal(signal) => {
+                let signal = expand(signal, &scope);
+                let deps = base_deps.clone();
+                metadata.parallelizable = true;
+                metadata.tags.push("stopsignal".to_string());
+
+                (
+                    format!("STOPSIGNAL {}", signal),
+                    None,
+                    crate::graph::NodeKind::Stopsignal { signal },
+                    deps,
+                    true,
+                )
+            }
+            Instruction::Onbuild(trigger) => {
+                // Triggers run in downstream builds, so they are recorded but not expanded
+                let deps = base_deps.clone();
+                metadata.parallelizable = true;
+                metadata.tags.push("onbuild".to_string());
+
+                (
+                    format!("ONBUILD {}", trigger),
+                    None,
+                    crate::graph::NodeKind::Onbuild {
+                        trigger: trigger.clone(),
+                    },
                     deps,
                     true,
                 )
             }
             Instruction::Git(url, target) => {
-                let deps = if i > 0 { vec![i - 1] } else { vec![] };
+                let url = expand(url, &scope);
+                let target = expand(target, &scope);
+                let deps = base_deps.clone();
                 metadata.parallelizable = true;
                 metadata.tags.push("git".to_string());
 
@@ -143,7 +564,7 @@ pub fn build_graph_from_instructions(
                     format!("GIT {} {}", url, target),
                     None,
                     crate::graph::NodeKind::Git {
-                        url: url.clone(),
+                        url,
                         target: PathBuf::from(target),
                     },
                     deps,
@@ -151,10 +572,11 @@ pub fn build_graph_from_instructions(
                 )
             }
             Instruction::RunExtend(cmd, parallelizable) => {
-                let deps = if i > 0 { vec![i - 1] } else { vec![] };
+                let deps = base_deps.clone();
                 metadata.parallelizable = *parallelizable;
                 metadata.tags.push("extension".to_string());
                 metadata.tags.push("run-extend".to_string());
+                env = scope.clone();
 
                 (
                     cmd.clone(),
@@ -168,7 +590,9 @@ pub fn build_graph_from_instructions(
                 )
             }
             Instruction::CopyExtend(src, dst, tags) => {
-                let deps = if i > 0 { vec![i - 1] } else { vec![] };
+                let src = expand(src, &scope);
+                let dst = expand(dst, &scope);
+                let deps = base_deps.clone();
                 metadata.parallelizable = true;
                 metadata.tags.extend(tags.clone());
                 metadata.tags.push("extension".to_string());
@@ -176,7 +600,7 @@ pub fn build_graph_from_instructions(
                 let path = if src == "." {
                     project_root.clone()
                 } else {
-                    project_root.join(src)
+                    project_root.join(&src)
                 };
 
                 (
@@ -192,9 +616,10 @@ pub fn build_graph_from_instructions(
                 )
             }
             Instruction::Hook(name, params) => {
-                let deps = if i > 0 { vec![i - 1] } else { vec![] };
+                let deps = base_deps.clone();
                 metadata.parallelizable = false; // Hooks execute sequentially by default
                 metadata.tags.push("hook".to_string());
+                env = scope.clone();
 
                 (
                     format!("HOOK {} {:?}", name, params),
@@ -207,8 +632,11 @@ pub fn build_graph_from_instructions(
                     false,
                 )
             }
+            Instruction::Limits(_) | Instruction::Network(_) => {
+                unreachable!("directives do not produce nodes")
+            }
             Instruction::Other(s) => {
-                let deps = if i > 0 { vec![i - 1] } else { vec![] };
+                let deps = base_deps.clone();
                 metadata.tags.push("other".to_string());
 
                 (
@@ -222,7 +650,7 @@ pub fn build_graph_from_instructions(
         };
 
         let node = Node {
-            id: i,
+            id,
             name,
             content,
             kind,
@@ -236,7 +664,349 @@ pub fn build_graph_from_instructions(
         };
 
         nodes.push(node);
+        match instr {
+            Instruction::From(..) => {
+                stage_from = Some(id);
+                barrier = Some(id);
+                filesystem = vec![id];
+            }
+            _ => match role {
+                Role::Barrier => {
+                    barrier = Some(id);
+                    filesystem = vec![id];
+                }
+                Role::Writer => filesystem.push(id),
+                Role::Metadata => {}
+            },
+        }
+        if let Some(stage) = stages.last_mut() {
+            stage.1 = filesystem.clone();
+        }
+    }
+
+    BuildGraph {
+        nodes,
+        ignore_file: None,
+    }
+}
+
+/// Resolve a WORKDIR or COPY/ADD destination against the current WORKDIR. A trailing `/`
+/// (or `.`) marks a directory destination and is kept.
+fn resolve_dst(workdir: &str, dst: &str) -> String {
+    let base = if workdir.is_empty() { "/" } else { workdir };
+    let mut parts: Vec<&str> = Vec::new();
+    let joined = if dst.starts_with('/') {
+        dst.to_string()
+    } else {
+        format!("{}/{}", base, dst)
+    };
+    for part in joined.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+
+    let mut resolved = format!("/{}", parts.join("/"));
+    let is_dir = dst.ends_with('/') || dst == "." || dst.ends_with("/.");
+    if is_dir && !resolved.ends_with('/') {
+        resolved.push('/');
+    }
+    resolved
+}
+
+/// Absolute path of a COPY/ADD source in the build context.
+fn context_path(project_root: &std::path::Path, src: &str) -> PathBuf {
+    if src == "." {
+        // Fix 3: COPY . . → hash entire project root
+        project_root.to_path_buf()
+    } else {
+        project_root.join(src)
+    }
+}
+
+/// `--chown`, `--chmod` and `--exclude` values may reference ARG and ENV variables.
+fn expand_options(options: &CopyOptions, scope: &HashMap<String, String>) -> CopyOptions {
+    CopyOptions {
+        chown: options.chown.as_deref().map(|c| expand(c, scope)),
+        chmod: options.chmod.as_deref().map(|c| expand(c, scope)),
+        link: options.link,
+        exclude: options.exclude.iter().map(|e| expand(e, scope)).collect(),
+    }
+}
+
+/// Mount options may reference ARG and ENV variables; targets are resolved against WORKDIR.
+fn expand_mount(mount: &RunMount, scope: &HashMap<String, String>, workdir: &str) -> RunMount {
+    let text = |value: &str| expand(value, scope);
+    let target = |path: &std::path::Path| {
+        PathBuf::from(resolve_dst(workdir, &text(&path.to_string_lossy())))
+    };
+    match mount {
+        RunMount::Bind {
+            target: t,
+            source,
+            from,
+            readwrite,
+        } => RunMount::Bind {
+            target: target(t),
+            source: source.as_deref().map(text),
+            from: from.as_deref().map(text),
+            readwrite: *readwrite,
+        },
+        RunMount::Cache {
+            target: t,
+            id,
+            sharing,
+            readonly,
+        } => RunMount::Cache {
+            target: target(t),
+            id: text(id),
+            sharing: *sharing,
+            readonly: *readonly,
+        },
+        RunMount::Tmpfs { target: t, size } => RunMount::Tmpfs {
+            target: target(t),
+            size: size.clone(),
+        },
+        RunMount::Secret {
+            id,
+            target: t,
+            env,
+            required,
+        } => RunMount::Secret {
+            id: text(id),
+            target: t.as_deref().map(target),
+            env: env.clone(),
+            required: *required,
+        },
+        RunMount::Ssh {
+            id,
+            target: t,
+            required,
+        } => RunMount::Ssh {
+            id: text(id),
+            target: target(t),
+            required: *required,
+        },
+    }
+}
+
+/// Instruction text of a COPY/ADD node, including every flag that changes its result.
+fn copy_content(
+    keyword: &str,
+    from: Option<&str>,
+    checksum: Option<&str>,
+    options: &CopyOptions,
+    sources: &[String],
+    dst: &str,
+) -> String {
+    let mut parts = vec![keyword.to_string()];
+    if let Some(from) = from {
+        parts.push(format!("--from={}", from));
+    }
+    if let Some(checksum) = checksum {
+        parts.push(format!("--checksum={}", checksum));
+    }
+    if let Some(chown) = &options.chown {
+        parts.push(format!("--chown={}", chown));
+    }
+    if let Some(chmod) = &options.chmod {
+        parts.push(format!("--chmod={}", chmod));
+    }
+    if options.link {
+        parts.push("--link".to_string());
+    }
+    for pattern in &options.exclude {
+        parts.push(format!("--exclude={}", pattern));
+    }
+    parts.extend(sources.iter().cloned());
+    parts.push(dst.to_string());
+    parts.join(" ")
+}
+
+/// How an instruction interacts with the filesystem of its stage.
+#[derive(Clone, Copy, PartialEq)]
+enum Role {
+    /// Reads and may change any file: depends on all prior filesystem work
+    Barrier,
+    /// Only adds files: depends on the last barrier
+    Writer,
+    /// Only changes image configuration: depends on the stage's FROM
+    Metadata,
+}
+
+impl Role {
+    fn of(instr: &Instruction) -> Self {
+        match instr {
+            Instruction::Run(..)
+            | Instruction::RunExtend(..)
+            | Instruction::Hook(..)
+            | Instruction::Other(_) => Role::Barrier,
+            Instruction::Copy(_)
+            | Instruction::Add(_)
+            | Instruction::CopyHeredoc(..)
+            | Instruction::Git(..)
+            | Instruction::CopyExtend(..) => Role::Writer,
+            _ => Role::Metadata,
+        }
+    }
+}
+
+/// Nodes of `stage` that no other node of the stage depends on: together they are
+/// everything the stage produced.
+fn stage_sinks(nodes: &[Node], stage: usize) -> Vec<usize> {
+    let in_stage: Vec<&Node> = nodes.iter().filter(|n| n.metadata.stage == stage).collect();
+    in_stage
+        .iter()
+        .filter(|n| !in_stage.iter().any(|other| other.deps.contains(&n.id)))
+        .map(|n| n.id)
+        .collect()
+}
+
+/// `COPY --link` / `ADD --link` layers do not depend on the filesystem below them.
+fn is_linked(instr: &Instruction) -> bool {
+    match instr {
+        Instruction::Copy(spec) | Instruction::Add(spec) => spec.options.link,
+        Instruction::CopyHeredoc(_, _, options) => options.link,
+        _ => false,
+    }
+}
+
+/// Image settings a stage passes on to the stages built `FROM` it.
+#[derive(Clone, Default)]
+struct StageState {
+    /// Image at the root of the stage, None for `scratch`
+    base_image: Option<String>,
+    env: HashMap<String, String>,
+    /// `SHELL` in effect; empty means the default shell
+    shell: Vec<String>,
+    /// Absolute WORKDIR; empty means `/`
+    workdir: String,
+    /// Nodes of this stage that set the configuration a RUN executes with
+    env_nodes: HashMap<String, usize>,
+    workdir_node: Option<usize>,
+    user_node: Option<usize>,
+    shell_node: Option<usize>,
+}
+
+impl StageState {
+    /// Settings for a stage built `FROM` this one. Its FROM node already depends on the
+    /// nodes that set them.
+    fn inherit(&self) -> Self {
+        StageState {
+            base_image: self.base_image.clone(),
+            env: self.env.clone(),
+            shell: self.shell.clone(),
+            workdir: self.workdir.clone(),
+            ..Default::default()
+        }
+    }
+
+    fn config_nodes(&self) -> impl Iterator<Item = usize> + '_ {
+        self.env_nodes
+            .values()
+            .copied()
+            .chain(self.workdir_node)
+            .chain(self.user_node)
+            .chain(self.shell_node)
+    }
+
+    fn shell(&self) -> Vec<String> {
+        if self.shell.is_empty() {
+            default_shell()
+        } else {
+            self.shell.clone()
+        }
+    }
+}
+
+/// Find a stage by `AS` name (case-insensitive, as in Docker) or by numeric index.
+fn resolve_stage<T>(stages: &[(Option<String>, T)], reference: &str) -> Option<usize> {
+    stages
+        .iter()
+        .position(|(name, _)| {
+            name.as_deref()
+                .is_some_and(|n| n.eq_ignore_ascii_case(reference))
+        })
+        .or_else(|| {
+            reference
+                .parse::<usize>()
+                .ok()
+                .filter(|&s| s < stages.len())
+        })
+}
+
+/// Index of the stage named `target` (or with that numeric index) in a built graph.
+pub fn find_stage(graph: &BuildGraph, target: &str) -> Option<usize> {
+    graph
+        .nodes
+        .iter()
+        .find(|n| {
+            n.metadata
+                .stage_name
+                .as_deref()
+                .is_some_and(|name| name.eq_ignore_ascii_case(target))
+        })
+        .map(|n| n.metadata.stage)
+        .or_else(|| {
+            let stage = target.parse::<usize>().ok()?;
+            graph
+                .nodes
+                .iter()
+                .any(|n| n.metadata.stage == stage)
+                .then_some(stage)
+        })
+}
+
+/// Prune the graph to the stages needed to build `target` (`--target`), dropping
+/// every stage the target does not depend on.
+pub fn prune_to_target(graph: &BuildGraph, target: &str) -> anyhow::Result<BuildGraph> {
+    let stage = find_stage(graph, target)
+        .ok_or_else(|| anyhow::anyhow!("Target stage '{}' not found in Dockerfile", target))?;
+    // Every node of the stage is a root: `--link` copies are not ancestors of the last node
+    let roots: Vec<usize> = graph
+        .nodes
+        .iter()
+        .filter(|n| n.metadata.stage == stage)
+        .map(|n| n.id)
+        .collect();
+    if roots.is_empty() {
+        anyhow::bail!("Target stage '{}' has no instructions", target);
+    }
+
+    Ok(graph.subgraph(&graph.ancestors(&roots)))
+}
+
+/// Nodes that make up the final image: the last stage plus the stages it is based on
+/// through `FROM <stage>`. Stages only used as `COPY --from` sources are excluded.
+pub fn image_nodes(graph: &BuildGraph) -> Vec<usize> {
+    let Some(last) = graph.nodes.last() else {
+        return Vec::new();
+    };
+
+    let mut stages = vec![last.metadata.stage];
+    let mut stage = last.metadata.stage;
+    while let Some(from) = graph
+        .nodes
+        .iter()
+        .find(|n| n.metadata.stage == stage && matches!(n.kind, crate::graph::NodeKind::From))
+    {
+        match from.deps.first() {
+            Some(&base) if graph.nodes[base].metadata.stage != stage => {
+                stage = graph.nodes[base].metadata.stage;
+                stages.push(stage);
+            }
+            _ => break,
+        }
     }
 
-    BuildGraph { nodes }
+    graph
+        .nodes
+        .iter()
+        .filter(|n| stages.contains(&n.metadata.stage))
+        .map(|n| n.id)
+        .collect()
 }
@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+
+/// Expand variable references in an instruction argument, following Docker's rules:
+///
+/// - `$VAR` and `${VAR}` are replaced by the value, or by nothing when unset
+/// - `${VAR:-word}` / `${VAR-word}` use `word` when VAR is unset or empty / unset
+/// - `${VAR:+word}` / `${VAR+word}` use `word` when VAR is set and non-empty / set
+/// - `\$` is a literal dollar sign, and nothing inside single quotes is expanded
+///
+/// `word` is itself expanded. Anything that is not a valid reference is kept as written.
+pub fn expand(input: &str, vars: &HashMap<String, String>) -> String {
+    expand_with(input, vars, false)
+}
+
+/// Expand a word of ENV, ARG, LABEL or VOLUME as written in the Dockerfile, and remove its
+/// quotes and escapes in the same pass: quoting decides what is expanded, so it cannot be
+/// removed first. Outside quotes a backslash escapes any character; inside double quotes
+/// only `"`, `\` and `$`.
+pub fn expand_word(input: &str, vars: &HashMap<String, String>) -> String {
+    expand_with(input, vars, true)
+}
+
+fn expand_with(input: &str, vars: &HashMap<String, String>, unquote: bool) -> String {
+    let chars: Vec<char> = input.chars().collect();
+    let mut out = String::with_capacity(input.len());
+    let mut single_quoted = false;
+    let mut double_quoted = false;
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            '\'' if !double_quoted => {
+                single_quoted = !single_quoted;
+                if !unquote {
+                    out.push(c);
+                }
+                i += 1;
+            }
+            '"' if !single_quoted => {
+                double_quoted = !double_quoted;
+                if !unquote {
+                    out.push(c);
+                }
+                i += 1;
+            }
+            '\\' if !single_quoted && chars.get(i + 1) == Some(&'$') => {
+                out.push('$');
+                i += 2;
+            }
+            '\\' if unquote && !single_quoted => match chars.get(i + 1) {
+                Some(&next) if !double_quoted || matches!(next, '"' | '\\') => {
+                    out.push(next);
+                    i += 2;
+                }
+                _ => {
+                    out.push(c);
+                    i += 1;
+                }
+            },
+            '$' if !single_quoted => match substitute(&chars[i + 1..], vars) {
+                Some((value, consumed)) => {
+                    out.push_str(&value);
+                    i += 1 + consumed;
+                }
+                None => {
+                    out.push(c);
+                    i += 1;
+                }
+            },
+            _ => {
+                out.push(c);
+                i += 1;
+            }
+        }
+    }
+
+    out
+}
+
+/// Resolve the reference following a `$`. Returns the value and the number of characters
+/// consumed, or `None` when the text is not a variable reference.
+fn substitute(rest: &[char], vars: &HashMap<String, String>) -> Option<(String, usize)> {
+    if rest.first() == Some(&'{') {
+        let close = matching_brace(rest)?;
+        let inner: String = rest[1..close].iter().collect();
+        let name_len = inner
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(inner.len());
+        if name_len == 0 {
+            return None;
+        }
+
+        let (name, modifier) = inner.split_at(name_len);
+        let value = vars.get(name);
+        let set_and_non_empty = value.is_some_and(|v| !v.is_empty());
+
+        let result = if modifier.is_empty() {
+            value.cloned().unwrap_or_default()
+        } else if let Some(word) = modifier.strip_prefix(":-") {
+            if set_and_non_empty {
+                value.cloned().unwrap_or_default()
+            } else {
+                expand(word, vars)
+            }
+        } else if let Some(word) = modifier.strip_prefix(":+") {
+            if set_and_non_empty {
+                expand(word, vars)
+            } else {
+                String::new()
+            }
+        } else if let Some(word) = modifier.strip_prefix('-') {
+            match value {
+                Some(v) => v.clone(),
+                None => expand(word, vars),
+            }
+        } else if let Some(word) = modifier.strip_prefix('+') {
+            match value {
+                Some(_) => expand(word, vars),
+                None => String::new(),
+            }
+        } else {
+            return None;
+        };
+
+        return Some((result, close + 1));
+    }
+
+    let first = rest.first()?;
+    if !(first.is_ascii_alphabetic() || *first == '_') {
+        return None;
+    }
+    let len = rest
+        .iter()
+        .position(|c| !(c.is_ascii_alphanumeric() || *c == '_'))
+        .unwrap_or(rest.len());
+    let name: String = rest[..len].iter().collect();
+    Some((vars.get(&name).cloned().unwrap_or_default(), len))
+}
+
+/// Index of the `}` closing the `{` at the start of `rest`, allowing nested references.
+fn matching_brace(rest: &[char]) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in rest.iter().enumerate() {
+        match c {
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
@@ -0,0 +1,129 @@
+//! `memobuild lint`: parser diagnostics plus patterns that defeat layer caching.
+
+use crate::docker::parser::{
+    is_url, parse_dockerfile_with_diagnostics, Diagnostic, Instruction, Span,
+};
+
+/// Commands that install dependencies; they should only depend on dependency manifests.
+const INSTALL_COMMANDS: &[&str] = &[
+    "npm install",
+    "npm ci",
+    "yarn install",
+    "pnpm install",
+    "pip install",
+    "pip3 install",
+    "poetry install",
+    "pipenv install",
+    "bundle install",
+    "composer install",
+    "go mod download",
+    "cargo fetch",
+    "cargo build",
+    "mvn dependency:",
+    "gradle dependencies",
+    "apt-get install",
+    "apk add",
+];
+
+/// Lint a Dockerfile. Diagnostics are sorted by line.
+pub fn lint_dockerfile(content: &str) -> Vec<Diagnostic> {
+    let parsed = parse_dockerfile_with_diagnostics(content);
+    let mut diagnostics = parsed.diagnostics.clone();
+    let instructions: Vec<(&Instruction, Span)> = parsed
+        .instructions
+        .iter()
+        .zip(parsed.spans.iter().copied())
+        .collect();
+
+    let mut stage_names: Vec<String> = Vec::new();
+    // Whole-context COPY in the current stage not yet followed by an install
+    let mut context_copies: Vec<Span> = Vec::new();
+
+    for (instruction, span) in instructions {
+        match instruction {
+            Instruction::From(image, stage) => {
+                context_copies.clear();
+                if is_unpinned(image, &stage_names) {
+                    diagnostics.push(Diagnostic::warning(
+                        span,
+                        "unpinned-base-image",
+                        format!(
+                            "Base image '{}' is not pinned to a version; its content can change \
+                             under the same name and silently invalidate the cache",
+                            image
+                        ),
+                    ));
+                }
+                if let Some(name) = stage {
+                    stage_names.push(name.to_lowercase());
+                }
+            }
+            Instruction::Copy(spec)
+                if spec.from.is_none()
+                    && spec
+                        .sources
+                        .iter()
+                        .any(|s| matches!(s.as_str(), "." | "./")) =>
+            {
+                context_copies.push(span);
+            }
+            Instruction::Add(spec)
+                if spec.checksum.is_none() && spec.sources.iter().any(|s| is_url(s)) =>
+            {
+                diagnostics.push(Diagnostic::warning(
+                    span,
+                    "add-url-without-checksum",
+                    "ADD of a URL without --checksum cannot tell when the download changes",
+                ));
+            }
+            Instruction::Run(cmd, _) => {
+                if let Some(install) = INSTALL_COMMANDS.iter().find(|c| cmd.contains(*c)) {
+                    for copy in context_copies.drain(..) {
+                        diagnostics.push(Diagnostic::warning(
+                            copy,
+                            "copy-context-before-install",
+                            format!(
+                                "COPY of the whole context before `{}` on line {} reruns the \
+                                 install on every source change; copy the dependency manifests \
+                                 first",
+                                install, span.line
+                            ),
+                        ));
+                    }
+                }
+                let updates = cmd.contains("apt-get update") || cmd.contains("apt update");
+                let installs = cmd.contains("apt-get install") || cmd.contains("apt install");
+                if updates && !installs {
+                    diagnostics.push(Diagnostic::warning(
+                        span,
+                        "apt-get-update-without-install",
+                        "apt-get update in its own layer stays cached while later installs use \
+                         stale package lists; run it in the same RUN as apt-get install",
+                    ));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    diagnostics.sort_by_key(|d| (d.span.line, d.span.column));
+    diagnostics
+}
+
+/// A base image without a tag or digest, or tagged `latest`. Earlier stages, `scratch` and
+/// images chosen through build arguments are exempt.
+fn is_unpinned(image: &str, stage_names: &[String]) -> bool {
+    if image.contains('$')
+        || image.contains('@')
+        || image.eq_ignore_ascii_case("scratch")
+        || stage_names.contains(&image.to_lowercase())
+    {
+        return false;
+    }
+    // The tag follows the last `:` after the last `/`, so a registry port is not a tag
+    let name = image.rsplit('/').next().unwrap_or(image);
+    match name.split_once(':') {
+        Some((_, tag)) => tag == "latest",
+        None => true,
+    }
+}
@@ -1,3 +1,5 @@
 pub mod dag;
+pub mod expand;
 pub mod extensions;
+pub mod lint;
 pub mod parser;
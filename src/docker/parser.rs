@@ -1,25 +1,647 @@
+use crate::graph::{CacheSharing, CopyOptions, HealthcheckConfig, RunMount};
+use crate::sandbox::{NetworkPolicy, ResourceLimits};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
 #[derive(Debug, Clone)]
 pub enum Instruction {
-    From(String),
+    From(String, Option<String>), // (image, stage name)
     Workdir(String),
-    Copy(String, String),
-    Run(String),
-    Env(String, String),
-    Cmd(String),
+    Copy(CopySpec),
+    CopyHeredoc(Vec<Heredoc>, String, CopyOptions), // (inline files, dst, flags)
+    Add(CopySpec),
+    Run(String, Vec<RunMount>), // (shell text or JSON exec form, --mount flags)
+    Env(String, String),        // (key, value) as written, with quotes and escapes
+    Arg(String, Option<String>), // (name, default value as written)
+    Cmd(CommandForm),
+    Entrypoint(CommandForm),
+    User(String),
+    Expose(Vec<String>),          // port[/protocol] entries
+    Label(Vec<(String, String)>), // (key, value) pairs as written
+    Volume(Vec<String>),          // mount points as written
+    Healthcheck(HealthcheckConfig),
+    Shell(Vec<String>), // shell executable and its flags
+    Stopsignal(String),
+    Onbuild(String),                         // trigger instruction
     Git(String, String),                     // (url, target_dir)
     RunExtend(String, bool),                 // (command, parallelizable)
     CopyExtend(String, String, Vec<String>), // (src, dst, tags)
     Hook(String, Vec<String>),               // (hook_name, params)
+    Limits(ResourceLimits),                  // `# memobuild:limits` for the next instruction
+    Network(NetworkPolicy),                  // `# memobuild:network` for the next instruction
     Other(String),
 }
 
-pub fn parse_dockerfile(content: &str) -> Vec<Instruction> {
-    let mut instructions = Vec::new();
+/// Sources, destination and flags of a COPY or ADD instruction.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CopySpec {
+    /// Source paths, or URLs for ADD, as written
+    pub sources: Vec<String>,
+    pub dst: String,
+    /// `--from=<stage|image>` (COPY only)
+    pub from: Option<String>,
+    /// `--checksum=<algorithm>:<hex>` (ADD only)
+    pub checksum: Option<String>,
+    pub options: CopyOptions,
+}
+
+/// ADD sources that are fetched over HTTP(S) rather than read from the context.
+pub fn is_url(source: &str) -> bool {
+    source.starts_with("http://") || source.starts_with("https://")
+}
+
+/// Exec form (`["executable", "arg"]`) or shell form (`executable arg`) of RUN, CMD and
+/// ENTRYPOINT.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CommandForm {
+    Exec(Vec<String>),
+    Shell(String),
+}
+
+impl CommandForm {
+    /// A JSON array of strings is exec form; anything else, including malformed JSON, is
+    /// shell form, as in Docker.
+    pub fn parse(args: &str) -> Self {
+        let args = args.trim();
+        if args.starts_with('[') {
+            if let Ok(argv) = serde_json::from_str::<Vec<String>>(args) {
+                return CommandForm::Exec(argv);
+            }
+        }
+        CommandForm::Shell(args.to_string())
+    }
+
+    /// Argument vector to execute, running shell form through `shell`.
+    pub fn argv(&self, shell: &[String]) -> Vec<String> {
+        match self {
+            CommandForm::Exec(argv) => argv.clone(),
+            CommandForm::Shell(cmd) => {
+                let mut argv = shell.to_vec();
+                argv.push(cmd.clone());
+                argv
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for CommandForm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommandForm::Exec(argv) => {
+                write!(f, "{}", serde_json::to_string(argv).unwrap_or_default())
+            }
+            CommandForm::Shell(cmd) => write!(f, "{}", cmd),
+        }
+    }
+}
+
+/// Shell used for shell-form commands until a `SHELL` instruction changes it.
+pub fn default_shell() -> Vec<String> {
+    vec!["/bin/sh".to_string(), "-c".to_string()]
+}
+
+/// A BuildKit heredoc (`<<EOF ... EOF`) attached to a RUN or COPY instruction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Heredoc {
+    /// Delimiter word, e.g. `EOF`
+    pub name: String,
+    /// Body lines, each terminated by a newline
+    pub content: String,
+    /// False when the delimiter was quoted, which disables variable expansion in the body
+    pub expand: bool,
+}
+
+/// One instruction after continuation lines are joined and heredoc bodies collected.
+#[derive(Debug, Clone)]
+pub struct LogicalLine {
+    /// 1-based line number of the first physical line
+    pub line: usize,
+    /// 1-based column of the instruction keyword
+    pub column: usize,
+    /// Last physical line, including continuations and heredoc bodies
+    pub end_line: usize,
+    pub text: String,
+    pub heredocs: Vec<Heredoc>,
+}
+
+/// Location of an instruction in the Dockerfile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Span {
+    /// 1-based line of the instruction keyword
+    pub line: usize,
+    /// 1-based column of the instruction keyword
+    pub column: usize,
+    /// Last line of the instruction, including continuations and heredocs
+    pub end_line: usize,
+}
+
+impl From<&LogicalLine> for Span {
+    fn from(logical: &LogicalLine) -> Self {
+        Span {
+            line: logical.line,
+            column: logical.column,
+            end_line: logical.end_line,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A problem found in a Dockerfile by the parser or by `memobuild lint`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Stable rule identifier, e.g. `missing-arguments`
+    pub code: String,
+    pub message: String,
+    #[serde(flatten)]
+    pub span: Span,
+}
+
+impl Diagnostic {
+    pub fn error(span: Span, code: &str, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Error,
+            code: code.to_string(),
+            message: message.into(),
+            span,
+        }
+    }
+
+    pub fn warning(span: Span, code: &str, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Warning,
+            ..Self::error(span, code, message)
+        }
+    }
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(
+            f,
+            "{}:{}: {}[{}]: {}",
+            self.span.line, self.span.column, severity, self.code, self.message
+        )
+    }
+}
+
+/// Instructions of a Dockerfile with the span of each and the problems found while parsing.
+#[derive(Debug, Clone, Default)]
+pub struct ParsedDockerfile {
+    pub instructions: Vec<Instruction>,
+    /// `spans[i]` locates `instructions[i]`
+    pub spans: Vec<Span>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl ParsedDockerfile {
+    pub fn has_errors(&self) -> bool {
+        self.diagnostics
+            .iter()
+            .any(|d| d.severity == Severity::Error)
+    }
+}
+
+/// Default escape character, overridable with the `# escape=` parser directive.
+pub const DEFAULT_ESCAPE: char = '\\';
+
+/// Read the `# escape=` parser directive. Directives are only honoured at the very top of
+/// the file; the first comment that is not a directive, empty line or instruction ends them.
+pub fn escape_directive(content: &str) -> char {
+    let directive = regex::Regex::new(r"^#\s*([A-Za-z][A-Za-z0-9_]*)\s*=\s*(\S+)\s*$").unwrap();
 
     for line in content.lines() {
-        let line = line.trim();
+        let Some(caps) = directive.captures(line.trim()) else {
+            break;
+        };
+        if caps[1].eq_ignore_ascii_case("escape") {
+            match &caps[2] {
+                "`" => return '`',
+                _ => return DEFAULT_ESCAPE,
+            }
+        }
+    }
+
+    DEFAULT_ESCAPE
+}
+
+/// Comment directives addressed to memobuild, e.g. `# memobuild:limits memory=512m`. They
+/// apply to the instruction that follows and are ignored by Docker.
+fn memobuild_directive() -> regex::Regex {
+    regex::Regex::new(r"^#\s*memobuild:([A-Za-z-]*)\s*(.*)$").unwrap()
+}
+
+/// Split a Dockerfile into logical lines: comments and blank lines are dropped, lines ending
+/// in the escape character are joined with the next one, and heredoc bodies following
+/// RUN/COPY/ADD instructions are attached to the instruction they belong to. Memobuild
+/// directive comments are kept as lines of their own.
+pub fn logical_lines(content: &str) -> Vec<LogicalLine> {
+    let escape = escape_directive(content);
+    let directive = memobuild_directive();
+    let heredoc_marker =
+        regex::Regex::new(r#"<<(-?)(["']?)([A-Za-z_][A-Za-z0-9_]*)(["']?)"#).unwrap();
 
-        if line.is_empty() || line.starts_with('#') {
+    let physical: Vec<&str> = content.lines().collect();
+    let mut result = Vec::new();
+    let mut i = 0;
+
+    while i < physical.len() {
+        let raw_first = physical[i];
+        let first = raw_first.trim();
+        i += 1;
+        let column = raw_first.chars().take_while(|c| c.is_whitespace()).count() + 1;
+        if directive.is_match(first) {
+            result.push(LogicalLine {
+                line: i,
+                column,
+                end_line: i,
+                text: first.to_string(),
+                heredocs: Vec::new(),
+            });
+            continue;
+        }
+        if first.is_empty() || first.starts_with('#') {
+            continue;
+        }
+
+        let start_line = i;
+        let (mut text, mut continued) = strip_continuation(first, escape);
+
+        while continued && i < physical.len() {
+            let next = physical[i];
+            i += 1;
+            // Comments and empty lines inside a continuation are skipped, as Docker does
+            let trimmed = next.trim();
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                continue;
+            }
+            let (part, more) = strip_continuation(next, escape);
+            text.push_str(&part);
+            continued = more;
+        }
+
+        let keyword = text.split_whitespace().next().unwrap_or("").to_uppercase();
+        let mut heredocs = Vec::new();
+
+        if matches!(keyword.as_str(), "RUN" | "COPY" | "ADD") {
+            for caps in heredoc_marker.captures_iter(&text) {
+                if caps[2] != caps[4] {
+                    continue;
+                }
+                let name = caps[3].to_string();
+                let strip_tabs = &caps[1] == "-";
+                let mut body = String::new();
+
+                while i < physical.len() {
+                    let raw = physical[i];
+                    i += 1;
+                    let line = if strip_tabs {
+                        raw.trim_start_matches('\t')
+                    } else {
+                        raw
+                    };
+                    if line == name {
+                        break;
+                    }
+                    body.push_str(line);
+                    body.push('\n');
+                }
+
+                heredocs.push(Heredoc {
+                    name,
+                    content: body,
+                    expand: caps[2].is_empty(),
+                });
+            }
+        }
+
+        result.push(LogicalLine {
+            line: start_line,
+            column,
+            end_line: i,
+            text: text.trim().to_string(),
+            heredocs,
+        });
+    }
+
+    result
+}
+
+/// Remove a trailing escape character (plus trailing whitespace) and report whether the
+/// instruction continues on the next line.
+fn strip_continuation(line: &str, escape: char) -> (String, bool) {
+    let trimmed = line.trim_end();
+    match trimmed.strip_suffix(escape) {
+        Some(rest) => (rest.to_string(), true),
+        None => (trimmed.to_string(), false),
+    }
+}
+
+/// Rebuild the shell text of a RUN with heredocs. A bare `RUN <<EOF` runs the body as the
+/// script; otherwise the heredocs are passed through so the shell feeds them to the command.
+fn heredoc_command(args: &str, heredocs: &[Heredoc]) -> String {
+    if heredocs.len() == 1 && args.starts_with("<<") && !args.contains(char::is_whitespace) {
+        return heredocs[0].content.trim_end().to_string();
+    }
+
+    let mut cmd = args.to_string();
+    for doc in heredocs {
+        cmd.push('\n');
+        cmd.push_str(&doc.content);
+        cmd.push_str(&doc.name);
+    }
+    cmd
+}
+
+/// Split arguments into words at whitespace outside quotes and escapes. The words keep their
+/// quotes and escapes, which decide what [`expand_word`](crate::docker::expand::expand_word)
+/// expands before it removes them.
+fn split_words(args: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut current = String::new();
+    let mut in_word = false;
+    let mut quote: Option<char> = None;
+    let mut chars = args.chars();
+
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => {
+                current.push(c);
+                quote = None;
+            }
+            (Some('"'), '\\') | (None, '\\') => {
+                current.push(c);
+                if let Some(next) = chars.next() {
+                    current.push(next);
+                }
+                in_word = true;
+            }
+            (Some(_), c) => current.push(c),
+            (None, '"' | '\'') => {
+                current.push(c);
+                quote = Some(c);
+                in_word = true;
+            }
+            (None, c) if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut current));
+                    in_word = false;
+                }
+            }
+            (None, c) => {
+                current.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if in_word {
+        words.push(current);
+    }
+    words
+}
+
+/// Parse `key=value` pairs (`LABEL a=1 "b c"="d e"`), or the legacy `key value` form. Keys
+/// and values are kept as written, like [`split_words`].
+fn key_value_pairs(args: &str) -> Vec<(String, String)> {
+    let words = split_words(args);
+    match words.first() {
+        Some(first) if !first.contains('=') => {
+            vec![(first.clone(), words[1..].join(" "))]
+        }
+        _ => words
+            .iter()
+            .filter_map(|w| w.split_once('='))
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect(),
+    }
+}
+
+/// Parse the arguments of COPY/ADD: leading `--flag[=value]` options, then the sources and
+/// destination, whitespace separated or as a JSON array.
+fn parse_copy_args(args: &str) -> (CopySpec, Vec<String>) {
+    let mut spec = CopySpec::default();
+    let mut rest = args.trim();
+
+    while let Some(flag) = rest.strip_prefix("--") {
+        let end = flag.find(char::is_whitespace).unwrap_or(flag.len());
+        let (name, value) = match flag[..end].split_once('=') {
+            Some((name, value)) => (name, Some(unquote(value).to_string())),
+            None => (&flag[..end], None),
+        };
+        match name {
+            "from" => spec.from = value,
+            "chown" => spec.options.chown = value,
+            "chmod" => spec.options.chmod = value,
+            "link" => spec.options.link = !matches!(value.as_deref(), Some("false")),
+            "exclude" => spec.options.exclude.extend(value),
+            "checksum" => spec.checksum = value,
+            // --parents, --keep-git-dir and friends are accepted but not modelled
+            _ => {}
+        }
+        rest = flag[end..].trim_start();
+    }
+
+    let positional = match CommandForm::parse(rest) {
+        CommandForm::Exec(words) => words,
+        CommandForm::Shell(text) => text.split_whitespace().map(String::from).collect(),
+    };
+    (spec, positional)
+}
+
+/// Split the leading `--mount`, `--network` and `--security` flags off a RUN. Returns the
+/// command, its mounts and its `--network` value, or `None` when a mount is invalid.
+/// `--security` is accepted but not modelled.
+fn parse_run_args(args: &str) -> Option<(&str, Vec<RunMount>, Option<&str>)> {
+    let mut mounts = Vec::new();
+    let mut network = None;
+    let mut rest = args.trim();
+
+    while let Some(flag) = rest.strip_prefix("--") {
+        let end = flag.find(char::is_whitespace).unwrap_or(flag.len());
+        match flag[..end].split_once('=') {
+            Some(("mount", spec)) => mounts.push(parse_mount(unquote(spec))?),
+            Some(("network", mode)) => network = Some(unquote(mode)),
+            Some(("security", _)) => {}
+            // Not a RUN flag: the command itself starts with `--`
+            _ => break,
+        }
+        rest = flag[end..].trim_start();
+    }
+
+    Some((rest, mounts, network))
+}
+
+/// Parse the comma-separated `key=value` options of `--mount`, e.g.
+/// `type=cache,target=/root/.cargo/registry,sharing=locked`. The type defaults to `bind`.
+/// Ownership and mode options are accepted but not modelled.
+pub fn parse_mount(spec: &str) -> Option<RunMount> {
+    let mut options: HashMap<&str, &str> = HashMap::new();
+    for field in spec.split(',').filter(|f| !f.is_empty()) {
+        let (key, value) = field.split_once('=').unwrap_or((field, "true"));
+        let key = match key {
+            "dst" | "destination" => "target",
+            "src" => "source",
+            "ro" => "readonly",
+            "rw" => "readwrite",
+            key => key,
+        };
+        options.insert(key, value);
+    }
+
+    let flag = |key: &str| options.get(key).is_some_and(|v| *v != "false");
+    let text = |key: &str| options.get(key).map(|v| v.to_string());
+    let target = options.get("target").map(PathBuf::from);
+
+    let mount = match options.get("type").copied().unwrap_or("bind") {
+        "bind" => RunMount::Bind {
+            target: target?,
+            source: text("source"),
+            from: text("from"),
+            readwrite: flag("readwrite") && !flag("readonly"),
+        },
+        "cache" => {
+            let target = target?;
+            RunMount::Cache {
+                id: text("id").unwrap_or_else(|| target.to_string_lossy().to_string()),
+                target,
+                sharing: match options.get("sharing").copied() {
+                    None | Some("shared") => CacheSharing::Shared,
+                    Some("private") => CacheSharing::Private,
+                    Some("locked") => CacheSharing::Locked,
+                    Some(_) => return None,
+                },
+                readonly: flag("readonly"),
+            }
+        }
+        "tmpfs" => RunMount::Tmpfs {
+            target: target?,
+            size: text("size"),
+        },
+        "secret" => {
+            let env = text("env");
+            let id = text("id").or_else(|| {
+                target
+                    .as_ref()
+                    .and_then(|t| t.file_name())
+                    .map(|n| n.to_string_lossy().to_string())
+            })?;
+            // Without a target or env the secret is a file under /run/secrets
+            let target = match (&target, &env) {
+                (None, None) => Some(PathBuf::from(format!("/run/secrets/{}", id))),
+                _ => target,
+            };
+            RunMount::Secret {
+                id,
+                target,
+                env,
+                required: flag("required"),
+            }
+        }
+        "ssh" => RunMount::Ssh {
+            id: text("id").unwrap_or_else(|| "default".to_string()),
+            target: target.unwrap_or_else(|| PathBuf::from("/run/buildkit/ssh_agent.0")),
+            required: flag("required"),
+        },
+        _ => return None,
+    };
+    Some(mount)
+}
+
+/// Parse `HEALTHCHECK [--interval=..] [--timeout=..] [--start-period=..] [--start-interval=..]
+/// [--retries=N] CMD <command>` or `HEALTHCHECK NONE`.
+fn parse_healthcheck(args: &str) -> Option<HealthcheckConfig> {
+    let mut config = HealthcheckConfig::default();
+    let mut rest = args.trim();
+
+    while let Some(flag) = rest.strip_prefix("--") {
+        let end = flag.find(char::is_whitespace).unwrap_or(flag.len());
+        let (name, value) = flag[..end].split_once('=')?;
+        match name {
+            "interval" => config.interval = Some(value.to_string()),
+            "timeout" => config.timeout = Some(value.to_string()),
+            "start-period" => config.start_period = Some(value.to_string()),
+            "start-interval" => config.start_interval = Some(value.to_string()),
+            "retries" => config.retries = value.parse().ok(),
+            _ => {}
+        }
+        rest = flag[end..].trim_start();
+    }
+
+    let keyword = rest.split_whitespace().next()?.to_uppercase();
+    config.test = match keyword.as_str() {
+        "NONE" => vec!["NONE".to_string()],
+        "CMD" => match CommandForm::parse(&rest[keyword.len()..]) {
+            CommandForm::Exec(argv) => std::iter::once("CMD".to_string()).chain(argv).collect(),
+            CommandForm::Shell(cmd) => vec!["CMD-SHELL".to_string(), cmd],
+        },
+        _ => return None,
+    };
+    Some(config)
+}
+
+/// Strip one pair of matching surrounding quotes.
+fn unquote(value: &str) -> &str {
+    for quote in ['"', '\''] {
+        if let Some(inner) = value
+            .strip_prefix(quote)
+            .and_then(|v| v.strip_suffix(quote))
+        {
+            return inner;
+        }
+    }
+    value
+}
+
+pub fn parse_dockerfile(content: &str) -> Vec<Instruction> {
+    parse_dockerfile_with_diagnostics(content).instructions
+}
+
+/// Parse a Dockerfile, reporting malformed instructions instead of silently dropping them.
+/// Malformed instructions are left out of the result; unknown ones are kept as `Other`.
+pub fn parse_dockerfile_with_diagnostics(content: &str) -> ParsedDockerfile {
+    let mut parsed = ParsedDockerfile::default();
+    let directive = memobuild_directive();
+
+    for logical in logical_lines(content) {
+        let line = logical.text.as_str();
+        let span = Span::from(&logical);
+
+        if let Some(caps) = directive.captures(line) {
+            match &caps[1] {
+                "limits" => match ResourceLimits::parse(&caps[2]) {
+                    Ok(limits) => parsed.instructions.push(Instruction::Limits(limits)),
+                    Err(err) => parsed.diagnostics.push(Diagnostic::error(
+                        span,
+                        "invalid-limits",
+                        err.to_string(),
+                    )),
+                },
+                "network" => match NetworkPolicy::parse(caps[2].trim()) {
+                    Ok(policy) => parsed.instructions.push(Instruction::Network(policy)),
+                    Err(err) => parsed.diagnostics.push(Diagnostic::error(
+                        span,
+                        "invalid-network",
+                        err.to_string(),
+                    )),
+                },
+                name => parsed.diagnostics.push(Diagnostic::warning(
+                    span,
+                    "unknown-directive",
+                    format!("Unknown memobuild directive {:?}", name),
+                )),
+            }
+            parsed.spans.resize(parsed.instructions.len(), span);
             continue;
         }
 
@@ -35,39 +657,221 @@ pub fn parse_dockerfile(content: &str) -> Vec<Instruction> {
             ""
         };
 
+        let instructions = &mut parsed.instructions;
+        let diagnostics = &mut parsed.diagnostics;
+        let missing = |usage: &str| {
+            Diagnostic::error(
+                span,
+                "missing-arguments",
+                format!("{} requires {}", keyword, usage),
+            )
+        };
+
         match keyword.as_str() {
             "FROM" => {
-                if parts.len() >= 2 {
-                    instructions.push(Instruction::From(parts[1].to_string()));
+                // FROM [--platform=<platform>] <image> [AS <name>]
+                let positional: Vec<&str> = parts[1..]
+                    .iter()
+                    .copied()
+                    .filter(|p| !p.starts_with("--"))
+                    .collect();
+                if let Some(image) = positional.first() {
+                    let stage = match positional.get(1) {
+                        Some(kw) if kw.eq_ignore_ascii_case("AS") => {
+                            positional.get(2).map(|name| name.to_string())
+                        }
+                        _ => None,
+                    };
+                    instructions.push(Instruction::From(image.to_string(), stage));
+                } else {
+                    diagnostics.push(missing("an image"));
                 }
             }
             "WORKDIR" => {
                 if parts.len() >= 2 {
                     instructions.push(Instruction::Workdir(parts[1].to_string()));
+                } else {
+                    diagnostics.push(missing("a path"));
                 }
             }
-            "COPY" => {
-                if parts.len() >= 3 {
-                    instructions.push(Instruction::Copy(
-                        parts[1].to_string(),
-                        parts[2].to_string(),
+            "COPY" | "ADD" => {
+                let (mut spec, mut positional) = parse_copy_args(args);
+
+                if !logical.heredocs.is_empty()
+                    && positional.len() == logical.heredocs.len() + 1
+                    && positional[..positional.len() - 1]
+                        .iter()
+                        .all(|p| p.starts_with("<<"))
+                {
+                    instructions.push(Instruction::CopyHeredoc(
+                        logical.heredocs.clone(),
+                        positional[positional.len() - 1].clone(),
+                        spec.options,
                     ));
+                } else if positional.len() >= 2 {
+                    spec.dst = positional.pop().unwrap_or_default();
+                    spec.sources = positional;
+                    if keyword == "COPY" {
+                        instructions.push(Instruction::Copy(spec));
+                    } else {
+                        instructions.push(Instruction::Add(spec));
+                    }
+                } else {
+                    diagnostics.push(missing("at least one source and a destination"));
                 }
             }
-            "RUN" => {
-                instructions.push(Instruction::Run(args.to_string()));
-            }
+            "RUN" => match parse_run_args(args) {
+                Some(("", _, _)) if logical.heredocs.is_empty() => {
+                    diagnostics.push(missing("a command"))
+                }
+                Some((cmd, mounts, network)) => {
+                    // `--network=none|host` sets the policy as `# memobuild:network` would
+                    match network.map(|mode| mode.to_ascii_lowercase()).as_deref() {
+                        None | Some("default") => {}
+                        Some("none") => {
+                            instructions.push(Instruction::Network(NetworkPolicy::None))
+                        }
+                        Some("host") => {
+                            instructions.push(Instruction::Network(NetworkPolicy::Host))
+                        }
+                        Some(_) => diagnostics.push(Diagnostic::error(
+                            span,
+                            "invalid-network",
+                            "RUN --network needs default, none or host",
+                        )),
+                    }
+                    let cmd = if logical.heredocs.is_empty() {
+                        cmd.to_string()
+                    } else {
+                        heredoc_command(cmd, &logical.heredocs)
+                    };
+                    instructions.push(Instruction::Run(cmd, mounts));
+                }
+                None => diagnostics.push(Diagnostic::error(
+                    span,
+                    "invalid-mount",
+                    "RUN --mount needs a known type (bind, cache, tmpfs, secret, ssh), a target \
+                     for bind/cache/tmpfs and an id or target for secrets",
+                )),
+            },
             "ENV" => {
-                let env_parts: Vec<&str> = args.splitn(2, [' ', '=']).collect();
-                if env_parts.len() == 2 {
-                    instructions.push(Instruction::Env(
-                        env_parts[0].to_string(),
-                        env_parts[1].to_string(),
-                    ));
+                // ENV <key>=<value> ... or the legacy ENV <key> <value>
+                let legacy = parts.len() >= 2 && !parts[1].contains('=');
+                let pairs = key_value_pairs(args);
+                if pairs.is_empty()
+                    || pairs.iter().any(|(key, _)| key.is_empty())
+                    || (legacy && parts.len() < 3)
+                {
+                    diagnostics.push(missing("a name and a value"));
+                } else {
+                    if legacy {
+                        diagnostics.push(Diagnostic::warning(
+                            span,
+                            "legacy-key-value",
+                            "Use ENV key=value instead of the legacy ENV key value form",
+                        ));
+                    }
+                    for (key, value) in pairs {
+                        instructions.push(Instruction::Env(key, value));
+                    }
+                }
+            }
+            "ARG" => {
+                // ARG <name>[=<default>] [<name>[=<default>] ...]
+                let decls = split_words(args);
+                if decls.is_empty() {
+                    diagnostics.push(missing("a name"));
+                }
+                for decl in decls {
+                    let (name, default) = match decl.split_once('=') {
+                        Some((name, value)) => (name.to_string(), Some(value.to_string())),
+                        None => (decl, None),
+                    };
+                    instructions.push(Instruction::Arg(name, default));
                 }
             }
+            "CMD" | "ENTRYPOINT" if args.is_empty() => diagnostics.push(missing("a command")),
             "CMD" => {
-                instructions.push(Instruction::Cmd(args.to_string()));
+                instructions.push(Instruction::Cmd(CommandForm::parse(args)));
+            }
+            "ENTRYPOINT" => {
+                instructions.push(Instruction::Entrypoint(CommandForm::parse(args)));
+            }
+            "USER" => {
+                if parts.len() >= 2 {
+                    instructions.push(Instruction::User(parts[1].to_string()));
+                } else {
+                    diagnostics.push(missing("a user"));
+                }
+            }
+            "EXPOSE" => {
+                if parts.len() < 2 {
+                    diagnostics.push(missing("at least one port"));
+                } else {
+                    let ports = parts[1..].iter().map(|p| p.to_string()).collect();
+                    instructions.push(Instruction::Expose(ports));
+                }
+            }
+            "LABEL" => {
+                let labels = key_value_pairs(args);
+                if labels.is_empty() {
+                    diagnostics.push(missing("at least one key=value pair"));
+                } else {
+                    instructions.push(Instruction::Label(labels));
+                }
+            }
+            "VOLUME" => {
+                // VOLUME ["/data", "/logs"] or VOLUME /data /logs
+                let paths = match CommandForm::parse(args) {
+                    CommandForm::Exec(paths) => paths,
+                    CommandForm::Shell(_) => split_words(args),
+                };
+                if paths.is_empty() {
+                    diagnostics.push(missing("at least one path"));
+                } else {
+                    instructions.push(Instruction::Volume(paths));
+                }
+            }
+            "HEALTHCHECK" => match parse_healthcheck(args) {
+                Some(config) => instructions.push(Instruction::Healthcheck(config)),
+                None => diagnostics.push(Diagnostic::error(
+                    span,
+                    "invalid-healthcheck",
+                    "HEALTHCHECK expects NONE or [--option=value ...] CMD <command>",
+                )),
+            },
+            "SHELL" => match CommandForm::parse(args) {
+                // SHELL only accepts the JSON form
+                CommandForm::Exec(shell) if !shell.is_empty() => {
+                    instructions.push(Instruction::Shell(shell))
+                }
+                _ => diagnostics.push(Diagnostic::error(
+                    span,
+                    "invalid-shell",
+                    "SHELL must be a non-empty JSON array, e.g. [\"/bin/bash\", \"-c\"]",
+                )),
+            },
+            "STOPSIGNAL" => {
+                if parts.len() >= 2 {
+                    instructions.push(Instruction::Stopsignal(parts[1].to_string()));
+                } else {
+                    diagnostics.push(missing("a signal"));
+                }
+            }
+            "ONBUILD" => {
+                if args.is_empty() {
+                    diagnostics.push(missing("an instruction"));
+                } else {
+                    instructions.push(Instruction::Onbuild(args.to_string()));
+                }
+            }
+            "MAINTAINER" => {
+                diagnostics.push(Diagnostic::warning(
+                    span,
+                    "deprecated",
+                    "MAINTAINER is deprecated; use LABEL maintainer=<name> instead",
+                ));
+                instructions.push(Instruction::Other(line.to_string()));
             }
             "GIT" => {
                 if parts.len() >= 3 {
@@ -75,6 +879,8 @@ pub fn parse_dockerfile(content: &str) -> Vec<Instruction> {
                 } else if parts.len() == 2 {
                     // Default target dir to the repo name or "."
                     instructions.push(Instruction::Git(parts[1].to_string(), ".".to_string()));
+                } else {
+                    diagnostics.push(missing("a repository URL"));
                 }
             }
             "RUN_EXTEND" => {
@@ -88,6 +894,8 @@ pub fn parse_dockerfile(content: &str) -> Vec<Instruction> {
                     let dst = parts[2].to_string();
                     let tags: Vec<String> = parts[3..].iter().map(|s| s.to_string()).collect();
                     instructions.push(Instruction::CopyExtend(src, dst, tags));
+                } else {
+                    diagnostics.push(missing("a source and a destination"));
                 }
             }
             "HOOK" => {
@@ -96,13 +904,39 @@ pub fn parse_dockerfile(content: &str) -> Vec<Instruction> {
                     let hook_name = parts[1].to_string();
                     let params = parts[2..].iter().map(|s| s.to_string()).collect();
                     instructions.push(Instruction::Hook(hook_name, params));
+                } else {
+                    diagnostics.push(missing("a hook name"));
                 }
             }
             _ => {
+                diagnostics.push(Diagnostic::error(
+                    span,
+                    "unknown-instruction",
+                    format!("Unknown instruction {}", parts[0]),
+                ));
                 instructions.push(Instruction::Other(line.to_string()));
             }
         }
+
+        parsed.spans.resize(parsed.instructions.len(), span);
+    }
+
+    // Only ARG (and directives) may come before the first FROM
+    let first = parsed.instructions.iter().position(|i| {
+        !matches!(
+            i,
+            Instruction::Arg(..) | Instruction::Limits(_) | Instruction::Network(_)
+        )
+    });
+    if let Some(index) = first {
+        if !matches!(parsed.instructions[index], Instruction::From(..)) {
+            parsed.diagnostics.push(Diagnostic::error(
+                parsed.spans[index],
+                "missing-from",
+                "A Dockerfile must start with FROM (optionally preceded by ARG)",
+            ));
+        }
     }
 
-    instructions
+    parsed
 }
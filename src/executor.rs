@@ -15,6 +15,8 @@ pub struct IncrementalExecutor {
     dry_run: bool,
     sandbox: Arc<dyn crate::sandbox::Sandbox>,
     remote_executor: Option<Arc<dyn crate::remote_exec::RemoteExecutor>>,
+    log_dir: Option<std::path::PathBuf>,
+    skip_clean: bool,
 }
 
 #[derive(Debug, Default, Clone)]
@@ -39,9 +41,29 @@ impl IncrementalExecutor {
                 std::env::current_dir().unwrap_or_else(|_| std::path::PathBuf::from(".")),
             )),
             remote_executor: None,
+            log_dir: None,
+            skip_clean: false,
         }
     }
 
+    /// Write the output of every command the build runs to `<log_dir>/<node id>.log`.
+    pub fn with_log_dir(mut self, log_dir: std::path::PathBuf) -> Self {
+        self.log_dir = Some(log_dir);
+        self
+    }
+
+    /// Change the log directory between builds run by the same executor.
+    pub fn set_log_dir(&mut self, log_dir: std::path::PathBuf) {
+        self.log_dir = Some(log_dir);
+    }
+
+    /// Run only dirty nodes and leave clean ones untouched, for rebuilds in a process that
+    /// has already built them (`build --watch`).
+    pub fn with_skip_clean(mut self, skip_clean: bool) -> Self {
+        self.skip_clean = skip_clean;
+        self
+    }
+
     pub fn with_dry_run(mut self, dry_run: bool) -> Self {
         self.dry_run = dry_run;
         self
@@ -76,6 +98,13 @@ impl IncrementalExecutor {
 
         let start_time = Instant::now();
 
+        let validation = graph.validate();
+        validation.ensure_valid()?;
+        for warning in validation.warnings() {
+            println!("   ⚠️  {}", warning.to_string().yellow());
+        }
+        graph.assign_input_layers();
+
         // Reset stats
         self.execution_stats = ExecutionStats::default();
         self.execution_stats.total_nodes = graph.nodes.len();
@@ -108,6 +137,23 @@ impl IncrementalExecutor {
                 continue;
             }
 
+            let level: Vec<usize> = if self.skip_clean {
+                let (dirty, clean): (Vec<usize>, Vec<usize>) =
+                    level.iter().partition(|&&id| graph.nodes[id].dirty);
+                // Clean nodes were built earlier in this process; their artifacts are cached
+                for &id in &clean {
+                    graph.nodes[id].cache_hit = true;
+                }
+                self.execution_stats.cache_hits += clean.len();
+                pb.inc(clean.len() as u64);
+                if dirty.is_empty() {
+                    continue;
+                }
+                dirty
+            } else {
+                level.clone()
+            };
+
             println!(" Executing level {}: {} nodes", level_idx, level.len());
 
             let (parallel_nodes, sequential_nodes): (Vec<_>, Vec<_>) = level
@@ -168,6 +214,7 @@ impl IncrementalExecutor {
             let remote_executor = self.remote_executor.clone();
             let reproducible = self.reproducible;
             let dry_run = self.dry_run;
+            let log_dir = self.log_dir.clone();
 
             futures.push(async move {
                 if let Some(ref obs) = observer {
@@ -188,6 +235,7 @@ impl IncrementalExecutor {
                     dry_run,
                     sandbox,
                     remote_executor,
+                    log_dir.as_deref(),
                     &node,
                 )
                 .await;
@@ -195,7 +243,7 @@ impl IncrementalExecutor {
 
                 if let Some(ref obs) = observer {
                     match &result {
-                        Ok((_, cache_hit)) => {
+                        Ok((_, cache_hit, _)) => {
                             obs.on_event(crate::dashboard::BuildEvent::NodeCompleted {
                                 node_id,
                                 name: name.clone(),
@@ -218,12 +266,13 @@ impl IncrementalExecutor {
 
         // Update graph status and stats
         for (node_id, result, execution_time) in results {
-            let (dirty, cache_hit) = result?;
+            let (dirty, cache_hit, fetched) = result?;
 
             graph.nodes[node_id].dirty = dirty;
             graph.nodes[node_id].cache_hit = cache_hit;
             graph.nodes[node_id].metadata.last_executed = Some(std::time::SystemTime::now());
             graph.nodes[node_id].metadata.execution_time_ms = Some(execution_time);
+            graph.nodes[node_id].metadata.fetched = fetched;
 
             if cache_hit {
                 self.execution_stats.cache_hits += 1;
@@ -271,6 +320,7 @@ impl IncrementalExecutor {
                 self.dry_run,
                 self.sandbox.clone(),
                 self.remote_executor.clone(),
+                self.log_dir.as_deref(),
                 node,
             )
             .await;
@@ -279,7 +329,7 @@ impl IncrementalExecutor {
 
             if let Some(ref obs) = self.observer {
                 match &result {
-                    Ok((_, cache_hit)) => {
+                    Ok((_, cache_hit, _)) => {
                         obs.on_event(crate::dashboard::BuildEvent::NodeCompleted {
                             node_id,
                             name: node.name.clone(),
@@ -295,12 +345,13 @@ impl IncrementalExecutor {
                 }
             }
 
-            let (dirty, cache_hit) = result?;
+            let (dirty, cache_hit, fetched) = result?;
 
             graph.nodes[node_id].dirty = dirty;
             graph.nodes[node_id].cache_hit = cache_hit;
             graph.nodes[node_id].metadata.last_executed = Some(std::time::SystemTime::now());
             graph.nodes[node_id].metadata.execution_time_ms = Some(execution_time);
+            graph.nodes[node_id].metadata.fetched = fetched;
 
             if cache_hit {
                 self.execution_stats.cache_hits += 1;
@@ -317,7 +368,7 @@ impl IncrementalExecutor {
     #[allow(clippy::too_many_arguments)]
     async fn execute_node_logic(
         cache: Arc<HybridCache>,
-        _node_id: usize,
+        node_id: usize,
         name: &str,
         hash: &str,
         dirty: bool,
@@ -326,13 +377,25 @@ impl IncrementalExecutor {
         dry_run: bool,
         sandbox: Arc<dyn crate::sandbox::Sandbox>,
         remote_executor: Option<Arc<dyn crate::remote_exec::RemoteExecutor>>,
+        log_dir: Option<&std::path::Path>,
         node: &crate::graph::Node,
-    ) -> Result<(bool, bool)> {
+    ) -> Result<(bool, bool, Vec<crate::sandbox::FetchedUrl>)> {
+        // Nodes that only write files run too when the sandbox records their changes
+        let records_files = sandbox.captures_filesystem() && node.kind.writes_files();
+
         // 1. Check cache first
         match cache.get_artifact(hash).await {
-            Ok(Some(_data)) => {
-                // Return silently, progress bar handles message visually without spam
-                return Ok((false, true));
+            Ok(Some(data)) => {
+                // A sandbox that captures filesystems needs the cached changes; if it cannot
+                // use them, the node runs again
+                if !records_files {
+                    // Return silently, progress bar handles message visually without spam
+                    return Ok((false, true, Vec::new()));
+                }
+                match sandbox.restore(node, &data).await {
+                    Ok(()) => return Ok((false, true, Vec::new())),
+                    Err(e) => eprintln!("{}", format!("⚠️ {}, rebuilding", e).yellow()),
+                }
             }
             Err(e) => eprintln!("{}", format!("⚠️ Cache error for {}: {}", name, e).red()),
             _ => {}
@@ -343,20 +406,22 @@ impl IncrementalExecutor {
                 "{}",
                 format!("Dry-run mode, skipping execution for {}", name).yellow()
             );
-            return Ok((dirty, false));
+            return Ok((dirty, false, Vec::new()));
         }
 
         // Check if node type needs actual execution in build farm
         let is_runnable = matches!(
             node.kind,
-            crate::graph::NodeKind::Run
+            crate::graph::NodeKind::Run { .. }
                 | crate::graph::NodeKind::RunExtend { .. }
                 | crate::graph::NodeKind::CustomHook { .. }
                 | crate::graph::NodeKind::Git { .. }
         );
 
-        let mut artifact_data = if is_runnable {
-            if let Some(remote) = remote_executor.as_ref() {
+        // URLs fetched through the network allowlist proxy, for the build record and provenance
+        let mut fetched = Vec::new();
+        let mut artifact_data = match remote_executor.as_ref() {
+            Some(remote) if is_runnable => {
                 // Ensure input manifest and required files are in CAS
                 if let Some(ref _manifest_hash) = node.metadata.input_manifest_hash {
                     // If it's a COPY node, we can re-generate and upload
@@ -386,14 +451,20 @@ impl IncrementalExecutor {
                         size_bytes: 0, // Placeholder
                     },
                     timeout: std::time::Duration::from_secs(
-                        crate::constants::DEFAULT_REMOTE_EXECUTION_TIMEOUT_SECS,
+                        node.metadata
+                            .limits
+                            .timeout_secs
+                            .unwrap_or(crate::constants::DEFAULT_REMOTE_EXECUTION_TIMEOUT_SECS),
                     ),
                     platform_properties: std::collections::HashMap::new(),
                     output_files: Vec::new(),
                     output_directories: Vec::new(),
+                    limits: node.metadata.limits.clone(),
+                    network: node.metadata.network.clone(),
                 };
 
                 let result = remote.execute(action).await?;
+                write_node_log(log_dir, node_id, &result.stdout_raw, &result.stderr_raw);
                 if result.exit_code != 0 {
                     anyhow::bail!(
                         "Remote execution failed with exit code {}: {}",
@@ -402,7 +473,8 @@ impl IncrementalExecutor {
                     );
                 }
                 result.stdout_raw
-            } else {
+            }
+            _ if is_runnable || records_files => {
                 // Prepare sandbox
                 if let crate::graph::NodeKind::RunExtend { command, .. } = &node.kind {
                     println!("⚡ Executing extended RUN: {}", command);
@@ -419,8 +491,18 @@ impl IncrementalExecutor {
                 let env = sandbox.prepare(node).await?;
 
                 // Execute command
-                let exec_result = sandbox.execute(&env, node).await?;
+                let exec_result = sandbox.execute(&env, node).await;
+                sandbox.cleanup(&env).await?;
+                let exec_result = exec_result?;
+                write_node_log(log_dir, node_id, &exec_result.stdout, &exec_result.stderr);
 
+                if let Some(failure) = &exec_result.failure {
+                    anyhow::bail!(
+                        "Command {}: {}",
+                        failure,
+                        String::from_utf8_lossy(&exec_result.stderr)
+                    );
+                }
                 if exec_result.exit_code != 0 {
                     anyhow::bail!(
                         "Command failed with exit code {}: {}",
@@ -429,12 +511,11 @@ impl IncrementalExecutor {
                     );
                 }
 
-                let data = exec_result.stdout;
-                sandbox.cleanup(&env).await?;
-                data
+                fetched = exec_result.fetched;
+                // The filesystem changes, where captured, are the node's output
+                exec_result.diff.unwrap_or(exec_result.stdout)
             }
-        } else {
-            Vec::new() // Default empty artifact data for non-runnable nodes
+            _ => Vec::new(), // Default empty artifact data for non-runnable nodes
         };
 
         if reproducible {
@@ -445,7 +526,7 @@ impl IncrementalExecutor {
             eprintln!("⚠️ Cache put error for {}: {}", name, e);
         }
 
-        Ok((false, false))
+        Ok((false, false, fetched))
     }
 
     /// Print execution summary
@@ -486,6 +567,18 @@ impl IncrementalExecutor {
     }
 }
 
+/// Best effort: a build never fails because its log could not be written.
+fn write_node_log(log_dir: Option<&std::path::Path>, node_id: usize, stdout: &[u8], stderr: &[u8]) {
+    let Some(dir) = log_dir else {
+        return;
+    };
+    let _ = std::fs::create_dir_all(dir);
+    let _ = std::fs::write(
+        dir.join(format!("{}.log", node_id)),
+        [stdout, stderr].concat(),
+    );
+}
+
 /// Legacy function for backward compatibility
 pub async fn execute_graph(
     graph: &mut BuildGraph,
@@ -0,0 +1,273 @@
+//! `memobuild explain-cache`: why each node of the next build would miss the cache.
+//!
+//! The current graph is compared with the last successful build of the context recorded in
+//! the local history. Key changes found by [`diff_builds`] are refined with the per-file
+//! hashes of `ContextState`, so a changed COPY names the files that changed, and a changed
+//! [`EnvFingerprint`] names the variables or toolchain versions that differ.
+
+use crate::cache::CacheLocation;
+use crate::core::{node_state_keys, ContextState, NodeInputs};
+use crate::diff::{diff_builds, BuildSnapshot, EnvChange, KeyChange, NodeStatus};
+use crate::graph::BuildGraph;
+use crate::visualize::label;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+
+/// The build a new one is compared with: its graph and fingerprint from the history, and
+/// the source inputs `ContextState` recorded for it.
+#[derive(Debug, Clone, Default)]
+pub struct Baseline {
+    /// Id of the recorded build
+    pub id: String,
+    pub snapshot: BuildSnapshot,
+    pub state: ContextState,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "cause", rename_all = "snake_case")]
+pub enum MissReason {
+    /// The context has no successful build to compare against
+    NoPreviousBuild,
+    /// The node has no counterpart in the previous build
+    NewNode,
+    /// Source files of a COPY/ADD or bind mount were added, removed or modified
+    FilesChanged {
+        added: Vec<String>,
+        removed: Vec<String>,
+        modified: Vec<String>,
+    },
+    /// Fields of the environment fingerprint (env vars, toolchain versions, platform) differ
+    EnvFingerprint { changes: Vec<EnvChange> },
+    /// An upstream node's key changed, so this node's key changed with it
+    UpstreamNode { id: usize, label: String },
+    /// Another input of the node's own key changed
+    KeyInput { change: KeyChange },
+    /// The key is the same as in the previous build, but neither cache holds the artifact
+    /// (evicted, garbage collected, or the previous build never uploaded it)
+    NotCached,
+}
+
+impl std::fmt::Display for MissReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MissReason::NoPreviousBuild => write!(f, "no previous build of this context"),
+            MissReason::NewNode => write!(f, "new instruction, not in the previous build"),
+            MissReason::FilesChanged {
+                added,
+                removed,
+                modified,
+            } => {
+                let mut parts = Vec::new();
+                for (verb, files) in [
+                    ("modified", modified),
+                    ("added", added),
+                    ("removed", removed),
+                ] {
+                    if !files.is_empty() {
+                        parts.push(format!("{} {}", verb, list(files)));
+                    }
+                }
+                write!(f, "source files {}", parts.join("; "))
+            }
+            MissReason::EnvFingerprint { changes } => {
+                let fields: Vec<String> = changes
+                    .iter()
+                    .map(|c| {
+                        format!(
+                            "{} {} → {}",
+                            c.field,
+                            c.before.as_deref().unwrap_or("(unset)"),
+                            c.after.as_deref().unwrap_or("(unset)")
+                        )
+                    })
+                    .collect();
+                write!(f, "environment changed: {}", fields.join(", "))
+            }
+            MissReason::UpstreamNode { id, label } => {
+                write!(f, "upstream node [{}] {} changed", id, label)
+            }
+            MissReason::KeyInput { change } => write!(f, "{}", change),
+            MissReason::NotCached => write!(
+                f,
+                "key unchanged since the previous build, but the artifact is in neither cache"
+            ),
+        }
+    }
+}
+
+fn list(files: &[String]) -> String {
+    const SHOWN: usize = 5;
+    if files.len() <= SHOWN {
+        files.join(", ")
+    } else {
+        format!(
+            "{} and {} more",
+            files[..SHOWN].join(", "),
+            files.len() - SHOWN
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeExplanation {
+    pub id: usize,
+    pub label: String,
+    pub hash: String,
+    pub cache: CacheLocation,
+    /// Key of the matching node in the previous build
+    pub previous_hash: Option<String>,
+    /// Empty when the node is cached
+    pub reasons: Vec<MissReason>,
+}
+
+/// Explain every node of `current`, whose source inputs are `state` and whose artifacts
+/// were found at `locations` (one per node).
+pub fn explain(
+    current: &BuildSnapshot,
+    state: &ContextState,
+    baseline: Option<&Baseline>,
+    locations: &[CacheLocation],
+) -> Vec<NodeExplanation> {
+    let graph = &current.graph;
+    let location = |id: usize| locations.get(id).copied().unwrap_or(CacheLocation::Missing);
+
+    let Some(baseline) = baseline else {
+        return graph
+            .nodes
+            .iter()
+            .map(|node| NodeExplanation {
+                id: node.id,
+                label: label(node),
+                hash: node.hash.clone(),
+                cache: location(node.id),
+                previous_hash: None,
+                reasons: match location(node.id) {
+                    CacheLocation::Missing => vec![MissReason::NoPreviousBuild],
+                    _ => Vec::new(),
+                },
+            })
+            .collect();
+    };
+
+    let diff = diff_builds(&baseline.snapshot, current);
+    let by_node: HashMap<usize, _> = diff
+        .nodes
+        .iter()
+        .filter_map(|n| n.after.map(|id| (id, n)))
+        .collect();
+    let current_inputs = inputs_by_node(graph, state);
+    let previous_inputs = inputs_by_node(&baseline.snapshot.graph, &baseline.state);
+
+    graph
+        .nodes
+        .iter()
+        .map(|node| {
+            let node_diff = by_node.get(&node.id);
+            let before = node_diff.and_then(|d| d.before);
+            let mut explanation = NodeExplanation {
+                id: node.id,
+                label: label(node),
+                hash: node.hash.clone(),
+                cache: location(node.id),
+                previous_hash: before.map(|b| baseline.snapshot.graph.nodes[b].hash.clone()),
+                reasons: Vec::new(),
+            };
+            if explanation.cache != CacheLocation::Missing {
+                return explanation;
+            }
+
+            let Some(node_diff) = node_diff.filter(|d| d.status != NodeStatus::Added) else {
+                explanation.reasons.push(MissReason::NewNode);
+                return explanation;
+            };
+            let files = (
+                before.and_then(|b| previous_inputs[b]),
+                current_inputs[node.id],
+            );
+            explanation.reasons = miss_reasons(&node_diff.changes, &diff.env, files);
+            explanation
+        })
+        .collect()
+}
+
+fn miss_reasons(
+    changes: &[KeyChange],
+    env: &[EnvChange],
+    (before, after): (Option<&NodeInputs>, Option<&NodeInputs>),
+) -> Vec<MissReason> {
+    if changes.is_empty() {
+        return vec![MissReason::NotCached];
+    }
+
+    let mut reasons = Vec::new();
+    for change in changes {
+        reasons.push(match change {
+            KeyChange::Dependency { id, label } => MissReason::UpstreamNode {
+                id: *id,
+                label: label.clone(),
+            },
+            KeyChange::EnvFingerprint => continue,
+            KeyChange::SourceHash { .. } => match (before, after) {
+                (Some(before), Some(after)) if !before.files.is_empty() => {
+                    match files_changed(before, after) {
+                        Some(reason) => reason,
+                        None => MissReason::KeyInput {
+                            change: change.clone(),
+                        },
+                    }
+                }
+                _ => MissReason::KeyInput {
+                    change: change.clone(),
+                },
+            },
+            other => MissReason::KeyInput {
+                change: other.clone(),
+            },
+        });
+    }
+
+    // The fingerprint is an input of every key; name it wherever the node's key did not
+    // already change through an upstream node
+    let via_upstream = changes
+        .iter()
+        .any(|c| matches!(c, KeyChange::Dependency { .. }));
+    if !env.is_empty() && !via_upstream {
+        reasons.push(MissReason::EnvFingerprint {
+            changes: env.to_vec(),
+        });
+    } else if reasons.is_empty() {
+        reasons.push(MissReason::KeyInput {
+            change: KeyChange::EnvFingerprint,
+        });
+    }
+    reasons
+}
+
+fn files_changed(before: &NodeInputs, after: &NodeInputs) -> Option<MissReason> {
+    let paths: BTreeSet<&String> = before.files.keys().chain(after.files.keys()).collect();
+    let (mut added, mut removed, mut modified) = (Vec::new(), Vec::new(), Vec::new());
+    for path in paths {
+        match (before.files.get(path), after.files.get(path)) {
+            (None, Some(_)) => added.push(path.clone()),
+            (Some(_), None) => removed.push(path.clone()),
+            (Some(a), Some(b)) if a != b => modified.push(path.clone()),
+            _ => {}
+        }
+    }
+    if added.is_empty() && removed.is_empty() && modified.is_empty() {
+        return None;
+    }
+    Some(MissReason::FilesChanged {
+        added,
+        removed,
+        modified,
+    })
+}
+
+/// Recorded inputs of each node, matched through the same keys `detect_changes` uses.
+fn inputs_by_node<'a>(graph: &BuildGraph, state: &'a ContextState) -> Vec<Option<&'a NodeInputs>> {
+    node_state_keys(graph)
+        .iter()
+        .map(|key| state.nodes.get(key))
+        .collect()
+}
@@ -1,7 +1,9 @@
 use crate::export::layer::LayerInfo;
-use crate::graph::BuildGraph;
+use crate::graph::{BuildGraph, NodeKind};
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct OCIConfig {
@@ -12,7 +14,7 @@ pub struct OCIConfig {
     pub history: Vec<OCIHistory>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct OCIImageConfig {
     #[serde(rename = "Env")]
     pub env: Vec<String>,
@@ -20,6 +22,76 @@ pub struct OCIImageConfig {
     pub cmd: Option<Vec<String>>,
     #[serde(rename = "WorkingDir")]
     pub working_dir: Option<String>,
+    #[serde(
+        rename = "Entrypoint",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub entrypoint: Option<Vec<String>>,
+    #[serde(rename = "User", default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(
+        rename = "ExposedPorts",
+        default,
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub exposed_ports: BTreeMap<String, EmptyObject>,
+    #[serde(rename = "Labels", default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    #[serde(
+        rename = "Volumes",
+        default,
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub volumes: BTreeMap<String, EmptyObject>,
+    #[serde(
+        rename = "StopSignal",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub stop_signal: Option<String>,
+    /// Docker extension, ignored by other OCI runtimes
+    #[serde(
+        rename = "Healthcheck",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub healthcheck: Option<OCIHealthcheck>,
+    /// Docker extension, ignored by other OCI runtimes
+    #[serde(rename = "OnBuild", default, skip_serializing_if = "Vec::is_empty")]
+    pub on_build: Vec<String>,
+    /// Docker extension, ignored by other OCI runtimes
+    #[serde(rename = "Shell", default, skip_serializing_if = "Option::is_none")]
+    pub shell: Option<Vec<String>>,
+}
+
+/// `{}` value used by the set-like maps of the image config (ports, volumes).
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EmptyObject {}
+
+/// Health check in Docker's image config format; durations are in nanoseconds.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct OCIHealthcheck {
+    #[serde(rename = "Test")]
+    pub test: Vec<String>,
+    #[serde(rename = "Interval", default, skip_serializing_if = "Option::is_none")]
+    pub interval: Option<u64>,
+    #[serde(rename = "Timeout", default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+    #[serde(
+        rename = "StartPeriod",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub start_period: Option<u64>,
+    #[serde(
+        rename = "StartInterval",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub start_interval: Option<u64>,
+    #[serde(rename = "Retries", default, skip_serializing_if = "Option::is_none")]
+    pub retries: Option<u32>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -37,14 +109,111 @@ pub struct OCIHistory {
     pub empty_layer: Option<bool>,
 }
 
-pub fn create_config(graph: &BuildGraph, layers: &[LayerInfo], reproducible: bool) -> OCIConfig {
-    let mut env = Vec::new();
+/// Build the image config by applying the image's instructions in order, later ones
+/// overriding earlier ones as in Docker.
+pub fn image_config(graph: &BuildGraph) -> OCIImageConfig {
+    let mut config = OCIImageConfig::default();
+    let mut env: Vec<(String, String)> = Vec::new();
+    let mut working_dir = PathBuf::from("/");
+
     for node in &graph.nodes {
-        for (k, v) in &node.env {
-            env.push(format!("{}={}", k, v));
+        match &node.kind {
+            // Only ENV persists into the image; RUN nodes also carry build args in their env
+            NodeKind::Env => {
+                for (k, v) in &node.env {
+                    match env.iter_mut().find(|(key, _)| key == k) {
+                        Some(entry) => entry.1 = v.clone(),
+                        None => env.push((k.clone(), v.clone())),
+                    }
+                }
+            }
+            NodeKind::Workdir => {
+                if let Some(dir) = node.content.strip_prefix("WORKDIR ") {
+                    working_dir = working_dir.join(dir.trim());
+                }
+            }
+            NodeKind::Cmd { command } => config.cmd = Some(command.clone()),
+            NodeKind::Entrypoint { command } => config.entrypoint = Some(command.clone()),
+            NodeKind::User { user } => config.user = Some(user.clone()),
+            NodeKind::Expose { ports } => {
+                for port in ports {
+                    let port = if port.contains('/') {
+                        port.clone()
+                    } else {
+                        format!("{}/tcp", port)
+                    };
+                    config.exposed_ports.insert(port, EmptyObject {});
+                }
+            }
+            NodeKind::Label { labels } => config.labels.extend(labels.iter().cloned()),
+            NodeKind::Volume { paths } => {
+                for path in paths {
+                    config.volumes.insert(path.clone(), EmptyObject {});
+                }
+            }
+            NodeKind::Stopsignal { signal } => config.stop_signal = Some(signal.clone()),
+            NodeKind::Healthcheck { config: check } => {
+                config.healthcheck = Some(OCIHealthcheck {
+                    test: check.test.clone(),
+                    interval: check.interval.as_deref().and_then(parse_duration_ns),
+                    timeout: check.timeout.as_deref().and_then(parse_duration_ns),
+                    start_period: check.start_period.as_deref().and_then(parse_duration_ns),
+                    start_interval: check.start_interval.as_deref().and_then(parse_duration_ns),
+                    retries: check.retries,
+                })
+            }
+            NodeKind::Onbuild { trigger } => config.on_build.push(trigger.clone()),
+            NodeKind::Shell { shell } => config.shell = Some(shell.clone()),
+            _ => {}
         }
     }
 
+    config.env = env
+        .into_iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect();
+    config.working_dir = Some(working_dir.to_string_lossy().to_string());
+    if config.cmd.is_none() && config.entrypoint.is_none() {
+        config.cmd = Some(vec!["/bin/sh".to_string()]);
+    }
+    config
+}
+
+/// Parse a Go-style duration (`30s`, `1m30s`, `500ms`, `1.5h`) into nanoseconds.
+pub fn parse_duration_ns(value: &str) -> Option<u64> {
+    let mut total = 0f64;
+    let mut rest = value.trim();
+    if rest.is_empty() {
+        return None;
+    }
+
+    while !rest.is_empty() {
+        let number_len = rest
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(rest.len());
+        let number: f64 = rest[..number_len].parse().ok()?;
+        rest = &rest[number_len..];
+
+        let unit_len = rest
+            .find(|c: char| c.is_ascii_digit() || c == '.')
+            .unwrap_or(rest.len());
+        let scale = match &rest[..unit_len] {
+            "ns" => 1.0,
+            "us" | "µs" => 1e3,
+            "ms" => 1e6,
+            "s" => 1e9,
+            "m" => 60e9,
+            "h" => 3600e9,
+            _ => return None,
+        };
+        total += number * scale;
+        rest = &rest[unit_len..];
+    }
+
+    Some(total as u64)
+}
+
+pub fn create_config(graph: &BuildGraph, layers: &[LayerInfo], reproducible: bool) -> OCIConfig {
     let timestamp = if reproducible {
         "1970-01-01T00:00:00Z".to_string()
     } else {
@@ -54,11 +223,7 @@ pub fn create_config(graph: &BuildGraph, layers: &[LayerInfo], reproducible: boo
     OCIConfig {
         architecture: "amd64".to_string(),
         os: "linux".to_string(),
-        config: OCIImageConfig {
-            env,
-            cmd: Some(vec!["/bin/sh".to_string()]),
-            working_dir: Some("/".to_string()),
-        },
+        config: image_config(graph),
         rootfs: OCIRootFS {
             fs_type: "layers".to_string(),
             diff_ids: layers.iter().map(|l| l.diff_id.clone()).collect(),
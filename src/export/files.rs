@@ -0,0 +1,425 @@
+use crate::core::resolve_source;
+use crate::docker::parser::is_url;
+use crate::export::utils::sha256_bytes;
+use crate::graph::{CopyOptions, Node, NodeKind};
+use crate::hasher::walker::{walk_entries, EntryKind};
+use crate::hasher::IgnoreRules;
+use anyhow::{Context, Result};
+use std::io::Read;
+use std::path::Path;
+
+/// A file or directory a COPY/ADD node contributes to its layer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayerEntry {
+    /// Path inside the image, without the leading `/`
+    pub path: String,
+    pub data: Vec<u8>,
+    pub mode: u32,
+    pub is_dir: bool,
+    /// Target of a symlink copied from the context
+    pub link_target: Option<String>,
+}
+
+/// Owner written to the tar headers of copied files (`--chown`).
+///
+/// Names cannot be resolved without the image's `/etc/passwd`, so they are written to the
+/// user/group name fields, which extractors prefer over the numeric ids.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Ownership {
+    pub uid: u64,
+    pub gid: u64,
+    pub user: Option<String>,
+    pub group: Option<String>,
+}
+
+/// Parse `--chown=<user>[:<group>]`. Without a group, the group has the same name or id.
+pub fn parse_chown(spec: &str) -> Ownership {
+    let (user, group) = spec.split_once(':').unwrap_or((spec, spec));
+    let (uid, user) = match user.parse::<u64>() {
+        Ok(id) => (id, None),
+        Err(_) => (0, Some(user.to_string())),
+    };
+    let (gid, group) = match group.parse::<u64>() {
+        Ok(id) => (id, None),
+        Err(_) => (0, Some(group.to_string())),
+    };
+    Ownership {
+        uid,
+        gid,
+        user,
+        group,
+    }
+}
+
+/// Parse `--chmod=<octal>`.
+pub fn parse_chmod(spec: &str) -> Option<u32> {
+    u32::from_str_radix(spec, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+}
+
+/// Collect the files a COPY/ADD node places in the image: context sources (honouring the
+/// build's ignore rules and `--exclude`), extracted local tar archives and downloaded URLs for
+/// ADD. Copies from other stages or images (see [`stage_layer_entries`]) and heredocs yield
+/// no entries.
+pub fn layer_entries(node: &Node, context: &Path, ignore: &IgnoreRules) -> Result<Vec<LayerEntry>> {
+    let (sources, dst, options, checksum, is_add): (Vec<String>, &Path, &CopyOptions, _, _) =
+        match &node.kind {
+            NodeKind::Copy {
+                sources,
+                dst,
+                from: None,
+                options,
+            } if node.source_path.is_some() => (
+                sources
+                    .iter()
+                    .map(|s| s.to_string_lossy().to_string())
+                    .collect(),
+                dst.as_path(),
+                options,
+                None,
+                false,
+            ),
+            NodeKind::Add {
+                sources,
+                dst,
+                checksum,
+                options,
+            } => (
+                sources.clone(),
+                dst.as_path(),
+                options,
+                checksum.as_deref(),
+                true,
+            ),
+            _ => return Ok(Vec::new()),
+        };
+
+    collect_entries(&sources, dst, options, checksum, is_add, context, ignore)
+}
+
+/// Entries a `COPY --from=<stage>` node places in the image, read from `stage_root`, where
+/// the source stage's filesystem has been laid out. Sources are paths in that filesystem.
+pub fn stage_layer_entries(node: &Node, stage_root: &Path) -> Result<Vec<LayerEntry>> {
+    let NodeKind::Copy {
+        sources,
+        dst,
+        from: Some(from),
+        options,
+    } = &node.kind
+    else {
+        return Ok(Vec::new());
+    };
+    let sources: Vec<String> = sources
+        .iter()
+        .map(|s| {
+            let s = s.to_string_lossy();
+            match s.trim_start_matches('/') {
+                "" => ".".to_string(),
+                rel => rel.to_string(),
+            }
+        })
+        .collect();
+    // The layers are laid out on the host: a symlink must not lead the copy out of them
+    let root = stage_root.canonicalize()?;
+    for src in &sources {
+        let matches = resolve_source(stage_root, src);
+        if !matches.iter().any(|p| p.exists()) {
+            anyhow::bail!("{} not found in stage {}", src, from);
+        }
+        for path in matches.iter().filter_map(|p| p.canonicalize().ok()) {
+            if !path.starts_with(&root) {
+                anyhow::bail!("{} in stage {} points outside its filesystem", src, from);
+            }
+        }
+    }
+    collect_entries(
+        &sources,
+        dst,
+        options,
+        None,
+        false,
+        stage_root,
+        &IgnoreRules::empty(),
+    )
+}
+
+/// Entries for `sources` under `root`, placed at `dst`.
+fn collect_entries(
+    sources: &[String],
+    dst: &Path,
+    options: &CopyOptions,
+    checksum: Option<&str>,
+    is_add: bool,
+    context: &Path,
+    ignore: &IgnoreRules,
+) -> Result<Vec<LayerEntry>> {
+    let dst = dst.to_string_lossy().to_string();
+
+    let mut entries = Vec::new();
+    let multiple = sources.len() > 1;
+
+    for src in sources {
+        if is_url(src) {
+            let data = fetch_url(src)?;
+            verify_checksum(src, &data, checksum)?;
+            let name = src
+                .split(['?', '#'])
+                .next()
+                .and_then(|u| u.rsplit('/').next())
+                .filter(|n| !n.is_empty())
+                .unwrap_or("index.html");
+            entries.push(LayerEntry {
+                path: target_path(&dst, name, true),
+                data,
+                // Docker gives downloaded files mode 600
+                mode: 0o600,
+                is_dir: false,
+                link_target: None,
+            });
+            continue;
+        }
+
+        let matches = resolve_source(context, src);
+        let into_dir = multiple || matches.len() > 1;
+        for path in matches {
+            if path.is_dir() {
+                let rel_dir = path.strip_prefix(context).unwrap_or(&path);
+                let mut dir_ignore = ignore.relative_to(rel_dir);
+                dir_ignore.add_patterns(&options.exclude);
+                for entry in walk_entries(&path, &dir_ignore) {
+                    let rel = entry.path.strip_prefix(&path).unwrap_or(&entry.path);
+                    let target = format!("{}/{}", dst.trim_end_matches('/'), rel.to_string_lossy());
+                    entries.push(match entry.kind {
+                        EntryKind::File => file_entry(&entry.path, target)?,
+                        EntryKind::Dir => LayerEntry {
+                            path: target,
+                            data: Vec::new(),
+                            mode: file_mode(&entry.path),
+                            is_dir: true,
+                            link_target: None,
+                        },
+                        EntryKind::Symlink => LayerEntry {
+                            path: target,
+                            data: Vec::new(),
+                            mode: 0o777,
+                            is_dir: false,
+                            link_target: Some(
+                                std::fs::read_link(&entry.path)
+                                    .with_context(|| {
+                                        format!("Failed to read {}", entry.path.display())
+                                    })?
+                                    .to_string_lossy()
+                                    .to_string(),
+                            ),
+                        },
+                    });
+                }
+            } else if path.is_file() {
+                if is_add && is_tar_archive(&path)? {
+                    entries.extend(extract_archive(&path, &dst)?);
+                    continue;
+                }
+                let name = path
+                    .file_name()
+                    .map(|n| n.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                entries.push(file_entry(&path, target_path(&dst, &name, into_dir))?);
+            }
+        }
+    }
+
+    if let Some(mode) = options.chmod.as_deref().and_then(parse_chmod) {
+        // Symlinks have no permissions of their own
+        for entry in entries.iter_mut().filter(|e| e.link_target.is_none()) {
+            entry.mode = mode;
+        }
+    }
+    for entry in &mut entries {
+        entry.path = entry.path.trim_start_matches('/').to_string();
+    }
+    Ok(entries)
+}
+
+/// Destination of a single file: inside `dst` when it is a directory, else `dst` itself.
+fn target_path(dst: &str, name: &str, into_dir: bool) -> String {
+    if into_dir || dst.ends_with('/') {
+        format!("{}/{}", dst.trim_end_matches('/'), name)
+    } else {
+        dst.to_string()
+    }
+}
+
+fn file_entry(path: &Path, target: String) -> Result<LayerEntry> {
+    let data = std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(LayerEntry {
+        path: target,
+        data,
+        mode: file_mode(path),
+        is_dir: false,
+        link_target: None,
+    })
+}
+
+#[cfg(unix)]
+fn file_mode(path: &Path) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::metadata(path)
+        .map(|m| m.permissions().mode() & 0o7777)
+        .unwrap_or(0o644)
+}
+
+#[cfg(not(unix))]
+fn file_mode(_path: &Path) -> u32 {
+    0o644
+}
+
+/// ADD extracts local tar archives, plain or gzip-compressed, recognised by content.
+fn is_tar_archive(path: &Path) -> Result<bool> {
+    let mut magic = [0u8; 2];
+    let n = std::fs::File::open(path)?.read(&mut magic)?;
+    let file = std::fs::File::open(path)?;
+    let mut reader: Box<dyn Read> = if n == 2 && magic == [0x1f, 0x8b] {
+        Box::new(flate2::read::GzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+
+    let mut header = [0u8; 512];
+    let mut read = 0;
+    while read < header.len() {
+        match reader.read(&mut header[read..]) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => read += n,
+        }
+    }
+    Ok(read == header.len() && &header[257..262] == b"ustar")
+}
+
+fn extract_archive(path: &Path, dst: &str) -> Result<Vec<LayerEntry>> {
+    let data = std::fs::read(path)?;
+    let reader: Box<dyn Read> = if data.starts_with(&[0x1f, 0x8b]) {
+        Box::new(flate2::read::GzDecoder::new(&data[..]))
+    } else {
+        Box::new(&data[..])
+    };
+
+    let mut archive = tar::Archive::new(reader);
+    let mut entries: Vec<LayerEntry> = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let rel = archive_path(&entry.path()?)
+            .with_context(|| format!("{} has an entry outside {}", path.display(), dst))?;
+        if rel.is_empty() {
+            continue;
+        }
+        let target = format!("{}/{}", dst.trim_end_matches('/'), rel);
+        let kind = entry.header().entry_type();
+        let mode = entry.header().mode().unwrap_or(0o644);
+        let link = entry.link_name()?.map(|l| l.to_string_lossy().to_string());
+        let layer_entry = match kind {
+            tar::EntryType::Directory => LayerEntry {
+                path: target,
+                data: Vec::new(),
+                mode,
+                is_dir: true,
+                link_target: None,
+            },
+            tar::EntryType::Symlink => LayerEntry {
+                path: target,
+                data: Vec::new(),
+                mode: 0o777,
+                is_dir: false,
+                link_target: link,
+            },
+            // Layers have no hard links: the entry gets a copy of the file it links to
+            tar::EntryType::Link => {
+                let linked = link
+                    .as_deref()
+                    .and_then(|l| archive_path(Path::new(l)).ok())
+                    .map(|rel| format!("{}/{}", dst.trim_end_matches('/'), rel))
+                    .and_then(|linked| entries.iter().rev().find(|e| e.path == linked))
+                    .with_context(|| {
+                        format!(
+                            "{}: hard link {} points outside the archive",
+                            path.display(),
+                            rel
+                        )
+                    })?;
+                LayerEntry {
+                    path: target,
+                    data: linked.data.clone(),
+                    mode: linked.mode,
+                    is_dir: false,
+                    link_target: None,
+                }
+            }
+            _ => {
+                let mut content = Vec::new();
+                entry.read_to_end(&mut content)?;
+                LayerEntry {
+                    path: target,
+                    data: content,
+                    mode,
+                    is_dir: false,
+                    link_target: None,
+                }
+            }
+        };
+        entries.push(layer_entry);
+    }
+    Ok(entries)
+}
+
+/// Path of an archive entry relative to the extraction directory, with `.` and `..`
+/// resolved. Fails for paths that climb out of it.
+fn archive_path(path: &Path) -> Result<String> {
+    let mut parts: Vec<String> = Vec::new();
+    for component in path.components() {
+        match component {
+            std::path::Component::Normal(part) => parts.push(part.to_string_lossy().to_string()),
+            // Climbing out of a directory the path entered pops it in the guard
+            std::path::Component::ParentDir if parts.pop().is_none() => {
+                anyhow::bail!("{} escapes the extraction directory", path.display());
+            }
+            // A leading `/` is relative to the destination, as in Docker
+            _ => {}
+        }
+    }
+    Ok(parts.join("/"))
+}
+
+/// Download a URL source. The blocking client runs on its own thread so this also works
+/// when called from within the async runtime.
+fn fetch_url(url: &str) -> Result<Vec<u8>> {
+    let owned = url.to_string();
+    std::thread::spawn(move || -> Result<Vec<u8>> {
+        let response = reqwest::blocking::get(&owned)?.error_for_status()?;
+        Ok(response.bytes()?.to_vec())
+    })
+    .join()
+    .map_err(|_| anyhow::anyhow!("Download thread panicked"))?
+    .with_context(|| format!("Failed to download {}", url))
+}
+
+fn verify_checksum(url: &str, data: &[u8], checksum: Option<&str>) -> Result<()> {
+    let Some(expected) = checksum else {
+        return Ok(());
+    };
+    let hex = expected.strip_prefix("sha256:").ok_or_else(|| {
+        anyhow::anyhow!(
+            "Unsupported --checksum '{}': expected sha256:<hex>",
+            expected
+        )
+    })?;
+    let actual = sha256_bytes(data);
+    if !actual.eq_ignore_ascii_case(hex) {
+        anyhow::bail!(
+            "Checksum mismatch for {}: expected sha256:{}, got sha256:{}",
+            url,
+            hex,
+            actual
+        );
+    }
+    Ok(())
+}
@@ -1,9 +1,12 @@
-use crate::export::utils::{sha256_bytes, sha256_string};
-use crate::graph::Node;
+use crate::export::files::{layer_entries, parse_chown};
+use crate::export::utils::sha256_bytes;
+use crate::graph::{Node, NodeKind};
+use crate::hasher::IgnoreRules;
 use anyhow::Result;
 
 use flate2::Compression;
 use std::fs::{self, File};
+use std::io::Write;
 use std::path::Path;
 use tar::Builder;
 
@@ -14,19 +17,22 @@ pub struct LayerInfo {
     pub diff_id: String,
 }
 
-pub fn create_layer_tar(output_dir: &Path, node: &Node) -> Result<LayerInfo> {
+/// Write the layer of a node. With a build context, COPY/ADD layers contain the copied
+/// files that `ignore` does not exclude, owned and moded as requested by `--chown`/`--chmod`.
+pub fn create_layer_tar(
+    output_dir: &Path,
+    node: &Node,
+    context: Option<&Path>,
+    ignore: &IgnoreRules,
+) -> Result<LayerInfo> {
     let layers_dir = output_dir.join("blobs").join("sha256");
     fs::create_dir_all(&layers_dir)?;
 
     let layer_filename = format!("layer-{}.tar.gz", node.id);
     let layer_path = layers_dir.join(&layer_filename);
 
-    let file = File::create(&layer_path)?;
-    // Use GzBuilder to fix gzip header mtime to 0 for reproducibility
-    let encoder = flate2::GzBuilder::new()
-        .mtime(0)
-        .write(file, Compression::default());
-    let mut tar = Builder::new(encoder);
+    // Build the uncompressed tar first: its digest is the layer's diff_id
+    let mut tar = Builder::new(Vec::new());
 
     // For now, we add a marker file representing the layer content.
     // In a real execution engine, this would include the actual filesystem diff.
@@ -48,12 +54,56 @@ pub fn create_layer_tar(output_dir: &Path, node: &Node) -> Result<LayerInfo> {
         content.as_bytes(),
     )?;
 
-    tar.finish()?;
+    if let Some(context) = context {
+        let owner = match &node.kind {
+            NodeKind::Copy { options, .. } | NodeKind::Add { options, .. } => {
+                options.chown.as_deref().map(parse_chown)
+            }
+            _ => None,
+        };
+        for entry in layer_entries(node, context, ignore)? {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(if entry.is_dir {
+                tar::EntryType::Directory
+            } else if entry.link_target.is_some() {
+                tar::EntryType::Symlink
+            } else {
+                tar::EntryType::Regular
+            });
+            if let Some(target) = &entry.link_target {
+                header.set_link_name(target)?;
+            }
+            header.set_size(entry.data.len() as u64);
+            header.set_mode(entry.mode);
+            header.set_mtime(0);
+            let owner = owner.clone().unwrap_or_default();
+            header.set_uid(owner.uid);
+            header.set_gid(owner.gid);
+            if let Some(user) = &owner.user {
+                header.set_username(user)?;
+            }
+            if let Some(group) = &owner.group {
+                header.set_groupname(group)?;
+            }
+            header.set_cksum();
+            tar.append_data(&mut header, &entry.path, entry.data.as_slice())?;
+        }
+    }
+
+    let tar_bytes = tar.into_inner()?;
+    let diff_id = format!("sha256:{}", sha256_bytes(&tar_bytes));
+
+    // Use GzBuilder to fix gzip header mtime to 0 for reproducibility
+    let file = File::create(&layer_path)?;
+    let mut encoder = flate2::GzBuilder::new()
+        .mtime(0)
+        .write(file, Compression::default());
+    encoder.write_all(&tar_bytes)?;
+    encoder.finish()?;
 
     let layer_content = fs::read(&layer_path)?;
     let digest = format!("sha256:{}", sha256_bytes(&layer_content));
     let size = layer_content.len() as u64;
-    let diff_id = format!("sha256:{}", sha256_string(&content));
 
     // Rename to its digest-based name for OCI layout
     let digest_path = layers_dir.join(&digest[7..]);
@@ -1,4 +1,5 @@
 pub mod config;
+pub mod files;
 pub mod layer;
 pub mod manifest;
 pub mod oci_exporter;
@@ -9,18 +10,37 @@ pub use oci_exporter::OciExporter;
 
 use crate::graph::BuildGraph;
 use anyhow::Result;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 pub fn export_image(graph: &BuildGraph, image_name: &str, reproducible: bool) -> Result<PathBuf> {
+    export_image_from_context(graph, image_name, reproducible, None)
+}
+
+/// Same as [`export_image`], reading COPY/ADD sources from the build context so their
+/// layers hold the copied files.
+pub fn export_image_from_context(
+    graph: &BuildGraph,
+    image_name: &str,
+    reproducible: bool,
+    context: Option<&Path>,
+) -> Result<PathBuf> {
     let output_dir = PathBuf::from(".memobuild-output").join(image_name.replace(':', "-"));
 
     let mut exporter = OciExporter::new(&output_dir);
+    if let Some(context) = context {
+        exporter = exporter
+            .with_context(context)
+            .with_ignore_rules(graph.ignore_rules(context));
+    }
+
+    // Only the final stage (and the stages it is based on) end up in the image
+    let image = graph.subgraph(&crate::docker::dag::image_nodes(graph));
 
-    for node in &graph.nodes {
+    for node in &image.nodes {
         // In this demo, we export all nodes as layers
         let layer_info = exporter.create_layer(node)?;
         exporter.add_layer(layer_info)?;
     }
 
-    exporter.write_manifest(graph, reproducible)
+    exporter.write_manifest(&image, reproducible)
 }
@@ -4,6 +4,7 @@ use crate::export::{
     utils,
 };
 use crate::graph::Node;
+use crate::hasher::IgnoreRules;
 use anyhow::Result;
 use std::fs;
 use std::path::{Path, PathBuf};
@@ -11,6 +12,8 @@ use std::path::{Path, PathBuf};
 pub struct OciExporter {
     output_dir: PathBuf,
     layers: Vec<layer::LayerInfo>,
+    context: Option<PathBuf>,
+    ignore: IgnoreRules,
 }
 
 impl OciExporter {
@@ -19,11 +22,31 @@ impl OciExporter {
         Self {
             output_dir,
             layers: Vec::new(),
+            context: None,
+            ignore: IgnoreRules::empty(),
         }
     }
 
+    /// Build context to read COPY/ADD sources from, with its `.dockerignore`.
+    pub fn with_context<P: AsRef<Path>>(mut self, context: P) -> Self {
+        self.ignore = IgnoreRules::for_context(context.as_ref(), None);
+        self.context = Some(context.as_ref().to_path_buf());
+        self
+    }
+
+    /// Ignore rules for the context files, replacing its `.dockerignore`.
+    pub fn with_ignore_rules(mut self, ignore: IgnoreRules) -> Self {
+        self.ignore = ignore;
+        self
+    }
+
     pub fn create_layer(&self, node: &Node) -> Result<layer::LayerInfo> {
-        layer::create_layer_tar(&self.output_dir, node)
+        layer::create_layer_tar(
+            &self.output_dir,
+            node,
+            self.context.as_deref(),
+            &self.ignore,
+        )
     }
 
     pub fn add_layer(&mut self, layer_info: layer::LayerInfo) -> Result<()> {
@@ -1,17 +1,73 @@
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub enum NodeKind {
     From,
-    Run,
+    Run {
+        /// `--mount` flags: filesystems available only while the command runs
+        #[serde(default)]
+        mounts: Vec<RunMount>,
+    },
     Copy {
-        src: PathBuf,
+        /// Source paths as written, relative to the context (or to the `--from` root)
+        sources: Vec<PathBuf>,
+        /// Destination resolved against WORKDIR; a trailing `/` marks a directory
         dst: PathBuf,
+        /// `--from=<stage|image>`: copy out of another stage or image instead of the context
+        from: Option<String>,
+        #[serde(default)]
+        options: CopyOptions,
     },
     Env,
+    /// `ARG`: declares a build argument; its value reaches later nodes through their env
+    Arg,
     Workdir,
-    Cmd,
+    /// `CMD`, resolved to the argument vector stored in the image config
+    Cmd {
+        command: Vec<String>,
+    },
+    Entrypoint {
+        command: Vec<String>,
+    },
+    User {
+        user: String,
+    },
+    Expose {
+        /// `port[/protocol]` entries
+        ports: Vec<String>,
+    },
+    Label {
+        labels: Vec<(String, String)>,
+    },
+    Volume {
+        paths: Vec<String>,
+    },
+    Healthcheck {
+        config: HealthcheckConfig,
+    },
+    /// `SHELL`: the shell used by later shell-form RUN, CMD and ENTRYPOINT instructions
+    Shell {
+        shell: Vec<String>,
+    },
+    Stopsignal {
+        signal: String,
+    },
+    /// `ONBUILD`: a trigger recorded in the image for builds that use it as a base
+    Onbuild {
+        trigger: String,
+    },
+    /// `ADD`: like COPY, but sources may be URLs and local tar archives are extracted
+    Add {
+        /// Context paths or `http(s)://` URLs
+        sources: Vec<String>,
+        /// Destination resolved against WORKDIR; a trailing `/` marks a directory
+        dst: PathBuf,
+        /// `--checksum=sha256:<hex>` pinning the content of a URL source
+        checksum: Option<String>,
+        #[serde(default)]
+        options: CopyOptions,
+    },
     Git {
         url: String,
         target: PathBuf,
@@ -33,6 +89,101 @@ pub enum NodeKind {
     Other,
 }
 
+impl NodeKind {
+    /// Whether the node changes the image filesystem, as opposed to only its config.
+    pub fn writes_files(&self) -> bool {
+        matches!(
+            self,
+            NodeKind::Run { .. }
+                | NodeKind::Copy { .. }
+                | NodeKind::Add { .. }
+                | NodeKind::Git { .. }
+                | NodeKind::RunExtend { .. }
+                | NodeKind::CopyExtend { .. }
+                | NodeKind::CustomHook { .. }
+                | NodeKind::Other
+        )
+    }
+}
+
+/// COPY/ADD flags that shape the copied files.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct CopyOptions {
+    /// `--chown=<user>[:<group>]`, names or numeric ids
+    pub chown: Option<String>,
+    /// `--chmod=<octal>`, applied to every copied file and directory
+    pub chmod: Option<String>,
+    /// `--link`: the copied files do not depend on the layers below them
+    pub link: bool,
+    /// `--exclude=<pattern>`, matched relative to each source
+    pub exclude: Vec<String>,
+}
+
+/// A `RUN --mount=type=...` flag. Targets are resolved against WORKDIR.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum RunMount {
+    /// Read-only view of the build context, or of another stage with `from`
+    Bind {
+        target: PathBuf,
+        /// Path inside the context or stage; defaults to its root
+        source: Option<String>,
+        from: Option<String>,
+        /// `rw`: writes are allowed but discarded
+        readwrite: bool,
+    },
+    /// Directory persisted across builds, shared by every mount with the same `id`
+    Cache {
+        target: PathBuf,
+        /// Defaults to the target path
+        id: String,
+        sharing: CacheSharing,
+        readonly: bool,
+    },
+    /// Empty scratch directory
+    Tmpfs {
+        target: PathBuf,
+        size: Option<String>,
+    },
+    /// Secret from the secret provider, exposed as a file at `target` and/or as the
+    /// environment variable `env`. Only the id is recorded; the value never is.
+    Secret {
+        id: String,
+        target: Option<PathBuf>,
+        env: Option<String>,
+        required: bool,
+    },
+    /// The host's SSH agent socket
+    Ssh {
+        id: String,
+        target: PathBuf,
+        required: bool,
+    },
+}
+
+/// How concurrent builds share a cache mount.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum CacheSharing {
+    /// Every writer uses the same directory at once
+    #[default]
+    Shared,
+    /// A writer finding the directory in use gets a fresh, throwaway one
+    Private,
+    /// Writers wait for each other
+    Locked,
+}
+
+/// `HEALTHCHECK` settings. Durations are kept as written (e.g. `30s`, `1m30s`).
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct HealthcheckConfig {
+    /// `["NONE"]`, `["CMD", args...]` or `["CMD-SHELL", command]`
+    pub test: Vec<String>,
+    pub interval: Option<String>,
+    pub timeout: Option<String>,
+    pub start_period: Option<String>,
+    pub start_interval: Option<String>,
+    pub retries: Option<u32>,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Node {
     pub id: usize,
@@ -70,6 +221,41 @@ pub struct NodeMetadata {
     pub output_manifest_hash: Option<String>,
     /// AI-detected extra dependencies (source paths)
     pub extra_source_paths: Vec<std::path::PathBuf>,
+    /// Index of the build stage (FROM) this node belongs to
+    #[serde(default)]
+    pub stage: usize,
+    /// Stage name from `FROM <image> AS <name>`
+    #[serde(default)]
+    pub stage_name: Option<String>,
+    /// Image the stage is built on, following `FROM <stage>` back to an image; None for
+    /// `scratch`
+    #[serde(default)]
+    pub base_image: Option<String>,
+    /// Shell for shell-form commands set by `SHELL`; empty means the default `/bin/sh -c`
+    #[serde(default)]
+    pub shell: Vec<String>,
+    /// Absolute `WORKDIR` commands run in; empty means `/`
+    #[serde(default)]
+    pub workdir: String,
+    /// Resource limits set by a `# memobuild:limits` directive above the instruction
+    #[serde(default)]
+    pub limits: crate::sandbox::ResourceLimits,
+    /// Network policy set by a `# memobuild:network` directive, `RUN --network` or
+    /// [`BuildGraph::set_default_network`]; None leaves it to the sandbox
+    #[serde(default)]
+    pub network: Option<crate::sandbox::NetworkPolicy>,
+    /// URLs the command fetched through the network allowlist proxy, with their digests
+    #[serde(default)]
+    pub fetched: Vec<crate::sandbox::FetchedUrl>,
+    /// Cache keys of the nodes whose filesystem changes, applied in order, make up the
+    /// filesystem this node runs on. Set by [`BuildGraph::assign_input_layers`].
+    #[serde(default)]
+    pub input_layers: Vec<String>,
+    /// For `COPY --from=<stage>`, cache keys of the layers making up that stage's final
+    /// filesystem, in the order they apply; None when copying from an image. Set by
+    /// [`BuildGraph::assign_input_layers`].
+    #[serde(default)]
+    pub source_layers: Option<Vec<String>>,
 }
 
 impl Node {
@@ -118,6 +304,12 @@ impl Node {
         // 6. Hash metadata that affects execution
         hasher.update(format!("parallelizable={}", self.metadata.parallelizable).as_bytes());
         hasher.update(format!("priority={}", self.metadata.priority).as_bytes());
+        if !self.metadata.shell.is_empty() {
+            hasher.update(format!("shell={:?}", self.metadata.shell).as_bytes());
+        }
+        if let Some(network) = &self.metadata.network {
+            hasher.update(format!("network={:?}", network).as_bytes());
+        }
 
         // 7. Hash environment fingerprint for global determinism
         if let Some(fp) = env_fingerprint {
@@ -131,16 +323,161 @@ impl Node {
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct BuildGraph {
     pub nodes: Vec<Node>,
+    /// The Dockerfile's own `<Dockerfile>.dockerignore`, which replaces the context's
+    /// `.dockerignore` for this build
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ignore_file: Option<PathBuf>,
 }
 
 impl BuildGraph {
     pub fn new() -> Self {
-        Self { nodes: Vec::new() }
+        Self {
+            nodes: Vec::new(),
+            ignore_file: None,
+        }
+    }
+
+    /// Give every command without a network policy of its own the build's `policy`, so
+    /// that it is part of their cache keys.
+    pub fn set_default_network(&mut self, policy: &crate::sandbox::NetworkPolicy) {
+        for node in &mut self.nodes {
+            if node.metadata.network.is_none() && crate::sandbox::command_form(node).is_some() {
+                node.metadata.network = Some(policy.clone());
+            }
+        }
+    }
+
+    /// Ignore rules that decide which context files this build reads.
+    pub fn ignore_rules(&self, context_dir: &Path) -> crate::hasher::IgnoreRules {
+        crate::hasher::IgnoreRules::for_context(context_dir, self.ignore_file.as_deref())
+    }
+
+    /// Every URL the build's commands fetched through the network allowlist proxy, once each,
+    /// in node order.
+    pub fn fetched_urls(&self) -> Vec<crate::sandbox::FetchedUrl> {
+        let mut seen = std::collections::HashSet::new();
+        self.nodes
+            .iter()
+            .flat_map(|node| &node.metadata.fetched)
+            .filter(|fetched| seen.insert(*fetched))
+            .cloned()
+            .collect()
     }
 }
 
 impl BuildGraph {
-    /// Get nodes in topological order for execution
+    /// Check the graph's structure: every dependency exists, ids are unique and match the
+    /// node positions (deps index into `nodes`), there are no dependency cycles, and every
+    /// stage contributes to the final stage. Only the last is a warning.
+    pub fn validate(&self) -> GraphValidation {
+        let mut issues = Vec::new();
+        let len = self.nodes.len();
+
+        let mut positions: std::collections::BTreeMap<usize, Vec<usize>> = Default::default();
+        for (index, node) in self.nodes.iter().enumerate() {
+            positions.entry(node.id).or_default().push(index);
+        }
+        for (&id, at) in &positions {
+            if at.len() > 1 {
+                issues.push(GraphIssue::DuplicateId {
+                    id,
+                    positions: at.clone(),
+                });
+            }
+        }
+        for (index, node) in self.nodes.iter().enumerate() {
+            if node.id != index && positions[&node.id].len() == 1 {
+                issues.push(GraphIssue::MisplacedId { index, id: node.id });
+            }
+            for &dep in &node.deps {
+                if dep >= len {
+                    issues.push(GraphIssue::DanglingDependency { node: index, dep });
+                }
+            }
+        }
+
+        issues.extend(
+            self.find_cycles()
+                .into_iter()
+                .map(|path| GraphIssue::Cycle { path }),
+        );
+
+        if let Some(last) = self.nodes.last() {
+            let final_stage = last.metadata.stage;
+            let roots: Vec<usize> = (0..len)
+                .filter(|&i| self.nodes[i].metadata.stage == final_stage)
+                .collect();
+            let needed = self.ancestors(&roots);
+            let mut seen = Vec::new();
+            for node in &self.nodes {
+                let stage = node.metadata.stage;
+                if seen.contains(&stage) {
+                    continue;
+                }
+                seen.push(stage);
+                let used =
+                    self.nodes.iter().enumerate().any(|(i, n)| {
+                        n.metadata.stage == stage && needed.binary_search(&i).is_ok()
+                    });
+                if !used {
+                    issues.push(GraphIssue::UnreachableStage {
+                        stage,
+                        name: node.metadata.stage_name.clone(),
+                    });
+                }
+            }
+        }
+
+        GraphValidation { issues }
+    }
+
+    /// Every dependency cycle, each as the path of node indices starting and ending at the
+    /// same node, where each node depends on the next
+    fn find_cycles(&self) -> Vec<Vec<usize>> {
+        // 0 = unvisited, 1 = on the current path, 2 = done
+        let mut state = vec![0u8; self.nodes.len()];
+        let mut cycles = Vec::new();
+
+        for start in 0..self.nodes.len() {
+            if state[start] != 0 {
+                continue;
+            }
+            // Iterative DFS: (node, next dependency to look at)
+            let mut path: Vec<(usize, usize)> = vec![(start, 0)];
+            state[start] = 1;
+            while let Some(top) = path.last_mut() {
+                let node = top.0;
+                let deps = &self.nodes[node].deps;
+                if top.1 == deps.len() {
+                    state[node] = 2;
+                    path.pop();
+                    continue;
+                }
+                let dep = deps[top.1];
+                top.1 += 1;
+                if dep >= self.nodes.len() {
+                    continue;
+                }
+                match state[dep] {
+                    0 => {
+                        state[dep] = 1;
+                        path.push((dep, 0));
+                    }
+                    1 => {
+                        let from = path.iter().position(|&(n, _)| n == dep).unwrap_or(0);
+                        let mut cycle: Vec<usize> = path[from..].iter().map(|&(n, _)| n).collect();
+                        cycle.push(dep);
+                        cycles.push(cycle);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        cycles
+    }
+
+    /// Get nodes in topological order for execution (dependencies before dependents).
+    /// The order is only meaningful for graphs that pass [`BuildGraph::validate`].
     pub fn topological_order(&self) -> Vec<usize> {
         let mut visited = vec![false; self.nodes.len()];
         let mut stack = Vec::new();
@@ -151,7 +488,7 @@ impl BuildGraph {
             }
         }
 
-        stack.reverse();
+        // dfs_topo pushes a node only after all of its dependencies
         stack
     }
 
@@ -191,4 +528,217 @@ impl BuildGraph {
 
         result
     }
+
+    /// Indices of `roots` and every node they transitively depend on, in ascending order
+    pub fn ancestors(&self, roots: &[usize]) -> Vec<usize> {
+        let mut keep = vec![false; self.nodes.len()];
+        let mut stack: Vec<usize> = roots
+            .iter()
+            .copied()
+            .filter(|&r| r < self.nodes.len())
+            .collect();
+
+        while let Some(id) = stack.pop() {
+            if keep[id] {
+                continue;
+            }
+            keep[id] = true;
+            stack.extend(
+                self.nodes[id]
+                    .deps
+                    .iter()
+                    .copied()
+                    .filter(|&d| d < self.nodes.len()),
+            );
+        }
+
+        (0..self.nodes.len()).filter(|&i| keep[i]).collect()
+    }
+
+    /// Nodes whose filesystem changes make up the filesystem `id` runs on, in the order they
+    /// apply: the file-writing ancestors in its stage and, through `FROM <stage>`, in the
+    /// stages it is based on. `COPY --from` and `RUN --mount=from` only read another
+    /// stage, so its layers are not part of this one.
+    pub fn filesystem_inputs(&self, id: usize) -> Vec<usize> {
+        let mut seen = vec![false; self.nodes.len()];
+        let mut stack = vec![id];
+        while let Some(current) = stack.pop() {
+            let node = &self.nodes[current];
+            for &dep in &node.deps {
+                if dep >= self.nodes.len() || seen[dep] {
+                    continue;
+                }
+                if matches!(node.kind, NodeKind::From)
+                    || self.nodes[dep].metadata.stage == node.metadata.stage
+                {
+                    seen[dep] = true;
+                    stack.push(dep);
+                }
+            }
+        }
+        (0..self.nodes.len())
+            .filter(|&i| seen[i] && self.nodes[i].kind.writes_files())
+            .collect()
+    }
+
+    /// For `COPY --from=<stage>`, the nodes whose filesystem changes make up that stage's
+    /// final filesystem, in the order they apply. None for other nodes and for copies out of
+    /// an image, which has no nodes in the graph.
+    pub fn copy_source_inputs(&self, id: usize) -> Option<Vec<usize>> {
+        let node = &self.nodes[id];
+        if !matches!(node.kind, NodeKind::Copy { from: Some(_), .. }) {
+            return None;
+        }
+        // The dag makes the copy depend on the source stage's filesystem
+        let sources: Vec<usize> = node
+            .deps
+            .iter()
+            .copied()
+            .filter(|&d| {
+                d < self.nodes.len() && self.nodes[d].metadata.stage != node.metadata.stage
+            })
+            .collect();
+        if sources.is_empty() {
+            return None;
+        }
+        let mut inputs: Vec<usize> = sources
+            .iter()
+            .flat_map(|&source| {
+                let mut inputs = self.filesystem_inputs(source);
+                if self.nodes[source].kind.writes_files() {
+                    inputs.push(source);
+                }
+                inputs
+            })
+            .collect();
+        inputs.sort_unstable();
+        inputs.dedup();
+        Some(inputs)
+    }
+
+    /// Record each node's [`NodeMetadata::input_layers`] and
+    /// [`NodeMetadata::source_layers`] from the current cache keys.
+    pub fn assign_input_layers(&mut self) {
+        for id in 0..self.nodes.len() {
+            let hashes = |ids: Vec<usize>| -> Vec<String> {
+                ids.into_iter()
+                    .map(|dep| self.nodes[dep].hash.clone())
+                    .collect()
+            };
+            let layers = hashes(self.filesystem_inputs(id));
+            let source_layers = self.copy_source_inputs(id).map(hashes);
+            self.nodes[id].metadata.input_layers = layers;
+            self.nodes[id].metadata.source_layers = source_layers;
+        }
+    }
+
+    /// Build a new graph holding only `keep` (in the given order), renumbering ids and deps.
+    /// Dependencies on nodes outside `keep` are dropped.
+    pub fn subgraph(&self, keep: &[usize]) -> BuildGraph {
+        let remap: std::collections::HashMap<usize, usize> = keep
+            .iter()
+            .enumerate()
+            .map(|(new_id, &old_id)| (old_id, new_id))
+            .collect();
+
+        let nodes = keep
+            .iter()
+            .enumerate()
+            .map(|(new_id, &old_id)| {
+                let mut node = self.nodes[old_id].clone();
+                node.id = new_id;
+                node.deps = node
+                    .deps
+                    .iter()
+                    .filter_map(|d| remap.get(d).copied())
+                    .collect();
+                node
+            })
+            .collect();
+
+        BuildGraph {
+            nodes,
+            ignore_file: self.ignore_file.clone(),
+        }
+    }
+}
+
+/// A structural problem found by [`BuildGraph::validate`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum GraphIssue {
+    /// Nodes that (transitively) depend on themselves; each depends on the next
+    Cycle { path: Vec<usize> },
+    /// A dependency on a node index outside the graph
+    DanglingDependency { node: usize, dep: usize },
+    /// Several nodes share an id
+    DuplicateId { id: usize, positions: Vec<usize> },
+    /// A node whose id differs from its position, which dependencies refer to
+    MisplacedId { index: usize, id: usize },
+    /// A stage the final stage never uses: it is built for nothing
+    UnreachableStage { stage: usize, name: Option<String> },
+}
+
+impl GraphIssue {
+    /// Errors make the graph impossible to execute correctly; the rest are warnings.
+    pub fn is_error(&self) -> bool {
+        !matches!(self, GraphIssue::UnreachableStage { .. })
+    }
+}
+
+impl std::fmt::Display for GraphIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GraphIssue::Cycle { path } => {
+                let path: Vec<String> = path.iter().map(|n| n.to_string()).collect();
+                write!(f, "dependency cycle: {}", path.join(" -> "))
+            }
+            GraphIssue::DanglingDependency { node, dep } => {
+                write!(f, "node {} depends on missing node {}", node, dep)
+            }
+            GraphIssue::DuplicateId { id, positions } => {
+                write!(f, "id {} is used by the nodes at {:?}", id, positions)
+            }
+            GraphIssue::MisplacedId { index, id } => {
+                write!(f, "node at position {} has id {}", index, id)
+            }
+            GraphIssue::UnreachableStage { stage, name } => match name {
+                Some(name) => write!(
+                    f,
+                    "stage {} ({}) is not used by the final stage",
+                    stage, name
+                ),
+                None => write!(f, "stage {} is not used by the final stage", stage),
+            },
+        }
+    }
+}
+
+/// Result of [`BuildGraph::validate`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GraphValidation {
+    pub issues: Vec<GraphIssue>,
+}
+
+impl GraphValidation {
+    pub fn is_valid(&self) -> bool {
+        self.errors().next().is_none()
+    }
+
+    pub fn errors(&self) -> impl Iterator<Item = &GraphIssue> {
+        self.issues.iter().filter(|i| i.is_error())
+    }
+
+    pub fn warnings(&self) -> impl Iterator<Item = &GraphIssue> {
+        self.issues.iter().filter(|i| !i.is_error())
+    }
+
+    /// Fail with every error when the graph cannot be executed.
+    pub fn ensure_valid(&self) -> anyhow::Result<()> {
+        if self.is_valid() {
+            return Ok(());
+        }
+        let errors: Vec<String> = self.errors().map(|e| e.to_string()).collect();
+        anyhow::bail!("Invalid build graph: {}", errors.join("; "))
+    }
 }
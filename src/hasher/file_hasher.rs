@@ -1,14 +1,23 @@
-use crate::hasher::{ignore::IgnoreRules, walker::walk_dir};
+use crate::hasher::walker::{walk_entries, EntryKind, WalkEntry};
+use crate::hasher::{ignore::IgnoreRules, stat_cache::StatCache};
 use anyhow::{Context, Result};
 use blake3::Hasher;
 use rayon::prelude::*;
-use std::fs::File;
+use std::fs::{File, Metadata};
 use std::io::{BufReader, Read};
 use std::path::Path;
 
 /// Chunk size for large-file streaming hashing (64 KB — BLAKE3 optimal)
 const CHUNK_SIZE: usize = 64 * 1024;
 
+/// Version of the context hash scheme, mixed into every entry and directory hash so that
+/// changing the scheme changes the cache keys of all nodes with context sources instead of
+/// mixing old and new hashes.
+///
+/// 1. File contents only
+/// 2. Permission bits of files and directories, symlink targets and directory entries
+pub const HASH_SCHEME_VERSION: u32 = 2;
+
 /// Hash a single file using BLAKE3, reading in 64 KB chunks.
 pub fn hash_file(path: &Path) -> Result<String> {
     let file = File::open(path)
@@ -32,34 +41,130 @@ pub fn hash_file(path: &Path) -> Result<String> {
 
 /// Hash a directory tree recursively using Rayon for parallel execution.
 pub fn hash_dir(root: &Path, ignore: &IgnoreRules) -> Result<String> {
-    let files = walk_dir(root, ignore);
+    Ok(combine_file_hashes(&hash_dir_files(root, ignore)?))
+}
+
+/// Hash every non-ignored file, directory and symlink under `root` with [`hash_entry`],
+/// keyed by its path relative to `root`, in path order.
+pub fn hash_dir_files(root: &Path, ignore: &IgnoreRules) -> Result<Vec<(String, String)>> {
+    hash_dir_files_with(root, ignore, None)
+}
+
+/// [`hash_dir_files`], skipping files the stat cache knows to be unchanged.
+pub fn hash_dir_files_with(
+    root: &Path,
+    ignore: &IgnoreRules,
+    stat_cache: Option<&StatCache>,
+) -> Result<Vec<(String, String)>> {
+    let entries = walk_entries(root, ignore);
 
     // Fix 2: Parallel hashing of file contents using Rayon
-    let results: Result<Vec<(String, String)>> = files
+    entries
         .par_iter()
-        .map(|abs_path| {
-            let rel = abs_path.strip_prefix(root).unwrap_or(abs_path.as_path());
+        .map(|entry| {
+            let rel = entry
+                .path
+                .strip_prefix(root)
+                .unwrap_or(entry.path.as_path());
             let rel_path_str = rel.to_string_lossy().to_string();
-            let file_hash = hash_file(abs_path)?;
-            Ok((rel_path_str, file_hash))
+            Ok((rel_path_str, hash_entry(entry, stat_cache)?))
         })
-        .collect();
+        .collect()
+}
+
+/// Hash what a COPY places in the image for one walked entry: the content and permission
+/// bits of a file, the target of a symlink or the permission bits of a directory.
+pub fn hash_entry(entry: &WalkEntry, stat_cache: Option<&StatCache>) -> Result<String> {
+    let path = entry.path.as_path();
+    let meta = std::fs::symlink_metadata(path)
+        .with_context(|| format!("Cannot stat {} for hashing", path.display()))?;
+    match entry.kind {
+        EntryKind::File => hash_file_entry(path, &meta, stat_cache),
+        EntryKind::Dir => Ok(versioned_hash(&[
+            b"dir",
+            format!("{:o}", mode_bits(&meta)).as_bytes(),
+        ])),
+        EntryKind::Symlink => {
+            let target = std::fs::read_link(path)
+                .with_context(|| format!("Cannot read symlink {}", path.display()))?;
+            Ok(versioned_hash(&[
+                b"symlink",
+                target.to_string_lossy().as_bytes(),
+            ]))
+        }
+    }
+}
+
+fn hash_file_entry(path: &Path, meta: &Metadata, stat_cache: Option<&StatCache>) -> Result<String> {
+    let content = match stat_cache {
+        Some(cache) => cache.hash_file(path)?,
+        None => hash_file(path)?,
+    };
+    Ok(versioned_hash(&[
+        b"file",
+        format!("{:o}", mode_bits(meta)).as_bytes(),
+        content.as_bytes(),
+    ]))
+}
 
+/// Hash of length-prefixed fields under the current [`HASH_SCHEME_VERSION`]
+fn versioned_hash(fields: &[&[u8]]) -> String {
+    let mut hasher = Hasher::new();
+    hasher.update(&HASH_SCHEME_VERSION.to_le_bytes());
+    for field in fields {
+        hasher.update(&(field.len() as u64).to_le_bytes());
+        hasher.update(field);
+    }
+    hasher.finalize().to_hex().to_string()
+}
+
+/// Permission bits as written to layer tar headers, setuid/setgid/sticky included
+#[cfg(unix)]
+fn mode_bits(meta: &Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    meta.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn mode_bits(meta: &Metadata) -> u32 {
+    match (meta.is_dir(), meta.permissions().readonly()) {
+        (true, _) => 0o755,
+        (false, true) => 0o444,
+        (false, false) => 0o644,
+    }
+}
+
+/// Directory hash of the per-entry hashes returned by [`hash_dir_files`].
+pub fn combine_file_hashes(files: &[(String, String)]) -> String {
     let mut top_hasher = Hasher::new();
-    for (rel_path, file_hash) in results? {
+    top_hasher.update(&HASH_SCHEME_VERSION.to_le_bytes());
+    for (rel_path, file_hash) in files {
         top_hasher.update(rel_path.as_bytes());
         top_hasher.update(file_hash.as_bytes());
     }
-
-    Ok(top_hasher.finalize().to_hex().to_string())
+    top_hasher.finalize().to_hex().to_string()
 }
 
 /// Dispatch: hash a file or a directory, respecting ignore rules.
 pub fn hash_path(path: &Path, ignore: &IgnoreRules) -> Result<String> {
+    hash_path_with(path, ignore, None)
+}
+
+/// [`hash_path`], skipping files the stat cache knows to be unchanged.
+pub fn hash_path_with(
+    path: &Path,
+    ignore: &IgnoreRules,
+    stat_cache: Option<&StatCache>,
+) -> Result<String> {
     if path.is_dir() {
-        hash_dir(path, ignore)
+        Ok(combine_file_hashes(&hash_dir_files_with(
+            path, ignore, stat_cache,
+        )?))
     } else if path.is_file() {
-        hash_file(path)
+        // A source that is itself a symlink is followed, as COPY does
+        let meta = std::fs::metadata(path)
+            .with_context(|| format!("Cannot stat {} for hashing", path.display()))?;
+        hash_file_entry(path, &meta, stat_cache)
     } else {
         let mut hasher = Hasher::new();
         hasher.update(path.to_string_lossy().as_bytes());
@@ -1,7 +1,66 @@
-use glob::Pattern;
-use std::path::Path;
+//! `.dockerignore` rules, matched the way Docker matches them.
+//!
+//! Lines are read like Docker's `dockerignore.ReadAll`: `#` comments and blank lines are
+//! skipped, `!` marks an exception, and each pattern is cleaned (`.`/`..` elements and
+//! duplicate slashes removed) and anchored at the context root, so `/foo` and `foo` are
+//! the same. Patterns follow Go's `filepath.Match` plus `**`, which matches any number of
+//! directories. Rules apply in order and the last matching one wins; a path is also
+//! excluded when one of its parent directories is, unless a later exception matches it.
+
+use regex::Regex;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone)]
+struct Pattern {
+    /// Cleaned pattern, without the leading `!`
+    text: String,
+    /// `!` exception: re-includes what earlier patterns excluded
+    exclusion: bool,
+    regex: Regex,
+    /// Directory, relative to the context root, that checked paths are relative to
+    base: Option<String>,
+}
+
+impl Pattern {
+    fn new(line: &str) -> Option<Self> {
+        let (exclusion, pattern) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest.trim()),
+            None => (false, line),
+        };
+        if pattern.is_empty() {
+            // A lone "!" is rejected by Docker
+            return None;
+        }
+        let mut text = clean(pattern);
+        if text.len() > 1 && text.starts_with('/') {
+            text.remove(0);
+        }
+        let regex = Regex::new(&to_regex(&text)?).ok()?;
+        Some(Pattern {
+            text,
+            exclusion,
+            regex,
+            base: None,
+        })
+    }
+
+    fn has_wildcards(&self) -> bool {
+        let mut chars = self.text.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => {
+                    chars.next();
+                }
+                '*' | '?' | '[' => return true,
+                _ => {}
+            }
+        }
+        false
+    }
+}
 
 /// Parsed ignore rules from .dockerignore or .gitignore
+#[derive(Debug, Clone, Default)]
 pub struct IgnoreRules {
     patterns: Vec<Pattern>,
 }
@@ -13,6 +72,27 @@ impl IgnoreRules {
         }
     }
 
+    /// The same rules, for paths relative to `dir` (itself relative to the directory the
+    /// rules apply to), e.g. to walk the directory a COPY source names.
+    pub fn relative_to(&self, dir: &Path) -> Self {
+        let dir = slash_path(dir);
+        let patterns = self
+            .patterns
+            .iter()
+            .map(|pattern| {
+                let base = match &pattern.base {
+                    Some(base) => format!("{}/{}", base, dir),
+                    None => dir.clone(),
+                };
+                Pattern {
+                    base: Some(clean(&base)).filter(|b| b != "."),
+                    ..pattern.clone()
+                }
+            })
+            .collect();
+        Self { patterns }
+    }
+
     /// Load rules from a file (e.g. .dockerignore)
     pub fn from_file(path: &Path) -> Self {
         let content = match std::fs::read_to_string(path) {
@@ -22,33 +102,215 @@ impl IgnoreRules {
         Self::parse(&content)
     }
 
-    /// Parse rules from a string using the glob crate for reliability.
+    /// Rules for a build of `context_dir`: `ignore_file` when the Dockerfile has its own
+    /// (see [`IgnoreRules::dockerfile_ignore_file`]), the context's `.dockerignore`
+    /// otherwise.
+    pub fn for_context(context_dir: &Path, ignore_file: Option<&Path>) -> Self {
+        match ignore_file {
+            Some(path) => Self::from_file(path),
+            None => Self::from_file(&context_dir.join(".dockerignore")),
+        }
+    }
+
+    /// `<Dockerfile>.dockerignore` next to the Dockerfile, if there is one. Like Docker, it
+    /// takes precedence over the `.dockerignore` at the context root.
+    pub fn dockerfile_ignore_file(dockerfile: &Path) -> Option<PathBuf> {
+        let name = dockerfile.file_name()?.to_string_lossy();
+        let path = dockerfile.with_file_name(format!("{}.dockerignore", name));
+        path.is_file().then_some(path)
+    }
+
+    /// Parse rules from the content of an ignore file. Invalid patterns are skipped.
     pub fn parse(content: &str) -> Self {
-        let patterns = content
-            .lines()
-            .map(|l| l.trim())
-            .filter(|l| !l.is_empty() && !l.starts_with('#'))
-            .filter_map(|l| Pattern::new(l).ok())
-            .collect();
-        Self { patterns }
+        let mut rules = Self::empty();
+        let content = content.strip_prefix('\u{feff}').unwrap_or(content);
+        for line in content.lines() {
+            // Only a '#' in the first column starts a comment
+            if line.starts_with('#') {
+                continue;
+            }
+            rules.push(line);
+        }
+        rules
+    }
+
+    /// Add patterns on top of the loaded ones, e.g. from `COPY --exclude`. Unlike the
+    /// loaded ones, they match paths as given, even after [`IgnoreRules::relative_to`].
+    pub fn add_patterns(&mut self, patterns: &[String]) {
+        for pattern in patterns {
+            self.push(pattern);
+        }
+    }
+
+    fn push(&mut self, line: &str) {
+        let line = line.trim();
+        if line.is_empty() {
+            return;
+        }
+        if let Some(pattern) = Pattern::new(line) {
+            self.patterns.push(pattern);
+        }
     }
 
     /// Returns true if the given path (relative to the build context root) should be ignored
     pub fn is_ignored(&self, path: &Path) -> bool {
-        // Check the path itself and all its parents
-        for ancestor in path.ancestors() {
-            let path_str = ancestor.to_string_lossy();
-            if path_str.is_empty() || path_str == "." {
+        let path = slash_path(path);
+        // The path and its parent directories, as seen from each pattern's base
+        let mut candidates: Vec<(Option<&str>, Vec<String>)> = Vec::new();
+
+        let mut matched = false;
+        for pattern in &self.patterns {
+            // An exclusion can only undo a match and an inclusion only add one
+            if pattern.exclusion != matched {
                 continue;
             }
-            for pattern in &self.patterns {
-                if pattern.matches(&path_str) {
-                    return true;
+            let base = pattern.base.as_deref();
+            let index = match candidates.iter().position(|(b, _)| *b == base) {
+                Some(index) => index,
+                None => {
+                    candidates.push((base, with_parents(&based_path(base, &path))));
+                    candidates.len() - 1
                 }
+            };
+            if candidates[index]
+                .1
+                .iter()
+                .any(|p| pattern.regex.is_match(p))
+            {
+                matched = !pattern.exclusion;
             }
         }
-        false
+        matched
+    }
+
+    /// Whether a walk can skip the directory `path` entirely: it is ignored and no exception
+    /// can re-include anything below it. Exceptions with wildcards keep every ignored
+    /// directory walked, as Docker does.
+    pub fn skips_dir(&self, path: &Path) -> bool {
+        if !self.is_ignored(path) {
+            return false;
+        }
+        let path = slash_path(path);
+        !self.patterns.iter().any(|p| {
+            let dir = format!("{}/", based_path(p.base.as_deref(), &path));
+            p.exclusion && (p.has_wildcards() || format!("{}/", p.text).starts_with(&dir))
+        })
+    }
+}
+
+fn based_path(base: Option<&str>, path: &str) -> String {
+    match base {
+        Some(base) => clean(&format!("{}/{}", base, path)),
+        None => clean(path),
+    }
+}
+
+/// `path` followed by its parent directories, longest first. Empty for the root itself.
+fn with_parents(path: &str) -> Vec<String> {
+    if path == "." {
+        return Vec::new();
+    }
+    let mut paths = vec![path.to_string()];
+    let mut rest = path;
+    while let Some(i) = rest.rfind('/') {
+        rest = &rest[..i];
+        paths.push(rest.to_string());
+    }
+    paths
+}
+
+fn slash_path(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Lexically clean a slash-separated path like Go's `path.Clean`.
+fn clean(path: &str) -> String {
+    let rooted = path.starts_with('/');
+    let mut parts: Vec<&str> = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => match parts.last() {
+                Some(&last) if last != ".." => {
+                    parts.pop();
+                }
+                _ if rooted => {}
+                _ => parts.push(".."),
+            },
+            part => parts.push(part),
+        }
+    }
+    let joined = parts.join("/");
+    match (rooted, joined.is_empty()) {
+        (true, _) => format!("/{}", joined),
+        (false, true) => ".".to_string(),
+        (false, false) => joined,
+    }
+}
+
+/// Translate a pattern into an anchored regex: `*` and `?` never cross a `/`, `**` spans
+/// any number of directories (all of them at the end of the pattern), `[...]` is a class
+/// (`[!...]` or `[^...]` negated) and `\` escapes the next character. Returns `None` for an
+/// unterminated class.
+fn to_regex(pattern: &str) -> Option<String> {
+    let mut re = String::from("^");
+    let mut chars = pattern.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                // Treat "**/" as "**"
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                }
+                if chars.peek().is_none() {
+                    re.push_str(".*");
+                } else {
+                    re.push_str("(.*/)?");
+                }
+            }
+            '*' => re.push_str("[^/]*"),
+            '?' => re.push_str("[^/]"),
+            '\\' => match chars.next() {
+                Some(next) => re.push_str(&regex::escape(&next.to_string())),
+                None => re.push_str(r"\\"),
+            },
+            '[' => {
+                re.push('[');
+                if matches!(chars.peek(), Some('!') | Some('^')) {
+                    chars.next();
+                    re.push('^');
+                }
+                let mut closed = false;
+                let mut first = true;
+                while let Some(c) = chars.next() {
+                    match c {
+                        ']' if !first => {
+                            closed = true;
+                            break;
+                        }
+                        '\\' => re.push_str(&regex::escape(&chars.next()?.to_string())),
+                        '[' | '&' | '~' | ']' => {
+                            re.push('\\');
+                            re.push(c);
+                        }
+                        c => re.push(c),
+                    }
+                    first = false;
+                }
+                if !closed {
+                    return None;
+                }
+                re.push(']');
+            }
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
     }
+    re.push('$');
+    Some(re)
 }
 
 #[cfg(test)]
@@ -1,6 +1,8 @@
 pub mod file_hasher;
 pub mod ignore;
+pub mod stat_cache;
 pub mod walker;
 
 pub use file_hasher::hash_path;
 pub use ignore::IgnoreRules;
+pub use stat_cache::StatCache;
@@ -0,0 +1,215 @@
+//! Persistent cache of file hashes keyed by `stat` data, in the spirit of the git index.
+//!
+//! A file whose size, modification time, inode and change time are the same as when it was
+//! last hashed is assumed unchanged and its recorded hash is reused, so a no-op build only
+//! stats the context instead of reading it. Filesystems with coarse timestamps can modify a
+//! file without changing its mtime when the write lands in the same second it was hashed;
+//! such "racily clean" entries are never trusted and the file is hashed again.
+
+use crate::hasher::file_hasher::hash_file;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Bumped whenever the entry format or the file hash changes
+const VERSION: u32 = 1;
+
+/// `stat` fields that change whenever a file's content may have changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileStat {
+    pub size: u64,
+    /// Modification time, nanoseconds since the epoch
+    pub mtime_ns: i64,
+    pub ino: u64,
+    /// Inode change time, nanoseconds since the epoch (0 where unavailable)
+    pub ctime_ns: i64,
+}
+
+impl FileStat {
+    pub fn of(path: &Path) -> std::io::Result<Self> {
+        Ok(Self::from_metadata(&std::fs::metadata(path)?))
+    }
+
+    #[cfg(unix)]
+    pub fn from_metadata(meta: &std::fs::Metadata) -> Self {
+        use std::os::unix::fs::MetadataExt;
+        FileStat {
+            size: meta.size(),
+            mtime_ns: meta.mtime() * 1_000_000_000 + meta.mtime_nsec(),
+            ino: meta.ino(),
+            ctime_ns: meta.ctime() * 1_000_000_000 + meta.ctime_nsec(),
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn from_metadata(meta: &std::fs::Metadata) -> Self {
+        FileStat {
+            size: meta.len(),
+            mtime_ns: meta.modified().map(epoch_ns).unwrap_or_default(),
+            ino: 0,
+            ctime_ns: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    stat: FileStat,
+    hash: String,
+    /// When hashing started, nanoseconds since the epoch
+    hashed_at_ns: i64,
+}
+
+impl Entry {
+    /// A file modified in the same second it was hashed may change again without its
+    /// mtime moving on filesystems with one-second timestamps.
+    fn is_racy(&self) -> bool {
+        const SECOND: i64 = 1_000_000_000;
+        self.stat.mtime_ns.div_euclid(SECOND) >= self.hashed_at_ns.div_euclid(SECOND)
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct StatCacheFile {
+    version: u32,
+    entries: HashMap<PathBuf, Entry>,
+}
+
+/// File hashes keyed by absolute path and [`FileStat`]. Safe to share between the threads
+/// hashing a directory.
+#[derive(Default)]
+pub struct StatCache {
+    path: Option<PathBuf>,
+    entries: Mutex<HashMap<PathBuf, (Entry, bool)>>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+impl StatCache {
+    /// Cache kept in memory only, e.g. across the rebuilds of one `build --watch` process.
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+
+    /// Location of the stat cache for a context directory.
+    pub fn cache_path(context_dir: &Path) -> Result<PathBuf> {
+        let canonical = context_dir
+            .canonicalize()
+            .unwrap_or_else(|_| context_dir.to_path_buf());
+        let key = blake3::hash(canonical.to_string_lossy().as_bytes())
+            .to_hex()
+            .to_string();
+        Ok(crate::cache::LocalCache::get_cache_dir()?
+            .join("stat-cache")
+            .join(format!("{}.json", key)))
+    }
+
+    /// Load the stat cache of `context_dir` from the local cache dir.
+    pub fn open(context_dir: &Path) -> Result<Self> {
+        Self::load_from(&Self::cache_path(context_dir)?)
+    }
+
+    /// Load a stat cache, starting empty if the file is missing, unreadable or written by
+    /// another version.
+    pub fn load_from(path: &Path) -> Result<Self> {
+        let file: StatCacheFile = match std::fs::read(path) {
+            Ok(content) => serde_json::from_slice(&content).unwrap_or_default(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => StatCacheFile::default(),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+        let entries = if file.version == VERSION {
+            file.entries
+                .into_iter()
+                .map(|(path, entry)| (path, (entry, false)))
+                .collect()
+        } else {
+            HashMap::new()
+        };
+        Ok(StatCache {
+            path: Some(path.to_path_buf()),
+            entries: Mutex::new(entries),
+            ..Default::default()
+        })
+    }
+
+    /// Write the entries used since the cache was loaded back to where it was loaded from,
+    /// dropping those of files that were not hashed (deleted or no longer in the context).
+    /// Does nothing for an in-memory cache.
+    pub fn save(&self) -> Result<()> {
+        let Some(ref path) = self.path else {
+            return Ok(());
+        };
+        let file = StatCacheFile {
+            version: VERSION,
+            entries: self
+                .entries
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(_, (_, used))| *used)
+                .map(|(path, (entry, _))| (path.clone(), entry.clone()))
+                .collect(),
+        };
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        // Write then rename, so that a concurrent build never reads half a cache
+        let tmp = path.with_extension(format!("tmp.{}", std::process::id()));
+        std::fs::write(&tmp, serde_json::to_vec(&file)?)
+            .with_context(|| format!("Failed to write {}", tmp.display()))?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// Hash `path` with [`hash_file`], reusing the recorded hash if the file's stat data is
+    /// unchanged since it was last hashed.
+    pub fn hash_file(&self, path: &Path) -> Result<String> {
+        // Stat before reading: a write during hashing then shows up as a changed stat
+        let stat = FileStat::of(path)
+            .with_context(|| format!("Cannot stat file for hashing: {}", path.display()))?;
+        {
+            let mut entries = self.entries.lock().unwrap();
+            if let Some((entry, used)) = entries.get_mut(path) {
+                if entry.stat == stat && !entry.is_racy() {
+                    *used = true;
+                    self.hits.fetch_add(1, Ordering::Relaxed);
+                    return Ok(entry.hash.clone());
+                }
+            }
+        }
+
+        let hashed_at_ns = epoch_ns(SystemTime::now());
+        let hash = hash_file(path)?;
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let entry = Entry {
+            stat,
+            hash: hash.clone(),
+            hashed_at_ns,
+        };
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), (entry, true));
+        Ok(hash)
+    }
+
+    /// Files whose recorded hash was reused
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Files that had to be read and hashed
+    pub fn misses(&self) -> usize {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+fn epoch_ns(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as i64)
+        .unwrap_or_default()
+}
@@ -2,31 +2,71 @@ use crate::hasher::ignore::IgnoreRules;
 use std::path::{Path, PathBuf};
 use walkdir::WalkDir;
 
+/// What a walked path is. Symlinks are never followed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Dir,
+    Symlink,
+}
+
+/// A file, directory or symlink found under a walked directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalkEntry {
+    pub path: PathBuf,
+    pub kind: EntryKind,
+}
+
 /// Walk a directory and return all non-ignored files.
 /// Fix 1 — Deterministic sort: sorted by absolute path before returning.
+/// Ignored directories are not entered unless an exception could re-include files in them.
 pub fn walk_dir(root: &Path, ignore: &IgnoreRules) -> Vec<PathBuf> {
-    let mut files: Vec<PathBuf> = WalkDir::new(root)
+    walk_entries(root, ignore)
+        .into_iter()
+        .filter(|entry| entry.kind == EntryKind::File)
+        .map(|entry| entry.path)
+        .collect()
+}
+
+/// Walk a directory and return every non-ignored file, symlink and directory below it, i.e.
+/// everything a COPY of it places in the image, sorted by path. Other file types (sockets,
+/// fifos, devices) are skipped.
+pub fn walk_entries(root: &Path, ignore: &IgnoreRules) -> Vec<WalkEntry> {
+    let mut entries: Vec<WalkEntry> = WalkDir::new(root)
         .follow_links(false)
+        .min_depth(1)
         .into_iter()
+        .filter_entry(|entry| {
+            let rel = entry.path().strip_prefix(root).unwrap_or(entry.path());
+            !entry.file_type().is_dir() || !ignore.skips_dir(rel)
+        })
         .filter_map(|entry| entry.ok())
-        .filter(|entry| entry.file_type().is_file())
         .filter_map(|entry| {
-            let rel = entry
-                .path()
-                .strip_prefix(root)
-                .unwrap_or(entry.path())
-                .to_path_buf();
-            if ignore.is_ignored(&rel) {
+            let file_type = entry.file_type();
+            let kind = if file_type.is_file() {
+                EntryKind::File
+            } else if file_type.is_dir() {
+                EntryKind::Dir
+            } else if file_type.is_symlink() {
+                EntryKind::Symlink
+            } else {
+                return None;
+            };
+            let rel = entry.path().strip_prefix(root).unwrap_or(entry.path());
+            if ignore.is_ignored(rel) {
                 None
             } else {
-                Some(entry.path().to_path_buf())
+                Some(WalkEntry {
+                    path: entry.path().to_path_buf(),
+                    kind,
+                })
             }
         })
         .collect();
 
     // Fix 1: explicit sort by path for OS-independent determinism
-    files.sort();
-    files
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    entries
 }
 
 #[cfg(test)]
@@ -57,6 +97,26 @@ mod tests {
         assert_eq!(walk_dir(dir.path(), &rules).len(), 2);
     }
 
+    #[cfg(unix)]
+    #[test]
+    fn test_walk_entries_reports_dirs_and_symlinks() {
+        let dir = make_temp_tree();
+        fs::create_dir(dir.path().join("empty")).unwrap();
+        std::os::unix::fs::symlink("a.txt", dir.path().join("link")).unwrap();
+        let kinds: Vec<(String, EntryKind)> = walk_entries(dir.path(), &IgnoreRules::empty())
+            .into_iter()
+            .map(|e| {
+                let rel = e.path.strip_prefix(dir.path()).unwrap();
+                (rel.to_string_lossy().to_string(), e.kind)
+            })
+            .collect();
+        assert!(kinds.contains(&("empty".to_string(), EntryKind::Dir)));
+        assert!(kinds.contains(&("link".to_string(), EntryKind::Symlink)));
+        assert_eq!(kinds.len(), 6);
+        // walk_dir still yields regular files only
+        assert_eq!(walk_dir(dir.path(), &IgnoreRules::empty()).len(), 3);
+    }
+
     #[test]
     fn test_walk_is_sorted() {
         let dir = make_temp_tree();
@@ -0,0 +1,368 @@
+//! Local record of every build (`memobuild history`).
+//!
+//! Each `memobuild build` writes a [`BuildRecord`] holding the executed graph, with node keys,
+//! dirty and cache-hit flags and durations, plus the environment it ran in, so analysis,
+//! diffing and cache explanations work without a cache server. Records live under
+//! `<cache dir>/history/<build id>/`, next to the command logs of that build, and
+//! `history/index.json` lists them by context. Each context keeps its newest
+//! `MEMOBUILD_HISTORY_LIMIT` builds (default [`DEFAULT_RETENTION`]).
+
+use crate::env::EnvFingerprint;
+use crate::graph::BuildGraph;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BuildStatus {
+    Succeeded,
+    Failed,
+}
+
+impl std::fmt::Display for BuildStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BuildStatus::Succeeded => write!(f, "succeeded"),
+            BuildStatus::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildRecord {
+    pub id: String,
+    pub started_at: DateTime<Utc>,
+    /// Canonical path of the build context
+    pub context: PathBuf,
+    pub dockerfile: String,
+    #[serde(default)]
+    pub target: Option<String>,
+    #[serde(default)]
+    pub dry_run: bool,
+    pub status: BuildStatus,
+    /// Why the build failed
+    #[serde(default)]
+    pub error: Option<String>,
+    pub wall_time_ms: u64,
+    /// Environment the node keys were computed with
+    pub env: EnvFingerprint,
+    /// Executed graph; `dirty` is as change detection found it before execution
+    pub graph: BuildGraph,
+    /// Directory holding `<node id>.log` for every command the build ran
+    pub logs: PathBuf,
+    /// Exported OCI image layout, when the build got that far
+    #[serde(default)]
+    pub output: Option<PathBuf>,
+}
+
+impl BuildRecord {
+    pub fn new(id: &str, context_dir: &Path, dockerfile: &str, env: EnvFingerprint) -> Self {
+        BuildRecord {
+            id: id.to_string(),
+            started_at: Utc::now(),
+            context: canonical(context_dir),
+            dockerfile: dockerfile.to_string(),
+            target: None,
+            dry_run: false,
+            status: BuildStatus::Succeeded,
+            error: None,
+            wall_time_ms: 0,
+            env,
+            graph: BuildGraph::default(),
+            logs: PathBuf::new(),
+            output: None,
+        }
+    }
+
+    pub fn cache_hits(&self) -> usize {
+        self.graph.nodes.iter().filter(|n| n.cache_hit).count()
+    }
+
+    /// Nodes that missed the cache and had to run
+    pub fn executed(&self) -> usize {
+        if self.dry_run {
+            return 0;
+        }
+        self.graph.nodes.iter().filter(|n| !n.cache_hit).count()
+    }
+}
+
+/// Builds kept per context when `MEMOBUILD_HISTORY_LIMIT` is not set.
+pub const DEFAULT_RETENTION: usize = 100;
+
+/// What `index.json` keeps of a record: enough to pick records without parsing them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct IndexEntry {
+    id: String,
+    started_at: DateTime<Utc>,
+    context: PathBuf,
+    dry_run: bool,
+    status: BuildStatus,
+    /// Keys of the nodes the build executed
+    executed: Vec<String>,
+}
+
+impl IndexEntry {
+    fn of(record: &BuildRecord) -> Self {
+        IndexEntry {
+            id: record.id.clone(),
+            started_at: record.started_at,
+            context: record.context.clone(),
+            dry_run: record.dry_run,
+            status: record.status,
+            executed: record
+                .graph
+                .nodes
+                .iter()
+                .filter(|n| !n.cache_hit)
+                .map(|n| n.hash.clone())
+                .collect(),
+        }
+    }
+}
+
+/// Directory of [`BuildRecord`]s, one sub-directory per build, plus an `index.json` of
+/// them so lookups only parse the records they return. Saving a build drops the oldest
+/// builds of its context beyond the retention limit.
+#[derive(Debug, Clone)]
+pub struct BuildHistory {
+    root: PathBuf,
+    retention: usize,
+}
+
+impl BuildHistory {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        BuildHistory {
+            root: root.into(),
+            retention: DEFAULT_RETENTION,
+        }
+    }
+
+    /// History kept in the local cache directory, holding `MEMOBUILD_HISTORY_LIMIT` builds
+    /// per context.
+    pub fn open_default() -> Result<Self> {
+        let history = Self::new(crate::cache::LocalCache::get_cache_dir()?.join("history"));
+        Ok(match std::env::var("MEMOBUILD_HISTORY_LIMIT") {
+            Ok(limit) => history.with_retention(limit.parse().with_context(|| {
+                format!("MEMOBUILD_HISTORY_LIMIT must be a number, got '{}'", limit)
+            })?),
+            Err(_) => history,
+        })
+    }
+
+    /// Keep at most `builds` builds of each context (at least one).
+    pub fn with_retention(mut self, builds: usize) -> Self {
+        self.retention = builds.max(1);
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Where the command logs of build `id` go.
+    pub fn log_dir(&self, id: &str) -> PathBuf {
+        self.root.join(id).join("logs")
+    }
+
+    pub fn save(&self, record: &BuildRecord) -> Result<PathBuf> {
+        let dir = self.root.join(&record.id);
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("record.json");
+        std::fs::write(&path, serde_json::to_string(record)?)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+
+        let mut entries = self.entries()?;
+        entries.retain(|e| e.id != record.id);
+        entries.push(IndexEntry::of(record));
+        sort_newest_first(&mut entries);
+
+        let expired: Vec<String> = entries
+            .iter()
+            .filter(|e| e.context == record.context && e.id != record.id)
+            .skip(self.retention - 1)
+            .map(|e| e.id.clone())
+            .collect();
+        for id in &expired {
+            let _ = std::fs::remove_dir_all(self.root.join(id));
+        }
+        entries.retain(|e| !expired.contains(&e.id));
+        self.write_index(&entries)?;
+        Ok(path)
+    }
+
+    /// All recorded builds, newest first. Unreadable records are skipped.
+    pub fn list(&self) -> Result<Vec<BuildRecord>> {
+        self.list_for(None, usize::MAX)
+    }
+
+    /// Up to `limit` recorded builds, newest first, of `context_dir` or of every context.
+    pub fn list_for(&self, context_dir: Option<&Path>, limit: usize) -> Result<Vec<BuildRecord>> {
+        let context = context_dir.map(canonical);
+        Ok(self
+            .entries()?
+            .iter()
+            .filter(|e| context.as_ref().is_none_or(|c| e.context == *c))
+            .filter_map(|e| self.load(&e.id))
+            .take(limit)
+            .collect())
+    }
+
+    /// Most recent build of `context_dir`, skipping dry runs, which executed nothing.
+    pub fn latest_for(&self, context_dir: &Path) -> Result<Option<BuildRecord>> {
+        let context = canonical(context_dir);
+        Ok(self
+            .entries()?
+            .iter()
+            .filter(|e| e.context == context && !e.dry_run)
+            .find_map(|e| self.load(&e.id)))
+    }
+
+    /// Most recent build of `context_dir` that succeeded, i.e. the one whose inputs
+    /// `ContextState` holds and whose artifacts should be in the cache.
+    pub fn last_successful(&self, context_dir: &Path) -> Result<Option<BuildRecord>> {
+        let context = canonical(context_dir);
+        Ok(self
+            .entries()?
+            .iter()
+            .filter(|e| e.context == context && !e.dry_run && e.status == BuildStatus::Succeeded)
+            .find_map(|e| self.load(&e.id)))
+    }
+
+    /// Fill in the URLs that cache-hit nodes of `graph` fetched when they last ran, from the
+    /// newest build that executed a node with the same key.
+    pub fn recall_fetched(&self, graph: &mut BuildGraph) -> Result<()> {
+        let mut pending: Vec<usize> = graph
+            .nodes
+            .iter()
+            .enumerate()
+            .filter(|(_, n)| n.cache_hit)
+            .map(|(i, _)| i)
+            .collect();
+        if pending.is_empty() {
+            return Ok(());
+        }
+        for entry in self.entries()?.iter().filter(|e| !e.dry_run) {
+            if !pending
+                .iter()
+                .any(|&id| entry.executed.contains(&graph.nodes[id].hash))
+            {
+                continue;
+            }
+            let Some(record) = self.load(&entry.id) else {
+                continue;
+            };
+            pending.retain(|&id| {
+                let node = &mut graph.nodes[id];
+                match record
+                    .graph
+                    .nodes
+                    .iter()
+                    .find(|n| !n.cache_hit && n.hash == node.hash)
+                {
+                    Some(earlier) => {
+                        node.metadata.fetched = earlier.metadata.fetched.clone();
+                        false
+                    }
+                    None => true,
+                }
+            });
+            if pending.is_empty() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Look up a build by `last` (the latest build of `context_dir`), its id or a unique
+    /// prefix of it.
+    pub fn resolve(&self, reference: &str, context_dir: &Path) -> Result<BuildRecord> {
+        if reference == "last" {
+            return self.latest_for(context_dir)?.with_context(|| {
+                format!(
+                    "No recorded build for {}; run `memobuild build` first",
+                    context_dir.display()
+                )
+            });
+        }
+
+        let mut matches: Vec<BuildRecord> = self
+            .entries()?
+            .iter()
+            .filter(|e| e.id.starts_with(reference))
+            .filter_map(|e| self.load(&e.id))
+            .collect();
+        match matches.len() {
+            0 => anyhow::bail!("No recorded build with id '{}'", reference),
+            1 => Ok(matches.remove(0)),
+            n => anyhow::bail!("Build id '{}' is ambiguous: {} builds match", reference, n),
+        }
+    }
+
+    fn load(&self, id: &str) -> Option<BuildRecord> {
+        let content = std::fs::read_to_string(self.root.join(id).join("record.json")).ok()?;
+        serde_json::from_str(&content).ok()
+    }
+
+    /// Index entries of every recorded build, newest first. Builds the index does not know
+    /// about yet, written by an older version or a concurrent build, are parsed and added;
+    /// entries whose record is gone are dropped.
+    fn entries(&self) -> Result<Vec<IndexEntry>> {
+        let dirs = match std::fs::read_dir(&self.root) {
+            Ok(dirs) => dirs,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let ids: Vec<String> = dirs
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().join("record.json").is_file())
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .collect();
+
+        let indexed: Vec<IndexEntry> = std::fs::read_to_string(self.root.join("index.json"))
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default();
+        let known = indexed.len();
+        let mut entries: Vec<IndexEntry> = indexed
+            .into_iter()
+            .filter(|e| ids.contains(&e.id))
+            .collect();
+        let mut changed = entries.len() != known;
+        for id in &ids {
+            if entries.iter().any(|e| e.id == *id) {
+                continue;
+            }
+            if let Some(record) = self.load(id) {
+                entries.push(IndexEntry::of(&record));
+                changed = true;
+            }
+        }
+        sort_newest_first(&mut entries);
+        if changed {
+            self.write_index(&entries)?;
+        }
+        Ok(entries)
+    }
+
+    fn write_index(&self, entries: &[IndexEntry]) -> Result<()> {
+        std::fs::create_dir_all(&self.root)?;
+        let path = self.root.join("index.json");
+        let staged = self.root.join(format!("index.json.{}", std::process::id()));
+        std::fs::write(&staged, serde_json::to_string(entries)?)
+            .with_context(|| format!("Failed to write {}", staged.display()))?;
+        std::fs::rename(&staged, &path)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        Ok(())
+    }
+}
+
+fn sort_newest_first(entries: &mut [IndexEntry]) {
+    entries.sort_by(|a, b| b.started_at.cmp(&a.started_at).then(b.id.cmp(&a.id)));
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
@@ -1,4 +1,5 @@
 pub mod ai;
+pub mod analysis;
 pub mod auth;
 pub mod auto_scaling;
 pub mod cache;
@@ -10,16 +11,19 @@ pub mod constants;
 pub mod core;
 
 pub mod dashboard;
+pub mod diff;
 pub mod docker;
 pub mod env;
 pub mod error;
 pub mod execution;
 pub mod executor;
+pub mod explain;
 pub mod export;
 pub mod git;
 pub mod gc;
 pub mod graph;
 pub mod hasher;
+pub mod history;
 pub mod loadtest;
 pub mod logging;
 pub mod metrics;
@@ -38,4 +42,6 @@ pub mod tls;
 pub mod slsa;
 pub mod sbom;
 pub mod verify;
+pub mod visualize;
+pub mod watch;
 pub mod audit;
@@ -1,8 +1,9 @@
 use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
+use hex::encode as hex_encode;
+use memobuild::hasher::IgnoreRules;
 use memobuild::server;
 use memobuild::{audit, cache, core, docker, executor, export, logging, sbom, slsa, verify};
-use hex::encode as hex_encode;
 use sha2::{Digest, Sha256};
 use std::env;
 use std::fs;
@@ -47,13 +48,36 @@ enum Commands {
         #[arg(long)]
         dry_run: bool,
 
-        /// Use a specific sandbox runtime (local, containerd)
+        /// Use a specific sandbox runtime (local, snapshot, rootless, containerd)
         #[arg(long)]
         sandbox: Option<String>,
 
+        /// Resource limits for every command, e.g. "memory=2g cpus=2 pids=512 timeout=30m".
+        /// `# memobuild:limits` directives in the Dockerfile override them per instruction
+        #[arg(long, value_name = "LIMITS")]
+        limits: Option<String>,
+
+        /// Network access of every command: "none", "host" or "allow=<host>,..." through a
+        /// proxy that logs each URL. `# memobuild:network` directives override it per
+        /// instruction. Defaults to host, or none in the rootless sandbox
+        #[arg(long, value_name = "POLICY")]
+        network: Option<String>,
+
         /// Use remote execution via scheduler
         #[arg(long)]
         remote_exec: bool,
+
+        /// Build only the named stage and the stages it depends on
+        #[arg(long)]
+        target: Option<String>,
+
+        /// Set a build-time variable declared with ARG (repeatable)
+        #[arg(long = "build-arg", value_name = "KEY=VALUE")]
+        build_args: Vec<String>,
+
+        /// Keep running and rebuild when files in the context change
+        #[arg(long)]
+        watch: bool,
     },
     /// Visualize the dependency graph
     Graph {
@@ -64,6 +88,71 @@ enum Commands {
         /// Path to the Dockerfile
         #[arg(short, long, default_value = "Dockerfile")]
         file: String,
+
+        /// Output format (text|dot|mermaid|json)
+        #[arg(long, default_value = "text")]
+        format: String,
+
+        /// Render a graph previously exported with `--format json` instead of the Dockerfile
+        #[arg(long)]
+        input: Option<PathBuf>,
+
+        /// Write the rendering to a file instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Network policy of the build to show, as with `build --network`
+        #[arg(long, value_name = "POLICY")]
+        network: Option<String>,
+    },
+    /// Check a Dockerfile for errors and cache-hostile patterns
+    Lint {
+        /// Path to the Dockerfile
+        #[arg(short, long, default_value = "Dockerfile")]
+        file: String,
+
+        /// Output format (text|json)
+        #[arg(long, default_value = "text")]
+        format: String,
+    },
+    /// Critical path, slack and parallelism of a recorded build
+    Analyze {
+        /// Path to the build context
+        #[arg(default_value = ".")]
+        path: PathBuf,
+
+        /// Build to analyze: `last` for the latest build of the context, or a build id
+        #[arg(long, default_value = "last")]
+        build: String,
+
+        /// Output format (text|json)
+        #[arg(long, default_value = "text")]
+        format: String,
+    },
+    /// Compare two recorded builds node by node and show which cache key inputs changed
+    Diff {
+        /// First build: `last`, a build id, a cache server URL or a build record/graph JSON file
+        build_a: String,
+
+        /// Second build, in the same forms
+        build_b: String,
+
+        /// Build context that `last` refers to
+        #[arg(long, default_value = ".")]
+        path: PathBuf,
+
+        /// Output format (text|json)
+        #[arg(long, default_value = "text")]
+        format: String,
+
+        /// Also list nodes whose keys did not change
+        #[arg(long)]
+        all: bool,
+    },
+    /// Browse the local record of past builds
+    History {
+        #[command(subcommand)]
+        command: HistoryCommand,
     },
     /// Explain the cache status for a specific node
     ExplainCache {
@@ -77,6 +166,22 @@ enum Commands {
 
         /// Specific node ID or name to explain (optional)
         node: Option<String>,
+
+        /// Explain the build of the named stage, as with `build --target`
+        #[arg(long)]
+        target: Option<String>,
+
+        /// Set a build-time variable declared with ARG, as with `build --build-arg`
+        #[arg(long = "build-arg", value_name = "KEY=VALUE")]
+        build_args: Vec<String>,
+
+        /// Network policy of the build to explain, as with `build --network`
+        #[arg(long, value_name = "POLICY")]
+        network: Option<String>,
+
+        /// Output format (text|json)
+        #[arg(long, default_value = "text")]
+        format: String,
     },
     /// Start the Remote Cache Server
     Server {
@@ -117,6 +222,10 @@ enum Commands {
         /// Scheduler endpoint to register with
         #[arg(long, env = "MEMOBUILD_SCHEDULER_URL")]
         scheduler_url: Option<String>,
+
+        /// Resource limits for every action this worker runs, e.g. "memory=2g cpus=2"
+        #[arg(long, value_name = "LIMITS")]
+        limits: Option<String>,
     },
     /// Pull an image from a registry
     Pull {
@@ -203,6 +312,38 @@ enum Commands {
     },
 }
 
+#[derive(Subcommand)]
+enum HistoryCommand {
+    /// List recorded builds, newest first
+    List {
+        /// Only list builds of this context
+        #[arg(long)]
+        path: Option<PathBuf>,
+
+        /// Maximum number of builds to list
+        #[arg(short = 'n', long, default_value_t = 20)]
+        limit: usize,
+
+        /// Output format (text|json)
+        #[arg(long, default_value = "text")]
+        format: String,
+    },
+    /// Show one recorded build
+    Show {
+        /// `last` for the latest build of the context, or a build id or unique prefix of one
+        #[arg(default_value = "last")]
+        build: String,
+
+        /// Build context that `last` refers to
+        #[arg(long, default_value = ".")]
+        path: PathBuf,
+
+        /// Output format (text|json)
+        #[arg(long, default_value = "text")]
+        format: String,
+    },
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
@@ -221,7 +362,12 @@ async fn main() -> Result<()> {
             reproducible,
             dry_run,
             sandbox,
+            limits,
+            network,
             remote_exec,
+            target,
+            build_args,
+            watch,
         } => {
             run_build(
                 path,
@@ -230,13 +376,51 @@ async fn main() -> Result<()> {
                 reproducible,
                 dry_run,
                 sandbox,
+                limits,
+                network,
                 remote_exec,
+                target,
+                build_args,
+                watch,
             )
             .await
         }
-        Commands::Graph { path, file } => run_graph(path, file).await,
-        Commands::ExplainCache { path, file, node } => run_explain_cache(path, file, node).await,
-        Commands::Server { port, postgres, database_url } => {
+        Commands::Graph {
+            path,
+            file,
+            format,
+            input,
+            output,
+            network,
+        } => run_graph(path, file, format, input, output, network).await,
+        Commands::Lint { file, format } => run_lint(file, format).await,
+        Commands::Analyze {
+            path,
+            build,
+            format,
+        } => run_analyze(path, build, format).await,
+        Commands::Diff {
+            build_a,
+            build_b,
+            path,
+            format,
+            all,
+        } => run_diff(build_a, build_b, path, format, all).await,
+        Commands::History { command } => run_history(command).await,
+        Commands::ExplainCache {
+            path,
+            file,
+            node,
+            target,
+            build_args,
+            network,
+            format,
+        } => run_explain_cache(path, file, node, target, build_args, network, format).await,
+        Commands::Server {
+            port,
+            postgres,
+            database_url,
+        } => {
             let webhook_url = env::var("MEMOBUILD_WEBHOOK").ok();
             let data_dir = env::current_dir()?.join(".memobuild-server");
             fs::create_dir_all(&data_dir)?;
@@ -259,10 +443,13 @@ async fn main() -> Result<()> {
                 if let Some(db_url) = database_url.as_ref() {
                     let config = parse_postgres_url(db_url)?;
                     let (client, connection) = tokio_postgres::connect(
-                        &format!("postgresql://{}:{}@{}:{}/{}",
-                            config.user, config.password, config.host, config.port, config.database),
+                        &format!(
+                            "postgresql://{}:{}@{}:{}/{}",
+                            config.user, config.password, config.host, config.port, config.database
+                        ),
                         NoTls,
-                    ).await?;
+                    )
+                    .await?;
                     tokio::spawn(async move {
                         if let Err(e) = connection.await {
                             eprintln!("connection error: {}", e);
@@ -276,7 +463,15 @@ async fn main() -> Result<()> {
                 None
             };
 
-            server::start_server(port, data_dir, webhook_url, tls_config, admin_token, auth_db_client).await
+            server::start_server(
+                port,
+                data_dir,
+                webhook_url,
+                tls_config,
+                admin_token,
+                auth_db_client,
+            )
+            .await
         }
         Commands::Scheduler { port } => start_scheduler(port).await,
         Commands::Grpc { port } => start_reapi_server(port).await,
@@ -284,7 +479,8 @@ async fn main() -> Result<()> {
             port,
             sandbox,
             scheduler_url,
-        } => start_worker(port, sandbox, scheduler_url).await,
+            limits,
+        } => start_worker(port, sandbox, scheduler_url, limits).await,
         Commands::Pull { image } => run_pull(image).await,
         Commands::GenerateCi { provider } => run_generate_ci(provider).await,
         Commands::Sbom {
@@ -315,6 +511,29 @@ async fn main() -> Result<()> {
     }
 }
 
+/// Parse `--build-arg KEY=VALUE` flags. A bare `KEY` takes its value from the environment,
+/// and is skipped when the variable is not set, as with `docker build`.
+fn parse_build_args(args: &[String]) -> Result<std::collections::HashMap<String, String>> {
+    let mut parsed = std::collections::HashMap::new();
+    for arg in args {
+        match arg.split_once('=') {
+            Some(("", _)) => {
+                anyhow::bail!("Invalid --build-arg '{}': expected KEY=VALUE", arg)
+            }
+            Some((key, value)) => {
+                parsed.insert(key.to_string(), value.to_string());
+            }
+            None => {
+                if let Ok(value) = env::var(arg) {
+                    parsed.insert(arg.clone(), value);
+                }
+            }
+        }
+    }
+    Ok(parsed)
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn run_build(
     context_dir: PathBuf,
     dockerfile_path: String,
@@ -322,33 +541,49 @@ async fn run_build(
     reproducible: bool,
     dry_run: bool,
     sandbox_type: Option<String>,
+    limits: Option<String>,
+    network: Option<String>,
     remote_exec: bool,
+    target: Option<String>,
+    build_args: Vec<String>,
+    watch: bool,
 ) -> Result<()> {
+    if watch && push {
+        anyhow::bail!("--watch cannot be combined with --push");
+    }
     println!("🚀 MemoBuild Engine Starting...");
 
     let build_id = Uuid::new_v4().to_string();
-    let audit_logger = audit::AuditLogger::new();
-    audit::log_build_event(&audit_logger, &build_id, "started");
+    let started_at = chrono::Utc::now();
 
     let env_fp = memobuild::env::EnvFingerprint::collect();
     println!("   🔑 Env Fingerprint: {}", &env_fp.hash()[..8]);
 
     let cache = Arc::new(create_cache().await?);
 
-    let dockerfile = fs::read_to_string(&dockerfile_path)
-        .with_context(|| format!("Failed to read Dockerfile at {}", dockerfile_path))?;
-
-    println!("📄 Parsing Dockerfile...");
-    let instructions = docker::parser::parse_dockerfile(&dockerfile);
-
-    println!("📊 Building DAG for context: {}...", context_dir.display());
-    let mut graph = docker::dag::build_graph_from_instructions(instructions, context_dir.clone());
-
-    let ai_layer = memobuild::ai::AiLayer::new();
-    ai_layer.analyze(&mut graph, &env_fp, &context_dir);
+    let build_args = parse_build_args(&build_args)?;
+    let limits = limits
+        .as_deref()
+        .map(memobuild::sandbox::ResourceLimits::parse)
+        .transpose()?
+        .unwrap_or_default();
+    let network = network
+        .as_deref()
+        .map(memobuild::sandbox::NetworkPolicy::parse)
+        .transpose()?;
+    let template = load_graph(
+        &dockerfile_path,
+        &context_dir,
+        &build_args,
+        target.as_deref(),
+        network.as_ref(),
+        &env_fp,
+        true,
+    )?;
+    let mut graph = template.clone();
 
     println!("🔍 Detecting changes (filesystem hashing)...");
-    core::detect_changes(&mut graph);
+    let context_state = core::detect_changes(&mut graph, &context_dir)?;
 
     println!("🔄 Propagating dirty flags...");
     core::propagate_dirty(&mut graph);
@@ -388,25 +623,72 @@ async fn run_build(
         cache.clone().prefetch_artifacts(dirty_hashes);
     }
 
-    let build_start = std::time::Instant::now();
     let mut executor = executor::IncrementalExecutor::new(cache.clone())
         .with_reproducible(reproducible)
         .with_dry_run(dry_run);
 
-    executor = executor.with_sandbox(Arc::new(memobuild::sandbox::local::LocalSandbox::new(
-        context_dir.clone(),
-    )));
+    let secrets: Arc<dyn memobuild::secrets::SecretProvider> =
+        Arc::from(memobuild::secrets::create_secret_provider()?);
+    let mut local = memobuild::sandbox::local::LocalSandbox::new(context_dir.clone())
+        .with_secrets(secrets.clone())
+        .with_limits(limits.clone());
+    if let Some(ref policy) = network {
+        local = local.with_network(policy.clone());
+    }
+    executor = executor.with_sandbox(Arc::new(local));
 
     if let Some(st) = sandbox_type {
+        if st.as_str() == "snapshot" {
+            let mut sandbox = memobuild::sandbox::snapshot::SnapshotSandbox::new(
+                context_dir.clone(),
+                memobuild::cache::LocalCache::get_cache_dir()?.join("snapshots"),
+            )
+            .with_ignore_rules(graph.ignore_rules(&context_dir))
+            .with_secrets(secrets.clone())
+            .with_limits(limits.clone());
+            if let Some(ref policy) = network {
+                sandbox = sandbox.with_network(policy.clone());
+            }
+            executor = executor.with_sandbox(Arc::new(sandbox));
+        }
+        if st.as_str() == "rootless" {
+            #[cfg(target_os = "linux")]
+            {
+                let mut sandbox = memobuild::sandbox::rootless::RootlessSandbox::new(
+                    context_dir.clone(),
+                    memobuild::cache::LocalCache::get_cache_dir()?.join("snapshots"),
+                )
+                .with_ignore_rules(graph.ignore_rules(&context_dir))
+                .with_secrets(secrets)
+                .with_limits(limits.clone());
+                if let Some(ref policy) = network {
+                    sandbox = sandbox.with_network(policy.clone());
+                }
+                executor = executor.with_sandbox(Arc::new(sandbox));
+            }
+            #[cfg(not(target_os = "linux"))]
+            anyhow::bail!("The rootless sandbox requires Linux namespaces");
+        }
         if st.as_str() == "containerd" {
-            #[cfg(feature = "containerd")]
+            #[cfg(all(feature = "containerd", target_os = "linux"))]
             {
-                let sandbox = Arc::new(memobuild::sandbox::containerd::ContainerdSandbox::new(
+                let mut sandbox = memobuild::sandbox::containerd::ContainerdSandbox::new(
                     "memobuild",
-                    "/run/containerd/containerd.sock",
-                ));
-                executor = executor.with_sandbox(sandbox);
+                    &memobuild::sandbox::containerd::configured_socket(),
+                    context_dir.clone(),
+                    memobuild::cache::LocalCache::get_cache_dir()?.join("snapshots"),
+                )
+                .with_ignore_rules(graph.ignore_rules(&context_dir))
+                .with_limits(limits);
+                if let Some(ref policy) = network {
+                    sandbox = sandbox.with_network(policy.clone());
+                }
+                executor = executor.with_sandbox(Arc::new(sandbox));
             }
+            #[cfg(not(all(feature = "containerd", target_os = "linux")))]
+            anyhow::bail!(
+                "The containerd sandbox requires Linux and building with --features containerd"
+            );
         }
     }
 
@@ -423,19 +705,354 @@ async fn run_build(
         }
     }
 
-    executor.execute(&mut graph).await?;
+    // Stream node progress to the cache server's dashboard
+    let observer = env::var("MEMOBUILD_REMOTE_URL").ok().map(|url| {
+        Arc::new(memobuild::dashboard::RemoteObserver::new(Arc::new(
+            memobuild::remote_cache::HttpRemoteCache::new(url),
+        ))) as Arc<dyn memobuild::dashboard::BuildObserver>
+    });
+    if let Some(ref observer) = observer {
+        executor = executor.with_observer(observer.clone());
+    }
+
+    let settings = BuildSettings {
+        context_dir,
+        dockerfile_path,
+        target,
+        reproducible,
+        dry_run,
+        push,
+        env_fp,
+        network,
+        cache,
+        history: memobuild::history::BuildHistory::open_default()?,
+        audit_logger: audit::AuditLogger::new(),
+        observer,
+    };
+    let result = execute_and_export(
+        &settings,
+        &mut executor,
+        &mut graph,
+        &context_state,
+        &build_id,
+        started_at,
+    )
+    .await;
+    if !watch {
+        return result;
+    }
+
+    let state = match result {
+        Ok(()) => context_state,
+        Err(e) => {
+            eprintln!("{}", format!("❌ Build failed: {:#}", e).red());
+            core::ContextState::load(&settings.context_dir)?
+        }
+    };
+    watch_context(
+        &settings,
+        executor.with_skip_clean(true),
+        template,
+        state,
+        &build_args,
+    )
+    .await
+}
+
+/// Settings shared by the first build and the rebuilds of `build --watch`.
+struct BuildSettings {
+    context_dir: PathBuf,
+    dockerfile_path: String,
+    target: Option<String>,
+    reproducible: bool,
+    dry_run: bool,
+    push: bool,
+    env_fp: memobuild::env::EnvFingerprint,
+    /// `--network`, the policy of commands without one of their own
+    network: Option<memobuild::sandbox::NetworkPolicy>,
+    cache: Arc<cache::HybridCache>,
+    history: memobuild::history::BuildHistory,
+    audit_logger: audit::AuditLogger,
+    observer: Option<Arc<dyn memobuild::dashboard::BuildObserver>>,
+}
+
+/// Parse a Dockerfile, printing the parser's diagnostics. Errors abort: the instructions they
+/// point at would be missing from the graph.
+fn parse_checked(
+    dockerfile: &str,
+    dockerfile_path: &str,
+) -> Result<Vec<docker::parser::Instruction>> {
+    let parsed = docker::parser::parse_dockerfile_with_diagnostics(dockerfile);
+    for diagnostic in &parsed.diagnostics {
+        let line = format!("{}:{}", dockerfile_path, diagnostic);
+        match diagnostic.severity {
+            docker::parser::Severity::Error => eprintln!("{}", line.red()),
+            docker::parser::Severity::Warning => eprintln!("{}", line.yellow()),
+        }
+    }
+    if parsed.has_errors() {
+        let errors = parsed
+            .diagnostics
+            .iter()
+            .filter(|d| d.severity == docker::parser::Severity::Error)
+            .count();
+        anyhow::bail!("{} error(s) in {}", errors, dockerfile_path);
+    }
+    Ok(parsed.instructions)
+}
+
+/// Parse the Dockerfile into the graph a build of it executes, before change detection.
+/// Every command that reports cache keys starts here, so they agree with `build`. `network`
+/// is the `--network` policy of commands without one of their own. `verbose` reports
+/// progress on stdout.
+fn load_graph(
+    dockerfile_path: &str,
+    context_dir: &Path,
+    build_args: &std::collections::HashMap<String, String>,
+    target: Option<&str>,
+    network: Option<&memobuild::sandbox::NetworkPolicy>,
+    env_fp: &memobuild::env::EnvFingerprint,
+    verbose: bool,
+) -> Result<memobuild::graph::BuildGraph> {
+    let dockerfile = fs::read_to_string(dockerfile_path)
+        .with_context(|| format!("Failed to read Dockerfile at {}", dockerfile_path))?;
+
+    if verbose {
+        println!("📄 Parsing Dockerfile...");
+    }
+    let instructions = parse_checked(&dockerfile, dockerfile_path)?;
+
+    if verbose {
+        println!("📊 Building DAG for context: {}...", context_dir.display());
+    }
+    let mut graph =
+        docker::dag::build_graph_with_args(instructions, context_dir.to_path_buf(), build_args);
+    graph.ignore_file = IgnoreRules::dockerfile_ignore_file(Path::new(dockerfile_path));
+
+    if let Some(target) = target {
+        graph = docker::dag::prune_to_target(&graph, target)?;
+        if verbose {
+            println!("🎯 Target stage '{}': {} nodes", target, graph.nodes.len());
+        }
+    }
+    if let Some(policy) = network {
+        graph.set_default_network(policy);
+    }
+
+    // The optimizer sets fields that are part of the cache keys
+    let ai_layer = memobuild::ai::AiLayer::new().with_verbose(verbose);
+    ai_layer.analyze(&mut graph, env_fp, context_dir);
+    Ok(graph)
+}
+
+/// Execute `graph`, record the build in the history and export the image. `context_state`
+/// becomes the reference for change detection once the build succeeds.
+async fn execute_and_export(
+    settings: &BuildSettings,
+    executor: &mut executor::IncrementalExecutor,
+    graph: &mut memobuild::graph::BuildGraph,
+    context_state: &core::ContextState,
+    build_id: &str,
+    started_at: chrono::DateTime<chrono::Utc>,
+) -> Result<()> {
+    let s = settings;
+    let audit_logger = &s.audit_logger;
+    audit::log_build_event(audit_logger, build_id, "started");
+
+    let dirty = graph.nodes.iter().filter(|n| n.dirty).count();
+    let detected_dirty: Vec<bool> = graph.nodes.iter().map(|n| n.dirty).collect();
+    let build_start = std::time::Instant::now();
+
+    let history = &s.history;
+    let mut record = memobuild::history::BuildRecord::new(
+        build_id,
+        &s.context_dir,
+        &s.dockerfile_path,
+        s.env_fp.clone(),
+    );
+    record.started_at = started_at;
+    record.target = s.target.clone();
+    record.dry_run = s.dry_run;
+    record.logs = history.log_dir(build_id);
+    executor.set_log_dir(record.logs.clone());
+
+    let result = executor.execute(graph).await;
     let duration = build_start.elapsed();
+    record.wall_time_ms = duration.as_millis() as u64;
+    // Cache hits fetched nothing this time; what they fetched when they ran still went in
+    history.recall_fetched(graph)?;
+    // The executor marks nodes clean once built; keep what change detection found
+    record.graph = graph.clone();
+    for (node, dirty) in record.graph.nodes.iter_mut().zip(detected_dirty) {
+        node.dirty = dirty;
+    }
+    if let Err(e) = result {
+        record.status = memobuild::history::BuildStatus::Failed;
+        record.error = Some(format!("{:#}", e).trim_end().to_string());
+        history.save(&record)?;
+        audit::log_build_event(audit_logger, build_id, "failed");
+        println!("📝 Build {} recorded in history", build_id);
+        return Err(e);
+    }
 
-    let _ = cache
+    if !s.dry_run {
+        context_state.save()?;
+    }
+    history.save(&record)?;
+
+    let _ = s.cache.report_dag(graph).await;
+    let analysis = memobuild::analysis::BuildAnalysis::from_graph(graph)
+        .ok()
+        .map(|analysis| analysis.with_wall_time(duration.as_millis() as u64));
+    let _ = s
+        .cache
         .report_analytics(
             dirty as u32,
             (graph.nodes.len() - dirty) as u32,
             duration.as_millis() as u64,
+            analysis.as_ref(),
         )
         .await;
 
+    match export_build(
+        graph,
+        &s.context_dir,
+        &s.dockerfile_path,
+        s.reproducible,
+        s.push,
+    ) {
+        Ok(output_dir) => {
+            record.output = Some(output_dir);
+            history.save(&record)?;
+        }
+        Err(e) => {
+            record.status = memobuild::history::BuildStatus::Failed;
+            record.error = Some(format!("{:#}", e).trim_end().to_string());
+            history.save(&record)?;
+            audit::log_build_event(audit_logger, build_id, "failed");
+            return Err(e);
+        }
+    }
+
+    audit::log_build_event(audit_logger, build_id, "completed");
+    println!("📝 Build {} recorded in history", build_id);
+    println!("✅ Build and Export completed successfully");
+    Ok(())
+}
+
+/// `build --watch`: rebuild whenever files in the context change, until interrupted.
+async fn watch_context(
+    settings: &BuildSettings,
+    mut executor: executor::IncrementalExecutor,
+    template: memobuild::graph::BuildGraph,
+    state: core::ContextState,
+    build_args: &std::collections::HashMap<String, String>,
+) -> Result<()> {
+    use memobuild::watch::{ContextWatcher, WatchSession, DEFAULT_DEBOUNCE};
+
+    let s = settings;
+    let mut watcher = ContextWatcher::new(&s.context_dir, Path::new(&s.dockerfile_path))?;
+    let mut session = WatchSession::new(template, &s.context_dir, s.env_fp.clone(), state)
+        .with_stat_cache(memobuild::hasher::StatCache::open(&s.context_dir)?);
+    println!(
+        "\n{}",
+        format!("👀 Watching {} for changes...", s.context_dir.display()).cyan()
+    );
+
+    while let Some(changes) = watcher.next_batch(DEFAULT_DEBOUNCE).await {
+        let changed = changes.paths;
+        if changes.reload {
+            match load_graph(
+                &s.dockerfile_path,
+                &s.context_dir,
+                build_args,
+                s.target.as_deref(),
+                s.network.as_ref(),
+                &s.env_fp,
+                true,
+            ) {
+                Ok(template) => session.set_template(template),
+                Err(e) => {
+                    eprintln!("{}", format!("❌ {:#}", e).red());
+                    continue;
+                }
+            }
+        }
+
+        let rebuild = match session.plan() {
+            Ok(rebuild) => rebuild,
+            Err(e) => {
+                eprintln!("{}", format!("❌ {:#}", e).red());
+                continue;
+            }
+        };
+        if let Some(ref observer) = s.observer {
+            observer.on_event(memobuild::dashboard::BuildEvent::RebuildTriggered {
+                changed_paths: changed
+                    .iter()
+                    .map(|p| p.to_string_lossy().to_string())
+                    .collect(),
+                dirty_nodes: rebuild.dirty.len(),
+            });
+        }
+        if rebuild.dirty.is_empty() {
+            println!(
+                "   {} changed path(s), no node affected",
+                changed.len().to_string().dimmed()
+            );
+            continue;
+        }
+
+        println!(
+            "\n{}",
+            format!(
+                "🔁 {} changed path(s), rebuilding {} of {} nodes",
+                changed.len(),
+                rebuild.dirty.len(),
+                rebuild.graph.nodes.len()
+            )
+            .bold()
+            .cyan()
+        );
+        let mut graph = rebuild.graph;
+        let build_id = Uuid::new_v4().to_string();
+        match execute_and_export(
+            s,
+            &mut executor,
+            &mut graph,
+            &rebuild.state,
+            &build_id,
+            chrono::Utc::now(),
+        )
+        .await
+        {
+            Ok(()) => session.commit(rebuild.state),
+            Err(e) => eprintln!("{}", format!("❌ Rebuild failed: {:#}", e).red()),
+        }
+        println!(
+            "{}",
+            format!("👀 Watching {} for changes...", s.context_dir.display()).cyan()
+        );
+    }
+    Ok(())
+}
+
+/// Export the built graph as an OCI image with its SBOM and SLSA attestation, and push it
+/// when requested. Returns the image layout directory.
+fn export_build(
+    graph: &memobuild::graph::BuildGraph,
+    context_dir: &Path,
+    dockerfile_path: &str,
+    reproducible: bool,
+    push: bool,
+) -> Result<PathBuf> {
     println!("📦 Exporting OCI Image...");
-    let output_dir = export::export_image(&graph, "memobuild-demo:latest", reproducible)?;
+    let output_dir = export::export_image_from_context(
+        graph,
+        "memobuild-demo:latest",
+        reproducible,
+        Some(context_dir),
+    )?;
 
     let image_digest = compute_image_digest(&output_dir)?;
     let sbom_generator = sbom::SbomGenerator::new(Some("NRELabs".to_string()));
@@ -443,26 +1060,30 @@ async fn run_build(
     let sbom = sbom_generator.generate_from_context(
         "memobuild-demo:latest",
         &image_digest,
-        &context_dir,
-        &PathBuf::from(&dockerfile_path),
+        context_dir,
+        &PathBuf::from(dockerfile_path),
     )?;
     sbom_generator.save_sbom(&sbom, &sbom_path, &sbom::OutputFormat::Json)?;
     println!("🔐 SBOM written to {}", sbom_path.display());
 
     let provenance_generator = slsa::ProvenanceGenerator::new("memobuild-builder".to_string());
-    let provenance = provenance_generator.generate_provenance(
+    let mut provenance = provenance_generator.generate_provenance(
         &format!("git+file://{}", context_dir.display()),
         &image_digest,
         "oci://memobuild-demo:latest",
         &image_digest,
         &slsa::InvocationParams::default(),
     )?;
+    for fetched in graph.fetched_urls() {
+        provenance.add_resolved_dependency(&fetched.url, fetched.digest.as_deref().unwrap_or(""));
+    }
     let attestation = provenance_generator.sign(&provenance)?;
     let attestation_path = output_dir.join("attestation.json");
     provenance_generator.save_attestation(&attestation, &attestation_path)?;
-    println!("🔐 SLSA attestation written to {}", attestation_path.display());
-
-    audit::log_build_event(&audit_logger, &build_id, "completed");
+    println!(
+        "🔐 SLSA attestation written to {}",
+        attestation_path.display()
+    );
 
     if push {
         let registry_url =
@@ -476,8 +1097,7 @@ async fn run_build(
         client.push(&output_dir)?;
     }
 
-    println!("✅ Build and Export completed successfully");
-    Ok(())
+    Ok(output_dir)
 }
 
 async fn run_sbom(
@@ -540,21 +1160,406 @@ fn compute_image_digest(output_dir: &Path) -> Result<String> {
     Ok(format!("sha256:{}", hex_encode(digest)))
 }
 
-async fn run_graph(context_dir: PathBuf, dockerfile_path: String) -> Result<()> {
-    let dockerfile = fs::read_to_string(&dockerfile_path)?;
-    let instructions = docker::parser::parse_dockerfile(&dockerfile);
-    let graph = docker::dag::build_graph_from_instructions(instructions, context_dir);
+async fn run_graph(
+    context_dir: PathBuf,
+    dockerfile_path: String,
+    format: String,
+    input: Option<PathBuf>,
+    output: Option<PathBuf>,
+    network: Option<String>,
+) -> Result<()> {
+    let format: memobuild::visualize::GraphFormat = format.parse()?;
+    let network = network
+        .as_deref()
+        .map(memobuild::sandbox::NetworkPolicy::parse)
+        .transpose()?;
+    let export = match input {
+        Some(input) => {
+            let json = fs::read_to_string(&input)
+                .with_context(|| format!("Failed to read {}", input.display()))?;
+            serde_json::from_str(&json)
+                .with_context(|| format!("{} is not a graph JSON export", input.display()))?
+        }
+        None => {
+            let env_fp = memobuild::env::EnvFingerprint::collect();
+            let cache = create_cache().await?;
+            let mut graph = load_graph(
+                &dockerfile_path,
+                &context_dir,
+                &Default::default(),
+                None,
+                network.as_ref(),
+                &env_fp,
+                false,
+            )?;
+
+            core::detect_changes(&mut graph, &context_dir)?;
+            core::propagate_dirty(&mut graph);
+            core::compute_composite_hashes(&mut graph, &env_fp);
+            for node in &mut graph.nodes {
+                node.cache_hit = cache.local.exists(&node.hash);
+            }
+            // Durations as measured by the last build, not the optimizer's predictions
+            let last =
+                memobuild::history::BuildHistory::open_default()?.latest_for(&context_dir)?;
+            let measured: std::collections::HashMap<&str, u64> = last
+                .iter()
+                .flat_map(|record| &record.graph.nodes)
+                .filter_map(|node| Some((node.hash.as_str(), node.metadata.execution_time_ms?)))
+                .collect();
+            for node in &mut graph.nodes {
+                node.metadata.execution_time_ms = measured.get(node.hash.as_str()).copied();
+            }
+            memobuild::visualize::GraphExport::from_graph(&graph)
+        }
+    };
+
+    let rendered = export.render(format)?;
+    match output {
+        Some(path) => {
+            fs::write(&path, rendered)
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+            println!("✅ Graph written to {}", path.display());
+        }
+        None if format == memobuild::visualize::GraphFormat::Text => {
+            println!("\n{}", "🕸️  Build Dependency Graph:".bold().cyan());
+            print!("{}", rendered);
+        }
+        None => print!("{}", rendered),
+    }
+    Ok(())
+}
+
+async fn run_lint(dockerfile_path: String, format: String) -> Result<()> {
+    let dockerfile = fs::read_to_string(&dockerfile_path)
+        .with_context(|| format!("Failed to read {}", dockerfile_path))?;
+    let diagnostics = docker::lint::lint_dockerfile(&dockerfile);
+    let errors = diagnostics
+        .iter()
+        .filter(|d| d.severity == docker::parser::Severity::Error)
+        .count();
+
+    match format.as_str() {
+        "json" => {
+            let report = serde_json::json!({
+                "file": dockerfile_path,
+                "errors": errors,
+                "warnings": diagnostics.len() - errors,
+                "diagnostics": diagnostics,
+            });
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        }
+        "text" => {
+            for diagnostic in &diagnostics {
+                let line = format!("{}:{}", dockerfile_path, diagnostic);
+                match diagnostic.severity {
+                    docker::parser::Severity::Error => println!("{}", line.red()),
+                    docker::parser::Severity::Warning => println!("{}", line.yellow()),
+                }
+            }
+            if diagnostics.is_empty() {
+                println!(
+                    "{}",
+                    format!("✅ {}: no problems found", dockerfile_path).green()
+                );
+            }
+        }
+        other => anyhow::bail!("Unsupported lint format '{}': expected text or json", other),
+    }
 
-    println!("\n{}", "🕸️  Build Dependency Graph:".bold().cyan());
-    for node in &graph.nodes {
-        let deps: Vec<String> = node
-            .deps
-            .iter()
-            .map(|&id| graph.nodes[id].name.clone())
-            .collect();
-        println!("  {} [{}]", node.name.green(), node.id);
-        if !deps.is_empty() {
-            println!("    └─ depends on: {:?}", deps);
+    if errors > 0 {
+        anyhow::bail!("{} error(s) in {}", errors, dockerfile_path);
+    }
+    Ok(())
+}
+
+async fn run_analyze(context_dir: PathBuf, build: String, format: String) -> Result<()> {
+    use memobuild::visualize::format_duration;
+
+    let last = memobuild::history::BuildHistory::open_default()?.resolve(&build, &context_dir)?;
+    let analysis = memobuild::analysis::BuildAnalysis::from_graph(&last.graph)?
+        .with_wall_time(last.wall_time_ms);
+
+    match format.as_str() {
+        "json" => println!("{}", serde_json::to_string_pretty(&analysis)?),
+        "text" => {
+            println!("\n{}", "⏱️  Build Time Analysis:".bold().cyan());
+            println!(
+                "  Total work:        {} across {} nodes",
+                format_duration(analysis.total_work_ms),
+                analysis.nodes.len()
+            );
+            println!(
+                "  Wall time:         {}",
+                format_duration(last.wall_time_ms)
+            );
+            println!(
+                "  Critical path:     {} ({} nodes)",
+                format_duration(analysis.critical_path_ms),
+                analysis.critical_path.len()
+            );
+            println!(
+                "  Parallelism:       {:.2}x (work / critical path)",
+                analysis.parallelism
+            );
+            if let Some(speedup) = analysis.potential_speedup {
+                println!(
+                    "  Potential speedup: {:.2}x with unlimited parallelism",
+                    speedup
+                );
+            }
+
+            println!("\n{}", "🔥 Critical path:".bold());
+            for &id in &analysis.critical_path {
+                let node = &analysis.nodes[id];
+                let share = (node.duration_ms * 100)
+                    .checked_div(analysis.critical_path_ms)
+                    .unwrap_or(0);
+                println!(
+                    "  [{}] {:<60} {:>8} {:>4}%",
+                    id,
+                    node.label,
+                    format_duration(node.duration_ms).yellow(),
+                    share
+                );
+            }
+
+            let mut slack: Vec<_> = analysis
+                .nodes
+                .iter()
+                .filter(|n| n.slack_ms > 0 && n.duration_ms > 0)
+                .collect();
+            slack.sort_by_key(|n| std::cmp::Reverse(n.slack_ms));
+            if !slack.is_empty() {
+                println!(
+                    "\n{}",
+                    "🕒 Slack (can be delayed without slowing the build):".bold()
+                );
+                for node in slack.iter().take(10) {
+                    println!(
+                        "  [{}] {:<60} {:>8} slack",
+                        node.id,
+                        node.label,
+                        format_duration(node.slack_ms).green()
+                    );
+                }
+            }
+        }
+        other => anyhow::bail!(
+            "Unsupported analyze format '{}': expected text or json",
+            other
+        ),
+    }
+    Ok(())
+}
+
+async fn run_diff(
+    build_a: String,
+    build_b: String,
+    context_dir: PathBuf,
+    format: String,
+    all: bool,
+) -> Result<()> {
+    use memobuild::diff::{diff_builds, BuildSnapshot, NodeStatus};
+
+    let a = BuildSnapshot::load(&build_a, &context_dir).await?;
+    let b = BuildSnapshot::load(&build_b, &context_dir).await?;
+    let diff = diff_builds(&a, &b);
+
+    match format.as_str() {
+        "json" => println!("{}", serde_json::to_string_pretty(&diff)?),
+        "text" => {
+            println!(
+                "\n{}",
+                format!("🔀 Build diff: {} → {}", build_a, build_b)
+                    .bold()
+                    .cyan()
+            );
+            if a.env.is_none() || b.env.is_none() {
+                println!(
+                    "   {}",
+                    "Environment fingerprint not recorded for both builds".dimmed()
+                );
+            } else if !diff.env.is_empty() {
+                println!("{}", "Environment fingerprint:".bold());
+                for change in &diff.env {
+                    println!(
+                        "  {}: {} → {}",
+                        change.field,
+                        change.before.as_deref().unwrap_or("(unset)").red(),
+                        change.after.as_deref().unwrap_or("(unset)").green()
+                    );
+                }
+            }
+
+            println!(
+                "{} {} changed, {} added, {} removed, {} unchanged",
+                "Nodes:".bold(),
+                diff.count(NodeStatus::Changed),
+                diff.count(NodeStatus::Added),
+                diff.count(NodeStatus::Removed),
+                diff.count(NodeStatus::Unchanged)
+            );
+            for node in &diff.nodes {
+                let ids = match (node.before, node.after) {
+                    (Some(a), Some(b)) => format!("{}→{}", a, b),
+                    (None, Some(b)) => format!("+{}", b),
+                    (Some(a), None) => format!("-{}", a),
+                    (None, None) => String::new(),
+                };
+                match node.status {
+                    NodeStatus::Unchanged if !all => continue,
+                    NodeStatus::Unchanged => println!("  [{}] {}", ids, node.label.dimmed()),
+                    NodeStatus::Added => println!("  [{}] {}", ids, node.label.green()),
+                    NodeStatus::Removed => println!("  [{}] {}", ids, node.label.red()),
+                    NodeStatus::Changed => {
+                        println!("  [{}] {}", ids, node.label.yellow());
+                        for change in &node.changes {
+                            println!("     • {}", change);
+                        }
+                    }
+                }
+            }
+        }
+        other => anyhow::bail!("Unsupported diff format '{}': expected text or json", other),
+    }
+    Ok(())
+}
+
+async fn run_history(command: HistoryCommand) -> Result<()> {
+    use memobuild::history::{BuildHistory, BuildStatus};
+    use memobuild::visualize::{format_duration, label};
+
+    let history = BuildHistory::open_default()?;
+    match command {
+        HistoryCommand::List {
+            path,
+            limit,
+            format,
+        } => {
+            let records = history.list_for(path.as_deref(), limit)?;
+
+            match format.as_str() {
+                "json" => println!("{}", serde_json::to_string_pretty(&records)?),
+                "text" => {
+                    if records.is_empty() {
+                        println!("No recorded builds in {}", history.root().display());
+                        return Ok(());
+                    }
+                    println!(
+                        "{:<8}  {:<19}  {:<9}  {:>8}  {:>11}  CONTEXT",
+                        "ID", "STARTED", "STATUS", "TIME", "CACHED"
+                    );
+                    for r in &records {
+                        let status = match r.status {
+                            BuildStatus::Succeeded if r.dry_run => "dry-run".yellow(),
+                            BuildStatus::Succeeded => r.status.to_string().green(),
+                            BuildStatus::Failed => r.status.to_string().red(),
+                        };
+                        println!(
+                            "{:<8}  {:<19}  {:<9}  {:>8}  {:>11}  {}",
+                            &r.id[..r.id.len().min(8)],
+                            r.started_at
+                                .with_timezone(&chrono::Local)
+                                .format("%Y-%m-%d %H:%M:%S"),
+                            status,
+                            format_duration(r.wall_time_ms),
+                            format!("{}/{}", r.cache_hits(), r.graph.nodes.len()),
+                            r.context.display()
+                        );
+                    }
+                }
+                other => anyhow::bail!(
+                    "Unsupported history format '{}': expected text or json",
+                    other
+                ),
+            }
+        }
+        HistoryCommand::Show {
+            build,
+            path,
+            format,
+        } => {
+            let r = history.resolve(&build, &path)?;
+            match format.as_str() {
+                "json" => println!("{}", serde_json::to_string_pretty(&r)?),
+                "text" => {
+                    println!("\n{}", format!("📝 Build {}", r.id).bold().cyan());
+                    println!(
+                        "  Started:     {}",
+                        r.started_at.with_timezone(&chrono::Local).to_rfc2822()
+                    );
+                    println!("  Context:     {}", r.context.display());
+                    println!("  Dockerfile:  {}", r.dockerfile);
+                    if let Some(ref target) = r.target {
+                        println!("  Target:      {}", target);
+                    }
+                    let status = match r.status {
+                        BuildStatus::Succeeded => r.status.to_string().green(),
+                        BuildStatus::Failed => r.status.to_string().red(),
+                    };
+                    println!(
+                        "  Status:      {}{}",
+                        status,
+                        if r.dry_run { " (dry run)" } else { "" }
+                    );
+                    if let Some(ref error) = r.error {
+                        println!("  Error:       {}", error.red());
+                    }
+                    println!("  Wall time:   {}", format_duration(r.wall_time_ms));
+                    println!(
+                        "  Nodes:       {} total, {} dirty, {} executed, {} cache hits",
+                        r.graph.nodes.len(),
+                        r.graph.nodes.iter().filter(|n| n.dirty).count(),
+                        r.executed(),
+                        r.cache_hits()
+                    );
+                    println!("  Env:         {}", &r.env.hash()[..8]);
+                    println!("  Logs:        {}", r.logs.display());
+                    if let Some(ref output) = r.output {
+                        println!("  Image:       {}", output.display());
+                    }
+                    let fetched = r.graph.fetched_urls();
+                    if !fetched.is_empty() {
+                        println!("  Fetched:");
+                        for fetched in fetched {
+                            println!(
+                                "    {} {}",
+                                fetched.url,
+                                fetched.digest.as_deref().unwrap_or("").dimmed()
+                            );
+                        }
+                    }
+
+                    println!("\n{}", "Nodes:".bold());
+                    for node in &r.graph.nodes {
+                        let status = if node.cache_hit {
+                            "cached".green()
+                        } else if node.dirty {
+                            "dirty".yellow()
+                        } else {
+                            "miss".red()
+                        };
+                        let duration = node
+                            .metadata
+                            .execution_time_ms
+                            .map(format_duration)
+                            .unwrap_or_default();
+                        println!(
+                            "  [{}] {:<60} {:<6} {:>8}  {}",
+                            node.id,
+                            label(node),
+                            status,
+                            duration,
+                            &node.hash[..node.hash.len().min(12)].dimmed()
+                        );
+                    }
+                }
+                other => anyhow::bail!(
+                    "Unsupported history format '{}': expected text or json",
+                    other
+                ),
+            }
         }
     }
     Ok(())
@@ -564,74 +1569,139 @@ async fn run_explain_cache(
     context_dir: PathBuf,
     dockerfile_path: String,
     target_node: Option<String>,
+    target: Option<String>,
+    build_args: Vec<String>,
+    network: Option<String>,
+    format: String,
 ) -> Result<()> {
+    use memobuild::cache::CacheLocation;
+    use memobuild::explain::{explain, Baseline};
+
     let env_fp = memobuild::env::EnvFingerprint::collect();
     let cache = Arc::new(create_cache().await?);
-    let dockerfile = fs::read_to_string(&dockerfile_path)?;
-    let instructions = docker::parser::parse_dockerfile(&dockerfile);
-    let mut graph = docker::dag::build_graph_from_instructions(instructions, context_dir.clone());
-
-    // AI Layer Analysis to get extra dependencies
-    let ai_layer = memobuild::ai::AiLayer::new();
-    ai_layer.analyze(&mut graph, &env_fp, &context_dir);
+    let build_args = parse_build_args(&build_args)?;
+    let network = network
+        .as_deref()
+        .map(memobuild::sandbox::NetworkPolicy::parse)
+        .transpose()?;
+    let mut graph = load_graph(
+        &dockerfile_path,
+        &context_dir,
+        &build_args,
+        target.as_deref(),
+        network.as_ref(),
+        &env_fp,
+        false,
+    )?;
 
-    core::detect_changes(&mut graph);
+    // The state the last build left behind is what `build` would compare against
+    let previous_state = core::ContextState::load(&context_dir)?;
+    let state = core::detect_changes(&mut graph, &context_dir)?;
     core::propagate_dirty(&mut graph);
     core::compute_composite_hashes(&mut graph, &env_fp);
 
-    println!("\n{}", "🔍 Cache Explanation:".bold().cyan());
-    for node in &graph.nodes {
-        if let Some(ref target) = target_node {
-            if !node.name.contains(target) && node.id.to_string() != *target {
-                continue;
-            }
-        }
-
-        let is_cached = cache.local.exists(&node.hash);
+    let baseline = memobuild::history::BuildHistory::open_default()?
+        .last_successful(&context_dir)?
+        .map(|record| Baseline {
+            id: record.id.clone(),
+            snapshot: record.into(),
+            state: previous_state,
+        });
 
-        println!("  {} (ID: {})", node.name.bold(), node.id);
-        println!(
-            "    Status: {}",
-            if is_cached {
-                "CACHED (Clean)".green()
-            } else {
-                "DIRTY (Rebuild Required)".red()
+    let mut locations = Vec::with_capacity(graph.nodes.len());
+    let mut remote_ok = true;
+    for node in &graph.nodes {
+        let location = if remote_ok {
+            match cache.locate(&node.hash).await {
+                Ok(location) => Some(location),
+                Err(e) => {
+                    eprintln!(
+                        "{}",
+                        format!("⚠️  Remote cache unavailable, checking local only: {}", e)
+                            .yellow()
+                    );
+                    remote_ok = false;
+                    None
+                }
             }
-        );
-        println!("    Hash: {}", node.hash.cyan());
+        } else {
+            None
+        };
+        locations.push(location.unwrap_or(if cache.local.exists(&node.hash) {
+            CacheLocation::Local
+        } else {
+            CacheLocation::Missing
+        }));
+    }
 
-        if !is_cached {
-            let mut reasons = Vec::new();
-            if node.source_path.is_some() {
-                reasons.push("Source files changed or untracked");
-            }
-            if !node.metadata.extra_source_paths.is_empty() {
-                reasons.push("AI-detected dependencies changed");
+    let current = memobuild::diff::BuildSnapshot {
+        graph,
+        env: Some(env_fp),
+    };
+    let explanations: Vec<_> = explain(&current, &state, baseline.as_ref(), &locations)
+        .into_iter()
+        .filter(|e| {
+            let node = &current.graph.nodes[e.id];
+            target_node.as_ref().is_none_or(|t| {
+                node.name.contains(t) || e.label.contains(t) || node.id.to_string() == *t
+            })
+        })
+        .collect();
+
+    match format.as_str() {
+        "json" => println!("{}", serde_json::to_string_pretty(&explanations)?),
+        "text" => {
+            match baseline {
+                Some(ref b) => println!(
+                    "\n{} {}",
+                    "🔍 Cache Explanation:".bold().cyan(),
+                    format!("(compared with build {})", &b.id[..b.id.len().min(8)]).dimmed()
+                ),
+                None => println!("\n{}", "🔍 Cache Explanation:".bold().cyan()),
             }
-
-            let dirty_deps: Vec<_> = node
-                .deps
-                .iter()
-                .filter(|&&id| !cache.local.exists(&graph.nodes[id].hash))
-                .map(|&id| &graph.nodes[id].name)
-                .collect();
-            if !dirty_deps.is_empty() {
-                reasons.push("Dependencies are dirty (recursive)");
-                println!("    Dirty Dependencies: {:?}", dirty_deps);
+            for e in &explanations {
+                println!("  {} (ID: {})", e.label.bold(), e.id);
+                println!(
+                    "    Status: {}",
+                    match e.cache {
+                        CacheLocation::Local => "CACHED (local)".green(),
+                        CacheLocation::Remote => "CACHED (remote)".green(),
+                        CacheLocation::Missing => "MISS (Rebuild Required)".red(),
+                    }
+                );
+                println!("    Hash: {}", e.hash.cyan());
+                if let Some(ref previous) = e.previous_hash {
+                    if *previous != e.hash {
+                        println!("    Previous hash: {}", previous.dimmed());
+                    }
+                }
+                if !e.reasons.is_empty() {
+                    println!("    Reasons:");
+                    for reason in &e.reasons {
+                        println!("      • {}", reason);
+                    }
+                }
             }
-            println!("    Reasons: {:?}", reasons);
         }
+        other => anyhow::bail!(
+            "Unsupported explain-cache format '{}': expected text or json",
+            other
+        ),
     }
     Ok(())
 }
 
+/// Local cache, backed by the cache server at `MEMOBUILD_REMOTE_URL` when it is set.
 async fn create_cache() -> Result<cache::HybridCache> {
-    cache::HybridCache::new(None)
+    let remote = env::var("MEMOBUILD_REMOTE_URL")
+        .ok()
+        .map(|url| Arc::new(cache::HttpRemoteCache::new(url)) as Arc<dyn cache::RemoteCache>);
+    cache::HybridCache::new(remote)
 }
 
 async fn _pull_base_images(instructions: &[docker::parser::Instruction]) -> Result<()> {
     for instr in instructions {
-        if let docker::parser::Instruction::From(img) = instr {
+        if let docker::parser::Instruction::From(img, _) = instr {
             println!("   📥 Pulling base image {}...", img);
         }
     }
@@ -710,6 +1780,7 @@ async fn start_worker(
     _port: u16,
     _sandbox_type: String,
     _scheduler_url: Option<String>,
+    _limits: Option<String>,
 ) -> Result<()> {
     #[cfg(feature = "remote-exec")]
     {
@@ -726,13 +1797,27 @@ async fn start_worker(
         let cache = Arc::new(cache);
 
         // Initialize sandbox
+        let limits = _limits
+            .as_deref()
+            .map(sandbox::ResourceLimits::parse)
+            .transpose()?
+            .unwrap_or_default();
         let sandbox: Arc<dyn sandbox::Sandbox> = match _sandbox_type.as_str() {
-            "local" => Arc::new(sandbox::local::LocalSandbox::new(std::env::current_dir()?)),
-            #[cfg(feature = "containerd")]
-            "containerd" => Arc::new(sandbox::containerd::ContainerdSandbox::new(
-                "memobuild",
-                "/run/containerd/containerd.sock",
-            )),
+            "local" => Arc::new(
+                sandbox::local::LocalSandbox::new(std::env::current_dir()?)
+                    .with_secrets(Arc::from(memobuild::secrets::create_secret_provider()?))
+                    .with_limits(limits),
+            ),
+            #[cfg(all(feature = "containerd", target_os = "linux"))]
+            "containerd" => Arc::new(
+                sandbox::containerd::ContainerdSandbox::new(
+                    "memobuild",
+                    &sandbox::containerd::configured_socket(),
+                    std::env::current_dir()?,
+                    memobuild::cache::LocalCache::get_cache_dir()?.join("snapshots"),
+                )
+                .with_limits(limits),
+            ),
             _ => anyhow::bail!("Unsupported sandbox type: {}", _sandbox_type),
         };
 
@@ -30,7 +30,13 @@ pub trait RemoteCache: Send + Sync {
 
     async fn report_build_event(&self, event: BuildEvent) -> Result<()>;
     async fn report_dag(&self, dag: &crate::graph::BuildGraph) -> Result<()>;
-    async fn report_analytics(&self, dirty: u32, cached: u32, duration_ms: u64) -> Result<()>;
+    async fn report_analytics(
+        &self,
+        dirty: u32,
+        cached: u32,
+        duration_ms: u64,
+        analysis: Option<&crate::analysis::BuildAnalysis>,
+    ) -> Result<()>;
 }
 
 #[derive(Clone)]
@@ -298,12 +304,19 @@ impl RemoteCache for HttpRemoteCache {
         Ok(())
     }
 
-    async fn report_analytics(&self, dirty: u32, cached: u32, duration_ms: u64) -> Result<()> {
+    async fn report_analytics(
+        &self,
+        dirty: u32,
+        cached: u32,
+        duration_ms: u64,
+        analysis: Option<&crate::analysis::BuildAnalysis>,
+    ) -> Result<()> {
         let url = format!("{}/analytics", self.base_url);
         let data = serde_json::json!({
             "dirty": dirty,
             "cached": cached,
-            "duration_ms": duration_ms
+            "duration_ms": duration_ms,
+            "analysis": analysis
         });
 
         let resp = self.client.post(&url).json(&data).send().await?;
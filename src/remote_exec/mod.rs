@@ -33,6 +33,13 @@ pub struct ActionRequest {
     pub platform_properties: HashMap<String, String>,
     pub output_files: Vec<String>,
     pub output_directories: Vec<String>,
+    /// Limits the node sets with `# memobuild:limits`; the worker's own apply to the rest.
+    /// `timeout` takes the place of their timeout.
+    #[serde(default)]
+    pub limits: crate::sandbox::ResourceLimits,
+    /// Network policy the node sets; None leaves it to the worker's sandbox
+    #[serde(default)]
+    pub network: Option<crate::sandbox::NetworkPolicy>,
 }
 
 /// ActionResult represents the result of a remote execution.
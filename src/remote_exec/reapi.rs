@@ -127,6 +127,8 @@ impl ExecutionService for ReapiExecutionService {
             platform_properties: HashMap::new(),
             output_files: vec![],
             output_directories: vec![],
+            limits: Default::default(),
+            network: None,
         };
 
         // Schedule execution
@@ -1,7 +1,7 @@
 use crate::cache::HybridCache;
 use crate::graph::{Node, NodeKind, NodeMetadata};
 use crate::remote_exec::{ActionRequest, ActionResult, Digest, ExecutionMetadata, RemoteExecutor};
-use crate::sandbox::Sandbox;
+use crate::sandbox::{ResourceLimits, Sandbox};
 use anyhow::{Context, Result};
 use async_trait::async_trait;
 use std::collections::HashMap;
@@ -36,7 +36,7 @@ impl RemoteExecutor for WorkerNode {
         let node = Node {
             id: 0,
             name: format!("remote-action-{}", &action.input_root_digest.hash[..8]),
-            kind: NodeKind::Run,
+            kind: NodeKind::Run { mounts: vec![] },
             content: action.command.join(" "),
             env: action.env.clone(),
             hash: action.input_root_digest.hash.clone(),
@@ -44,7 +44,15 @@ impl RemoteExecutor for WorkerNode {
             deps: Vec::new(),
             source_path: None,
             cache_hit: false,
-            metadata: NodeMetadata::default(),
+            metadata: NodeMetadata {
+                // The worker's own limits apply to whatever the node leaves unset
+                limits: ResourceLimits {
+                    timeout_secs: Some(action.timeout.as_secs()).filter(|&secs| secs > 0),
+                    ..action.limits.clone()
+                },
+                network: action.network.clone(),
+                ..Default::default()
+            },
         };
 
         // 2. Prepare Sandbox
@@ -80,11 +88,15 @@ impl RemoteExecutor for WorkerNode {
         }
 
         // 3. Execute
-        let exec_result = self
+        let mut exec_result = self
             .sandbox
             .execute(&env, &node)
             .await
             .context("Failed to execute command in remote sandbox")?;
+        if let Some(failure) = &exec_result.failure {
+            let reason = format!("memobuild: command {}\n", failure);
+            exec_result.stderr.extend_from_slice(reason.as_bytes());
+        }
 
         // 4. Capture Outputs
         let mut output_files = HashMap::new();
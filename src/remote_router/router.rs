@@ -150,11 +150,17 @@ impl CacheRouter {
         primary.client.report_dag(dag).await
     }
 
-    pub async fn report_analytics(&self, dirty: u32, cached: u32, duration_ms: u64) -> Result<()> {
+    pub async fn report_analytics(
+        &self,
+        dirty: u32,
+        cached: u32,
+        duration_ms: u64,
+        analysis: Option<&crate::analysis::BuildAnalysis>,
+    ) -> Result<()> {
         let primary = self.select_primary_for_write("").await;
         primary
             .client
-            .report_analytics(dirty, cached, duration_ms)
+            .report_analytics(dirty, cached, duration_ms, analysis)
             .await
     }
 
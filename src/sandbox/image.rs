@@ -0,0 +1,113 @@
+//! Base images as containerd stores them: references, manifests and the chain IDs their
+//! unpacked snapshots are named by, plus the names of the snapshots MemoBuild layers on top.
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+
+const DEFAULT_REGISTRY: &str = "docker.io";
+const INDEX_MEDIA_TYPES: &[&str] = &[
+    "application/vnd.oci.image.index.v1+json",
+    "application/vnd.docker.distribution.manifest.list.v2+json",
+];
+
+/// Fully qualified form of an image reference, the name containerd stores images under:
+/// `alpine` becomes `docker.io/library/alpine:latest`.
+pub fn normalize_reference(reference: &str) -> String {
+    let (name, digest) = match reference.split_once('@') {
+        Some((name, digest)) => (name, Some(digest)),
+        None => (reference, None),
+    };
+    let (repository, tag) = match name.rsplit_once(':') {
+        Some((repository, tag)) if !tag.contains('/') => (repository, Some(tag)),
+        _ => (name, None),
+    };
+    let repository = match repository.split_once('/') {
+        Some((host, _)) if host.contains('.') || host.contains(':') || host == "localhost" => {
+            repository.to_string()
+        }
+        Some(_) => format!("{}/{}", DEFAULT_REGISTRY, repository),
+        None => format!("{}/library/{}", DEFAULT_REGISTRY, repository),
+    };
+    match (tag, digest) {
+        (_, Some(digest)) => format!("{}@{}", repository, digest),
+        (Some(tag), None) => format!("{}:{}", repository, tag),
+        (None, None) => format!("{}:latest", repository),
+    }
+}
+
+/// Whether a descriptor of `media_type` points at an index of per-platform manifests
+pub fn is_index(media_type: &str) -> bool {
+    INDEX_MEDIA_TYPES.contains(&media_type)
+}
+
+/// The OCI name of the architecture this binary was built for
+pub fn host_architecture() -> &'static str {
+    match std::env::consts::ARCH {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        "x86" => "386",
+        "powerpc64" => "ppc64le",
+        other => other,
+    }
+}
+
+/// Digest of the manifest for `os`/`architecture` in an image index.
+pub fn select_manifest(index: &[u8], os: &str, architecture: &str) -> Result<String> {
+    let index: serde_json::Value = serde_json::from_slice(index).context("Invalid image index")?;
+    index["manifests"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find(|manifest| {
+            manifest["platform"]["os"] == os && manifest["platform"]["architecture"] == architecture
+        })
+        .and_then(|manifest| manifest["digest"].as_str())
+        .map(str::to_string)
+        .with_context(|| format!("Image has no manifest for {}/{}", os, architecture))
+}
+
+/// Digest of the config blob an image manifest refers to.
+pub fn manifest_config(manifest: &[u8]) -> Result<String> {
+    let manifest: serde_json::Value =
+        serde_json::from_slice(manifest).context("Invalid image manifest")?;
+    manifest["config"]["digest"]
+        .as_str()
+        .map(str::to_string)
+        .context("Image manifest has no config")
+}
+
+/// Digests of the uncompressed layers listed in an image config, bottom layer first.
+pub fn diff_ids(config: &[u8]) -> Result<Vec<String>> {
+    let config: serde_json::Value =
+        serde_json::from_slice(config).context("Invalid image config")?;
+    let ids = config["rootfs"]["diff_ids"]
+        .as_array()
+        .context("Image config lists no layers")?;
+    ids.iter()
+        .map(|id| {
+            id.as_str()
+                .map(str::to_string)
+                .context("Invalid layer digest")
+        })
+        .collect()
+}
+
+/// Chain ID of the topmost layer: the name of the snapshot holding the unpacked image.
+/// None for an image without layers.
+pub fn chain_id(diff_ids: &[String]) -> Option<String> {
+    let mut ids = diff_ids.iter();
+    let first = ids.next()?.clone();
+    Some(ids.fold(first, |chain, diff_id| {
+        format!(
+            "sha256:{}",
+            hex::encode(Sha256::digest(format!("{} {}", chain, diff_id)))
+        )
+    }))
+}
+
+/// Name of the committed snapshot holding `parent` with the layer of the node with cache key
+/// `hash` applied on top. `parent` is None for an empty filesystem.
+pub fn layer_snapshot(parent: Option<&str>, hash: &str) -> String {
+    let key = format!("{} {}", parent.unwrap_or(""), hash);
+    format!("memobuild/{}", hex::encode(Sha256::digest(key)))
+}
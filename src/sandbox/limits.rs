@@ -0,0 +1,402 @@
+//! Enforcement of [`ResourceLimits`] on the commands sandboxes run.
+//!
+//! Where cgroup v2 is delegated to MemoBuild, each command runs in a cgroup of its own with
+//! `memory.max` (and no swap), `pids.max`, `cpu.max` and `cpu.weight` set. The cgroup is
+//! created in the one named by `MEMOBUILD_CGROUP`, or else in the cgroup MemoBuild runs in or
+//! its parent, whichever is writable and has the `memory` and `pids` controllers enabled for
+//! its children. Its `memory.events` then tells an OOM kill apart from other failures.
+//!
+//! Without a delegated cgroup, memory and process counts fall back to `setrlimit`:
+//! `RLIMIT_AS` bounds address space rather than resident memory, so an allocation fails
+//! instead of the command being killed, and `RLIMIT_NPROC` counts every process of the user
+//! and does not apply to root. CPU limits need cgroups with the `cpu` controller, and a
+//! warning says so when they cannot be applied. The wall-clock timeout is enforced either
+//! way, by killing the command's process group.
+
+use crate::sandbox::{FailureReason, ResourceLimits};
+use anyhow::{Context, Result};
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Once, OnceLock};
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+
+/// Controllers a cgroup has to delegate for MemoBuild to use it
+const REQUIRED_CONTROLLERS: &[&str] = &["memory", "pids"];
+/// Period of `cpu.max`, in microseconds
+const CPU_PERIOD: u64 = 100_000;
+/// How long output is still collected from a command's pipes once it was killed
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Limits applied to one command.
+pub struct Limiter {
+    limits: ResourceLimits,
+    cgroup: Option<Cgroup>,
+}
+
+/// Output of a command run by a [`Limiter`]
+pub struct LimitedOutput {
+    pub output: Output,
+    pub failure: Option<FailureReason>,
+}
+
+impl Limiter {
+    /// Prepare `limits` for one command, in a cgroup if one is delegated.
+    pub fn new(limits: &ResourceLimits) -> Result<Self> {
+        Self::with_cgroup_parent(limits, delegated_cgroup())
+    }
+
+    /// Prepare `limits` for one command, in a new cgroup below `parent` if given.
+    pub fn with_cgroup_parent(limits: &ResourceLimits, parent: Option<&Path>) -> Result<Self> {
+        let cgroup = match parent {
+            Some(parent) if needs_cgroup(limits) => Some(Cgroup::create(parent, limits)?),
+            _ => None,
+        };
+        if cgroup.is_none() {
+            warn_cpu_unenforced(limits, "no cgroup v2 is delegated to MemoBuild");
+        }
+        Ok(Limiter {
+            limits: limits.clone(),
+            cgroup,
+        })
+    }
+
+    /// The cgroup the command runs in, if the limits are enforced through one
+    pub fn cgroup(&self) -> Option<&Path> {
+        self.cgroup.as_ref().map(|cgroup| cgroup.path.as_path())
+    }
+
+    /// Make `command` start under the limits, in a process group of its own. Sandboxes that
+    /// set up the child themselves call this before adding their own `pre_exec` hooks.
+    pub fn apply(&self, command: &mut Command) -> Result<()> {
+        let procs = match &self.cgroup {
+            Some(cgroup) => Some(CString::new(
+                cgroup.path.join("cgroup.procs").as_os_str().as_bytes(),
+            )?),
+            None => None,
+        };
+        let rlimits = match &self.cgroup {
+            Some(_) => Vec::new(),
+            None => fallback_rlimits(&self.limits),
+        };
+
+        command.process_group(0);
+        // SAFETY: only open, write, close and setrlimit, on data prepared before the fork
+        unsafe {
+            command.pre_exec(move || {
+                if let Some(procs) = &procs {
+                    let fd = libc::open(procs.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+                    if fd < 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                    // "0" moves the writing process
+                    let written = libc::write(fd, b"0".as_ptr() as *const libc::c_void, 1);
+                    let error = std::io::Error::last_os_error();
+                    libc::close(fd);
+                    if written != 1 {
+                        return Err(error);
+                    }
+                }
+                for (resource, value) in &rlimits {
+                    let limit = libc::rlimit {
+                        rlim_cur: *value,
+                        rlim_max: *value,
+                    };
+                    if libc::setrlimit(*resource, &limit) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+
+    /// Run a command prepared with [`Limiter::apply`] and collect its output. Processes it
+    /// leaves behind are killed when it exits, as when a container stops.
+    pub async fn output(&self, command: Command) -> Result<LimitedOutput> {
+        let mut command = tokio::process::Command::from(command);
+        command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let mut child = command.spawn()?;
+        let pid = child
+            .id()
+            .context("Command exited before it could be limited")? as i32;
+
+        let stdout = read_all(child.stdout.take());
+        let stderr = read_all(child.stderr.take());
+        let exited = wait_without_reaping(pid);
+        let mut failure = None;
+        match self.limits.timeout_secs {
+            Some(secs) => match tokio::time::timeout(Duration::from_secs(secs), exited).await {
+                Ok(exited) => exited?,
+                Err(_) => failure = Some(FailureReason::Timeout { after_secs: secs }),
+            },
+            None => exited.await?,
+        }
+        // Until it is reaped, the command keeps its process group from being reused
+        self.kill(pid);
+        let status = child.wait().await?;
+
+        if let (Some(cgroup), Some(limit_mb)) = (&self.cgroup, self.limits.memory_mb) {
+            if failure.is_none() && cgroup.oom_killed() {
+                failure = Some(FailureReason::OutOfMemory { limit_mb });
+            }
+        }
+        Ok(LimitedOutput {
+            output: Output {
+                status,
+                stdout: drain(stdout).await,
+                stderr: drain(stderr).await,
+            },
+            failure,
+        })
+    }
+
+    /// Kill the command's process group and everything left in its cgroup
+    fn kill(&self, pid: i32) {
+        // SAFETY: kill has no memory safety requirements
+        unsafe {
+            libc::kill(-pid, libc::SIGKILL);
+        }
+        if let Some(cgroup) = &self.cgroup {
+            cgroup.kill();
+        }
+    }
+}
+
+/// Wait for the process `pid` to exit, leaving it to be reaped
+async fn wait_without_reaping(pid: i32) -> Result<()> {
+    tokio::task::spawn_blocking(move || loop {
+        // SAFETY: waitid only writes to the zeroed siginfo
+        let ret = unsafe {
+            let mut info: libc::siginfo_t = std::mem::zeroed();
+            libc::waitid(
+                libc::P_PID,
+                pid as libc::id_t,
+                &mut info,
+                libc::WEXITED | libc::WNOWAIT,
+            )
+        };
+        let error = std::io::Error::last_os_error();
+        if ret == 0 {
+            return Ok(());
+        }
+        if error.kind() != std::io::ErrorKind::Interrupted {
+            return Err(error.into());
+        }
+    })
+    .await?
+}
+
+fn read_all<R>(pipe: Option<R>) -> tokio::task::JoinHandle<Vec<u8>>
+where
+    R: tokio::io::AsyncRead + Unpin + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut data = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut data).await;
+        }
+        data
+    })
+}
+
+/// Output read so far; a process that escaped the kill may keep the pipe open.
+async fn drain(reader: tokio::task::JoinHandle<Vec<u8>>) -> Vec<u8> {
+    match tokio::time::timeout(DRAIN_TIMEOUT, reader).await {
+        Ok(Ok(data)) => data,
+        _ => Vec::new(),
+    }
+}
+
+/// Whether any of `limits` is enforced through a cgroup
+fn needs_cgroup(limits: &ResourceLimits) -> bool {
+    limits.memory_mb.is_some()
+        || limits.pids.is_some()
+        || limits.cpus.is_some()
+        || limits.cpu_shares.is_some()
+}
+
+#[cfg(target_env = "gnu")]
+type Resource = libc::__rlimit_resource_t;
+#[cfg(not(target_env = "gnu"))]
+type Resource = libc::c_int;
+
+fn fallback_rlimits(limits: &ResourceLimits) -> Vec<(Resource, libc::rlim_t)> {
+    let mut rlimits = Vec::new();
+    if let Some(mb) = limits.memory_mb {
+        rlimits.push((libc::RLIMIT_AS, (mb << 20) as libc::rlim_t));
+    }
+    if let Some(pids) = limits.pids {
+        rlimits.push((libc::RLIMIT_NPROC, pids as libc::rlim_t));
+    }
+    rlimits
+}
+
+/// Tell the user once that the `cpus` and `cpu-shares` limits of `limits` are not applied
+fn warn_cpu_unenforced(limits: &ResourceLimits, why: &str) {
+    static WARNED: Once = Once::new();
+    if limits.cpus.is_some() || limits.cpu_shares.is_some() {
+        WARNED.call_once(|| {
+            eprintln!(
+                "⚠️  CPU limits are not enforced: {} (see MEMOBUILD_CGROUP)",
+                why
+            )
+        });
+    }
+}
+
+/// `cpu.weight` equivalent to Docker's `--cpu-shares`, mapping 2..=262144 onto 1..=10000
+pub fn cpu_weight(shares: u64) -> u64 {
+    let shares = shares.clamp(2, 262_144);
+    1 + ((shares - 2) * 9999) / 262_142
+}
+
+/// A cgroup created for one command, removed when dropped.
+struct Cgroup {
+    path: PathBuf,
+}
+
+impl Cgroup {
+    fn create(parent: &Path, limits: &ResourceLimits) -> Result<Self> {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = parent.join(format!(
+            "memobuild-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir(&path)
+            .with_context(|| format!("Failed to create cgroup {}", path.display()))?;
+        let cgroup = Cgroup { path };
+
+        let controllers = std::fs::read_to_string(parent.join("cgroup.subtree_control"))?;
+        let enabled = |name: &str| controllers.split_whitespace().any(|c| c == name);
+        if let Some(mb) = limits.memory_mb {
+            cgroup.write("memory.max", &(mb << 20).to_string())?;
+            // Swapping out would only postpone hitting the limit
+            if cgroup.path.join("memory.swap.max").exists() {
+                cgroup.write("memory.swap.max", "0")?;
+            }
+        }
+        if let Some(pids) = limits.pids {
+            cgroup.write("pids.max", &pids.to_string())?;
+        }
+        if enabled("cpu") {
+            if let Some(cpus) = limits.cpus {
+                let quota = ((cpus * CPU_PERIOD as f64).round() as u64).max(1000);
+                cgroup.write("cpu.max", &format!("{} {}", quota, CPU_PERIOD))?;
+            }
+            if let Some(shares) = limits.cpu_shares {
+                cgroup.write("cpu.weight", &cpu_weight(shares).to_string())?;
+            }
+        } else {
+            warn_cpu_unenforced(
+                limits,
+                &format!("{} does not delegate the cpu controller", parent.display()),
+            );
+        }
+        Ok(cgroup)
+    }
+
+    fn write(&self, file: &str, value: &str) -> Result<()> {
+        let path = self.path.join(file);
+        std::fs::write(&path, value)
+            .with_context(|| format!("Failed to set {} to {}", path.display(), value))
+    }
+
+    /// Whether the kernel killed a process of the cgroup for going over `memory.max`
+    fn oom_killed(&self) -> bool {
+        let events = std::fs::read_to_string(self.path.join("memory.events")).unwrap_or_default();
+        events.lines().any(|line| {
+            line.strip_prefix("oom_kill ")
+                .and_then(|count| count.trim().parse::<u64>().ok())
+                .is_some_and(|count| count > 0)
+        })
+    }
+
+    fn kill(&self) {
+        // cgroup.kill needs Linux 5.14; older kernels get every listed process killed
+        if std::fs::write(self.path.join("cgroup.kill"), "1").is_ok() {
+            return;
+        }
+        let procs = std::fs::read_to_string(self.path.join("cgroup.procs")).unwrap_or_default();
+        for pid in procs
+            .lines()
+            .filter_map(|pid| pid.trim().parse::<i32>().ok())
+        {
+            // SAFETY: kill has no memory safety requirements
+            unsafe {
+                libc::kill(pid, libc::SIGKILL);
+            }
+        }
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        self.kill();
+        // Killed processes take a moment to leave the cgroup
+        for _ in 0..50 {
+            if std::fs::remove_dir(&self.path).is_ok() {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+/// The cgroup v2 directory that command cgroups are created in, if one is delegated.
+pub fn delegated_cgroup() -> Option<&'static Path> {
+    static DELEGATED: OnceLock<Option<PathBuf>> = OnceLock::new();
+    DELEGATED
+        .get_or_init(|| {
+            let candidates = match std::env::var_os("MEMOBUILD_CGROUP") {
+                Some(dir) => vec![PathBuf::from(dir)],
+                None => {
+                    let own = own_cgroup()?;
+                    let parent = own.parent().map(Path::to_path_buf);
+                    std::iter::once(own).chain(parent).collect()
+                }
+            };
+            candidates.into_iter().find(|dir| is_delegated(dir))
+        })
+        .as_deref()
+}
+
+/// Directory of the cgroup v2 this process runs in
+fn own_cgroup() -> Option<PathBuf> {
+    let mounts = std::fs::read_to_string("/proc/self/mounts").ok()?;
+    let mount = mounts.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        (fields.get(2) == Some(&"cgroup2")).then(|| PathBuf::from(fields[1]))
+    })?;
+    let cgroups = std::fs::read_to_string("/proc/self/cgroup").ok()?;
+    let own = cgroups.lines().find_map(|line| line.strip_prefix("0::"))?;
+    Some(mount.join(own.trim().trim_start_matches('/')))
+}
+
+/// Whether this process may create cgroups with memory and pids limits below `dir`.
+pub fn is_delegated(dir: &Path) -> bool {
+    let Ok(controllers) = std::fs::read_to_string(dir.join("cgroup.subtree_control")) else {
+        return false;
+    };
+    let enabled: Vec<&str> = controllers.split_whitespace().collect();
+    REQUIRED_CONTROLLERS.iter().all(|c| enabled.contains(c))
+        && writable(dir)
+        && writable(&dir.join("cgroup.procs"))
+}
+
+fn writable(path: &Path) -> bool {
+    match CString::new(path.as_os_str().as_bytes()) {
+        // SAFETY: access only reads the NUL-terminated path
+        Ok(path) => unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 },
+        Err(_) => false,
+    }
+}
@@ -1,16 +1,59 @@
-use crate::graph::Node;
-use crate::sandbox::{ExecResult, Sandbox, SandboxEnv};
+use crate::graph::{Node, NodeKind};
+use crate::sandbox::mounts::{default_cache_mount_dir, MountSet};
+use crate::sandbox::{
+    ExecResult, FailureReason, FetchedUrl, NetworkPolicy, ResourceLimits, Sandbox, SandboxEnv,
+};
+use crate::secrets::SecretProvider;
 use anyhow::Result;
 use async_trait::async_trait;
-use std::process::Command;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+use std::sync::Arc;
 
 pub struct LocalSandbox {
     pub workspace_dir: std::path::PathBuf,
+    /// Source of `RUN --mount=type=secret` values
+    secrets: Option<Arc<dyn SecretProvider>>,
+    /// Root of the persistent `type=cache` mounts; defaults to the MemoBuild cache directory
+    cache_mount_dir: Option<PathBuf>,
+    /// Limits for nodes that do not set their own
+    limits: ResourceLimits,
+    /// Network policy for nodes that do not set their own
+    network: NetworkPolicy,
 }
 
 impl LocalSandbox {
     pub fn new(workspace_dir: std::path::PathBuf) -> Self {
-        Self { workspace_dir }
+        Self {
+            workspace_dir,
+            secrets: None,
+            cache_mount_dir: None,
+            limits: ResourceLimits::default(),
+            network: NetworkPolicy::Host,
+        }
+    }
+
+    pub fn with_secrets(mut self, secrets: Arc<dyn SecretProvider>) -> Self {
+        self.secrets = Some(secrets);
+        self
+    }
+
+    pub fn with_cache_mount_dir(mut self, dir: PathBuf) -> Self {
+        self.cache_mount_dir = Some(dir);
+        self
+    }
+
+    /// Limits applied to every command, where the node does not set its own.
+    pub fn with_limits(mut self, limits: ResourceLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Network policy of every command, where the node does not set its own. Defaults to
+    /// the host's network.
+    pub fn with_network(mut self, policy: NetworkPolicy) -> Self {
+        self.network = policy;
+        self
     }
 }
 
@@ -24,13 +67,14 @@ impl Sandbox for LocalSandbox {
     }
 
     async fn execute(&self, env: &SandboxEnv, node: &Node) -> Result<ExecResult> {
-        let cmd = match &node.kind {
-            crate::graph::NodeKind::Run => node.content.clone(),
-            crate::graph::NodeKind::RunExtend { command, .. } => command.clone(),
-            crate::graph::NodeKind::CustomHook { hook_name, params } => {
-                format!("{} {}", hook_name, params.join(" "))
-            }
-            crate::graph::NodeKind::CopyExtend { src, dst, .. } => {
+        let host_shell: Vec<String> = if cfg!(target_os = "windows") {
+            vec!["cmd".to_string(), "/C".to_string()]
+        } else {
+            crate::docker::parser::default_shell()
+        };
+
+        let argv = match &node.kind {
+            NodeKind::CopyExtend { src, dst, .. } => {
                 // Perform file copy directly in Rust
                 let src_path = env.workspace_dir.join(src);
                 let dst_path = env.workspace_dir.join(dst);
@@ -42,11 +86,14 @@ impl Sandbox for LocalSandbox {
                 if src_path.is_dir() {
                     // Simple recursive copy using fs_extra or similar would be nice,
                     // but for now let's use system command cp -r for simplicity in sh/cmd
-                    if cfg!(target_os = "windows") {
+                    let cmd = if cfg!(target_os = "windows") {
                         format!("xcopy /E /I {} {}", src.display(), dst.display())
                     } else {
                         format!("cp -r {} {}", src.display(), dst.display())
-                    }
+                    };
+                    let mut argv = host_shell.clone();
+                    argv.push(cmd);
+                    argv
                 } else {
                     std::fs::copy(&src_path, &dst_path)?;
                     return Ok(ExecResult {
@@ -54,39 +101,60 @@ impl Sandbox for LocalSandbox {
                         stdout: format!("Copied {} to {}", src.display(), dst.display())
                             .into_bytes(),
                         stderr: Vec::new(),
+                        diff: None,
+                        failure: None,
+                        fetched: Vec::new(),
                     });
                 }
             }
-            _ => {
-                // For non-RUN nodes, we simulate success and return a metadata-based artifact
-                return Ok(ExecResult {
-                    exit_code: 0,
-                    stdout: format!("Artifact for {}", node.name).into_bytes(),
-                    stderr: Vec::new(),
-                });
-            }
+            _ => match crate::sandbox::command_argv(node, &host_shell) {
+                Some(argv) if !argv.is_empty() => argv,
+                _ => {
+                    // For non-RUN nodes, we simulate success and return a metadata-based artifact
+                    return Ok(ExecResult {
+                        exit_code: 0,
+                        stdout: format!("Artifact for {}", node.name).into_bytes(),
+                        stderr: Vec::new(),
+                        diff: None,
+                        failure: None,
+                        fetched: Vec::new(),
+                    });
+                }
+            },
         };
 
-        let output = if cfg!(target_os = "windows") {
-            Command::new("cmd")
-                .arg("/C")
-                .arg(cmd)
-                .envs(&env.env_vars)
-                .current_dir(&env.workspace_dir)
-                .output()?
-        } else {
-            Command::new("sh")
-                .arg("-c")
-                .arg(cmd)
-                .envs(&env.env_vars)
-                .current_dir(&env.workspace_dir)
-                .output()?
+        let mounts = match &node.kind {
+            NodeKind::Run { mounts } if !mounts.is_empty() => {
+                let cache_root = match &self.cache_mount_dir {
+                    Some(dir) => dir.clone(),
+                    None => default_cache_mount_dir()?,
+                };
+                // Commands run on the host filesystem, where container paths cannot be claimed
+                MountSet::prepare(mounts, None, &cache_root, self.secrets.as_deref()).await?
+            }
+            _ => MountSet::default(),
         };
 
+        let mut command = Command::new(&argv[0]);
+        command
+            .args(&argv[1..])
+            .envs(&env.env_vars)
+            .envs(&mounts.env)
+            .current_dir(&env.workspace_dir);
+        let (output, failure, fetched) = output(
+            command,
+            &node.metadata.limits.or(&self.limits),
+            node.metadata.network.as_ref().unwrap_or(&self.network),
+        )
+        .await?;
+
         Ok(ExecResult {
             exit_code: output.status.code().unwrap_or(1),
-            stdout: output.stdout,
-            stderr: output.stderr,
+            stdout: mounts.redact(output.stdout),
+            stderr: mounts.redact(output.stderr),
+            diff: None,
+            failure,
+            fetched,
         })
     }
 
@@ -94,3 +162,44 @@ impl Sandbox for LocalSandbox {
         Ok(())
     }
 }
+
+/// Run `command` to completion under `limits` and the network `policy`.
+#[cfg(target_os = "linux")]
+async fn output(
+    mut command: Command,
+    limits: &ResourceLimits,
+    policy: &NetworkPolicy,
+) -> Result<(Output, Option<FailureReason>, Vec<FetchedUrl>)> {
+    if limits.is_empty() && *policy == NetworkPolicy::Host {
+        return Ok((command.output()?, None, Vec::new()));
+    }
+    // The limiter moves the child into its cgroup before the network namespace is entered
+    let limiter = crate::sandbox::limits::Limiter::new(limits)?;
+    limiter.apply(&mut command)?;
+    let network = crate::sandbox::network::Network::new(policy)?;
+    network.apply(&mut command)?;
+    let proxy = network.start_proxy()?;
+    let limited = limiter.output(command).await?;
+    let log = match proxy {
+        Some(proxy) => proxy.finish().await,
+        None => Default::default(),
+    };
+    let exit_code = limited.output.status.code().unwrap_or(1);
+    let failure = limited.failure.or_else(|| log.failure(exit_code));
+    Ok((limited.output, failure, log.fetched))
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn output(
+    mut command: Command,
+    limits: &ResourceLimits,
+    policy: &NetworkPolicy,
+) -> Result<(Output, Option<FailureReason>, Vec<FetchedUrl>)> {
+    if !limits.is_empty() {
+        anyhow::bail!("Resource limits are only enforced on Linux");
+    }
+    if *policy != NetworkPolicy::Host {
+        anyhow::bail!("Network policy '{}' is only enforced on Linux", policy);
+    }
+    Ok((command.output()?, None, Vec::new()))
+}
@@ -1,4 +1,5 @@
-use crate::graph::Node;
+use crate::docker::parser::CommandForm;
+use crate::graph::{Node, NodeKind};
 use anyhow::Result;
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
@@ -7,13 +8,232 @@ use std::collections::HashMap;
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum SandboxKind {
     Local,
+    Snapshot,
+    Rootless,
     Containerd,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+/// Limits on the resources one command may use. Enforced by [`limits::Limiter`].
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
 pub struct ResourceLimits {
+    /// Relative CPU weight, as Docker's `--cpu-shares`: 1024 is an even share
     pub cpu_shares: Option<u64>,
     pub memory_mb: Option<u64>,
+    /// Number of CPUs the command may keep busy at most, e.g. `1.5`
+    #[serde(default)]
+    pub cpus: Option<f64>,
+    /// Maximum number of processes and threads
+    #[serde(default)]
+    pub pids: Option<u64>,
+    /// Wall-clock time after which the command is killed
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+}
+
+impl ResourceLimits {
+    /// Parse `memory=512m cpus=1.5 cpu-shares=512 pids=256 timeout=10m`, with pairs
+    /// separated by whitespace or commas. Memory takes `b`, `k`, `m` and `g` suffixes and is
+    /// in bytes without one, as in Docker; timeouts take `s`, `m` and `h` and default to
+    /// seconds.
+    pub fn parse(spec: &str) -> Result<Self> {
+        let mut limits = ResourceLimits::default();
+        for pair in spec.split(|c: char| c == ',' || c.is_whitespace()) {
+            if pair.is_empty() {
+                continue;
+            }
+            let (key, value) = pair
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("Expected <limit>=<value>, got '{}'", pair))?;
+            let invalid = || anyhow::anyhow!("Invalid value '{}' for limit '{}'", value, key);
+            match key.to_ascii_lowercase().as_str() {
+                "memory" => {
+                    let bytes = parse_with_unit(
+                        value,
+                        1,
+                        &[("b", 1), ("k", 1 << 10), ("m", 1 << 20), ("g", 1 << 30)],
+                    )
+                    .ok_or_else(invalid)?;
+                    limits.memory_mb = Some(bytes.div_ceil(1 << 20).max(1));
+                }
+                "cpus" => {
+                    let cpus: f64 = value.parse().map_err(|_| invalid())?;
+                    if !(cpus > 0.0 && cpus.is_finite()) {
+                        return Err(invalid());
+                    }
+                    limits.cpus = Some(cpus);
+                }
+                "cpu-shares" => {
+                    limits.cpu_shares =
+                        Some(value.parse().ok().filter(|&s| s > 0).ok_or_else(invalid)?)
+                }
+                "pids" => {
+                    limits.pids = Some(value.parse().ok().filter(|&p| p > 0).ok_or_else(invalid)?)
+                }
+                "timeout" => {
+                    let secs = parse_with_unit(value, 1, &[("s", 1), ("m", 60), ("h", 3600)])
+                        .filter(|&s| s > 0)
+                        .ok_or_else(invalid)?;
+                    limits.timeout_secs = Some(secs);
+                }
+                _ => anyhow::bail!(
+                    "Unknown limit '{}' (expected memory, cpus, cpu-shares, pids or timeout)",
+                    key
+                ),
+            }
+        }
+        Ok(limits)
+    }
+
+    /// These limits, with the ones left unset taken from `defaults`.
+    pub fn or(&self, defaults: &ResourceLimits) -> ResourceLimits {
+        ResourceLimits {
+            cpu_shares: self.cpu_shares.or(defaults.cpu_shares),
+            memory_mb: self.memory_mb.or(defaults.memory_mb),
+            cpus: self.cpus.or(defaults.cpus),
+            pids: self.pids.or(defaults.pids),
+            timeout_secs: self.timeout_secs.or(defaults.timeout_secs),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == ResourceLimits::default()
+    }
+}
+
+/// `<number><unit>`, with `default_unit` as the multiplier when there is no unit
+fn parse_with_unit(value: &str, default_unit: u64, units: &[(&str, u64)]) -> Option<u64> {
+    let value = value.trim().to_ascii_lowercase();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let multiplier = match unit {
+        "" => default_unit,
+        unit => units.iter().find(|(name, _)| *name == unit)?.1,
+    };
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+/// Network access of a build step. Enforced by [`network::Network`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NetworkPolicy {
+    /// Loopback only
+    None,
+    /// The host's network
+    Host,
+    /// HTTP(S) to the listed hosts only, through a logging proxy. `example.com` matches that
+    /// host, `*.example.com` its subdomains; a `:<port>` suffix restricts the port.
+    Allow(Vec<String>),
+}
+
+impl NetworkPolicy {
+    /// Parse `none`, `host` or `allow=<host>[,<host>...]`.
+    pub fn parse(spec: &str) -> Result<Self> {
+        let spec = spec.trim();
+        match spec.split_once('=') {
+            None if spec.eq_ignore_ascii_case("none") => Ok(NetworkPolicy::None),
+            None if spec.eq_ignore_ascii_case("host") => Ok(NetworkPolicy::Host),
+            Some((mode, hosts)) if mode.eq_ignore_ascii_case("allow") => {
+                let hosts: Vec<String> = hosts
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|host| !host.is_empty())
+                    .map(str::to_string)
+                    .collect();
+                if hosts.is_empty() {
+                    anyhow::bail!("Network allowlist '{}' lists no hosts", spec);
+                }
+                Ok(NetworkPolicy::Allow(hosts))
+            }
+            _ => anyhow::bail!(
+                "Unknown network policy '{}' (expected none, host or allow=<host>,...)",
+                spec
+            ),
+        }
+    }
+
+    /// Whether the policy lets a command connect to `host` on `port`.
+    pub fn allows(&self, host: &str, port: u16) -> bool {
+        match self {
+            NetworkPolicy::None => false,
+            NetworkPolicy::Host => true,
+            NetworkPolicy::Allow(patterns) => patterns
+                .iter()
+                .any(|pattern| host_matches(pattern, host, port)),
+        }
+    }
+}
+
+impl std::fmt::Display for NetworkPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NetworkPolicy::None => write!(f, "none"),
+            NetworkPolicy::Host => write!(f, "host"),
+            NetworkPolicy::Allow(hosts) => write!(f, "allow={}", hosts.join(",")),
+        }
+    }
+}
+
+fn host_matches(pattern: &str, host: &str, port: u16) -> bool {
+    let (name, wanted_port) = match pattern.rsplit_once(':') {
+        Some((name, p)) if !name.is_empty() => match p.parse::<u16>() {
+            Ok(p) => (name, Some(p)),
+            Err(_) => (pattern, None),
+        },
+        _ => (pattern, None),
+    };
+    if wanted_port.is_some_and(|p| p != port) {
+        return false;
+    }
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    let name = name.to_ascii_lowercase();
+    match name.strip_prefix("*.") {
+        Some(domain) => host.ends_with(&format!(".{}", domain)),
+        None => host == name,
+    }
+}
+
+/// A URL a command fetched through the allowlist proxy.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct FetchedUrl {
+    pub url: String,
+    /// `sha256:<hex>` of the response body. None for HTTPS, whose content the proxy only
+    /// tunnels.
+    #[serde(default)]
+    pub digest: Option<String>,
+}
+
+/// Why a command failed, when a resource limit or the network policy rather than the command
+/// itself was the cause.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FailureReason {
+    /// Killed by the kernel for going over its memory limit
+    OutOfMemory { limit_mb: u64 },
+    /// Killed for running longer than its timeout
+    Timeout { after_secs: u64 },
+    /// Failed after the network policy refused it a request
+    NetworkBlocked { url: String },
+}
+
+impl std::fmt::Display for FailureReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FailureReason::OutOfMemory { limit_mb } => {
+                write!(
+                    f,
+                    "was killed for exceeding its memory limit of {} MB",
+                    limit_mb
+                )
+            }
+            FailureReason::Timeout { after_secs } => write!(f, "timed out after {}s", after_secs),
+            FailureReason::NetworkBlocked { url } => write!(
+                f,
+                "failed after the network policy refused access to {}",
+                url
+            ),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -27,6 +247,13 @@ pub struct ExecResult {
     pub exit_code: i32,
     pub stdout: Vec<u8>,
     pub stderr: Vec<u8>,
+    /// Filesystem changes made by the node as an uncompressed OCI layer tar, for sandboxes
+    /// that capture them. Cached as the node's artifact instead of stdout.
+    pub diff: Option<Vec<u8>>,
+    /// Set when a resource limit or the network policy made the command fail
+    pub failure: Option<FailureReason>,
+    /// URLs the command fetched through the network allowlist proxy
+    pub fetched: Vec<FetchedUrl>,
 }
 
 #[async_trait]
@@ -34,9 +261,56 @@ pub trait Sandbox: Send + Sync {
     async fn prepare(&self, node: &Node) -> Result<SandboxEnv>;
     async fn execute(&self, env: &SandboxEnv, node: &Node) -> Result<ExecResult>;
     async fn cleanup(&self, env: &SandboxEnv) -> Result<()>;
+
+    /// Whether the sandbox captures filesystem changes. Nodes that only write files (COPY,
+    /// ADD) then run in it too, and cache hits are handed to [`Sandbox::restore`].
+    fn captures_filesystem(&self) -> bool {
+        false
+    }
+
+    /// Make the filesystem changes of a node restored from the cache, as returned in
+    /// [`ExecResult::diff`] by an earlier build, available to the nodes built on top of it.
+    /// An error means the cached artifact cannot be used and the node must run again.
+    async fn restore(&self, _node: &Node, _diff: &[u8]) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Command a node runs, if any: RUN in exec or shell form, RUN_EXTEND and hooks in shell form.
+pub fn command_form(node: &Node) -> Option<CommandForm> {
+    match &node.kind {
+        NodeKind::Run { .. } => Some(CommandForm::parse(&node.content)),
+        NodeKind::RunExtend { command, .. } => Some(CommandForm::Shell(command.clone())),
+        NodeKind::CustomHook { hook_name, params } => Some(CommandForm::Shell(format!(
+            "{} {}",
+            hook_name,
+            params.join(" ")
+        ))),
+        _ => None,
+    }
+}
+
+/// Argument vector to execute for a node. Shell form goes through the node's `SHELL`, or
+/// `default_shell` when the Dockerfile did not set one.
+pub fn command_argv(node: &Node, default_shell: &[String]) -> Option<Vec<String>> {
+    let form = command_form(node)?;
+    if node.metadata.shell.is_empty() {
+        Some(form.argv(default_shell))
+    } else {
+        Some(form.argv(&node.metadata.shell))
+    }
 }
 
-#[cfg(feature = "containerd")]
+#[cfg(all(feature = "containerd", target_os = "linux"))]
 pub mod containerd;
+pub mod image;
+#[cfg(target_os = "linux")]
+pub mod limits;
 pub mod local;
+pub mod mounts;
+#[cfg(target_os = "linux")]
+pub mod network;
+#[cfg(target_os = "linux")]
+pub mod rootless;
+pub mod snapshot;
 pub mod spec;
@@ -0,0 +1,345 @@
+//! `RUN --mount` support.
+//!
+//! Mount targets are paths in the container. Sandboxes with a filesystem of their own get a
+//! symlink at each target inside it, to be replaced by a real mount in their mount namespace.
+//! Sandboxes that run commands on the host filesystem cannot place anything at a container
+//! path: they skip cache and tmpfs mounts, which only speed a command up or give it scratch
+//! space, and refuse the mounts a command cannot do without.
+
+use crate::cache::LocalCache;
+use crate::graph::{CacheSharing, RunMount};
+use crate::secrets::SecretProvider;
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// Directory holding the persistent `type=cache` mounts, under the MemoBuild cache directory.
+pub fn default_cache_mount_dir() -> Result<PathBuf> {
+    Ok(LocalCache::get_cache_dir()?.join("mounts"))
+}
+
+/// Directory backing the cache mount `id` under `root`. The name keeps a readable prefix of
+/// the id and a hash that keeps distinct ids apart.
+pub fn cache_mount_path(root: &Path, id: &str) -> PathBuf {
+    let readable: String = id
+        .trim_matches('/')
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .take(48)
+        .collect();
+    let hash = blake3::hash(id.as_bytes()).to_hex();
+    root.join(format!("{}-{}", readable, &hash[..12]))
+}
+
+/// Locks serializing `sharing=locked` (and detecting busy `sharing=private`) cache mounts
+/// within this process.
+fn cache_lock(path: &Path) -> Arc<tokio::sync::Mutex<()>> {
+    static LOCKS: OnceLock<Mutex<HashMap<PathBuf, Arc<tokio::sync::Mutex<()>>>>> = OnceLock::new();
+    let mut locks = LOCKS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    locks.entry(path.to_path_buf()).or_default().clone()
+}
+
+/// The mounts of one RUN, set up before the command and torn down when dropped.
+#[derive(Default)]
+pub struct MountSet {
+    /// Extra environment: `env=` secrets and `SSH_AUTH_SOCK`
+    pub env: HashMap<String, String>,
+    secret_values: Vec<String>,
+    links: Vec<PathBuf>,
+    created_dirs: Vec<PathBuf>,
+    scratch_dirs: Vec<PathBuf>,
+    _locks: Vec<tokio::sync::OwnedMutexGuard<()>>,
+}
+
+impl MountSet {
+    /// Set up `mounts` inside `root`, the filesystem the command sees as `/`. Without one, only
+    /// the mounts that need no target take effect. Secrets are read from `secrets`; a missing
+    /// secret or SSH agent is an error only for `required=true` mounts.
+    pub async fn prepare(
+        mounts: &[RunMount],
+        root: Option<&Path>,
+        cache_root: &Path,
+        secrets: Option<&dyn SecretProvider>,
+    ) -> Result<Self> {
+        let mut set = MountSet::default();
+        for mount in mounts {
+            let Some(root) = root else {
+                set.prepare_on_host(mount, secrets).await?;
+                continue;
+            };
+            match mount {
+                RunMount::Bind { target, source, .. } => {
+                    // Other stages share the filesystem in a local build
+                    let source = root.join(source.as_deref().unwrap_or("."));
+                    set.link(root, target, &source)?;
+                }
+                RunMount::Cache {
+                    target,
+                    id,
+                    sharing,
+                    ..
+                } => {
+                    let mut dir = cache_mount_path(cache_root, id);
+                    match sharing {
+                        CacheSharing::Shared => {}
+                        CacheSharing::Locked => {
+                            set._locks.push(cache_lock(&dir).lock_owned().await);
+                        }
+                        CacheSharing::Private => match cache_lock(&dir).try_lock_owned() {
+                            Ok(guard) => set._locks.push(guard),
+                            Err(_) => dir = set.scratch_dir("cache")?,
+                        },
+                    }
+                    std::fs::create_dir_all(&dir).with_context(|| {
+                        format!("Failed to create cache mount {}", dir.display())
+                    })?;
+                    set.link(root, target, &dir)?;
+                }
+                RunMount::Tmpfs { target, .. } => {
+                    let dir = set.scratch_dir("tmpfs")?;
+                    set.link(root, target, &dir)?;
+                }
+                RunMount::Secret {
+                    id,
+                    target,
+                    env,
+                    required,
+                } => {
+                    let Some(value) = set.secret(id, env, *required, secrets).await? else {
+                        continue;
+                    };
+                    if let Some(target) = target {
+                        let file = set.scratch_dir("secret")?.join("secret");
+                        write_private(&file, value.as_bytes())?;
+                        set.link(root, target, &file)?;
+                    }
+                }
+                RunMount::Ssh {
+                    id,
+                    target,
+                    required,
+                } => match std::env::var("SSH_AUTH_SOCK") {
+                    Ok(socket) => {
+                        set.link(root, target, Path::new(&socket))?;
+                        set.env.insert("SSH_AUTH_SOCK".to_string(), socket);
+                    }
+                    Err(_) if *required => {
+                        anyhow::bail!("SSH mount '{}' requires an SSH agent (SSH_AUTH_SOCK)", id)
+                    }
+                    Err(_) => {}
+                },
+            }
+        }
+        Ok(set)
+    }
+
+    /// Set up `mount` for a command that runs on the host filesystem.
+    async fn prepare_on_host(
+        &mut self,
+        mount: &RunMount,
+        secrets: Option<&dyn SecretProvider>,
+    ) -> Result<()> {
+        match mount {
+            RunMount::Cache { target, .. } | RunMount::Tmpfs { target, .. } => {
+                eprintln!(
+                    "⚠️  Skipping the mount at {}: this sandbox runs commands on the host \
+                     filesystem (use --sandbox rootless)",
+                    target.display()
+                );
+            }
+            RunMount::Bind { target, .. } => anyhow::bail!(
+                "RUN --mount=type=bind at {} needs a sandbox with its own filesystem; use \
+                 --sandbox rootless",
+                target.display()
+            ),
+            RunMount::Secret {
+                id,
+                target,
+                env,
+                required,
+            } => {
+                if let Some(target) = target {
+                    anyhow::bail!(
+                        "Secret '{}' is mounted as a file at {}, which needs a sandbox with its \
+                         own filesystem; use --sandbox rootless or pass it with env=<NAME>",
+                        id,
+                        target.display()
+                    );
+                }
+                self.secret(id, env, *required, secrets).await?;
+            }
+            RunMount::Ssh { id, required, .. } => match std::env::var("SSH_AUTH_SOCK") {
+                // The agent's socket is reachable at its host path
+                Ok(socket) => {
+                    self.env.insert("SSH_AUTH_SOCK".to_string(), socket);
+                }
+                Err(_) if *required => {
+                    anyhow::bail!("SSH mount '{}' requires an SSH agent (SSH_AUTH_SOCK)", id)
+                }
+                Err(_) => {}
+            },
+        }
+        Ok(())
+    }
+
+    /// Value of the secret `id`, also exported as `env` and redacted from the output. None if
+    /// the secret is missing and not `required`.
+    async fn secret(
+        &mut self,
+        id: &str,
+        env: &Option<String>,
+        required: bool,
+        secrets: Option<&dyn SecretProvider>,
+    ) -> Result<Option<String>> {
+        let value = match secrets {
+            Some(provider) => provider.get(id).await.ok(),
+            None => None,
+        };
+        let Some(value) = value else {
+            if required {
+                anyhow::bail!("Required secret '{}' is not available", id);
+            }
+            return Ok(None);
+        };
+        if let Some(name) = env {
+            self.env.insert(name.clone(), value.clone());
+        }
+        if !value.is_empty() {
+            self.secret_values.push(value.clone());
+        }
+        Ok(Some(value))
+    }
+
+    /// Replace every secret value in command output, so that secrets never reach artifacts
+    /// or logs.
+    pub fn redact(&self, output: Vec<u8>) -> Vec<u8> {
+        if self.secret_values.is_empty() {
+            return output;
+        }
+        // Bytes, not text: commands may print binary data
+        self.secret_values.iter().fold(output, |output, value| {
+            replace_bytes(output, value.as_bytes(), b"****")
+        })
+    }
+
+    /// Symlinks standing in for the mounts inside the root, to be replaced by real mounts in
+    /// the sandbox's mount namespace.
+    pub fn links(&self) -> &[PathBuf] {
+        &self.links
+    }
+
+    /// Make `source` visible at the container path `target` inside `root`.
+    fn link(&mut self, root: &Path, target: &Path, source: &Path) -> Result<()> {
+        let path = root.join(target.strip_prefix("/").unwrap_or(target));
+        if path == root || path == source {
+            return Ok(());
+        }
+        if path.symlink_metadata().is_ok() {
+            anyhow::bail!(
+                "Mount target {} already exists in {}",
+                target.display(),
+                root.display()
+            );
+        }
+
+        let mut missing = Vec::new();
+        let mut parent = path.parent();
+        while let Some(dir) = parent {
+            if dir.exists() {
+                break;
+            }
+            missing.push(dir.to_path_buf());
+            parent = dir.parent();
+        }
+        for dir in missing.into_iter().rev() {
+            std::fs::create_dir(&dir)?;
+            self.created_dirs.push(dir);
+        }
+
+        symlink(source, &path).with_context(|| {
+            format!(
+                "Failed to mount {} at {}",
+                source.display(),
+                target.display()
+            )
+        })?;
+        self.links.push(path);
+        Ok(())
+    }
+
+    fn scratch_dir(&mut self, kind: &str) -> Result<PathBuf> {
+        static COUNTER: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let n = COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let dir =
+            std::env::temp_dir().join(format!("memobuild-{}-{}-{}", kind, std::process::id(), n));
+        std::fs::create_dir_all(&dir)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700))?;
+        }
+        self.scratch_dirs.push(dir.clone());
+        Ok(dir)
+    }
+}
+
+impl Drop for MountSet {
+    fn drop(&mut self) {
+        for link in &self.links {
+            let _ = std::fs::remove_file(link);
+        }
+        for dir in &self.scratch_dirs {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+        // Deepest first; directories the command wrote into are kept
+        for dir in self.created_dirs.iter().rev() {
+            let _ = std::fs::remove_dir(dir);
+        }
+    }
+}
+
+fn replace_bytes(haystack: Vec<u8>, needle: &[u8], with: &[u8]) -> Vec<u8> {
+    if needle.is_empty() || haystack.len() < needle.len() {
+        return haystack;
+    }
+    let mut out = Vec::with_capacity(haystack.len());
+    let mut i = 0;
+    while i < haystack.len() {
+        if haystack[i..].starts_with(needle) {
+            out.extend_from_slice(with);
+            i += needle.len();
+        } else {
+            out.push(haystack[i]);
+            i += 1;
+        }
+    }
+    out
+}
+
+fn write_private(path: &Path, data: &[u8]) -> Result<()> {
+    std::fs::write(path, data)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o400))?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn symlink(source: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(source, link)
+}
+
+#[cfg(windows)]
+fn symlink(source: &Path, link: &Path) -> std::io::Result<()> {
+    if source.is_dir() {
+        std::os::windows::fs::symlink_dir(source, link)
+    } else {
+        std::os::windows::fs::symlink_file(source, link)
+    }
+}
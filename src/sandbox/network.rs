@@ -0,0 +1,566 @@
+//! Network policies of build steps.
+//!
+//! - `host`: the command shares the host's network, as it always did in the local sandbox
+//! - `none`: the command runs in a network namespace of its own with only loopback
+//! - `allow=<host>,...`: as `none`, except for an HTTP(S) proxy on `127.0.0.1:3128` inside
+//!   that namespace. The proxy itself runs in MemoBuild, outside it, and only connects to the
+//!   listed hosts. `HTTP_PROXY` and friends point commands at it. Every request is logged.
+//!   Plain HTTP responses are hashed. HTTPS is tunnelled with `CONNECT`, so for HTTPS only the
+//!   host is known.
+//!
+//! The proxy's listening socket is created inside the command's namespace, before `exec`.
+//! It is passed back to MemoBuild over a Unix socket, so a command can never connect before
+//! the proxy listens.
+
+use crate::sandbox::{FailureReason, FetchedUrl, NetworkPolicy};
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Port the allowlist proxy listens on in the command's network namespace
+pub const PROXY_PORT: u16 = 3128;
+/// Longest request head the proxy reads
+const MAX_HEAD: usize = 64 << 10;
+/// How long requests still in flight when the command exits get to finish
+const DRAIN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+/// Headers that only apply to the connection to the proxy
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "upgrade",
+];
+
+/// What the proxy saw while one command ran.
+#[derive(Debug, Clone, Default)]
+pub struct ProxyLog {
+    pub fetched: Vec<FetchedUrl>,
+    /// URLs refused because the policy does not allow their host
+    pub blocked: Vec<String>,
+}
+
+impl ProxyLog {
+    /// A blocked request is the likely reason a command failed.
+    pub fn failure(&self, exit_code: i32) -> Option<FailureReason> {
+        match self.blocked.first() {
+            Some(url) if exit_code != 0 => Some(FailureReason::NetworkBlocked { url: url.clone() }),
+            _ => None,
+        }
+    }
+}
+
+/// The network one command runs with.
+pub struct Network {
+    policy: NetworkPolicy,
+    /// MemoBuild's end and the command's end of the socket the proxy listener comes back on
+    channel: Option<(UnixStream, UnixStream)>,
+}
+
+impl Network {
+    pub fn new(policy: &NetworkPolicy) -> Result<Self> {
+        let channel = match policy {
+            NetworkPolicy::Allow(_) => Some(UnixStream::pair()?),
+            _ => None,
+        };
+        Ok(Network {
+            policy: policy.clone(),
+            channel,
+        })
+    }
+
+    /// Whether the command needs a network namespace of its own
+    pub fn is_isolated(&self) -> bool {
+        self.policy != NetworkPolicy::Host
+    }
+
+    /// Environment pointing HTTP clients at the proxy
+    pub fn env(&self) -> Vec<(&'static str, String)> {
+        if self.channel.is_none() {
+            return Vec::new();
+        }
+        let proxy = format!("http://127.0.0.1:{}", PROXY_PORT);
+        let mut env: Vec<(&'static str, String)> = [
+            "HTTP_PROXY",
+            "HTTPS_PROXY",
+            "ALL_PROXY",
+            "http_proxy",
+            "https_proxy",
+            "all_proxy",
+        ]
+        .into_iter()
+        .map(|key| (key, proxy.clone()))
+        .collect();
+        env.extend([("NO_PROXY", String::new()), ("no_proxy", String::new())]);
+        env
+    }
+
+    /// The command's end of the proxy channel, for [`listen_for_proxy`]
+    pub(crate) fn proxy_channel(&self) -> Option<RawFd> {
+        self.channel.as_ref().map(|(_, child)| child.as_raw_fd())
+    }
+
+    /// Make `command` run in a network namespace of its own, unless the policy is `host`.
+    /// Without root, a user namespace mapping the current user to itself is created first.
+    pub fn apply(&self, command: &mut std::process::Command) -> Result<()> {
+        use std::os::unix::process::CommandExt;
+
+        command.envs(self.env());
+        if !self.is_isolated() {
+            return Ok(());
+        }
+        // SAFETY: geteuid and getegid cannot fail
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        let uid_map = format!("{} {} 1", uid, uid);
+        let gid_map = format!("{} {} 1", gid, gid);
+        let channel = self.proxy_channel();
+        // SAFETY: only async-signal-safe calls on data prepared before the fork
+        unsafe {
+            command.pre_exec(move || {
+                if uid != 0 || libc::unshare(libc::CLONE_NEWNET) != 0 {
+                    check(libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNET))?;
+                    write_file(c"/proc/self/setgroups", b"deny")?;
+                    write_file(c"/proc/self/uid_map", uid_map.as_bytes())?;
+                    write_file(c"/proc/self/gid_map", gid_map.as_bytes())?;
+                }
+                loopback_up();
+                if let Some(channel) = channel {
+                    listen_for_proxy(channel)?;
+                }
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+
+    /// Start the proxy for an allowlist. Call before the command is spawned; the proxy
+    /// serves once the command's namespace hands it the listener.
+    pub fn start_proxy(&self) -> Result<Option<Proxy>> {
+        let Some((ours, _)) = &self.channel else {
+            return Ok(None);
+        };
+        let ours = ours.try_clone()?;
+        ours.set_nonblocking(true)?;
+        let ours = tokio::net::UnixStream::from_std(ours)?;
+        let log = Arc::new(Mutex::new(ProxyLog::default()));
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let policy = self.policy.clone();
+        let task = tokio::spawn({
+            let log = log.clone();
+            let requests = requests.clone();
+            async move {
+                let Ok(listener) = receive_listener(&ours).await else {
+                    return;
+                };
+                serve(listener, policy, log, requests).await
+            }
+        });
+        Ok(Some(Proxy {
+            task,
+            requests,
+            log,
+        }))
+    }
+}
+
+/// The allowlist proxy of one command.
+pub struct Proxy {
+    task: tokio::task::JoinHandle<()>,
+    /// Handlers of the requests accepted so far
+    requests: Arc<Mutex<Vec<tokio::task::JoinHandle<()>>>>,
+    log: Arc<Mutex<ProxyLog>>,
+}
+
+impl Proxy {
+    /// Stop accepting connections and return what the command fetched. A request is only
+    /// logged once its response is through, so those in flight get to finish first.
+    pub async fn finish(self) -> ProxyLog {
+        self.task.abort();
+        let requests = std::mem::take(&mut *self.requests.lock().unwrap());
+        for request in requests {
+            let abort = request.abort_handle();
+            if tokio::time::timeout(DRAIN_TIMEOUT, request).await.is_err() {
+                abort.abort();
+            }
+        }
+        std::mem::take(&mut *self.log.lock().unwrap())
+    }
+}
+
+/// Receive the listening socket the command's namespace sent with [`listen_for_proxy`]
+async fn receive_listener(channel: &tokio::net::UnixStream) -> Result<TcpListener> {
+    loop {
+        channel.readable().await?;
+        match channel.try_io(tokio::io::Interest::READABLE, || {
+            receive_fd(channel.as_raw_fd())
+        }) {
+            Ok(fd) => {
+                // SAFETY: the fd was just received and is owned by nothing else
+                let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+                listener.set_nonblocking(true)?;
+                return Ok(TcpListener::from_std(listener)?);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+async fn serve(
+    listener: TcpListener,
+    policy: NetworkPolicy,
+    log: Arc<Mutex<ProxyLog>>,
+    requests: Arc<Mutex<Vec<tokio::task::JoinHandle<()>>>>,
+) {
+    while let Ok((client, _)) = listener.accept().await {
+        let policy = policy.clone();
+        let log = log.clone();
+        let request = tokio::spawn(async move {
+            let _ = handle(client, &policy, &log).await;
+        });
+        requests.lock().unwrap().push(request);
+    }
+}
+
+/// Where a proxied request goes
+struct Target {
+    host: String,
+    port: u16,
+    url: String,
+    /// Origin-form request target of a plain HTTP request; None for a `CONNECT` tunnel
+    path: Option<String>,
+}
+
+impl Target {
+    fn parse(method: &str, target: &str) -> Option<Self> {
+        if method.eq_ignore_ascii_case("CONNECT") {
+            let (host, port) = split_host_port(target, 443)?;
+            let url = match port {
+                443 => format!("https://{}", host),
+                _ => format!("https://{}:{}", host, port),
+            };
+            return Some(Target {
+                host,
+                port,
+                url,
+                path: None,
+            });
+        }
+        let rest = target
+            .get(..7)
+            .filter(|scheme| scheme.eq_ignore_ascii_case("http://"))
+            .map(|_| &target[7..])?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let authority = authority.rsplit('@').next()?;
+        let (host, port) = split_host_port(authority, 80)?;
+        Some(Target {
+            host,
+            port,
+            url: format!("http://{}{}", authority, path),
+            path: Some(path.to_string()),
+        })
+    }
+}
+
+fn split_host_port(authority: &str, default_port: u16) -> Option<(String, u16)> {
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(rest) => {
+            let (host, rest) = rest.split_once(']')?;
+            (host, rest.strip_prefix(':'))
+        }
+        None => match authority.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        },
+    };
+    let port = match port {
+        Some(port) => port.parse().ok()?,
+        None => default_port,
+    };
+    (!host.is_empty()).then(|| (host.to_string(), port))
+}
+
+async fn handle(
+    mut client: TcpStream,
+    policy: &NetworkPolicy,
+    log: &Mutex<ProxyLog>,
+) -> Result<()> {
+    let (head, body) = read_head(&mut client).await?;
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().unwrap_or_default();
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let Some(target) = Target::parse(method, target) else {
+        return respond(
+            &mut client,
+            "400 Bad Request",
+            "memobuild: not a proxy request\n",
+        )
+        .await;
+    };
+
+    if !policy.allows(&target.host, target.port) {
+        println!("   🚫 Blocked {} {}", method, target.url);
+        log.lock().unwrap().blocked.push(target.url.clone());
+        let message = format!(
+            "memobuild: {} is not allowed by the network policy ({})\n",
+            target.url, policy
+        );
+        return respond(&mut client, "403 Forbidden", &message).await;
+    }
+    println!("   🌐 {} {}", method, target.url);
+    let mut upstream = match TcpStream::connect((target.host.as_str(), target.port)).await {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            let message = format!("memobuild: cannot reach {}: {}\n", target.url, e);
+            return respond(&mut client, "502 Bad Gateway", &message).await;
+        }
+    };
+
+    let Some(path) = target.path else {
+        log.lock().unwrap().fetched.push(FetchedUrl {
+            url: target.url,
+            digest: None,
+        });
+        client
+            .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+            .await?;
+        upstream.write_all(&body).await?;
+        tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+        return Ok(());
+    };
+
+    // Forwarded as HTTP/1.0, so that the response body is neither chunked nor kept alive:
+    // it is everything upstream sends until it closes
+    let mut request = format!("{} {} HTTP/1.0\r\n", method, path);
+    for line in lines.filter(|line| !line.is_empty()) {
+        let name = line
+            .split(':')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_ascii_lowercase();
+        if !HOP_BY_HOP.contains(&name.as_str()) {
+            request.push_str(line);
+            request.push_str("\r\n");
+        }
+    }
+    request.push_str("Connection: close\r\n\r\n");
+    upstream.write_all(request.as_bytes()).await?;
+    upstream.write_all(&body).await?;
+
+    let (mut client_read, mut client_write) = client.into_split();
+    let (mut upstream_read, mut upstream_write) = upstream.into_split();
+    let upload = tokio::spawn(async move {
+        let _ = tokio::io::copy(&mut client_read, &mut upstream_write).await;
+    });
+    let digest = relay_response(&mut upstream_read, &mut client_write).await;
+    upload.abort();
+    log.lock().unwrap().fetched.push(FetchedUrl {
+        url: target.url,
+        digest: Some(format!("sha256:{}", hex::encode(digest?))),
+    });
+    Ok(())
+}
+
+/// Read up to the end of the request head; returns the head and any bytes read past it
+async fn read_head(client: &mut TcpStream) -> Result<(String, Vec<u8>)> {
+    let mut data = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        if let Some(end) = find_head_end(&data) {
+            let body = data.split_off(end + 4);
+            data.truncate(end);
+            return Ok((String::from_utf8_lossy(&data).into_owned(), body));
+        }
+        if data.len() > MAX_HEAD {
+            anyhow::bail!("Request head too long");
+        }
+        let n = client.read(&mut buf).await?;
+        if n == 0 {
+            anyhow::bail!("Connection closed before the request head ended");
+        }
+        data.extend_from_slice(&buf[..n]);
+    }
+}
+
+fn find_head_end(data: &[u8]) -> Option<usize> {
+    data.windows(4).position(|window| window == b"\r\n\r\n")
+}
+
+/// Copy the response to the client, hashing its body
+async fn relay_response(
+    upstream: &mut tokio::net::tcp::OwnedReadHalf,
+    client: &mut tokio::net::tcp::OwnedWriteHalf,
+) -> Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    let mut head = Vec::new();
+    let mut in_body = false;
+    let mut buf = [0u8; 16 << 10];
+    loop {
+        let n = upstream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        client.write_all(&buf[..n]).await?;
+        if in_body {
+            hasher.update(&buf[..n]);
+            continue;
+        }
+        head.extend_from_slice(&buf[..n]);
+        if let Some(end) = find_head_end(&head) {
+            hasher.update(&head[end + 4..]);
+            in_body = true;
+        }
+    }
+    client.shutdown().await?;
+    Ok(hasher.finalize().into())
+}
+
+async fn respond(client: &mut TcpStream, status: &str, message: &str) -> Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        message.len(),
+        message
+    );
+    client.write_all(response.as_bytes()).await?;
+    client.shutdown().await?;
+    Ok(())
+}
+
+/// Receive one file descriptor sent with `SCM_RIGHTS`
+fn receive_fd(channel: RawFd) -> std::io::Result<RawFd> {
+    let mut control = ControlBuffer([0; 64]);
+    let mut byte = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr() as *mut libc::c_void,
+        iov_len: 1,
+    };
+    // SAFETY: the message header points at buffers that outlive the call, and the control
+    // message is only read within the length the kernel reported
+    unsafe {
+        let mut message: libc::msghdr = std::mem::zeroed();
+        message.msg_iov = &mut iov;
+        message.msg_iovlen = 1;
+        message.msg_control = control.0.as_mut_ptr() as *mut libc::c_void;
+        message.msg_controllen = control.0.len() as _;
+        let received = libc::recvmsg(channel, &mut message, libc::MSG_CMSG_CLOEXEC);
+        if received < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let header = libc::CMSG_FIRSTHDR(&message);
+        if received == 0
+            || header.is_null()
+            || (*header).cmsg_level != libc::SOL_SOCKET
+            || (*header).cmsg_type != libc::SCM_RIGHTS
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "No proxy listener was sent",
+            ));
+        }
+        Ok(std::ptr::read_unaligned(
+            libc::CMSG_DATA(header) as *const RawFd
+        ))
+    }
+}
+
+/// Room for one control message carrying a file descriptor, aligned for `cmsghdr`
+#[repr(C, align(8))]
+struct ControlBuffer([u8; 64]);
+
+/// Listen on the proxy port in the current network namespace and send the socket over
+/// `channel`. Runs in the forked child, after it entered its network namespace.
+///
+/// # Safety
+/// Only makes async-signal-safe calls, so it may run between `fork` and `exec`.
+pub(crate) unsafe fn listen_for_proxy(channel: RawFd) -> std::io::Result<()> {
+    let fd = check(libc::socket(
+        libc::AF_INET,
+        libc::SOCK_STREAM | libc::SOCK_CLOEXEC,
+        0,
+    ))?;
+    let mut address: libc::sockaddr_in = std::mem::zeroed();
+    address.sin_family = libc::AF_INET as libc::sa_family_t;
+    address.sin_port = PROXY_PORT.to_be();
+    address.sin_addr.s_addr = u32::from_ne_bytes([127, 0, 0, 1]);
+    let result = (|| {
+        check(libc::bind(
+            fd,
+            &address as *const libc::sockaddr_in as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+        ))?;
+        check(libc::listen(fd, 128))?;
+        send_fd(channel, fd)
+    })();
+    libc::close(fd);
+    result
+}
+
+unsafe fn send_fd(channel: RawFd, fd: RawFd) -> std::io::Result<()> {
+    let mut control = ControlBuffer([0; 64]);
+    let mut byte = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr() as *mut libc::c_void,
+        iov_len: 1,
+    };
+    let mut message: libc::msghdr = std::mem::zeroed();
+    message.msg_iov = &mut iov;
+    message.msg_iovlen = 1;
+    message.msg_control = control.0.as_mut_ptr() as *mut libc::c_void;
+    message.msg_controllen = libc::CMSG_SPACE(std::mem::size_of::<RawFd>() as u32) as _;
+    let header = libc::CMSG_FIRSTHDR(&message);
+    (*header).cmsg_level = libc::SOL_SOCKET;
+    (*header).cmsg_type = libc::SCM_RIGHTS;
+    (*header).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<RawFd>() as u32) as _;
+    std::ptr::write_unaligned(libc::CMSG_DATA(header) as *mut RawFd, fd);
+    check(libc::sendmsg(channel, &message, 0) as libc::c_int).map(|_| ())
+}
+
+/// Bring up the loopback interface of a new network namespace. Commands still run if this
+/// fails, without networking.
+///
+/// # Safety
+/// Only makes async-signal-safe calls, so it may run between `fork` and `exec`.
+pub(crate) unsafe fn loopback_up() {
+    let fd = libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0);
+    if fd < 0 {
+        return;
+    }
+    let mut request: libc::ifreq = std::mem::zeroed();
+    request.ifr_name[0] = b'l' as libc::c_char;
+    request.ifr_name[1] = b'o' as libc::c_char;
+    if libc::ioctl(fd, libc::SIOCGIFFLAGS as _, &mut request) == 0 {
+        request.ifr_ifru.ifru_flags |= libc::IFF_UP as libc::c_short;
+        libc::ioctl(fd, libc::SIOCSIFFLAGS as _, &request);
+    }
+    libc::close(fd);
+}
+
+unsafe fn write_file(path: &std::ffi::CStr, data: &[u8]) -> std::io::Result<()> {
+    let fd = check(libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC))?;
+    let written = libc::write(fd, data.as_ptr() as *const libc::c_void, data.len());
+    libc::close(fd);
+    if written != data.len() as isize {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn check(result: libc::c_int) -> std::io::Result<libc::c_int> {
+    if result < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
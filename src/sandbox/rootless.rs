@@ -0,0 +1,616 @@
+//! Sandbox that runs commands in unprivileged Linux namespaces, without a daemon or root.
+//!
+//! Each node gets the private filesystem of a [`SnapshotSandbox`], built from the layer diffs
+//! of its inputs, and its changes are recorded the same way. Commands run as root of a new
+//! user namespace, mapped to the invoking user, in their own mount, PID, UTS, IPC and network
+//! namespaces, after `pivot_root` into that filesystem: host paths and processes are out of
+//! reach. By default only loopback networking is available; see [`NetworkPolicy`] for the
+//! alternatives. Base images are not unpacked, so the host's `/bin`, `/usr` and library
+//! directories are mounted read-only, next to a fresh `/proc` and a minimal `/dev`. Where
+//! the node's filesystem has one of those directories of its own, the host entries it lacks
+//! are mounted inside it.
+
+use crate::docker::parser::default_shell;
+use crate::graph::{Node, NodeKind};
+use crate::hasher::IgnoreRules;
+use crate::sandbox::limits::Limiter;
+use crate::sandbox::mounts::{default_cache_mount_dir, MountSet};
+use crate::sandbox::network::{listen_for_proxy, loopback_up, Network};
+use crate::sandbox::snapshot::SnapshotSandbox;
+use crate::sandbox::spec::build_spec;
+use crate::sandbox::{
+    command_argv, ExecResult, NetworkPolicy, ResourceLimits, Sandbox, SandboxEnv,
+};
+use crate::secrets::SecretProvider;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use oci_spec::runtime::Spec;
+use std::ffi::{CStr, CString};
+use std::os::fd::RawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+
+const NAMESPACES: libc::c_int = libc::CLONE_NEWUSER
+    | libc::CLONE_NEWNS
+    | libc::CLONE_NEWPID
+    | libc::CLONE_NEWUTS
+    | libc::CLONE_NEWIPC
+    | libc::CLONE_NEWNET;
+
+/// Host directories that provide the shell and tools commands run with
+const HOST_TOOLCHAIN: &[&str] = &["bin", "sbin", "usr", "lib", "lib32", "lib64"];
+/// Host files a command sharing the host's network resolves names with
+const HOST_RESOLVER: &[&str] = &["etc/resolv.conf", "etc/hosts"];
+/// Device nodes bound from the host into the sandbox's `/dev`
+const DEVICES: &[&str] = &["null", "zero", "full", "random", "urandom", "tty"];
+const HOSTNAME: &str = "memobuild";
+/// `PATH` of commands whose node does not set one, as in Docker
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+pub struct RootlessSandbox {
+    /// Prepares the node filesystems and records their changes
+    filesystem: SnapshotSandbox,
+    /// Source of `RUN --mount=type=secret` values
+    secrets: Option<Arc<dyn SecretProvider>>,
+    /// Root of the persistent `type=cache` mounts; defaults to the MemoBuild cache directory
+    cache_mount_dir: Option<PathBuf>,
+    /// Limits for nodes that do not set their own
+    limits: ResourceLimits,
+    /// Network policy for nodes that do not set their own
+    network: NetworkPolicy,
+}
+
+impl RootlessSandbox {
+    pub fn new(context_dir: PathBuf, work_dir: PathBuf) -> Self {
+        Self {
+            filesystem: SnapshotSandbox::new(context_dir, work_dir),
+            secrets: None,
+            cache_mount_dir: None,
+            limits: ResourceLimits::default(),
+            network: NetworkPolicy::None,
+        }
+    }
+
+    /// Rules deciding which context files COPY and ADD place in their layers.
+    pub fn with_ignore_rules(mut self, ignore: IgnoreRules) -> Self {
+        self.filesystem = self.filesystem.with_ignore_rules(ignore);
+        self
+    }
+
+    pub fn with_secrets(mut self, secrets: Arc<dyn SecretProvider>) -> Self {
+        self.filesystem = self.filesystem.with_secrets(secrets.clone());
+        self.secrets = Some(secrets);
+        self
+    }
+
+    pub fn with_cache_mount_dir(mut self, dir: PathBuf) -> Self {
+        self.filesystem = self.filesystem.with_cache_mount_dir(dir.clone());
+        self.cache_mount_dir = Some(dir);
+        self
+    }
+
+    /// Limits applied to every command, where the node does not set its own.
+    pub fn with_limits(mut self, limits: ResourceLimits) -> Self {
+        self.filesystem = self.filesystem.with_limits(limits.clone());
+        self.limits = limits;
+        self
+    }
+
+    /// Network policy of every command, where the node does not set its own. Defaults to
+    /// `none`: loopback only.
+    pub fn with_network(mut self, policy: NetworkPolicy) -> Self {
+        self.filesystem = self.filesystem.with_network(policy.clone());
+        self.network = policy;
+        self
+    }
+
+    /// Where the diff of the node with cache key `hash` is kept
+    pub fn layer_path(&self, hash: &str) -> PathBuf {
+        self.filesystem.layer_path(hash)
+    }
+
+    /// Whether the kernel lets this process create the namespaces the sandbox runs in.
+    /// Unprivileged user namespaces are disabled on some distributions.
+    pub fn is_supported() -> bool {
+        let mut command = Command::new("/bin/sh");
+        command
+            .args(["-c", ":"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        // SAFETY: unshare is async-signal-safe and nothing is allocated
+        unsafe {
+            command.pre_exec(|| check(libc::unshare(NAMESPACES)).map(|_| ()));
+        }
+        command.status().is_ok_and(|status| status.success())
+    }
+
+    /// Run the process of `spec` in new namespaces with the spec's root as `/`.
+    async fn run(&self, spec: &Spec, node: &Node) -> Result<ExecResult> {
+        let process = spec
+            .process()
+            .as_ref()
+            .context("Runtime spec has no process")?;
+        let rootfs = spec
+            .root()
+            .as_ref()
+            .context("Runtime spec has no root")?
+            .path()
+            .clone();
+        let args = process.args().clone().unwrap_or_default();
+        if args.is_empty() {
+            anyhow::bail!("{} has no command to run", node.name);
+        }
+
+        let mounts = match &node.kind {
+            NodeKind::Run { mounts } if !mounts.is_empty() => {
+                let cache_root = match &self.cache_mount_dir {
+                    Some(dir) => dir.clone(),
+                    None => default_cache_mount_dir()?,
+                };
+                MountSet::prepare(mounts, Some(&rootfs), &cache_root, self.secrets.as_deref())
+                    .await?
+            }
+            _ => MountSet::default(),
+        };
+        let network = Network::new(node.metadata.network.as_ref().unwrap_or(&self.network))?;
+        // Dropped before the mounts, whose links some of them replace
+        let mut mountpoints = Mountpoints::default();
+        let mut setup = Setup::new(&rootfs, process.cwd(), &network)?;
+
+        let mut ssh_socket = None;
+        for link in mounts.links() {
+            // The mount's symlink to a host path would dangle after pivot_root: bind the path
+            let source = std::fs::read_link(link)?;
+            std::fs::remove_file(link)?;
+            mountpoints.create(link, source.is_dir())?;
+            setup.bind(&source, link, false)?;
+            if mounts.env.get("SSH_AUTH_SOCK").map(PathBuf::from) == Some(source) {
+                ssh_socket = Some(Path::new("/").join(link.strip_prefix(&rootfs)?));
+            }
+        }
+        for dir in HOST_TOOLCHAIN {
+            let host = Path::new("/").join(dir);
+            if host.exists() {
+                bind_host_dir(&host, &rootfs.join(dir), &mut mountpoints, &mut setup)?;
+            }
+        }
+        if !network.is_isolated() {
+            for file in HOST_RESOLVER {
+                let host = Path::new("/").join(file);
+                let path = rootfs.join(file);
+                if host.exists() && path.symlink_metadata().is_err() {
+                    if let Some(dir) = path.parent().filter(|dir| !dir.exists()) {
+                        mountpoints.create(dir, true)?;
+                    }
+                    mountpoints.create(&path, false)?;
+                    setup.bind(&host, &path, true)?;
+                }
+            }
+        }
+        for dir in ["proc", "dev"] {
+            let path = rootfs.join(dir);
+            match path.symlink_metadata() {
+                Ok(meta) if meta.is_dir() => {}
+                Ok(_) => anyhow::bail!(
+                    "/{} in the filesystem of {} is not a directory",
+                    dir,
+                    node.name
+                ),
+                Err(_) => mountpoints.create(&path, true)?,
+            }
+        }
+        std::fs::create_dir_all(rootfs.join(process.cwd().strip_prefix("/")?))?;
+
+        let mut command = Command::new(&args[0]);
+        command
+            .args(&args[1..])
+            .env_clear()
+            .env("PATH", DEFAULT_PATH);
+        for var in process.env().iter().flatten() {
+            if let Some((key, value)) = var.split_once('=') {
+                command.env(key, value);
+            }
+        }
+        command.envs(&mounts.env).envs(network.env());
+        if let Some(socket) = ssh_socket {
+            command.env("SSH_AUTH_SOCK", socket);
+        }
+        // The limiter joins the cgroup before the namespaces are entered
+        let limiter = Limiter::new(&node.metadata.limits.or(&self.limits))?;
+        limiter.apply(&mut command)?;
+        // SAFETY: Setup::enter only makes async-signal-safe calls and does not allocate
+        unsafe {
+            command.pre_exec(move || setup.enter());
+        }
+        let proxy = network.start_proxy()?;
+        let limited = limiter
+            .output(command)
+            .await
+            .with_context(|| format!("Failed to run {} in a rootless sandbox", node.name))?;
+        let log = match proxy {
+            Some(proxy) => proxy.finish().await,
+            None => Default::default(),
+        };
+        drop(mountpoints);
+
+        let exit_code = limited.output.status.code().unwrap_or(1);
+        Ok(ExecResult {
+            exit_code,
+            stdout: mounts.redact(limited.output.stdout),
+            stderr: mounts.redact(limited.output.stderr),
+            diff: None,
+            failure: limited.failure.or_else(|| log.failure(exit_code)),
+            fetched: log.fetched,
+        })
+    }
+}
+
+#[async_trait]
+impl Sandbox for RootlessSandbox {
+    async fn prepare(&self, node: &Node) -> Result<SandboxEnv> {
+        self.filesystem.prepare(node).await
+    }
+
+    async fn execute(&self, env: &SandboxEnv, node: &Node) -> Result<ExecResult> {
+        let Some(argv) = command_argv(node, &default_shell()) else {
+            // COPY and ADD layers and metadata-only nodes need no process
+            return self.filesystem.execute(env, node).await;
+        };
+        let spec = build_spec(
+            &argv,
+            &env.env_vars,
+            &node.metadata.workdir,
+            &env.workspace_dir,
+        );
+        self.filesystem
+            .record(env, node, self.run(&spec, node))
+            .await
+    }
+
+    async fn cleanup(&self, env: &SandboxEnv) -> Result<()> {
+        self.filesystem.cleanup(env).await
+    }
+
+    fn captures_filesystem(&self) -> bool {
+        true
+    }
+
+    async fn restore(&self, node: &Node, diff: &[u8]) -> Result<()> {
+        self.filesystem.restore(node, diff).await
+    }
+}
+
+/// Bind the host directory `host` read-only at `path`. Where the node's filesystem already
+/// has a directory there, e.g. `/usr` after `COPY tool /usr/local/bin/`, the host entries it
+/// lacks are bound inside it instead, so that its own files and the host's tools are both
+/// visible. A file or symlink of the node's own hides the host directory.
+fn bind_host_dir(
+    host: &Path,
+    path: &Path,
+    mountpoints: &mut Mountpoints,
+    setup: &mut Setup,
+) -> Result<()> {
+    match path.symlink_metadata() {
+        Err(_) => {
+            mountpoints.create(path, true)?;
+            setup.bind(host, path, true)
+        }
+        Ok(meta) if meta.is_dir() => {
+            for entry in std::fs::read_dir(host)? {
+                let entry = entry?;
+                let host_child = entry.path();
+                let child = path.join(entry.file_name());
+                // Dangling host symlinks have nothing to bind
+                let Ok(meta) = std::fs::metadata(&host_child) else {
+                    continue;
+                };
+                if meta.is_dir() {
+                    bind_host_dir(&host_child, &child, mountpoints, setup)?;
+                } else if child.symlink_metadata().is_err() {
+                    mountpoints.create(&child, false)?;
+                    setup.bind(&host_child, &child, true)?;
+                }
+            }
+            Ok(())
+        }
+        Ok(_) => Ok(()),
+    }
+}
+
+/// Directories and files created in a node's filesystem to mount on. They are removed
+/// again when dropped, so that they are not recorded as changes of the node.
+#[derive(Default)]
+struct Mountpoints(Vec<PathBuf>);
+
+impl Mountpoints {
+    fn create(&mut self, path: &Path, dir: bool) -> Result<()> {
+        if dir {
+            std::fs::create_dir(path)?;
+        } else {
+            std::fs::File::create(path)?;
+        }
+        self.0.push(path.to_path_buf());
+        Ok(())
+    }
+}
+
+impl Drop for Mountpoints {
+    fn drop(&mut self) {
+        for path in self.0.iter().rev() {
+            if path.is_dir() {
+                let _ = std::fs::remove_dir(path);
+            } else {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+}
+
+/// A bind mount of a host path into the node's filesystem
+struct Bind {
+    source: CString,
+    target: CString,
+    /// Flags for a read-only remount, or None to keep the mount writable
+    read_only: Option<libc::c_ulong>,
+}
+
+/// Everything the child process needs to enter its namespaces, prepared in advance: after
+/// `fork` only async-signal-safe calls are allowed, so nothing may be allocated.
+struct Setup {
+    /// Namespaces to create: all of [`NAMESPACES`], less the network one for `host`
+    namespaces: libc::c_int,
+    /// Where to send the listener of the allowlist proxy
+    proxy_channel: Option<RawFd>,
+    rootfs: CString,
+    cwd: CString,
+    binds: Vec<Bind>,
+    proc_dir: CString,
+    dev_dir: CString,
+    /// Host device and its mount point below `dev_dir`
+    devices: Vec<(CString, CString)>,
+    /// Symlinks below `dev_dir` and their targets
+    dev_links: Vec<(CString, CString)>,
+    uid_map: String,
+    gid_map: String,
+}
+
+impl Setup {
+    fn new(rootfs: &Path, cwd: &Path, network: &Network) -> Result<Self> {
+        let dev = rootfs.join("dev");
+        let devices = DEVICES
+            .iter()
+            .map(|name| Path::new("/dev").join(name))
+            .filter(|host| host.exists())
+            .map(|host| {
+                Ok((
+                    c_path(&host)?,
+                    c_path(&dev.join(host.file_name().unwrap()))?,
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let dev_links = [
+            ("fd", "/proc/self/fd"),
+            ("stdin", "/proc/self/fd/0"),
+            ("stdout", "/proc/self/fd/1"),
+            ("stderr", "/proc/self/fd/2"),
+        ]
+        .iter()
+        .map(|(name, target)| Ok((c_path(&dev.join(name))?, c_path(Path::new(target))?)))
+        .collect::<Result<Vec<_>>>()?;
+
+        // SAFETY: geteuid and getegid cannot fail
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        let namespaces = match network.is_isolated() {
+            true => NAMESPACES,
+            false => NAMESPACES & !libc::CLONE_NEWNET,
+        };
+        Ok(Setup {
+            namespaces,
+            proxy_channel: network.proxy_channel(),
+            rootfs: c_path(rootfs)?,
+            cwd: c_path(cwd)?,
+            binds: Vec::new(),
+            proc_dir: c_path(&rootfs.join("proc"))?,
+            dev_dir: c_path(&dev)?,
+            devices,
+            dev_links,
+            uid_map: format!("0 {} 1", uid),
+            gid_map: format!("0 {} 1", gid),
+        })
+    }
+
+    fn bind(&mut self, source: &Path, target: &Path, read_only: bool) -> Result<()> {
+        let source = c_path(source)?;
+        let read_only = match read_only {
+            true => {
+                Some(locked_flags(&source)? | libc::MS_REMOUNT | libc::MS_BIND | libc::MS_RDONLY)
+            }
+            false => None,
+        };
+        self.binds.push(Bind {
+            source,
+            target: c_path(target)?,
+            read_only,
+        });
+        Ok(())
+    }
+
+    /// Runs in the forked child before `exec`.
+    fn enter(&self) -> std::io::Result<()> {
+        // SAFETY: only async-signal-safe calls on data prepared before the fork
+        unsafe {
+            check(libc::unshare(self.namespaces))?;
+            write_file(c"/proc/self/setgroups", b"deny")?;
+            write_file(c"/proc/self/uid_map", self.uid_map.as_bytes())?;
+            write_file(c"/proc/self/gid_map", self.gid_map.as_bytes())?;
+
+            // The new PID namespace only applies to children: fork its init process and
+            // pass on its exit status
+            let pid = check(libc::fork())?;
+            if pid > 0 {
+                wait_and_exit(pid);
+            }
+            libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL);
+
+            self.mount_filesystems()?;
+            check(libc::sethostname(
+                HOSTNAME.as_ptr() as *const libc::c_char,
+                HOSTNAME.len(),
+            ))?;
+            if self.namespaces & libc::CLONE_NEWNET != 0 {
+                loopback_up();
+            }
+            if let Some(channel) = self.proxy_channel {
+                listen_for_proxy(channel)?;
+            }
+
+            check(libc::chdir(self.rootfs.as_ptr()))?;
+            check(
+                libc::syscall(libc::SYS_pivot_root, c".".as_ptr(), c".".as_ptr()) as libc::c_int,
+            )?;
+            // The old root is stacked on the new one: detach it
+            check(libc::umount2(c".".as_ptr(), libc::MNT_DETACH))?;
+            check(libc::chdir(self.cwd.as_ptr()))?;
+        }
+        Ok(())
+    }
+
+    unsafe fn mount_filesystems(&self) -> std::io::Result<()> {
+        let none = std::ptr::null::<libc::c_char>();
+        // Keep every mount below out of the parent namespace
+        check(libc::mount(
+            none,
+            c"/".as_ptr(),
+            none,
+            libc::MS_REC | libc::MS_PRIVATE,
+            std::ptr::null(),
+        ))?;
+        // pivot_root needs the new root to be a mount point
+        mount_bind(&self.rootfs, &self.rootfs)?;
+        for bind in &self.binds {
+            mount_bind(&bind.source, &bind.target)?;
+            if let Some(flags) = bind.read_only {
+                check(libc::mount(
+                    none,
+                    bind.target.as_ptr(),
+                    none,
+                    flags,
+                    std::ptr::null(),
+                ))?;
+            }
+        }
+
+        let proc_flags = libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC;
+        if libc::mount(
+            c"proc".as_ptr(),
+            self.proc_dir.as_ptr(),
+            c"proc".as_ptr(),
+            proc_flags,
+            std::ptr::null(),
+        ) != 0
+        {
+            // A fresh proc is refused where the host's is partly masked, as in containers
+            mount_bind(c"/proc", &self.proc_dir)?;
+        }
+
+        check(libc::mount(
+            c"tmpfs".as_ptr(),
+            self.dev_dir.as_ptr(),
+            c"tmpfs".as_ptr(),
+            libc::MS_NOSUID | libc::MS_NOEXEC,
+            c"mode=755".as_ptr() as *const libc::c_void,
+        ))?;
+        for (host, target) in &self.devices {
+            let fd = check(libc::open(
+                target.as_ptr(),
+                libc::O_CREAT | libc::O_WRONLY | libc::O_CLOEXEC,
+                0o666,
+            ))?;
+            libc::close(fd);
+            mount_bind(host, target)?;
+        }
+        for (link, target) in &self.dev_links {
+            check(libc::symlink(target.as_ptr(), link.as_ptr()))?;
+        }
+        Ok(())
+    }
+}
+
+unsafe fn mount_bind(source: &CStr, target: &CStr) -> std::io::Result<()> {
+    check(libc::mount(
+        source.as_ptr(),
+        target.as_ptr(),
+        std::ptr::null(),
+        libc::MS_BIND | libc::MS_REC,
+        std::ptr::null(),
+    ))
+    .map(|_| ())
+}
+
+unsafe fn write_file(path: &CStr, data: &[u8]) -> std::io::Result<()> {
+    let fd = check(libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC))?;
+    let written = libc::write(fd, data.as_ptr() as *const libc::c_void, data.len());
+    libc::close(fd);
+    if written != data.len() as isize {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Wait for the namespace's init process and exit with its status, as a shell would.
+unsafe fn wait_and_exit(pid: libc::pid_t) -> ! {
+    // Command::spawn returns once every copy of its exec-error pipe is closed: drop ours so
+    // that it returns when the init process execs, not when it exits
+    libc::syscall(libc::SYS_close_range, 3, libc::c_uint::MAX, 0);
+    let mut status = 0;
+    while libc::waitpid(pid, &mut status, 0) < 0
+        && std::io::Error::last_os_error().raw_os_error() == Some(libc::EINTR)
+    {}
+    let code = if libc::WIFEXITED(status) {
+        libc::WEXITSTATUS(status)
+    } else if libc::WIFSIGNALED(status) {
+        128 + libc::WTERMSIG(status)
+    } else {
+        1
+    };
+    libc::_exit(code)
+}
+
+/// Flags of the mount holding `path` that a user namespace may not clear, and so have to be
+/// repeated when it is remounted read-only.
+fn locked_flags(path: &CStr) -> Result<libc::c_ulong> {
+    // SAFETY: statvfs only writes to the zeroed struct
+    let stat = unsafe {
+        let mut stat: libc::statvfs = std::mem::zeroed();
+        check(libc::statvfs(path.as_ptr(), &mut stat))?;
+        stat
+    };
+    let flags = [
+        (libc::ST_NOSUID, libc::MS_NOSUID),
+        (libc::ST_NODEV, libc::MS_NODEV),
+        (libc::ST_NOEXEC, libc::MS_NOEXEC),
+        (libc::ST_NOATIME, libc::MS_NOATIME),
+        (libc::ST_NODIRATIME, libc::MS_NODIRATIME),
+        (libc::ST_RELATIME, libc::MS_RELATIME),
+    ];
+    Ok(flags
+        .iter()
+        .filter(|(st, _)| stat.f_flag & st != 0)
+        .fold(0, |acc, (_, ms)| acc | ms))
+}
+
+fn c_path(path: &Path) -> Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .with_context(|| format!("Path {} contains a NUL byte", path.display()))
+}
+
+fn check<T: Default + PartialOrd>(ret: T) -> std::io::Result<T> {
+    if ret < T::default() {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
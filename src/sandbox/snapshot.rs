@@ -0,0 +1,570 @@
+//! Sandbox that runs each node in a private copy of its input filesystem and records what
+//! the node changed as a layer diff.
+//!
+//! The filesystem a node runs on is rebuilt in a fresh directory from the diffs of the nodes
+//! in its [`NodeMetadata::input_layers`](crate::graph::NodeMetadata::input_layers), starting
+//! from nothing: base images are not unpacked. Commands run on the host, starting in the
+//! node's `WORKDIR` below that directory, so the build context is never touched, but
+//! absolute paths still reach the host filesystem. Afterwards the directory is compared
+//! with its state before the command, and the added, modified and deleted paths become an
+//! OCI layer diff, deletions as `.wh.<name>` whiteouts. `COPY --from=<stage>` reads its
+//! sources from the source stage's filesystem, rebuilt the same way from
+//! [`NodeMetadata::source_layers`](crate::graph::NodeMetadata::source_layers). The diff is
+//! the node's cached artifact; a cache hit hands it back to [`Sandbox::restore`], so later
+//! nodes see the same files as after a run.
+
+use crate::export::files::{layer_entries, stage_layer_entries, LayerEntry};
+use crate::graph::{Node, NodeKind};
+use crate::hasher::stat_cache::FileStat;
+use crate::hasher::walker::{walk_entries, EntryKind};
+use crate::hasher::IgnoreRules;
+use crate::sandbox::local::LocalSandbox;
+use crate::sandbox::{ExecResult, NetworkPolicy, ResourceLimits, Sandbox, SandboxEnv};
+use crate::secrets::SecretProvider;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Name of the entry that hides everything below its directory in lower layers
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+const WHITEOUT_PREFIX: &str = ".wh.";
+
+pub struct SnapshotSandbox {
+    /// Holds the layer diffs, `layers/<cache key>.tar`, and the filesystems of running nodes
+    work_dir: PathBuf,
+    /// Build context that COPY and ADD read from
+    context_dir: PathBuf,
+    ignore: IgnoreRules,
+    /// Runs the commands, with the node's filesystem as workspace
+    runner: LocalSandbox,
+}
+
+impl SnapshotSandbox {
+    pub fn new(context_dir: PathBuf, work_dir: PathBuf) -> Self {
+        Self {
+            work_dir,
+            ignore: IgnoreRules::for_context(&context_dir, None),
+            runner: LocalSandbox::new(context_dir.clone()),
+            context_dir,
+        }
+    }
+
+    /// Rules deciding which context files COPY and ADD place in their layers.
+    pub fn with_ignore_rules(mut self, ignore: IgnoreRules) -> Self {
+        self.ignore = ignore;
+        self
+    }
+
+    pub fn with_secrets(mut self, secrets: Arc<dyn SecretProvider>) -> Self {
+        self.runner = self.runner.with_secrets(secrets);
+        self
+    }
+
+    pub fn with_cache_mount_dir(mut self, dir: PathBuf) -> Self {
+        self.runner = self.runner.with_cache_mount_dir(dir);
+        self
+    }
+
+    pub fn with_limits(mut self, limits: ResourceLimits) -> Self {
+        self.runner = self.runner.with_limits(limits);
+        self
+    }
+
+    pub fn with_network(mut self, policy: NetworkPolicy) -> Self {
+        self.runner = self.runner.with_network(policy);
+        self
+    }
+
+    /// Where the diff of the node with cache key `hash` is kept
+    pub fn layer_path(&self, hash: &str) -> PathBuf {
+        self.work_dir.join("layers").join(format!("{}.tar", hash))
+    }
+
+    fn store_layer(&self, hash: &str, diff: &[u8]) -> Result<()> {
+        let path = self.layer_path(hash);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        // Write then rename, so that a concurrent build never reads half a layer
+        let tmp = path.with_extension(format!("tmp.{}", std::process::id()));
+        std::fs::write(&tmp, diff).with_context(|| format!("Failed to write {}", tmp.display()))?;
+        std::fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    /// Run `command` on the filesystem prepared in `env` and store what it changed as the
+    /// node's layer diff. Sandboxes that run commands differently reuse this.
+    pub(crate) async fn record(
+        &self,
+        env: &SandboxEnv,
+        node: &Node,
+        command: impl Future<Output = Result<ExecResult>> + Send,
+    ) -> Result<ExecResult> {
+        let before = Snapshot::take(&env.workspace_dir)?;
+        let result = command.await?;
+        if result.exit_code != 0 {
+            return Ok(result);
+        }
+        let after = Snapshot::take(&env.workspace_dir)?;
+        let diff = before.diff(&after, &env.workspace_dir)?;
+        self.store_layer(&node.hash, &diff)?;
+        Ok(ExecResult {
+            diff: Some(diff),
+            ..result
+        })
+    }
+
+    fn rootfs_dir(&self) -> PathBuf {
+        self.work_dir.join("rootfs")
+    }
+
+    /// Fresh, empty directory below [`Self::rootfs_dir`] for a filesystem of `node`.
+    fn scratch_dir(&self, node: &Node) -> Result<PathBuf> {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let dir = self.rootfs_dir().join(format!(
+            "{}-{}-{}",
+            node.id,
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        if dir.exists() {
+            remove_tree(&dir)?;
+        }
+        std::fs::create_dir_all(&dir)?;
+        Ok(dir)
+    }
+
+    fn read_layers(&self, node: &Node, hashes: &[String]) -> Result<Vec<Vec<u8>>> {
+        hashes
+            .iter()
+            .map(|hash| {
+                std::fs::read(self.layer_path(hash)).with_context(|| {
+                    format!(
+                        "Filesystem changes of {} are not available to {}",
+                        &hash[..hash.len().min(12)],
+                        node.name
+                    )
+                })
+            })
+            .collect()
+    }
+
+    /// Layer of a `COPY --from=<stage>` node: the sources, read from the source stage's
+    /// filesystem rebuilt from its layers.
+    fn stage_copy_layer(&self, node: &Node, from: &str) -> Result<Vec<u8>> {
+        let layers = node.metadata.source_layers.as_ref().with_context(|| {
+            format!(
+                "COPY --from={} needs the filesystem of that image, which this sandbox does not unpack",
+                from
+            )
+        })?;
+        let stage_root = self.scratch_dir(node)?;
+        let entries = apply_layers(&stage_root, &self.read_layers(node, layers)?)
+            .and_then(|()| stage_layer_entries(node, &stage_root));
+        remove_tree(&stage_root)?;
+        entries_layer(entries?)
+    }
+}
+
+#[async_trait]
+impl Sandbox for SnapshotSandbox {
+    async fn prepare(&self, node: &Node) -> Result<SandboxEnv> {
+        let rootfs = self.scratch_dir(node)?;
+        apply_layers(
+            &rootfs,
+            &self.read_layers(node, &node.metadata.input_layers)?,
+        )?;
+
+        Ok(SandboxEnv {
+            workspace_dir: rootfs,
+            env_vars: node.env.clone(),
+        })
+    }
+
+    async fn execute(&self, env: &SandboxEnv, node: &Node) -> Result<ExecResult> {
+        // COPY and ADD layers hold exactly the files they place in the image
+        if matches!(node.kind, NodeKind::Copy { .. } | NodeKind::Add { .. }) {
+            let diff = match &node.kind {
+                NodeKind::Copy {
+                    from: Some(from), ..
+                } => self.stage_copy_layer(node, from)?,
+                _ => entries_layer(layer_entries(node, &self.context_dir, &self.ignore)?)?,
+            };
+            self.store_layer(&node.hash, &diff)?;
+            return Ok(ExecResult {
+                exit_code: 0,
+                stdout: Vec::new(),
+                stderr: Vec::new(),
+                diff: Some(diff),
+                failure: None,
+                fetched: Vec::new(),
+            });
+        }
+
+        // Commands start in the node's WORKDIR, which is created first, as runtimes do
+        let workdir = entry_path(Path::new(node.metadata.workdir.trim_start_matches('/')))?;
+        ensure_no_symlinks(&env.workspace_dir, &workdir)?;
+        let workdir = env.workspace_dir.join(workdir);
+        let run_env = SandboxEnv {
+            workspace_dir: workdir.clone(),
+            env_vars: env.env_vars.clone(),
+        };
+        let command = async {
+            std::fs::create_dir_all(&workdir)
+                .with_context(|| format!("Failed to create WORKDIR {}", workdir.display()))?;
+            self.runner.execute(&run_env, node).await
+        };
+        self.record(env, node, command).await
+    }
+
+    async fn cleanup(&self, env: &SandboxEnv) -> Result<()> {
+        if env.workspace_dir.starts_with(self.rootfs_dir()) && env.workspace_dir.exists() {
+            remove_tree(&env.workspace_dir)?;
+        }
+        Ok(())
+    }
+
+    fn captures_filesystem(&self) -> bool {
+        true
+    }
+
+    async fn restore(&self, node: &Node, diff: &[u8]) -> Result<()> {
+        if !is_layer(diff) {
+            anyhow::bail!("Cached artifact of {} is not a layer diff", node.name);
+        }
+        self.store_layer(&node.hash, diff)
+    }
+}
+
+/// State of one path in a [`Snapshot`]
+#[derive(Debug, Clone, PartialEq)]
+struct PathState {
+    kind: EntryKind,
+    mode: u32,
+    stat: FileStat,
+    link_target: Option<PathBuf>,
+}
+
+impl PathState {
+    /// Whether going from `self` to `other` is a change a layer has to record. Directories
+    /// change with every entry added or removed below them; only their mode matters.
+    fn differs_from(&self, other: &PathState) -> bool {
+        if self.kind != other.kind || self.mode != other.mode {
+            return true;
+        }
+        match self.kind {
+            EntryKind::File => self.stat != other.stat,
+            EntryKind::Dir => false,
+            EntryKind::Symlink => self.link_target != other.link_target,
+        }
+    }
+}
+
+/// Every path below a directory with its `stat` data, to tell what a command changed.
+struct Snapshot {
+    paths: BTreeMap<PathBuf, PathState>,
+}
+
+impl Snapshot {
+    fn take(root: &Path) -> Result<Self> {
+        let mut paths = BTreeMap::new();
+        for entry in walk_entries(root, &IgnoreRules::empty()) {
+            let meta = match std::fs::symlink_metadata(&entry.path) {
+                Ok(meta) => meta,
+                // Removed while walking, e.g. by a background process of the command
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            let link_target = match entry.kind {
+                EntryKind::Symlink => Some(std::fs::read_link(&entry.path)?),
+                _ => None,
+            };
+            let rel = entry.path.strip_prefix(root)?.to_path_buf();
+            paths.insert(
+                rel,
+                PathState {
+                    kind: entry.kind,
+                    mode: mode_bits(&meta),
+                    stat: FileStat::from_metadata(&meta),
+                    link_target,
+                },
+            );
+        }
+        Ok(Snapshot { paths })
+    }
+
+    /// Layer tar turning the filesystem of `self` into that of `after`, read from `root`.
+    fn diff(&self, after: &Snapshot, root: &Path) -> Result<Vec<u8>> {
+        let mut tar = tar::Builder::new(Vec::new());
+
+        for rel in self.paths.keys() {
+            if after.paths.contains_key(rel) {
+                continue;
+            }
+            // One whiteout hides a whole removed tree, and a directory replaced by a file or
+            // symlink takes what was below it along when the new entry is applied
+            let parent = rel.parent().unwrap_or(Path::new(""));
+            let parent_kept = parent.as_os_str().is_empty()
+                || after.paths.get(parent).map(|s| s.kind) == Some(EntryKind::Dir);
+            if !parent_kept {
+                continue;
+            }
+            let name = rel.file_name().unwrap_or_default().to_string_lossy();
+            let whiteout = parent.join(format!("{}{}", WHITEOUT_PREFIX, name));
+            append(&mut tar, &whiteout, tar::EntryType::Regular, 0o644, &[])?;
+        }
+
+        for (rel, state) in &after.paths {
+            if self
+                .paths
+                .get(rel)
+                .is_some_and(|old| !old.differs_from(state))
+            {
+                continue;
+            }
+            let path = root.join(rel);
+            match state.kind {
+                EntryKind::Dir => {
+                    append(&mut tar, rel, tar::EntryType::Directory, state.mode, &[])?
+                }
+                EntryKind::File => {
+                    let data = std::fs::read(&path)
+                        .with_context(|| format!("Failed to read {}", path.display()))?;
+                    append(&mut tar, rel, tar::EntryType::Regular, state.mode, &data)?;
+                }
+                EntryKind::Symlink => {
+                    let mut header = layer_header(tar::EntryType::Symlink, 0o777, 0);
+                    let target = state.link_target.as_deref().unwrap_or(Path::new(""));
+                    tar.append_link(&mut header, rel, target)?;
+                }
+            }
+        }
+
+        Ok(tar.into_inner()?)
+    }
+}
+
+fn layer_header(kind: tar::EntryType, mode: u32, size: u64) -> tar::Header {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(kind);
+    header.set_mode(mode);
+    header.set_size(size);
+    header.set_mtime(0);
+    header.set_uid(0);
+    header.set_gid(0);
+    header
+}
+
+fn append(
+    tar: &mut tar::Builder<Vec<u8>>,
+    path: &Path,
+    kind: tar::EntryType,
+    mode: u32,
+    data: &[u8],
+) -> Result<()> {
+    let mut header = layer_header(kind, mode, data.len() as u64);
+    tar.append_data(&mut header, path, data)?;
+    Ok(())
+}
+
+/// Layer of a COPY or ADD node holding the files it places in the image.
+fn entries_layer(entries: Vec<LayerEntry>) -> Result<Vec<u8>> {
+    let mut tar = tar::Builder::new(Vec::new());
+    for entry in entries {
+        match (&entry.link_target, entry.is_dir) {
+            (Some(target), _) => {
+                let mut header = layer_header(tar::EntryType::Symlink, 0o777, 0);
+                tar.append_link(&mut header, &entry.path, target)?;
+            }
+            (None, true) => append(
+                &mut tar,
+                Path::new(&entry.path),
+                tar::EntryType::Directory,
+                entry.mode,
+                &[],
+            )?,
+            (None, false) => append(
+                &mut tar,
+                Path::new(&entry.path),
+                tar::EntryType::Regular,
+                entry.mode,
+                &entry.data,
+            )?,
+        }
+    }
+    Ok(tar.into_inner()?)
+}
+
+/// Whether `data` is a complete uncompressed tar, as [`SnapshotSandbox`] diffs are, rather
+/// than an artifact cached by another sandbox.
+pub fn is_layer(data: &[u8]) -> bool {
+    // A tar ends with two zero blocks, so even an empty layer is not empty
+    if data.len() < 1024 || !data.len().is_multiple_of(512) {
+        return false;
+    }
+    match tar::Archive::new(data).entries() {
+        Ok(entries) => entries.into_iter().all(|entry| entry.is_ok()),
+        Err(_) => false,
+    }
+}
+
+/// Apply layer diffs in order on top of `root`, honouring whiteouts.
+pub fn apply_layers(root: &Path, layers: &[Vec<u8>]) -> Result<()> {
+    // Directory modes are set last: a read-only directory must still receive its files
+    let mut dir_modes = Vec::new();
+    for layer in layers {
+        apply_layer(root, layer, &mut dir_modes)?;
+    }
+    for (dir, mode) in dir_modes.iter().rev() {
+        set_mode(dir, *mode)?;
+    }
+    Ok(())
+}
+
+fn apply_layer(root: &Path, layer: &[u8], dir_modes: &mut Vec<(PathBuf, u32)>) -> Result<()> {
+    let mut archive = tar::Archive::new(layer);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let rel = entry_path(&entry.path()?)?;
+        let Some(name) = rel.file_name().map(|n| n.to_string_lossy().to_string()) else {
+            continue;
+        };
+        let parent = rel.parent().unwrap_or(Path::new(""));
+        ensure_no_symlinks(root, parent)?;
+
+        if name == OPAQUE_WHITEOUT {
+            let dir = root.join(parent);
+            if dir.is_dir() {
+                for child in std::fs::read_dir(&dir)? {
+                    remove_path(&child?.path())?;
+                }
+            }
+            continue;
+        }
+        if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) {
+            remove_path(&root.join(parent).join(hidden))?;
+            continue;
+        }
+
+        let target = root.join(&rel);
+        std::fs::create_dir_all(root.join(parent))?;
+        let kind = entry.header().entry_type();
+        let mode = entry.header().mode()? & 0o7777;
+        if let Ok(existing) = std::fs::symlink_metadata(&target) {
+            if !(existing.is_dir() && kind.is_dir()) {
+                remove_path(&target)?;
+            }
+        }
+
+        if kind.is_dir() {
+            std::fs::create_dir_all(&target)?;
+            dir_modes.push((target, mode));
+        } else if kind.is_symlink() {
+            let link = entry
+                .link_name()?
+                .with_context(|| format!("Symlink {} has no target", rel.display()))?;
+            make_symlink(&link, &target)?;
+        } else if kind.is_file() {
+            let mut file = std::fs::File::create(&target)
+                .with_context(|| format!("Failed to create {}", target.display()))?;
+            std::io::copy(&mut entry, &mut file)?;
+            set_mode(&target, mode)?;
+        }
+        // Hard links, devices and fifos are not produced by diffs and are skipped
+    }
+    Ok(())
+}
+
+/// Path of a layer entry relative to the root it is applied to. Absolute paths and `..`
+/// would escape it.
+fn entry_path(path: &Path) -> Result<PathBuf> {
+    let mut rel = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => rel.push(part),
+            Component::CurDir => {}
+            _ => anyhow::bail!("Layer entry {} escapes the filesystem root", path.display()),
+        }
+    }
+    Ok(rel)
+}
+
+/// Refuse to write through a symlink placed by an earlier entry, which could point anywhere.
+fn ensure_no_symlinks(root: &Path, rel: &Path) -> Result<()> {
+    let mut path = root.to_path_buf();
+    for component in rel.components() {
+        path.push(component);
+        match std::fs::symlink_metadata(&path) {
+            Ok(meta) if meta.file_type().is_symlink() => {
+                anyhow::bail!("Layer entry below symlink {}", rel.display())
+            }
+            Ok(_) => {}
+            Err(_) => break,
+        }
+    }
+    Ok(())
+}
+
+/// Remove a file, symlink or directory tree; a missing path is not an error.
+fn remove_path(path: &Path) -> Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => remove_tree(path),
+        Ok(_) => Ok(std::fs::remove_file(path)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// `remove_dir_all` that also removes trees containing read-only directories.
+fn remove_tree(path: &Path) -> Result<()> {
+    for entry in walkdir::WalkDir::new(path).follow_links(false) {
+        let entry = entry?;
+        if entry.file_type().is_dir() {
+            let mode = mode_bits(&entry.metadata()?);
+            set_mode(entry.path(), mode | 0o700)?;
+        }
+    }
+    std::fs::remove_dir_all(path).with_context(|| format!("Failed to remove {}", path.display()))
+}
+
+#[cfg(unix)]
+fn mode_bits(meta: &std::fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    meta.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn mode_bits(meta: &std::fs::Metadata) -> u32 {
+    if meta.is_dir() {
+        0o755
+    } else {
+        0o644
+    }
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn make_symlink(target: &Path, link: &Path) -> Result<()> {
+    std::os::unix::fs::symlink(target, link)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn make_symlink(_target: &Path, link: &Path) -> Result<()> {
+    anyhow::bail!("Cannot create symlink {} on this platform", link.display())
+}
@@ -2,15 +2,22 @@ use oci_spec::runtime::*;
 use std::collections::HashMap;
 use std::path::Path;
 
-pub fn build_spec(cmd: &str, env: &HashMap<String, String>, rootfs: &Path) -> Spec {
+/// Runtime spec running `args` (already wrapped in the node's shell for shell form) in the
+/// directory `cwd` of the container, `/` when empty.
+pub fn build_spec(
+    args: &[String],
+    env: &HashMap<String, String>,
+    cwd: &str,
+    rootfs: &Path,
+) -> Spec {
     let process = ProcessBuilder::default()
-        .args(vec!["/bin/sh".into(), "-c".into(), cmd.into()])
+        .args(args.to_vec())
         .env(
             env.iter()
                 .map(|(k, v)| format!("{}={}", k, v))
                 .collect::<Vec<_>>(),
         )
-        .cwd("/workspace")
+        .cwd(if cwd.is_empty() { "/" } else { cwd })
         .terminal(false)
         .build()
         .unwrap();
@@ -69,3 +76,47 @@ pub fn build_spec(cmd: &str, env: &HashMap<String, String>, rootfs: &Path) -> Sp
         .build()
         .unwrap()
 }
+
+/// Enforce `limits` through the runtime's cgroup. Timeouts are up to whoever waits for the
+/// process.
+pub fn apply_limits(spec: &mut Spec, limits: &crate::sandbox::ResourceLimits) {
+    let mut resources = LinuxResources::default();
+    if let Some(mb) = limits.memory_mb {
+        let memory = LinuxMemoryBuilder::default()
+            .limit((mb << 20) as i64)
+            .build()
+            .unwrap();
+        resources.set_memory(Some(memory));
+    }
+    if limits.cpus.is_some() || limits.cpu_shares.is_some() {
+        let mut cpu = LinuxCpu::default();
+        cpu.set_shares(limits.cpu_shares);
+        if let Some(cpus) = limits.cpus {
+            cpu.set_quota(Some((cpus * 100_000.0) as i64));
+            cpu.set_period(Some(100_000));
+        }
+        resources.set_cpu(Some(cpu));
+    }
+    if let Some(pids) = limits.pids {
+        let mut limit = LinuxPids::default();
+        limit.set_limit(pids as i64);
+        resources.set_pids(Some(limit));
+    }
+    if let Some(linux) = spec.linux_mut() {
+        linux.set_resources(Some(resources));
+    }
+}
+
+/// Give the process a network namespace of its own, with only loopback.
+pub fn isolate_network(spec: &mut Spec) {
+    let namespace = LinuxNamespaceBuilder::default()
+        .typ(LinuxNamespaceType::Network)
+        .build()
+        .unwrap();
+    if let Some(linux) = spec.linux_mut() {
+        linux
+            .namespaces_mut()
+            .get_or_insert_with(Vec::new)
+            .push(namespace);
+    }
+}
@@ -323,41 +323,67 @@ impl MetadataStore {
         Ok(hashes)
     }
 
-    pub fn record_build(&self, dirty: u32, cached: u32, duration_ms: u64) -> Result<()> {
+    pub fn record_build(
+        &self,
+        dirty: u32,
+        cached: u32,
+        duration_ms: u64,
+        analysis: Option<&crate::analysis::BuildAnalysis>,
+    ) -> Result<()> {
         let conn = self.conn.lock().unwrap();
+        Self::ensure_analytics_table(&conn)?;
+
+        let analysis = analysis.map(serde_json::to_string).transpose()?;
+        conn.execute(
+            "INSERT INTO build_analytics (dirty_nodes, cached_nodes, duration_ms, critical_path) VALUES (?1, ?2, ?3, ?4)",
+            params![dirty, cached, duration_ms, analysis],
+        )?;
+
+        Ok(())
+    }
+
+    fn ensure_analytics_table(conn: &Connection) -> Result<()> {
         conn.execute(
             "CREATE TABLE IF NOT EXISTS build_analytics (
                 id INTEGER PRIMARY KEY AUTOINCREMENT,
                 timestamp TEXT DEFAULT CURRENT_TIMESTAMP,
                 dirty_nodes INTEGER,
                 cached_nodes INTEGER,
-                duration_ms INTEGER
+                duration_ms INTEGER,
+                critical_path TEXT
             )",
             [],
         )?;
-
-        conn.execute(
-            "INSERT INTO build_analytics (dirty_nodes, cached_nodes, duration_ms) VALUES (?1, ?2, ?3)",
-            params![dirty, cached, duration_ms],
-        )?;
-
+        // Databases created before the critical-path analysis lack its column
+        let has_column = conn
+            .prepare("SELECT critical_path FROM build_analytics LIMIT 0")
+            .is_ok();
+        if !has_column {
+            conn.execute(
+                "ALTER TABLE build_analytics ADD COLUMN critical_path TEXT",
+                [],
+            )?;
+        }
         Ok(())
     }
 
     pub fn get_analytics(&self, limit: u32) -> Result<Vec<BuildRecord>> {
         let conn = self.conn.lock().unwrap();
+        Self::ensure_analytics_table(&conn)?;
         let mut stmt = conn.prepare(
-            "SELECT id, timestamp, dirty_nodes, cached_nodes, duration_ms 
+            "SELECT id, timestamp, dirty_nodes, cached_nodes, duration_ms, critical_path 
              FROM build_analytics 
              ORDER BY timestamp DESC LIMIT ?1",
         )?;
         let rows = stmt.query_map(params![limit], |row| {
+            let analysis: Option<String> = row.get(5)?;
             Ok(BuildRecord {
                 id: row.get(0)?,
                 timestamp: row.get(1)?,
                 dirty_nodes: row.get(2)?,
                 cached_nodes: row.get(3)?,
                 duration_ms: row.get(4)?,
+                critical_path: analysis.and_then(|json| serde_json::from_str(&json).ok()),
             })
         })?;
 
@@ -423,6 +449,9 @@ pub struct BuildRecord {
     pub dirty_nodes: u32,
     pub cached_nodes: u32,
     pub duration_ms: u64,
+    /// Critical-path analysis of the build's graph, when the client reported it
+    #[serde(default)]
+    pub critical_path: Option<crate::analysis::BuildAnalysis>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -458,4 +487,31 @@ mod tests {
         let updated_entry = store.get(hash).unwrap().unwrap();
         assert_eq!(updated_entry.hit_count, 1);
     }
+
+    #[test]
+    fn test_build_analytics_keep_critical_path() {
+        let db_file = NamedTempFile::new().unwrap();
+        let store = MetadataStore::new(db_file.path()).unwrap();
+        assert!(store.get_analytics(10).unwrap().is_empty());
+
+        let mut graph = crate::graph::BuildGraph::new();
+        graph.nodes = crate::docker::dag::build_graph_from_instructions(
+            crate::docker::parser::parse_dockerfile("FROM alpine\nRUN make"),
+            std::env::temp_dir(),
+        )
+        .nodes;
+        graph.nodes[1].metadata.execution_time_ms = Some(400);
+        let analysis = crate::analysis::BuildAnalysis::from_graph(&graph)
+            .unwrap()
+            .with_wall_time(500);
+
+        store.record_build(1, 1, 500, Some(&analysis)).unwrap();
+        store.record_build(0, 2, 20, None).unwrap();
+
+        let records = store.get_analytics(10).unwrap();
+        assert_eq!(records.len(), 2);
+        let analyzed = records.iter().find(|r| r.duration_ms == 500).unwrap();
+        assert_eq!(analyzed.critical_path.as_ref(), Some(&analysis));
+        assert!(records.iter().any(|r| r.critical_path.is_none()));
+    }
 }
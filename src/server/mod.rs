@@ -45,6 +45,9 @@ pub struct AnalyticsData {
     pub dirty: u32,
     pub cached: u32,
     pub duration_ms: u64,
+    /// Critical-path analysis of the build being reported, computed by the client
+    #[serde(default)]
+    pub analysis: Option<crate::analysis::BuildAnalysis>,
 }
 
 async fn add_api_version_header<B>(req: Request<B>, next: Next<B>) -> Response {
@@ -157,9 +160,13 @@ async fn register_dag(
     State(state): State<Arc<AppState>>,
     Json(dag): Json<crate::graph::BuildGraph>,
 ) -> impl IntoResponse {
+    let validation = dag.validate();
+    if !validation.is_valid() {
+        return (StatusCode::UNPROCESSABLE_ENTITY, Json(validation)).into_response();
+    }
     let mut current_dag = state.current_dag.lock().unwrap();
     *current_dag = Some(dag);
-    StatusCode::OK
+    StatusCode::OK.into_response()
 }
 
 #[derive(Serialize)]
@@ -434,6 +441,8 @@ async fn dashboard() -> Html<String> {
                 document.getElementById('active-nodes').textContent = Math.max(0, active - 1);
             } else if (event.NodeFailed) {
                 nodesDS.update({ id: event.NodeFailed.node_id, color: { background: '#ef4444' } });
+            } else if (event.RebuildTriggered) {
+                document.getElementById('active-nodes').textContent = event.RebuildTriggered.dirty_nodes;
             }
         }
 
@@ -721,9 +730,12 @@ async fn report_analytics(
     State(state): State<Arc<AppState>>,
     axum::Json(data): axum::Json<AnalyticsData>,
 ) -> impl IntoResponse {
-    let result = state
-        .metadata
-        .record_build(data.dirty, data.cached, data.duration_ms);
+    let result = state.metadata.record_build(
+        data.dirty,
+        data.cached,
+        data.duration_ms,
+        data.analysis.as_ref(),
+    );
 
     // Send build notification if webhook is configured
     if let Some(webhook_url) = state.webhook_url.clone() {
@@ -74,7 +74,7 @@ impl ProvenanceGenerator {
     pub fn sign(&self, provenance: &Provenance) -> Result<Attestation> {
         let payload = serde_json::to_string(provenance)?;
         let signature = in_toto::sign_dsse(&payload)?;
-        
+
         Ok(Attestation {
             payload_type: "application/vnd.in-toto+json".to_string(),
             payload,
@@ -186,6 +186,20 @@ pub struct Provenance {
     subject: Vec<Product>,
 }
 
+impl Provenance {
+    /// Record something the build fetched, such as a URL downloaded during a step, as a
+    /// resolved dependency. `digest` is empty where the content could not be hashed.
+    pub fn add_resolved_dependency(&mut self, uri: &str, digest: &str) {
+        self.predicate
+            .build_definition
+            .resolved_dependencies
+            .push(Material {
+                uri: uri.to_string(),
+                digest: digest.to_string(),
+            });
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Predicate {
     #[serde(rename = "buildType")]
@@ -262,4 +276,4 @@ impl Default for InvocationParams {
             inputs: vec![],
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,325 @@
+//! Renderings of a [`BuildGraph`] for `memobuild graph`: Graphviz DOT, Mermaid and JSON.
+//!
+//! All formats go through [`GraphExport`], so a JSON export can be rendered again later
+//! (e.g. after a build, when durations are known) and diffed as plain text in CI.
+
+use crate::graph::{BuildGraph, Node, NodeKind};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+use std::str::FromStr;
+
+/// Longest instruction text shown in a node label.
+const MAX_LABEL_LEN: usize = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GraphFormat {
+    Text,
+    Dot,
+    Mermaid,
+    Json,
+}
+
+impl FromStr for GraphFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(GraphFormat::Text),
+            "dot" | "graphviz" => Ok(GraphFormat::Dot),
+            "mermaid" => Ok(GraphFormat::Mermaid),
+            "json" => Ok(GraphFormat::Json),
+            other => anyhow::bail!(
+                "Unsupported graph format '{}': expected text, dot, mermaid or json",
+                other
+            ),
+        }
+    }
+}
+
+/// Cache state of a node when the graph was exported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheStatus {
+    /// The node's artifact was found in the cache
+    Cached,
+    /// The node's inputs changed since the last build
+    Dirty,
+    /// Unchanged inputs, but no cached artifact
+    Miss,
+}
+
+impl CacheStatus {
+    pub fn of(node: &Node) -> Self {
+        if node.cache_hit {
+            CacheStatus::Cached
+        } else if node.dirty {
+            CacheStatus::Dirty
+        } else {
+            CacheStatus::Miss
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            CacheStatus::Cached => "cached",
+            CacheStatus::Dirty => "dirty",
+            CacheStatus::Miss => "miss",
+        }
+    }
+
+    /// Fill color used by the DOT and Mermaid renderings
+    fn color(self) -> &'static str {
+        match self {
+            CacheStatus::Cached => "#c8e6c9",
+            CacheStatus::Dirty => "#ffcdd2",
+            CacheStatus::Miss => "#fff9c4",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExportNode {
+    pub id: usize,
+    /// Instruction keyword, e.g. `RUN`
+    pub kind: String,
+    /// Instruction text, shortened to one line
+    pub label: String,
+    pub stage: usize,
+    pub deps: Vec<usize>,
+    pub cache: CacheStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub hash: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExportStage {
+    pub index: usize,
+    pub name: Option<String>,
+}
+
+/// Format-independent view of a build graph.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GraphExport {
+    pub stages: Vec<ExportStage>,
+    pub nodes: Vec<ExportNode>,
+}
+
+impl GraphExport {
+    pub fn from_graph(graph: &BuildGraph) -> Self {
+        let mut stages: Vec<ExportStage> = Vec::new();
+        for node in &graph.nodes {
+            if !stages.iter().any(|s| s.index == node.metadata.stage) {
+                stages.push(ExportStage {
+                    index: node.metadata.stage,
+                    name: node.metadata.stage_name.clone(),
+                });
+            }
+        }
+
+        let nodes = graph
+            .nodes
+            .iter()
+            .map(|node| ExportNode {
+                id: node.id,
+                kind: kind_name(&node.kind).to_string(),
+                label: label(node),
+                stage: node.metadata.stage,
+                deps: node.deps.clone(),
+                cache: CacheStatus::of(node),
+                duration_ms: node.metadata.execution_time_ms,
+                hash: node.hash.clone(),
+            })
+            .collect();
+
+        GraphExport { stages, nodes }
+    }
+
+    pub fn render(&self, format: GraphFormat) -> Result<String> {
+        Ok(match format {
+            GraphFormat::Text => self.to_text(),
+            GraphFormat::Dot => self.to_dot(),
+            GraphFormat::Mermaid => self.to_mermaid(),
+            GraphFormat::Json => serde_json::to_string_pretty(self)? + "\n",
+        })
+    }
+
+    /// Indented listing of each node and what it depends on.
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+        for stage in &self.stages {
+            let _ = writeln!(out, "{}", self.stage_title(stage));
+            for node in self.nodes.iter().filter(|n| n.stage == stage.index) {
+                let _ = writeln!(
+                    out,
+                    "  [{}] {} ({})",
+                    node.id,
+                    node.label,
+                    self.annotation(node)
+                );
+                if !node.deps.is_empty() {
+                    let deps: Vec<String> = node.deps.iter().map(|d| d.to_string()).collect();
+                    let _ = writeln!(out, "      └─ depends on: {}", deps.join(", "));
+                }
+            }
+        }
+        out
+    }
+
+    /// Graphviz DOT, one `cluster` subgraph per stage.
+    pub fn to_dot(&self) -> String {
+        let mut out = String::from("digraph memobuild {\n");
+        out.push_str("  rankdir=TB;\n");
+        out.push_str("  node [shape=box, style=\"rounded,filled\", fontname=\"monospace\"];\n");
+        for stage in &self.stages {
+            let _ = writeln!(out, "  subgraph cluster_stage_{} {{", stage.index);
+            let _ = writeln!(
+                out,
+                "    label=\"{}\";",
+                dot_escape(&self.stage_title(stage))
+            );
+            out.push_str("    style=dashed;\n");
+            for node in self.nodes.iter().filter(|n| n.stage == stage.index) {
+                let _ = writeln!(
+                    out,
+                    "    n{} [label=\"{}\\n{}\", fillcolor=\"{}\"];",
+                    node.id,
+                    dot_escape(&node.label),
+                    dot_escape(&self.annotation(node)),
+                    node.cache.color()
+                );
+            }
+            out.push_str("  }\n");
+        }
+        for node in &self.nodes {
+            for dep in &node.deps {
+                let _ = writeln!(out, "  n{} -> n{};", dep, node.id);
+            }
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    /// Mermaid flowchart, one `subgraph` per stage; renders in GitHub comments and docs.
+    pub fn to_mermaid(&self) -> String {
+        let mut out = String::from("flowchart TD\n");
+        for stage in &self.stages {
+            let _ = writeln!(
+                out,
+                "  subgraph stage{}[\"{}\"]",
+                stage.index,
+                mermaid_escape(&self.stage_title(stage))
+            );
+            for node in self.nodes.iter().filter(|n| n.stage == stage.index) {
+                let _ = writeln!(
+                    out,
+                    "    n{}[\"{}<br/>{}\"]:::{}",
+                    node.id,
+                    mermaid_escape(&node.label),
+                    mermaid_escape(&self.annotation(node)),
+                    node.cache.as_str()
+                );
+            }
+            out.push_str("  end\n");
+        }
+        for node in &self.nodes {
+            for dep in &node.deps {
+                let _ = writeln!(out, "  n{} --> n{}", dep, node.id);
+            }
+        }
+        for status in [CacheStatus::Cached, CacheStatus::Dirty, CacheStatus::Miss] {
+            let _ = writeln!(
+                out,
+                "  classDef {} fill:{}",
+                status.as_str(),
+                status.color()
+            );
+        }
+        out
+    }
+
+    fn stage_title(&self, stage: &ExportStage) -> String {
+        match &stage.name {
+            Some(name) => format!("stage {} ({})", stage.index, name),
+            None => format!("stage {}", stage.index),
+        }
+    }
+
+    /// Cache status and, once the node has run, its duration
+    fn annotation(&self, node: &ExportNode) -> String {
+        match node.duration_ms {
+            Some(ms) => format!("{}, {}", node.cache.as_str(), format_duration(ms)),
+            None => node.cache.as_str().to_string(),
+        }
+    }
+}
+
+/// Instruction keyword of a node kind.
+pub fn kind_name(kind: &NodeKind) -> &'static str {
+    match kind {
+        NodeKind::From => "FROM",
+        NodeKind::Run { .. } => "RUN",
+        NodeKind::Copy { .. } => "COPY",
+        NodeKind::Env => "ENV",
+        NodeKind::Arg => "ARG",
+        NodeKind::Workdir => "WORKDIR",
+        NodeKind::Cmd { .. } => "CMD",
+        NodeKind::Entrypoint { .. } => "ENTRYPOINT",
+        NodeKind::User { .. } => "USER",
+        NodeKind::Expose { .. } => "EXPOSE",
+        NodeKind::Label { .. } => "LABEL",
+        NodeKind::Volume { .. } => "VOLUME",
+        NodeKind::Healthcheck { .. } => "HEALTHCHECK",
+        NodeKind::Shell { .. } => "SHELL",
+        NodeKind::Stopsignal { .. } => "STOPSIGNAL",
+        NodeKind::Onbuild { .. } => "ONBUILD",
+        NodeKind::Add { .. } => "ADD",
+        NodeKind::Git { .. } => "GIT",
+        NodeKind::RunExtend { .. } => "RUN_EXTEND",
+        NodeKind::CopyExtend { .. } => "COPY_EXTEND",
+        NodeKind::CustomHook { .. } => "HOOK",
+        NodeKind::Other => "OTHER",
+    }
+}
+
+/// First line of the instruction, with its keyword, shortened to [`MAX_LABEL_LEN`].
+pub fn label(node: &Node) -> String {
+    let first = node.content.lines().next().unwrap_or("").trim();
+    let text = match node.kind {
+        // RUN nodes hold the bare command
+        NodeKind::Run { .. } | NodeKind::RunExtend { .. } => {
+            format!("{} {}", kind_name(&node.kind), first)
+        }
+        _ => first.to_string(),
+    };
+    if text.chars().count() > MAX_LABEL_LEN {
+        let short: String = text.chars().take(MAX_LABEL_LEN - 1).collect();
+        format!("{}…", short)
+    } else {
+        text
+    }
+}
+
+/// Human-readable duration: `850ms`, `12.3s`, `2m05s`.
+pub fn format_duration(ms: u64) -> String {
+    if ms >= 60_000 {
+        format!("{}m{:02}s", ms / 60_000, (ms % 60_000) / 1000)
+    } else if ms >= 1000 {
+        format!("{:.1}s", ms as f64 / 1000.0)
+    } else {
+        format!("{}ms", ms)
+    }
+}
+
+fn dot_escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Mermaid labels are quoted; quotes and HTML-like characters need entity codes.
+fn mermaid_escape(text: &str) -> String {
+    text.replace('"', "#quot;")
+        .replace('<', "#lt;")
+        .replace('>', "#gt;")
+}
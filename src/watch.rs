@@ -0,0 +1,460 @@
+//! `memobuild build --watch`: rebuild the context whenever its files change.
+//!
+//! [`ContextWatcher`] reports changed paths under the context (inotify on Linux, polling
+//! elsewhere), skipping those the `.dockerignore` excludes, and batches them until the
+//! context has been quiet for a debounce interval. The Dockerfile and ignore files are
+//! always reported, and a batch where one of them changed, or where inotify dropped
+//! events, asks for the graph to be loaded again. [`WatchSession`] keeps the parsed graph
+//! and the inputs of the last successful build in memory, so each rebuild only re-hashes
+//! the context and runs the nodes whose inputs changed, plus everything downstream of them.
+
+use crate::core::ContextState;
+use crate::core::{compute_composite_hashes, detect_changes_with, propagate_dirty};
+use crate::env::EnvFingerprint;
+use crate::graph::BuildGraph;
+use crate::hasher::{IgnoreRules, StatCache};
+use anyhow::Result;
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+/// Debounce interval used when none is given
+pub const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// Written by builds and servers run from the context; watching them would make every
+/// build trigger the next one.
+const ALWAYS_IGNORED: [&str; 4] = [
+    ".git",
+    ".memobuild-output",
+    ".memobuild-server",
+    ".memobuild-cluster",
+];
+
+enum Event {
+    Changed(PathBuf),
+    /// The kernel dropped events, so any file may have changed
+    Overflow,
+}
+
+/// Changes collected by [`ContextWatcher::next_batch`].
+#[derive(Debug, Clone, Default)]
+pub struct Changes {
+    /// Changed paths, relative to the context. The Dockerfile and its ignore files are
+    /// absolute when they live outside the context.
+    pub paths: BTreeSet<PathBuf>,
+    /// The Dockerfile or an ignore file changed, or events were lost, so the graph has to
+    /// be loaded again and the whole context checked
+    pub reload: bool,
+}
+
+/// Stream of paths that changed on disk.
+pub struct ContextWatcher {
+    rx: UnboundedReceiver<Event>,
+    root: PathBuf,
+    control: Vec<PathBuf>,
+}
+
+impl ContextWatcher {
+    /// Watch `context_dir` recursively, skipping the paths its ignore rules exclude, and the
+    /// Dockerfile and ignore files wherever they are. The rules are loaded again whenever an
+    /// ignore file changes.
+    pub fn new(context_dir: &Path, dockerfile: &Path) -> Result<Self> {
+        let filter = Filter::new(context_dir, dockerfile);
+        let root = filter.root.clone();
+        let control = filter.control.clone();
+
+        let (tx, rx) = unbounded_channel();
+        #[cfg(target_os = "linux")]
+        {
+            match inotify::spawn(filter, tx.clone()) {
+                Ok(()) => return Ok(Self { rx, root, control }),
+                Err((e, filter)) => {
+                    eprintln!("⚠️  inotify unavailable ({}), polling for changes", e);
+                    spawn_poller(filter, tx);
+                }
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        spawn_poller(filter, tx);
+        Ok(Self { rx, root, control })
+    }
+
+    /// Wait for a change, then collect changes until none arrives for `debounce`. Returns
+    /// `None` once the watcher has stopped.
+    pub async fn next_batch(&mut self, debounce: Duration) -> Option<Changes> {
+        let mut changes = Changes::default();
+        let mut event = self.rx.recv().await?;
+        loop {
+            match event {
+                Event::Changed(path) => {
+                    changes.reload |= self.control.contains(&self.root.join(&path));
+                    changes.paths.insert(path);
+                }
+                Event::Overflow => changes.reload = true,
+            }
+            event = match tokio::time::timeout(debounce, self.rx.recv()).await {
+                Ok(Some(event)) => event,
+                _ => return Some(changes),
+            };
+        }
+    }
+}
+
+/// Which paths the watcher reports: files of the context its ignore rules keep, plus the
+/// Dockerfile and ignore files, even when those are excluded or outside the context.
+struct Filter {
+    root: PathBuf,
+    dockerfile: PathBuf,
+    /// The Dockerfile, `<Dockerfile>.dockerignore` and the context's `.dockerignore`
+    control: Vec<PathBuf>,
+    ignore: IgnoreRules,
+}
+
+impl Filter {
+    fn new(context_dir: &Path, dockerfile: &Path) -> Self {
+        let root = canonical(context_dir);
+        let dockerfile = canonical(dockerfile);
+        let name = dockerfile
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+        let control = vec![
+            dockerfile.clone(),
+            dockerfile.with_file_name(format!("{}.dockerignore", name)),
+            root.join(".dockerignore"),
+        ];
+        let mut filter = Filter {
+            root,
+            dockerfile,
+            control,
+            ignore: IgnoreRules::empty(),
+        };
+        filter.reload();
+        filter
+    }
+
+    /// Read the ignore rules the build uses again.
+    fn reload(&mut self) {
+        let ignore_file = IgnoreRules::dockerfile_ignore_file(&self.dockerfile);
+        self.ignore = IgnoreRules::for_context(&self.root, ignore_file.as_deref());
+        self.ignore.add_patterns(&ALWAYS_IGNORED.map(String::from));
+    }
+
+    fn is_ignore_file(&self, path: &Path) -> bool {
+        self.control[1..].iter().any(|c| c == path)
+    }
+
+    /// Path to report for `path`, unless the ignore rules exclude it.
+    fn relevant(&self, path: &Path) -> Option<PathBuf> {
+        if self.control.iter().any(|c| c == path) {
+            return Some(match path.strip_prefix(&self.root) {
+                Ok(rel) => rel.to_path_buf(),
+                Err(_) => path.to_path_buf(),
+            });
+        }
+        let rel = path.strip_prefix(&self.root).ok()?;
+        if rel.as_os_str().is_empty() || self.ignore.is_ignored(rel) {
+            None
+        } else {
+            Some(rel.to_path_buf())
+        }
+    }
+
+    /// Whether files under the directory `path` may be relevant.
+    fn descend(&self, path: &Path) -> bool {
+        match path.strip_prefix(&self.root) {
+            Ok(rel) => rel.as_os_str().is_empty() || !self.ignore.skips_dir(rel),
+            Err(_) => false,
+        }
+    }
+
+    /// Directories under `dir` that may hold relevant files, `dir` included.
+    fn watched_dirs(&self, dir: &Path) -> Vec<PathBuf> {
+        walkdir::WalkDir::new(dir)
+            .follow_links(false)
+            .into_iter()
+            .filter_entry(|e| !e.file_type().is_dir() || self.descend(e.path()))
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_dir())
+            .map(|e| e.into_path())
+            .collect()
+    }
+
+    /// Every directory to watch: the context's relevant ones and those holding the
+    /// Dockerfile and ignore files.
+    fn all_dirs(&self) -> Vec<PathBuf> {
+        let mut dirs = self.watched_dirs(&self.root);
+        for parent in self.control.iter().filter_map(|c| c.parent()) {
+            if !dirs.iter().any(|d| d == parent) {
+                dirs.push(parent.to_path_buf());
+            }
+        }
+        dirs
+    }
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+#[cfg(target_os = "linux")]
+mod inotify {
+    use super::{Event, Filter};
+    use crate::hasher::IgnoreRules;
+    use std::collections::HashMap;
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::{Path, PathBuf};
+    use tokio::sync::mpsc::UnboundedSender;
+
+    const MASK: u32 = libc::IN_MODIFY
+        | libc::IN_ATTRIB
+        | libc::IN_CLOSE_WRITE
+        | libc::IN_CREATE
+        | libc::IN_DELETE
+        | libc::IN_MOVED_FROM
+        | libc::IN_MOVED_TO
+        | libc::IN_ONLYDIR;
+
+    const HEADER: usize = std::mem::size_of::<libc::inotify_event>();
+
+    /// Start a thread reading inotify events for every watched directory. On failure the
+    /// filter is handed back for the fallback.
+    pub(super) fn spawn(
+        filter: Filter,
+        tx: UnboundedSender<Event>,
+    ) -> Result<(), (std::io::Error, Filter)> {
+        // SAFETY: plain syscall; the descriptor is owned by the thread below
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err((std::io::Error::last_os_error(), filter));
+        }
+        let mut watches = HashMap::new();
+        for dir in filter.all_dirs() {
+            if let Err(e) = add_watch(fd, &dir, &mut watches) {
+                // SAFETY: fd was returned by inotify_init1 and is not used afterwards
+                unsafe { libc::close(fd) };
+                return Err((e, filter));
+            }
+        }
+
+        std::thread::spawn(move || {
+            let mut filter = filter;
+            read_events(fd, &mut filter, &mut watches, &tx);
+            // SAFETY: the thread owns fd
+            unsafe { libc::close(fd) };
+        });
+        Ok(())
+    }
+
+    fn add_watch(fd: i32, dir: &Path, watches: &mut HashMap<i32, PathBuf>) -> std::io::Result<()> {
+        let path = CString::new(dir.as_os_str().as_bytes())?;
+        // SAFETY: path is a valid NUL-terminated string for the duration of the call
+        let wd = unsafe { libc::inotify_add_watch(fd, path.as_ptr(), MASK) };
+        if wd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        watches.insert(wd, dir.to_path_buf());
+        Ok(())
+    }
+
+    fn read_events(
+        fd: i32,
+        filter: &mut Filter,
+        watches: &mut HashMap<i32, PathBuf>,
+        tx: &UnboundedSender<Event>,
+    ) {
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            // SAFETY: buf is valid for writes of buf.len() bytes
+            let n = unsafe { libc::read(fd, buf.as_mut_ptr().cast(), buf.len()) };
+            if n < 0 {
+                if std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return;
+            }
+
+            let mut offset = 0;
+            while offset + HEADER <= n as usize {
+                // SAFETY: the kernel writes whole events; the header may be unaligned in buf
+                let event: libc::inotify_event =
+                    unsafe { std::ptr::read_unaligned(buf[offset..].as_ptr().cast()) };
+                let name = &buf[offset + HEADER..offset + HEADER + event.len as usize];
+                offset += HEADER + event.len as usize;
+
+                // Events were dropped, directories created meanwhile included: watch
+                // whatever is new and let the receiver check everything
+                if event.mask & libc::IN_Q_OVERFLOW != 0 {
+                    filter.reload();
+                    for dir in filter.all_dirs() {
+                        let _ = add_watch(fd, &dir, watches);
+                    }
+                    if tx.send(Event::Overflow).is_err() {
+                        return;
+                    }
+                    continue;
+                }
+                if event.mask & libc::IN_IGNORED != 0 {
+                    watches.remove(&event.wd);
+                    continue;
+                }
+                let Some(dir) = watches.get(&event.wd).cloned() else {
+                    continue;
+                };
+                let name = name.split(|&b| b == 0).next().unwrap_or_default();
+                let path = dir.join(std::ffi::OsStr::from_bytes(name));
+
+                // Directories the old rules skipped may hold relevant files now
+                if filter.is_ignore_file(&path) {
+                    filter.reload();
+                    for dir in filter.watched_dirs(&filter.root) {
+                        let _ = add_watch(fd, &dir, watches);
+                    }
+                }
+
+                // New directories are watched too, and files created in them before the
+                // watch was added are reported
+                if event.mask & libc::IN_ISDIR != 0
+                    && event.mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0
+                    && filter.descend(&path)
+                {
+                    for sub in filter.watched_dirs(&path) {
+                        let _ = add_watch(fd, &sub, watches);
+                    }
+                    for file in crate::hasher::walker::walk_dir(&path, &IgnoreRules::empty()) {
+                        if let Some(rel) = filter.relevant(&file) {
+                            let _ = tx.send(Event::Changed(rel));
+                        }
+                    }
+                }
+                let Some(rel) = filter.relevant(&path) else {
+                    continue;
+                };
+                if tx.send(Event::Changed(rel)).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Fallback watcher: compare modification times and sizes every half second.
+fn spawn_poller(mut filter: Filter, tx: UnboundedSender<Event>) {
+    std::thread::spawn(move || {
+        let mut known = snapshot(&filter);
+        loop {
+            std::thread::sleep(Duration::from_millis(500));
+            filter.reload();
+            let current = snapshot(&filter);
+            let paths: BTreeSet<&PathBuf> = known.keys().chain(current.keys()).collect();
+            for path in paths {
+                if known.get(path) != current.get(path)
+                    && tx.send(Event::Changed(path.clone())).is_err()
+                {
+                    return;
+                }
+            }
+            known = current;
+        }
+    });
+}
+
+fn snapshot(filter: &Filter) -> HashMap<PathBuf, (Option<SystemTime>, u64)> {
+    let files = walkdir::WalkDir::new(&filter.root)
+        .follow_links(false)
+        .into_iter()
+        .filter_entry(|e| !e.file_type().is_dir() || filter.descend(e.path()))
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .map(|e| e.into_path());
+    files
+        .chain(filter.control.iter().cloned())
+        .filter_map(|path| {
+            let meta = std::fs::metadata(&path).ok()?;
+            let rel = filter.relevant(&path)?;
+            Some((rel, (meta.modified().ok(), meta.len())))
+        })
+        .collect()
+}
+
+/// A rebuild planned by [`WatchSession::plan`].
+#[derive(Debug, Clone)]
+pub struct Rebuild {
+    /// Graph with fresh keys, where nodes whose inputs changed and their downstream nodes
+    /// are dirty
+    pub graph: BuildGraph,
+    /// Inputs to commit once the rebuild succeeds
+    pub state: ContextState,
+    pub dirty: Vec<usize>,
+}
+
+/// What a watching process keeps between rebuilds.
+pub struct WatchSession {
+    context_dir: PathBuf,
+    template: BuildGraph,
+    env: EnvFingerprint,
+    state: ContextState,
+    stat_cache: StatCache,
+}
+
+impl WatchSession {
+    /// `template` is the graph built from the Dockerfile, before change detection; `state`
+    /// holds the inputs of the last successful build.
+    pub fn new(
+        template: BuildGraph,
+        context_dir: &Path,
+        env: EnvFingerprint,
+        state: ContextState,
+    ) -> Self {
+        WatchSession {
+            context_dir: context_dir.to_path_buf(),
+            template,
+            env,
+            state,
+            stat_cache: StatCache::in_memory(),
+        }
+    }
+
+    /// Reuse the hashes of unchanged files from `stat_cache`, which is saved whenever a
+    /// rebuild is committed.
+    pub fn with_stat_cache(mut self, stat_cache: StatCache) -> Self {
+        self.stat_cache = stat_cache;
+        self
+    }
+
+    /// Replace the graph after the Dockerfile changed.
+    pub fn set_template(&mut self, template: BuildGraph) {
+        self.template = template;
+    }
+
+    /// Hash the context again and find what changed since the last successful build.
+    pub fn plan(&self) -> Result<Rebuild> {
+        let mut graph = self.template.clone();
+        let state =
+            detect_changes_with(&mut graph, &self.context_dir, &self.state, &self.stat_cache)?;
+        propagate_dirty(&mut graph);
+        compute_composite_hashes(&mut graph, &self.env);
+        let dirty = graph
+            .nodes
+            .iter()
+            .filter(|n| n.dirty)
+            .map(|n| n.id)
+            .collect();
+        Ok(Rebuild {
+            graph,
+            state,
+            dirty,
+        })
+    }
+
+    /// Make `state` the reference for the next rebuild, after a build succeeded.
+    pub fn commit(&mut self, state: ContextState) {
+        self.state = state;
+        if let Err(e) = self.stat_cache.save() {
+            eprintln!("⚠️  Failed to save the file hash cache: {:#}", e);
+        }
+    }
+}
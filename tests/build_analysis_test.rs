@@ -0,0 +1,85 @@
+/// Tests for the critical-path analysis behind `memobuild analyze`
+#[cfg(test)]
+mod build_analysis_tests {
+    use memobuild::analysis::BuildAnalysis;
+    use memobuild::docker::dag::build_graph_from_instructions;
+    use memobuild::docker::parser::parse_dockerfile;
+    use memobuild::graph::BuildGraph;
+
+    /// FROM, two independent copies, a RUN needing both, and a LABEL needing only the FROM
+    fn timed_graph() -> BuildGraph {
+        let mut graph = build_graph_from_instructions(
+            parse_dockerfile("FROM alpine\nCOPY a /a\nCOPY b /b\nRUN make\nLABEL team=core"),
+            std::env::temp_dir(),
+        );
+        for (node, ms) in graph.nodes.iter_mut().zip([50, 100, 300, 1000, 0]) {
+            node.metadata.execution_time_ms = Some(ms);
+        }
+        graph
+    }
+
+    #[test]
+    fn test_critical_path_follows_longest_chain() {
+        let analysis = BuildAnalysis::from_graph(&timed_graph()).unwrap();
+        assert_eq!(analysis.critical_path, vec![0, 2, 3]);
+        assert_eq!(analysis.critical_path_ms, 1350);
+        assert_eq!(analysis.total_work_ms, 1450);
+        assert!((analysis.parallelism - 1450.0 / 1350.0).abs() < 1e-9);
+        assert!(analysis.nodes[3].critical);
+        assert!(!analysis.nodes[1].critical);
+    }
+
+    #[test]
+    fn test_slack_of_nodes_off_the_critical_path() {
+        let analysis = BuildAnalysis::from_graph(&timed_graph()).unwrap();
+        let slack: Vec<u64> = analysis.nodes.iter().map(|n| n.slack_ms).collect();
+        // COPY a may finish as late as the start of RUN; the LABEL as late as the build
+        assert_eq!(slack, vec![0, 200, 0, 0, 1300]);
+        assert_eq!(analysis.nodes[1].earliest_start_ms, 50);
+        assert_eq!(analysis.nodes[1].latest_start_ms, 250);
+    }
+
+    #[test]
+    fn test_potential_speedup_is_relative_to_wall_time() {
+        let analysis = BuildAnalysis::from_graph(&timed_graph()).unwrap();
+        assert_eq!(analysis.potential_speedup, None);
+
+        let analysis = analysis.with_wall_time(2700);
+        assert_eq!(analysis.wall_time_ms, Some(2700));
+        assert_eq!(analysis.potential_speedup, Some(2.0));
+    }
+
+    #[test]
+    fn test_nodes_without_durations_and_invalid_graphs() {
+        let mut graph = timed_graph();
+        for node in &mut graph.nodes {
+            node.metadata.execution_time_ms = None;
+        }
+        let analysis = BuildAnalysis::from_graph(&graph).unwrap();
+        assert_eq!(analysis.critical_path_ms, 0);
+        assert_eq!(analysis.parallelism, 1.0);
+
+        graph.nodes[0].deps.push(3);
+        assert!(BuildAnalysis::from_graph(&graph).is_err());
+    }
+    #[test]
+    fn test_analytics_payload_carries_the_analysis() {
+        use memobuild::server::AnalyticsData;
+        let analysis = BuildAnalysis::from_graph(&timed_graph())
+            .unwrap()
+            .with_wall_time(1500);
+        let payload = serde_json::json!({
+            "dirty": 4,
+            "cached": 1,
+            "duration_ms": 1500,
+            "analysis": analysis,
+        });
+        let data: AnalyticsData = serde_json::from_value(payload).unwrap();
+        assert_eq!(data.analysis, Some(analysis));
+
+        let legacy: AnalyticsData =
+            serde_json::from_value(serde_json::json!({"dirty": 1, "cached": 0, "duration_ms": 9}))
+                .unwrap();
+        assert!(legacy.analysis.is_none());
+    }
+}
@@ -0,0 +1,165 @@
+/// Tests for `memobuild diff` between two recorded builds
+#[cfg(test)]
+mod build_diff_tests {
+    use memobuild::core::compute_composite_hashes;
+    use memobuild::diff::{diff_builds, BuildSnapshot, EnvChange, KeyChange, NodeStatus};
+    use memobuild::docker::dag::build_graph_with_args;
+    use memobuild::docker::parser::parse_dockerfile;
+    use memobuild::env::EnvFingerprint;
+    use std::collections::HashMap;
+
+    const DOCKERFILE: &str =
+        "FROM alpine:3.19\nARG MODE=debug\nCOPY src /src\nRUN make $MODE\nRUN make install";
+
+    fn build(dockerfile: &str, args: &[(&str, &str)], env: EnvFingerprint) -> BuildSnapshot {
+        let args: HashMap<String, String> = args
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let mut graph =
+            build_graph_with_args(parse_dockerfile(dockerfile), std::env::temp_dir(), &args);
+        compute_composite_hashes(&mut graph, &env);
+        BuildSnapshot {
+            graph,
+            env: Some(env),
+        }
+    }
+
+    #[test]
+    fn test_identical_builds_have_no_changes() {
+        let a = build(DOCKERFILE, &[], EnvFingerprint::default());
+        let diff = diff_builds(&a, &a.clone());
+        assert!(diff.env.is_empty());
+        assert_eq!(diff.count(NodeStatus::Unchanged), 5);
+    }
+
+    #[test]
+    fn test_build_arg_change_names_env_and_upstream() {
+        let a = build(DOCKERFILE, &[], EnvFingerprint::default());
+        let b = build(
+            DOCKERFILE,
+            &[("MODE", "release")],
+            EnvFingerprint::default(),
+        );
+        let diff = diff_builds(&a, &b);
+
+        assert_eq!(diff.nodes[3].status, NodeStatus::Changed);
+        assert_eq!(
+            diff.nodes[3].changes,
+            vec![KeyChange::Env {
+                key: "MODE".to_string(),
+                before: Some("debug".to_string()),
+                after: Some("release".to_string()),
+            }]
+        );
+        // The second RUN sees the same variable, and builds on the first
+        assert!(diff.nodes[4].changes.contains(&KeyChange::Dependency {
+            id: 3,
+            label: "RUN make $MODE".to_string()
+        }));
+        assert_eq!(diff.nodes[2].status, NodeStatus::Unchanged);
+    }
+
+    #[test]
+    fn test_source_hash_change() {
+        let a = build(DOCKERFILE, &[], EnvFingerprint::default());
+        let mut b = a.clone();
+        b.graph.nodes[2].metadata.source_content_hash = Some("abc".to_string());
+        compute_composite_hashes(&mut b.graph, &EnvFingerprint::default());
+
+        let diff = diff_builds(&a, &b);
+        assert_eq!(
+            diff.nodes[2].changes,
+            vec![KeyChange::SourceHash {
+                before: None,
+                after: Some("abc".to_string())
+            }]
+        );
+    }
+
+    #[test]
+    fn test_network_policy_change() {
+        let a = build(DOCKERFILE, &[], EnvFingerprint::default());
+        let b = build(
+            &DOCKERFILE.replace("RUN make install", "RUN --network=none make install"),
+            &[],
+            EnvFingerprint::default(),
+        );
+
+        let diff = diff_builds(&a, &b);
+        assert_eq!(diff.count(NodeStatus::Changed), 1);
+        assert_eq!(
+            diff.nodes[4].changes,
+            vec![KeyChange::Setting {
+                name: "network".to_string(),
+                before: "(default)".to_string(),
+                after: "none".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_env_fingerprint_fields() {
+        let mut rust_179 = EnvFingerprint::default();
+        rust_179
+            .toolchain
+            .insert("rustc".to_string(), "1.79.0".to_string());
+        let mut rust_180 = rust_179.clone();
+        rust_180
+            .toolchain
+            .insert("rustc".to_string(), "1.80.0".to_string());
+
+        let diff = diff_builds(
+            &build(DOCKERFILE, &[], rust_179),
+            &build(DOCKERFILE, &[], rust_180),
+        );
+        assert_eq!(
+            diff.env,
+            vec![EnvChange {
+                field: "toolchain.rustc".to_string(),
+                before: Some("1.79.0".to_string()),
+                after: Some("1.80.0".to_string()),
+            }]
+        );
+        assert_eq!(diff.nodes[0].changes, vec![KeyChange::EnvFingerprint]);
+    }
+
+    #[test]
+    fn test_edited_and_added_instructions() {
+        let a = build(DOCKERFILE, &[], EnvFingerprint::default());
+        let b = build(
+            "FROM alpine:3.19\nARG MODE=debug\nCOPY src /src\nRUN make -j4 $MODE\nRUN make install\n\
+             LABEL team=core",
+            &[],
+            EnvFingerprint::default(),
+        );
+        let diff = diff_builds(&a, &b);
+
+        assert!(diff.nodes[3].changes.contains(&KeyChange::Instruction {
+            before: "RUN make $MODE".to_string(),
+            after: "RUN make -j4 $MODE".to_string(),
+        }));
+        assert_eq!(diff.nodes[5].status, NodeStatus::Added);
+        assert_eq!(diff.count(NodeStatus::Removed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_load_graph_json() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("graph.json");
+        let snapshot = build(DOCKERFILE, &[], EnvFingerprint::default());
+        std::fs::write(&file, serde_json::to_string(&snapshot.graph).unwrap()).unwrap();
+
+        let loaded = BuildSnapshot::load(file.to_str().unwrap(), dir.path())
+            .await
+            .unwrap();
+        assert!(loaded.env.is_none());
+        assert_eq!(loaded.graph.nodes.len(), 5);
+
+        std::fs::write(&file, serde_json::to_string(&snapshot).unwrap()).unwrap();
+        let loaded = BuildSnapshot::load(file.to_str().unwrap(), dir.path())
+            .await
+            .unwrap();
+        assert!(loaded.env.is_some());
+    }
+}
@@ -0,0 +1,132 @@
+/// Tests for the local build history behind `memobuild history`
+#[cfg(test)]
+mod build_history_tests {
+    use chrono::{Duration, Utc};
+    use memobuild::diff::BuildSnapshot;
+    use memobuild::docker::dag::build_graph_from_instructions;
+    use memobuild::docker::parser::parse_dockerfile;
+    use memobuild::env::EnvFingerprint;
+    use memobuild::history::{BuildHistory, BuildRecord, BuildStatus};
+    use std::path::Path;
+
+    fn record(id: &str, context: &Path, minutes_ago: i64) -> BuildRecord {
+        let mut record = BuildRecord::new(id, context, "Dockerfile", EnvFingerprint::default());
+        record.started_at = Utc::now() - Duration::minutes(minutes_ago);
+        record.graph = build_graph_from_instructions(
+            parse_dockerfile("FROM alpine\nCOPY . /app\nRUN make"),
+            context.to_path_buf(),
+        );
+        record.graph.nodes[0].cache_hit = true;
+        record.graph.nodes[2].dirty = true;
+        record
+    }
+
+    #[test]
+    fn test_list_is_newest_first_and_skips_corrupt_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = BuildHistory::new(dir.path().join("history"));
+        assert!(history.list().unwrap().is_empty());
+
+        history.save(&record("aaa111", dir.path(), 10)).unwrap();
+        history.save(&record("bbb222", dir.path(), 5)).unwrap();
+        std::fs::create_dir_all(history.root().join("broken")).unwrap();
+        std::fs::write(history.root().join("broken/record.json"), "{").unwrap();
+
+        let ids: Vec<String> = history.list().unwrap().into_iter().map(|r| r.id).collect();
+        assert_eq!(ids, vec!["bbb222", "aaa111"]);
+    }
+
+    #[test]
+    fn test_resolve_last_ids_and_prefixes() {
+        let dir = tempfile::tempdir().unwrap();
+        let other = tempfile::tempdir().unwrap();
+        let history = BuildHistory::new(dir.path().join("history"));
+        history.save(&record("abc123", dir.path(), 30)).unwrap();
+        history.save(&record("abd456", dir.path(), 20)).unwrap();
+        history.save(&record("fff789", other.path(), 1)).unwrap();
+        let mut dry_run = record("eee000", dir.path(), 0);
+        dry_run.dry_run = true;
+        history.save(&dry_run).unwrap();
+
+        assert_eq!(history.resolve("last", dir.path()).unwrap().id, "abd456");
+        assert_eq!(history.resolve("last", other.path()).unwrap().id, "fff789");
+        assert_eq!(history.resolve("abc", dir.path()).unwrap().id, "abc123");
+        assert_eq!(history.resolve("abd456", dir.path()).unwrap().id, "abd456");
+        assert!(history.resolve("ab", dir.path()).is_err());
+        assert!(history.resolve("zzz", dir.path()).is_err());
+    }
+
+    #[test]
+    fn test_failed_build_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = BuildHistory::new(dir.path().join("history"));
+        let mut failed = record("c0ffee", dir.path(), 0);
+        failed.status = BuildStatus::Failed;
+        failed.error = Some("Command failed with exit code 2".to_string());
+        failed.logs = history.log_dir(&failed.id);
+        history.save(&failed).unwrap();
+
+        let loaded = history.resolve("c0ffee", dir.path()).unwrap();
+        assert_eq!(loaded.status, BuildStatus::Failed);
+        assert_eq!(loaded.error, failed.error);
+        assert_eq!(loaded.logs, history.root().join("c0ffee").join("logs"));
+        assert_eq!(loaded.cache_hits(), 1);
+        assert_eq!(loaded.executed(), 2);
+        assert_eq!(loaded.graph.nodes.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_diff_loads_builds_from_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = BuildHistory::new(dir.path().join("history"));
+        history.save(&record("1234abcd", dir.path(), 0)).unwrap();
+
+        let snapshot = BuildSnapshot::load_from(&history, "1234", dir.path())
+            .await
+            .unwrap();
+        assert_eq!(snapshot.graph.nodes.len(), 3);
+        assert!(snapshot.env.is_some());
+        assert!(BuildSnapshot::load_from(&history, "last", dir.path())
+            .await
+            .is_ok());
+    }
+
+    #[test]
+    fn test_retention_is_per_context() {
+        let dir = tempfile::tempdir().unwrap();
+        let other = tempfile::tempdir().unwrap();
+        let history = BuildHistory::new(dir.path().join("history")).with_retention(2);
+        history.save(&record("old", other.path(), 60)).unwrap();
+        for (id, minutes_ago) in [("a1", 40), ("a2", 30), ("a3", 20), ("a4", 10)] {
+            history.save(&record(id, dir.path(), minutes_ago)).unwrap();
+        }
+
+        let ids: Vec<String> = history.list().unwrap().into_iter().map(|r| r.id).collect();
+        assert_eq!(ids, vec!["a4", "a3", "old"]);
+        assert!(!history.root().join("a1").exists());
+        assert_eq!(history.list_for(Some(dir.path()), 1).unwrap()[0].id, "a4");
+    }
+
+    #[test]
+    fn test_index_picks_up_and_drops_records_behind_its_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = BuildHistory::new(dir.path().join("history"));
+        history.save(&record("kept", dir.path(), 20)).unwrap();
+        history.save(&record("removed", dir.path(), 10)).unwrap();
+        assert!(history.root().join("index.json").is_file());
+
+        // A record written without the index, e.g. by an older version
+        let unindexed = record("added", dir.path(), 0);
+        std::fs::create_dir_all(history.root().join("added")).unwrap();
+        std::fs::write(
+            history.root().join("added/record.json"),
+            serde_json::to_string(&unindexed).unwrap(),
+        )
+        .unwrap();
+        std::fs::remove_dir_all(history.root().join("removed")).unwrap();
+
+        assert_eq!(history.resolve("last", dir.path()).unwrap().id, "added");
+        let ids: Vec<String> = history.list().unwrap().into_iter().map(|r| r.id).collect();
+        assert_eq!(ids, vec!["added", "kept"]);
+    }
+}
@@ -154,7 +154,7 @@ mod change_detection_tests {
         let node = Node {
             id: 0,
             name: "test".to_string(),
-            kind: NodeKind::Run,
+            kind: NodeKind::Run { mounts: vec![] },
             content: "test".to_string(),
             deps: vec![],
             dirty: true,
@@ -173,7 +173,7 @@ mod change_detection_tests {
         let node = Node {
             id: 0,
             name: "consistent".to_string(),
-            kind: NodeKind::Run,
+            kind: NodeKind::Run { mounts: vec![] },
             content: "echo hello".to_string(),
             deps: vec![],
             dirty: false,
@@ -196,7 +196,7 @@ mod change_detection_tests {
             Node {
                 id: 0,
                 name: "A".to_string(),
-                kind: NodeKind::Run,
+                kind: NodeKind::Run { mounts: vec![] },
                 content: "A".to_string(),
                 deps: vec![],
                 dirty: true,
@@ -209,7 +209,7 @@ mod change_detection_tests {
             Node {
                 id: 1,
                 name: "B".to_string(),
-                kind: NodeKind::Run,
+                kind: NodeKind::Run { mounts: vec![] },
                 content: "B".to_string(),
                 deps: vec![0],
                 dirty: false,
@@ -222,7 +222,7 @@ mod change_detection_tests {
             Node {
                 id: 2,
                 name: "C".to_string(),
-                kind: NodeKind::Run,
+                kind: NodeKind::Run { mounts: vec![] },
                 content: "C".to_string(),
                 deps: vec![1],
                 dirty: false,
@@ -247,6 +247,305 @@ mod change_detection_tests {
     }
 }
 
+/// Tests for filesystem-based change detection against the previous build state
+#[cfg(test)]
+mod detect_changes_tests {
+    use memobuild::core::{self, ContextState};
+    use memobuild::docker;
+    use tempfile::tempdir;
+
+    fn graph_for(context: &std::path::Path) -> memobuild::graph::BuildGraph {
+        let dockerfile = "FROM alpine\nCOPY a.txt /a.txt\nCOPY b.txt /b.txt\nRUN cat /a.txt";
+        let instructions = docker::parser::parse_dockerfile(dockerfile);
+        docker::dag::build_graph_from_instructions(instructions, context.to_path_buf())
+    }
+
+    #[test]
+    fn test_first_build_marks_everything_dirty() {
+        let dir = tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), "a").unwrap();
+        std::fs::write(dir.path().join("b.txt"), "b").unwrap();
+
+        let mut graph = graph_for(dir.path());
+        core::detect_changes_against(&mut graph, dir.path(), &ContextState::default()).unwrap();
+        assert!(graph.nodes.iter().all(|n| n.dirty));
+    }
+
+    #[test]
+    fn test_unchanged_context_is_clean() {
+        let dir = tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), "a").unwrap();
+        std::fs::write(dir.path().join("b.txt"), "b").unwrap();
+
+        let mut graph = graph_for(dir.path());
+        let state =
+            core::detect_changes_against(&mut graph, dir.path(), &ContextState::default()).unwrap();
+
+        let mut graph = graph_for(dir.path());
+        core::detect_changes_against(&mut graph, dir.path(), &state).unwrap();
+        assert!(graph.nodes.iter().all(|n| !n.dirty));
+    }
+
+    #[test]
+    fn test_only_changed_source_is_dirty() {
+        let dir = tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), "a").unwrap();
+        std::fs::write(dir.path().join("b.txt"), "b").unwrap();
+
+        let mut graph = graph_for(dir.path());
+        let state =
+            core::detect_changes_against(&mut graph, dir.path(), &ContextState::default()).unwrap();
+
+        std::fs::write(dir.path().join("b.txt"), "changed").unwrap();
+        let mut graph = graph_for(dir.path());
+        core::detect_changes_against(&mut graph, dir.path(), &state).unwrap();
+
+        let dirty: Vec<usize> = graph
+            .nodes
+            .iter()
+            .filter(|n| n.dirty)
+            .map(|n| n.id)
+            .collect();
+        assert_eq!(dirty, vec![2], "Only COPY b.txt should be dirty");
+
+        core::propagate_dirty(&mut graph);
+        assert!(
+            graph.nodes[3].dirty,
+            "Dependents of COPY b.txt must be rebuilt"
+        );
+    }
+
+    #[test]
+    fn test_dockerignored_files_do_not_dirty_nodes() {
+        let dir = tempdir().unwrap();
+        std::fs::write(dir.path().join(".dockerignore"), "*.log").unwrap();
+        std::fs::write(dir.path().join("app.txt"), "app").unwrap();
+
+        let instructions = docker::parser::parse_dockerfile("FROM alpine\nCOPY . /app");
+        let mut graph = docker::dag::build_graph_from_instructions(
+            instructions.clone(),
+            dir.path().to_path_buf(),
+        );
+        let state =
+            core::detect_changes_against(&mut graph, dir.path(), &ContextState::default()).unwrap();
+
+        std::fs::write(dir.path().join("debug.log"), "noise").unwrap();
+        let mut graph =
+            docker::dag::build_graph_from_instructions(instructions, dir.path().to_path_buf());
+        core::detect_changes_against(&mut graph, dir.path(), &state).unwrap();
+        assert!(
+            !graph.nodes[1].dirty,
+            "Ignored files must not invalidate COPY"
+        );
+    }
+
+    #[test]
+    fn test_state_roundtrip() {
+        let dir = tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), "a").unwrap();
+        std::fs::write(dir.path().join("b.txt"), "b").unwrap();
+
+        let mut graph = graph_for(dir.path());
+        let state =
+            core::detect_changes_against(&mut graph, dir.path(), &ContextState::default()).unwrap();
+
+        let state_file = dir.path().join("state").join("context.json");
+        state.save_to(&state_file).unwrap();
+        let loaded = ContextState::load_from(&state_file).unwrap();
+        assert_eq!(loaded.nodes, state.nodes);
+    }
+}
+
+/// Golden tests for Merkle-style composite cache keys
+#[cfg(test)]
+mod composite_hash_tests {
+    use memobuild::core::{self, ContextState};
+    use memobuild::env::EnvFingerprint;
+    use memobuild::graph::{BuildGraph, Node, NodeKind, NodeMetadata};
+    use std::collections::HashSet;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    fn node(id: usize, kind: NodeKind, content: &str, deps: Vec<usize>) -> Node {
+        Node {
+            id,
+            name: content.to_string(),
+            kind,
+            content: content.to_string(),
+            hash: String::new(),
+            deps,
+            dirty: false,
+            source_path: None,
+            env: Default::default(),
+            cache_hit: false,
+            metadata: NodeMetadata::default(),
+        }
+    }
+
+    #[test]
+    fn test_dirty_propagates_against_storage_order() {
+        // Dependents stored before their dependencies, as the graph may be after pruning
+        let mut graph = BuildGraph {
+            nodes: vec![
+                node(0, NodeKind::Run { mounts: vec![] }, "RUN b", vec![1]),
+                node(1, NodeKind::Run { mounts: vec![] }, "RUN a", vec![2]),
+                node(2, NodeKind::From, "alpine", vec![]),
+            ],
+            ..Default::default()
+        };
+        graph.nodes[2].dirty = true;
+
+        core::propagate_dirty(&mut graph);
+        assert!(graph.nodes.iter().all(|n| n.dirty));
+    }
+
+    /// FROM -> {COPY a.txt -> RUN, COPY b.txt -> RUN}
+    fn diamond(context: &Path) -> BuildGraph {
+        let mut copy_a = node(
+            1,
+            NodeKind::Copy {
+                sources: vec!["a.txt".into()],
+                dst: "/a".into(),
+                from: None,
+                options: Default::default(),
+            },
+            "COPY a.txt /a",
+            vec![0],
+        );
+        copy_a.source_path = Some(context.join("a.txt"));
+        let mut copy_b = node(
+            3,
+            NodeKind::Copy {
+                sources: vec!["b.txt".into()],
+                dst: "/b".into(),
+                from: None,
+                options: Default::default(),
+            },
+            "COPY b.txt /b",
+            vec![0],
+        );
+        copy_b.source_path = Some(context.join("b.txt"));
+
+        BuildGraph {
+            nodes: vec![
+                node(0, NodeKind::From, "FROM alpine", vec![]),
+                copy_a,
+                node(2, NodeKind::Run { mounts: vec![] }, "RUN cat /a", vec![1]),
+                copy_b,
+                node(4, NodeKind::Run { mounts: vec![] }, "RUN cat /b", vec![3]),
+            ],
+            ignore_file: None,
+        }
+    }
+
+    fn keys(context: &Path, env_fp: &EnvFingerprint) -> Vec<String> {
+        let mut graph = diamond(context);
+        core::detect_changes_against(&mut graph, context, &ContextState::default()).unwrap();
+        core::compute_composite_hashes(&mut graph, env_fp);
+        graph.nodes.into_iter().map(|n| n.hash).collect()
+    }
+
+    fn changed(before: &[String], after: &[String]) -> HashSet<usize> {
+        (0..before.len())
+            .filter(|&i| before[i] != after[i])
+            .collect()
+    }
+
+    fn write_context(dir: &Path) {
+        std::fs::write(dir.join("a.txt"), "a").unwrap();
+        std::fs::write(dir.join("b.txt"), "b").unwrap();
+    }
+
+    #[test]
+    fn test_keys_are_deterministic() {
+        let dir = tempdir().unwrap();
+        write_context(dir.path());
+        let env_fp = EnvFingerprint::default();
+        assert_eq!(keys(dir.path(), &env_fp), keys(dir.path(), &env_fp));
+    }
+
+    #[test]
+    fn test_editing_source_changes_exactly_downstream_keys() {
+        let dir = tempdir().unwrap();
+        write_context(dir.path());
+        let env_fp = EnvFingerprint::default();
+
+        let before = keys(dir.path(), &env_fp);
+        std::fs::write(dir.path().join("b.txt"), "edited").unwrap();
+        let after_b = keys(dir.path(), &env_fp);
+        assert_eq!(changed(&before, &after_b), HashSet::from([3, 4]));
+
+        std::fs::write(dir.path().join("a.txt"), "edited").unwrap();
+        let after_a = keys(dir.path(), &env_fp);
+        assert_eq!(changed(&after_b, &after_a), HashSet::from([1, 2]));
+    }
+
+    #[test]
+    fn test_source_hash_enters_the_key_once() {
+        let dir = tempdir().unwrap();
+        write_context(dir.path());
+        let env_fp = EnvFingerprint::default();
+        let mut graph = diamond(dir.path());
+        core::detect_changes_against(&mut graph, dir.path(), &ContextState::default()).unwrap();
+        core::compute_composite_hashes(&mut graph, &env_fp);
+
+        let copy = &graph.nodes[1];
+        assert!(copy.metadata.source_content_hash.is_some());
+        let deps = [graph.nodes[0].hash.clone()];
+        assert_eq!(copy.hash, copy.compute_node_key(&deps, None, Some(&env_fp)));
+    }
+
+    #[test]
+    fn test_different_copy_contexts_do_not_collide() {
+        let first = tempdir().unwrap();
+        let second = tempdir().unwrap();
+        std::fs::write(first.path().join("main.rs"), "fn main() {}").unwrap();
+        std::fs::write(second.path().join("main.rs"), "fn main() { panic!() }").unwrap();
+
+        let env_fp = EnvFingerprint::default();
+        let key_for = |context: &Path| {
+            let instructions = memobuild::docker::parser::parse_dockerfile("FROM alpine\nCOPY . .");
+            let mut graph = memobuild::docker::dag::build_graph_from_instructions(
+                instructions,
+                context.to_path_buf(),
+            );
+            core::detect_changes_against(&mut graph, context, &ContextState::default()).unwrap();
+            core::compute_composite_hashes(&mut graph, &env_fp);
+            graph.nodes[1].hash.clone()
+        };
+
+        assert_ne!(key_for(first.path()), key_for(second.path()));
+    }
+
+    #[test]
+    fn test_env_fingerprint_changes_every_key() {
+        let dir = tempdir().unwrap();
+        write_context(dir.path());
+
+        let before = keys(dir.path(), &EnvFingerprint::default());
+        let mut env_fp = EnvFingerprint::default();
+        env_fp
+            .toolchain
+            .insert("rustc".to_string(), "rustc 1.80.0".to_string());
+        let after = keys(dir.path(), &env_fp);
+        assert_eq!(changed(&before, &after).len(), before.len());
+    }
+
+    #[test]
+    fn test_topological_order_puts_dependencies_first() {
+        let dir = tempdir().unwrap();
+        let graph = diamond(dir.path());
+        let order = graph.topological_order();
+        let position = |id: usize| order.iter().position(|&n| n == id).unwrap();
+        for node in &graph.nodes {
+            for &dep in &node.deps {
+                assert!(position(dep) < position(node.id));
+            }
+        }
+        assert_eq!(graph.levels(), vec![vec![0], vec![1, 3], vec![2, 4]]);
+    }
+}
+
 /// Environment fingerprinting tests
 #[cfg(test)]
 mod env_fingerprint_tests {
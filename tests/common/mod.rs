@@ -0,0 +1,59 @@
+//! Helpers shared by the sandbox tests: a small build context, graphs ready to execute, and a
+//! cache in a directory of the test's own.
+#![allow(dead_code)]
+
+use memobuild::cache::{HybridCache, LocalCache};
+use memobuild::core::{compute_composite_hashes, detect_changes_against, ContextState};
+use memobuild::docker::dag::build_graph_from_instructions;
+use memobuild::docker::parser::parse_dockerfile;
+use memobuild::env::EnvFingerprint;
+use memobuild::graph::BuildGraph;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Context holding `src/x.txt` ("hello") and `src/keep.txt` ("keep")
+pub fn context() -> tempfile::TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    fs::create_dir_all(dir.path().join("src")).unwrap();
+    fs::write(dir.path().join("src/x.txt"), "hello").unwrap();
+    fs::write(dir.path().join("src/keep.txt"), "keep").unwrap();
+    dir
+}
+
+/// Graph of `dockerfile` with every node dirty, cache keys computed and input layers
+/// assigned, as the executor sees it
+pub fn graph(dockerfile: &str, context: &Path) -> BuildGraph {
+    let mut graph =
+        build_graph_from_instructions(parse_dockerfile(dockerfile), context.to_path_buf());
+    detect_changes_against(&mut graph, context, &ContextState::default()).unwrap();
+    compute_composite_hashes(&mut graph, &EnvFingerprint::default());
+    graph.assign_input_layers();
+    graph
+}
+
+/// Entry paths of a layer tar with their content
+pub fn layer_contents(layer: &[u8]) -> BTreeMap<String, String> {
+    let mut archive = tar::Archive::new(layer);
+    archive
+        .entries()
+        .unwrap()
+        .map(|entry| {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().to_string_lossy().to_string();
+            let mut content = String::new();
+            entry.read_to_string(&mut content).unwrap();
+            (path, content)
+        })
+        .collect()
+}
+
+/// Local-only cache kept in `dir`
+pub fn cache(dir: &Path) -> Arc<HybridCache> {
+    Arc::new(HybridCache {
+        local: LocalCache::in_dir(dir.to_path_buf()).unwrap(),
+        remote: None,
+    })
+}
@@ -0,0 +1,256 @@
+mod common;
+
+/// Tests for the containerd sandbox: base image resolution from the Dockerfile, image
+/// references and manifests, snapshot naming, and runtime specs. The sandbox itself only runs
+/// where a containerd socket is present.
+#[cfg(target_os = "linux")]
+mod containerd_sandbox_tests {
+    use crate::common::graph;
+    use memobuild::sandbox::image::{
+        chain_id, diff_ids, is_index, layer_snapshot, manifest_config, normalize_reference,
+        select_manifest,
+    };
+    use memobuild::sandbox::spec::{apply_limits, build_spec, isolate_network};
+    use memobuild::sandbox::ResourceLimits;
+    use std::collections::HashMap;
+    use std::path::Path;
+
+    #[test]
+    fn test_base_image_follows_stages() {
+        let ctx = tempfile::tempdir().unwrap();
+        let graph = graph(
+            "ARG VERSION=3.19\nFROM alpine:${VERSION} AS base\nRUN echo a\nFROM base\nRUN echo b\nFROM scratch\nCOPY --from=base /a /a",
+            ctx.path(),
+        );
+        let images: Vec<Option<&str>> = graph
+            .nodes
+            .iter()
+            .map(|node| node.metadata.base_image.as_deref())
+            .collect();
+        assert_eq!(
+            images,
+            vec![
+                Some("alpine:3.19"),
+                Some("alpine:3.19"),
+                Some("alpine:3.19"),
+                Some("alpine:3.19"),
+                None,
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn test_normalize_reference() {
+        let cases = [
+            ("alpine", "docker.io/library/alpine:latest"),
+            ("alpine:3.19", "docker.io/library/alpine:3.19"),
+            ("library/alpine", "docker.io/library/alpine:latest"),
+            ("bitnami/redis:7", "docker.io/bitnami/redis:7"),
+            ("ghcr.io/org/app", "ghcr.io/org/app:latest"),
+            ("localhost:5000/app:dev", "localhost:5000/app:dev"),
+            ("localhost/app", "localhost/app:latest"),
+            ("alpine@sha256:abcd", "docker.io/library/alpine@sha256:abcd"),
+            (
+                "quay.io/org/app:1.0@sha256:abcd",
+                "quay.io/org/app@sha256:abcd",
+            ),
+        ];
+        for (reference, expected) in cases {
+            assert_eq!(normalize_reference(reference), expected, "{}", reference);
+        }
+    }
+
+    #[test]
+    fn test_manifest_and_config_lookup() {
+        assert!(is_index("application/vnd.oci.image.index.v1+json"));
+        assert!(is_index(
+            "application/vnd.docker.distribution.manifest.list.v2+json"
+        ));
+        assert!(!is_index("application/vnd.oci.image.manifest.v1+json"));
+
+        let index = br#"{"manifests": [
+            {"digest": "sha256:arm", "platform": {"os": "linux", "architecture": "arm64"}},
+            {"digest": "sha256:amd", "platform": {"os": "linux", "architecture": "amd64"}},
+            {"digest": "sha256:win", "platform": {"os": "windows", "architecture": "amd64"}}
+        ]}"#;
+        assert_eq!(
+            select_manifest(index, "linux", "amd64").unwrap(),
+            "sha256:amd"
+        );
+        assert_eq!(
+            select_manifest(index, "linux", "arm64").unwrap(),
+            "sha256:arm"
+        );
+        assert!(select_manifest(index, "linux", "s390x").is_err());
+
+        let manifest = br#"{"config": {"digest": "sha256:cfg"}, "layers": []}"#;
+        assert_eq!(manifest_config(manifest).unwrap(), "sha256:cfg");
+        assert!(manifest_config(b"{}").is_err());
+
+        let config = br#"{"rootfs": {"type": "layers", "diff_ids": ["sha256:a", "sha256:b"]}}"#;
+        assert_eq!(
+            diff_ids(config).unwrap(),
+            vec!["sha256:a".to_string(), "sha256:b".to_string()]
+        );
+        assert!(diff_ids(b"{}").is_err());
+    }
+
+    #[test]
+    fn test_chain_id_and_layer_snapshots() {
+        let ids: Vec<String> = ["a", "b", "c"]
+            .iter()
+            .map(|c| format!("sha256:{}", c.repeat(64)))
+            .collect();
+        assert_eq!(chain_id(&[]), None);
+        assert_eq!(chain_id(&ids[..1]), Some(ids[0].clone()));
+        assert_eq!(
+            chain_id(&ids[..2]).unwrap(),
+            "sha256:ccd722928bd92476ba1745586fed6e45a102504185ad88cd89e01ff116fd146c"
+        );
+        assert_eq!(
+            chain_id(&ids).unwrap(),
+            "sha256:c1377126441fb2f5ec2c21ae2a60255331d639e830f0ee1b40a36e52d4c40588"
+        );
+
+        let first = layer_snapshot(Some("sha256:base"), "abc");
+        assert_eq!(first, layer_snapshot(Some("sha256:base"), "abc"));
+        assert_ne!(first, layer_snapshot(Some("sha256:other"), "abc"));
+        assert_ne!(first, layer_snapshot(Some("sha256:base"), "abd"));
+        assert_eq!(
+            layer_snapshot(None, "abc"),
+            "memobuild/d92b1cb3a32147b86a4db0647e4bf6eda6cf160fd3b2da264c5b088c9f9ccbfa"
+        );
+    }
+
+    #[test]
+    fn test_spec_limits_and_network() {
+        let mut spec = build_spec(
+            &["/bin/sh".to_string(), "-c".to_string(), "true".to_string()],
+            &HashMap::new(),
+            "",
+            Path::new("rootfs"),
+        );
+        let limits = ResourceLimits::parse("memory=64m cpus=1.5 cpu-shares=512 pids=32").unwrap();
+        apply_limits(&mut spec, &limits);
+        isolate_network(&mut spec);
+
+        let json = serde_json::to_value(&spec).unwrap();
+        let resources = &json["linux"]["resources"];
+        assert_eq!(resources["memory"]["limit"], 64 << 20);
+        assert_eq!(resources["cpu"]["shares"], 512);
+        assert_eq!(resources["cpu"]["quota"], 150_000);
+        assert_eq!(resources["cpu"]["period"], 100_000);
+        assert_eq!(resources["pids"]["limit"], 32);
+        let namespaces: Vec<&str> = json["linux"]["namespaces"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|namespace| namespace["type"].as_str().unwrap())
+            .collect();
+        assert!(namespaces.contains(&"network"), "{:?}", namespaces);
+    }
+
+    /// These run against a live containerd that has `alpine:latest` pulled into the
+    /// `memobuild` namespace, at `MEMOBUILD_CONTAINERD_SOCKET` or the default socket.
+    #[cfg(feature = "containerd")]
+    mod live {
+        use crate::common::graph;
+        use memobuild::sandbox::containerd::{configured_socket, is_available, ContainerdSandbox};
+        use memobuild::sandbox::{ExecResult, FailureReason, ResourceLimits, Sandbox};
+
+        fn sandbox(context: &std::path::Path, work: &std::path::Path) -> Option<ContainerdSandbox> {
+            let socket = configured_socket();
+            is_available(&socket).then(|| {
+                ContainerdSandbox::new(
+                    "memobuild",
+                    &socket,
+                    context.to_path_buf(),
+                    work.to_path_buf(),
+                )
+            })
+        }
+
+        async fn run(
+            sandbox: &ContainerdSandbox,
+            graph: &memobuild::graph::BuildGraph,
+            id: usize,
+        ) -> ExecResult {
+            let env = sandbox.prepare(&graph.nodes[id]).await.unwrap();
+            let result = sandbox.execute(&env, &graph.nodes[id]).await.unwrap();
+            sandbox.cleanup(&env).await.unwrap();
+            result
+        }
+
+        #[tokio::test]
+        async fn test_runs_on_the_base_image_and_parent_layers() {
+            let context = tempfile::tempdir().unwrap();
+            let work = tempfile::tempdir().unwrap();
+            let Some(sandbox) = sandbox(context.path(), work.path()) else {
+                return;
+            };
+            std::fs::write(context.path().join("greeting"), "hi").unwrap();
+            let graph = graph(
+                "FROM alpine\nCOPY greeting /greeting\nRUN echo made > /made && cat /etc/alpine-release > /dev/null\nRUN cat /greeting /made && echo oops >&2",
+                context.path(),
+            );
+
+            assert_eq!(run(&sandbox, &graph, 1).await.exit_code, 0);
+            let made = run(&sandbox, &graph, 2).await;
+            assert_eq!(
+                made.exit_code,
+                0,
+                "{}",
+                String::from_utf8_lossy(&made.stderr)
+            );
+            assert!(made.diff.is_some());
+            assert!(sandbox.layer_path(&graph.nodes[2].hash).exists());
+
+            let read = run(&sandbox, &graph, 3).await;
+            assert_eq!(read.exit_code, 0);
+            assert_eq!(String::from_utf8_lossy(&read.stdout), "himade\n");
+            assert_eq!(String::from_utf8_lossy(&read.stderr), "oops\n");
+        }
+
+        #[tokio::test]
+        async fn test_exit_code_and_timeout() {
+            let context = tempfile::tempdir().unwrap();
+            let work = tempfile::tempdir().unwrap();
+            let Some(sandbox) = sandbox(context.path(), work.path()) else {
+                return;
+            };
+            let failing = graph("FROM alpine\nRUN exit 3", context.path());
+            let result = run(&sandbox, &failing, 1).await;
+            assert_eq!(result.exit_code, 3);
+            assert!(result.diff.is_none());
+
+            let sandbox = sandbox.with_limits(ResourceLimits::parse("timeout=1s").unwrap());
+            let sleeping = graph("FROM alpine\nRUN sleep 30", context.path());
+            let result = run(&sandbox, &sleeping, 1).await;
+            assert_ne!(result.exit_code, 0);
+            assert_eq!(
+                result.failure,
+                Some(FailureReason::Timeout { after_secs: 1 })
+            );
+        }
+
+        #[tokio::test]
+        async fn test_missing_image_names_the_pull_command() {
+            let context = tempfile::tempdir().unwrap();
+            let work = tempfile::tempdir().unwrap();
+            let Some(sandbox) = sandbox(context.path(), work.path()) else {
+                return;
+            };
+            let graph = graph(
+                "FROM example.invalid/memobuild/missing:1\nRUN true",
+                context.path(),
+            );
+            let error = sandbox.prepare(&graph.nodes[1]).await.unwrap_err();
+            assert!(
+                format!("{:#}", error).contains("images pull example.invalid/memobuild/missing:1"),
+                "{:#}",
+                error
+            );
+        }
+    }
+}
@@ -0,0 +1,163 @@
+/// Tests for context hashing of file modes, symlinks and directories
+#[cfg(all(test, unix))]
+mod content_hash_tests {
+    use memobuild::core::{detect_changes_against, ContextState};
+    use memobuild::docker::dag::build_graph_from_instructions;
+    use memobuild::docker::parser::parse_dockerfile;
+    use memobuild::export::files::layer_entries;
+    use memobuild::hasher::file_hasher::{hash_dir_files, HASH_SCHEME_VERSION};
+    use memobuild::hasher::{hash_path, IgnoreRules};
+    use std::fs;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+    use std::path::Path;
+
+    fn context() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("app/bin")).unwrap();
+        fs::write(dir.path().join("app/bin/run.sh"), "#!/bin/sh\necho hi\n").unwrap();
+        fs::write(dir.path().join("app/config.toml"), "debug = false\n").unwrap();
+        symlink("config.toml", dir.path().join("app/current.toml")).unwrap();
+        dir
+    }
+
+    fn app_hash(dir: &Path) -> String {
+        hash_path(&dir.join("app"), &IgnoreRules::empty()).unwrap()
+    }
+
+    fn set_mode(path: &Path, mode: u32) {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    #[test]
+    fn test_executable_bit_changes_the_hash() {
+        let dir = context();
+        let script = dir.path().join("app/bin/run.sh");
+        set_mode(&script, 0o644);
+        let before = app_hash(dir.path());
+        set_mode(&script, 0o755);
+        assert_ne!(before, app_hash(dir.path()));
+        set_mode(&script, 0o644);
+        assert_eq!(before, app_hash(dir.path()));
+
+        // A single-file source is covered too
+        let file_hash = hash_path(&script, &IgnoreRules::empty()).unwrap();
+        set_mode(&script, 0o755);
+        assert_ne!(
+            file_hash,
+            hash_path(&script, &IgnoreRules::empty()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_symlink_target_changes_the_hash() {
+        let dir = context();
+        fs::write(dir.path().join("app/other.toml"), "debug = false\n").unwrap();
+        let before = app_hash(dir.path());
+
+        let link = dir.path().join("app/current.toml");
+        fs::remove_file(&link).unwrap();
+        symlink("other.toml", &link).unwrap();
+        assert_ne!(before, app_hash(dir.path()));
+
+        // The link is hashed, not followed: its target's content is not part of its hash
+        let files = hash_dir_files(&dir.path().join("app"), &IgnoreRules::empty()).unwrap();
+        let link_hash = |files: &[(String, String)]| {
+            files
+                .iter()
+                .find(|(path, _)| path == "current.toml")
+                .unwrap()
+                .1
+                .clone()
+        };
+        fs::write(dir.path().join("app/other.toml"), "debug = true\n").unwrap();
+        let after = hash_dir_files(&dir.path().join("app"), &IgnoreRules::empty()).unwrap();
+        assert_eq!(link_hash(&files), link_hash(&after));
+    }
+
+    #[test]
+    fn test_empty_directories_and_their_modes_change_the_hash() {
+        let dir = context();
+        let before = app_hash(dir.path());
+        fs::create_dir(dir.path().join("app/cache")).unwrap();
+        let with_dir = app_hash(dir.path());
+        assert_ne!(before, with_dir);
+
+        set_mode(&dir.path().join("app/cache"), 0o700);
+        assert_ne!(with_dir, app_hash(dir.path()));
+
+        // Ignored directories are not part of the hash
+        let rules = IgnoreRules::parse("cache");
+        fs::remove_dir(dir.path().join("app/cache")).unwrap();
+        let without = hash_path(&dir.path().join("app"), &rules).unwrap();
+        fs::create_dir(dir.path().join("app/cache")).unwrap();
+        assert_eq!(without, hash_path(&dir.path().join("app"), &rules).unwrap());
+    }
+
+    #[test]
+    fn test_copy_node_is_dirty_after_chmod() {
+        let dir = context();
+        let mut graph = build_graph_from_instructions(
+            parse_dockerfile("FROM alpine\nCOPY app /app\nRUN /app/bin/run.sh"),
+            dir.path().to_path_buf(),
+        );
+        let first =
+            detect_changes_against(&mut graph, dir.path(), &ContextState::default()).unwrap();
+        assert!(first.nodes["COPY app /app"]
+            .files
+            .contains_key("app/current.toml"));
+
+        set_mode(&dir.path().join("app/bin/run.sh"), 0o755);
+        let mut graph = build_graph_from_instructions(
+            parse_dockerfile("FROM alpine\nCOPY app /app\nRUN /app/bin/run.sh"),
+            dir.path().to_path_buf(),
+        );
+        detect_changes_against(&mut graph, dir.path(), &first).unwrap();
+        let dirty: Vec<&str> = graph
+            .nodes
+            .iter()
+            .filter(|n| n.dirty)
+            .map(|n| n.content.as_str())
+            .collect();
+        assert_eq!(dirty, vec!["COPY app /app"]);
+    }
+
+    #[test]
+    fn test_layer_contains_what_is_hashed() {
+        let dir = context();
+        fs::create_dir(dir.path().join("app/empty")).unwrap();
+        set_mode(&dir.path().join("app/bin/run.sh"), 0o755);
+        let graph = build_graph_from_instructions(
+            parse_dockerfile("FROM alpine\nCOPY app /srv"),
+            dir.path().to_path_buf(),
+        );
+        let entries = layer_entries(&graph.nodes[1], dir.path(), &IgnoreRules::empty()).unwrap();
+        let entry = |path: &str| entries.iter().find(|e| e.path == path).unwrap();
+
+        assert_eq!(entry("srv/bin/run.sh").mode, 0o755);
+        assert!(entry("srv/bin").is_dir);
+        assert!(entry("srv/empty").is_dir);
+        let link = entry("srv/current.toml");
+        assert_eq!(link.link_target.as_deref(), Some("config.toml"));
+        assert!(link.data.is_empty());
+
+        let hashed: Vec<String> = hash_dir_files(&dir.path().join("app"), &IgnoreRules::empty())
+            .unwrap()
+            .into_iter()
+            .map(|(path, _)| format!("srv/{}", path))
+            .collect();
+        let mut in_layer: Vec<String> = entries.iter().map(|e| e.path.clone()).collect();
+        in_layer.sort();
+        assert_eq!(hashed, in_layer);
+    }
+
+    #[test]
+    fn test_hash_scheme_is_versioned() {
+        assert_eq!(HASH_SCHEME_VERSION, 2);
+        // Even an empty directory hashes differently from the unversioned scheme
+        let dir = tempfile::tempdir().unwrap();
+        assert_ne!(
+            hash_path(dir.path(), &IgnoreRules::empty()).unwrap(),
+            blake3::Hasher::new().finalize().to_hex().to_string()
+        );
+    }
+}
@@ -0,0 +1,1510 @@
+/// Tests for logical line handling in the Dockerfile parser
+#[cfg(test)]
+mod logical_line_tests {
+    use memobuild::docker::parser::{
+        escape_directive, logical_lines, parse_dockerfile, Instruction,
+    };
+
+    #[test]
+    fn test_backslash_continuation_joins_lines() {
+        let dockerfile = r#"
+FROM debian:bookworm
+RUN apt-get update \
+    && apt-get install -y \
+        curl \
+        git
+"#;
+
+        let instructions = parse_dockerfile(dockerfile);
+        assert_eq!(instructions.len(), 2);
+        match &instructions[1] {
+            Instruction::Run(cmd, _) => {
+                assert!(cmd.starts_with("apt-get update"));
+                assert!(cmd.contains("apt-get install -y"));
+                assert!(cmd.ends_with("git"));
+                assert!(!cmd.contains('\\'));
+            }
+            other => panic!("Expected RUN, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_comments_inside_continuation_are_skipped() {
+        let dockerfile = "RUN echo one \\\n# a comment\n\n    && echo two";
+        let instructions = parse_dockerfile(dockerfile);
+        assert_eq!(instructions.len(), 1);
+        match &instructions[0] {
+            Instruction::Run(cmd, _) => assert_eq!(cmd, "echo one     && echo two"),
+            other => panic!("Expected RUN, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_logical_lines_record_start_line() {
+        let dockerfile = "FROM alpine\n\nRUN a \\\n  b\nCMD c";
+        let lines = logical_lines(dockerfile);
+        let starts: Vec<usize> = lines.iter().map(|l| l.line).collect();
+        assert_eq!(starts, vec![1, 3, 5]);
+    }
+
+    #[test]
+    fn test_escape_directive() {
+        let dockerfile = "# escape=`\nFROM mcr.microsoft.com/windows/servercore\nRUN dir c:\\ `\n    && echo done";
+        assert_eq!(escape_directive(dockerfile), '`');
+
+        let instructions = parse_dockerfile(dockerfile);
+        assert_eq!(instructions.len(), 2);
+        match &instructions[1] {
+            Instruction::Run(cmd, _) => assert_eq!(cmd, "dir c:\\     && echo done"),
+            other => panic!("Expected RUN, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_escape_directive_only_at_top() {
+        let dockerfile = "FROM alpine\n# escape=`\nRUN echo hi";
+        assert_eq!(escape_directive(dockerfile), '\\');
+
+        let dockerfile = "# a comment\n# escape=`\nFROM alpine";
+        assert_eq!(escape_directive(dockerfile), '\\');
+    }
+
+    #[test]
+    fn test_run_heredoc_is_the_script() {
+        let dockerfile = r#"
+FROM alpine
+RUN <<EOF
+apk add --no-cache curl
+echo done
+EOF
+CMD ["sh"]
+"#;
+
+        let instructions = parse_dockerfile(dockerfile);
+        assert_eq!(instructions.len(), 3);
+        match &instructions[1] {
+            Instruction::Run(cmd, _) => assert_eq!(cmd, "apk add --no-cache curl\necho done"),
+            other => panic!("Expected RUN, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_run_heredoc_fed_to_command() {
+        let dockerfile = "RUN python3 <<'PY'\nprint('$HOME')\nPY";
+        let lines = logical_lines(dockerfile);
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].heredocs.len(), 1);
+        assert!(!lines[0].heredocs[0].expand);
+
+        match &parse_dockerfile(dockerfile)[0] {
+            Instruction::Run(cmd, _) => assert_eq!(cmd, "python3 <<'PY'\nprint('$HOME')\nPY"),
+            other => panic!("Expected RUN, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_heredoc_with_tab_stripping() {
+        let dockerfile = "RUN <<-EOF\n\techo indented\n\tEOF\nCMD done";
+        let instructions = parse_dockerfile(dockerfile);
+        assert_eq!(instructions.len(), 2);
+        match &instructions[0] {
+            Instruction::Run(cmd, _) => assert_eq!(cmd, "echo indented"),
+            other => panic!("Expected RUN, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_copy_heredoc() {
+        let dockerfile =
+            "FROM alpine\nCOPY <<EOF /etc/app.conf\nkey=value\nEOF\nRUN cat /etc/app.conf";
+        let instructions = parse_dockerfile(dockerfile);
+        assert_eq!(instructions.len(), 3);
+        match &instructions[1] {
+            Instruction::CopyHeredoc(docs, dst, _) => {
+                assert_eq!(dst, "/etc/app.conf");
+                assert_eq!(docs[0].content, "key=value\n");
+            }
+            other => panic!("Expected COPY heredoc, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_heredoc_body_changes_node_content() {
+        let build = |body: &str| {
+            let dockerfile = format!("FROM alpine\nCOPY <<EOF /conf\n{}\nEOF", body);
+            let graph = memobuild::docker::dag::build_graph_from_instructions(
+                parse_dockerfile(&dockerfile),
+                std::env::temp_dir(),
+            );
+            graph.nodes[1].content.clone()
+        };
+        assert_ne!(build("a=1"), build("a=2"));
+    }
+}
+
+/// Tests for multi-stage builds: named stages, COPY --from and --target pruning
+#[cfg(test)]
+mod multi_stage_tests {
+    use memobuild::docker::dag::{
+        build_graph_from_instructions, find_stage, image_nodes, prune_to_target,
+    };
+    use memobuild::docker::parser::{parse_dockerfile, Instruction};
+    use memobuild::graph::{BuildGraph, NodeKind};
+
+    const DOCKERFILE: &str = r#"
+FROM rust:1.80 AS backend
+WORKDIR /src
+RUN cargo build --release
+
+FROM node:20 AS frontend
+WORKDIR /web
+RUN npm run build
+
+FROM alpine:3.20 AS docs
+RUN echo docs
+
+FROM debian:bookworm-slim
+COPY --from=backend /src/target/release/app /usr/bin/app
+COPY --from=frontend /web/dist /srv/www
+CMD ["app"]
+"#;
+
+    fn graph() -> BuildGraph {
+        build_graph_from_instructions(parse_dockerfile(DOCKERFILE), std::env::temp_dir())
+    }
+
+    fn node_index(graph: &BuildGraph, content: &str) -> usize {
+        graph
+            .nodes
+            .iter()
+            .position(|n| n.content == content)
+            .unwrap_or_else(|| panic!("No node {}", content))
+    }
+
+    #[test]
+    fn test_parse_stage_names_and_copy_from() {
+        let instructions = parse_dockerfile(
+            "FROM --platform=linux/amd64 golang:1.22 as build\nCOPY --from=build /out /app",
+        );
+        match &instructions[0] {
+            Instruction::From(image, stage) => {
+                assert_eq!(image, "golang:1.22");
+                assert_eq!(stage.as_deref(), Some("build"));
+            }
+            other => panic!("Expected FROM, got {:?}", other),
+        }
+        match &instructions[1] {
+            Instruction::Copy(spec) => {
+                assert_eq!(spec.sources, vec!["/out".to_string()]);
+                assert_eq!(spec.dst, "/app");
+                assert_eq!(spec.from.as_deref(), Some("build"));
+            }
+            other => panic!("Expected COPY, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_stages_do_not_chain_across_from() {
+        let graph = graph();
+        let frontend_from = node_index(&graph, "FROM node:20");
+        assert!(graph.nodes[frontend_from].deps.is_empty());
+        assert_eq!(graph.nodes[frontend_from].metadata.stage, 1);
+        assert_eq!(
+            graph.nodes[frontend_from].metadata.stage_name.as_deref(),
+            Some("frontend")
+        );
+    }
+
+    #[test]
+    fn test_copy_from_links_to_stage_final_node() {
+        let graph = graph();
+        let backend_tail = node_index(&graph, "cargo build --release");
+        let frontend_tail = node_index(&graph, "npm run build");
+
+        let copy_backend = node_index(
+            &graph,
+            "COPY --from=backend /src/target/release/app /usr/bin/app",
+        );
+        assert!(graph.nodes[copy_backend].deps.contains(&backend_tail));
+        assert!(graph.nodes[copy_backend].source_path.is_none());
+        assert!(matches!(
+            &graph.nodes[copy_backend].kind,
+            NodeKind::Copy { from: Some(f), .. } if f == "backend"
+        ));
+
+        let copy_frontend = node_index(&graph, "COPY --from=frontend /web/dist /srv/www");
+        assert!(graph.nodes[copy_frontend].deps.contains(&frontend_tail));
+    }
+
+    #[test]
+    fn test_independent_stages_share_levels() {
+        let graph = graph();
+        let levels = graph.levels();
+        let level_of = |id: usize| levels.iter().position(|l| l.contains(&id)).unwrap();
+
+        let backend = node_index(&graph, "FROM rust:1.80");
+        let frontend = node_index(&graph, "FROM node:20");
+        let docs = node_index(&graph, "FROM alpine:3.20");
+        assert_eq!(level_of(backend), 0);
+        assert_eq!(level_of(frontend), 0);
+        assert_eq!(level_of(docs), 0);
+        assert!(levels.len() < graph.nodes.len());
+    }
+
+    #[test]
+    fn test_prune_to_target_keeps_needed_stages() {
+        let graph = graph();
+        let pruned = prune_to_target(&graph, "frontend").unwrap();
+        assert_eq!(pruned.nodes.len(), 3);
+        assert!(pruned.nodes.iter().all(|n| n.metadata.stage == 1));
+
+        let full = prune_to_target(&graph, "3").unwrap();
+        assert!(full.nodes.iter().all(|n| n.content != "echo docs"));
+        for node in &full.nodes {
+            assert!(node.deps.iter().all(|&d| d < full.nodes.len()));
+        }
+
+        assert!(prune_to_target(&graph, "missing").is_err());
+    }
+
+    #[test]
+    fn test_from_previous_stage() {
+        let dockerfile = "FROM alpine AS base\nRUN apk add curl\nFROM base AS app\nRUN curl --version\nFROM scratch AS other\nCMD x\nFROM app\nCMD run";
+        let graph =
+            build_graph_from_instructions(parse_dockerfile(dockerfile), std::env::temp_dir());
+
+        let base_tail = node_index(&graph, "apk add curl");
+        let app_from = node_index(&graph, "FROM base");
+        assert_eq!(graph.nodes[app_from].deps, vec![base_tail]);
+        assert_eq!(find_stage(&graph, "APP"), Some(1));
+
+        let image: Vec<String> = image_nodes(&graph)
+            .into_iter()
+            .map(|id| graph.nodes[id].content.clone())
+            .collect();
+        assert!(image.contains(&"apk add curl".to_string()));
+        assert!(image.contains(&"CMD run".to_string()));
+        assert!(!image.contains(&"CMD x".to_string()));
+    }
+}
+
+/// Tests for ARG scoping, --build-arg values and variable expansion
+#[cfg(test)]
+mod arg_expansion_tests {
+    use memobuild::core::compute_composite_hashes;
+    use memobuild::docker::dag::{build_graph_from_instructions, build_graph_with_args};
+    use memobuild::docker::expand::expand;
+    use memobuild::docker::parser::{parse_dockerfile, Instruction};
+    use memobuild::env::EnvFingerprint;
+    use memobuild::graph::{BuildGraph, NodeKind};
+    use std::collections::HashMap;
+
+    fn vars(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn graph_with(dockerfile: &str, args: &[(&str, &str)]) -> BuildGraph {
+        build_graph_with_args(
+            parse_dockerfile(dockerfile),
+            std::env::temp_dir(),
+            &vars(args),
+        )
+    }
+
+    #[test]
+    fn test_expansion_rules() {
+        let v = vars(&[("NAME", "app"), ("EMPTY", "")]);
+        assert_eq!(expand("/srv/$NAME/bin", &v), "/srv/app/bin");
+        assert_eq!(expand("${NAME}_v1", &v), "app_v1");
+        assert_eq!(expand("$MISSING/x", &v), "/x");
+        assert_eq!(expand("${MISSING:-dflt}", &v), "dflt");
+        assert_eq!(expand("${EMPTY:-dflt}", &v), "dflt");
+        assert_eq!(expand("${EMPTY-dflt}", &v), "");
+        assert_eq!(expand("${NAME:+set}", &v), "set");
+        assert_eq!(expand("${EMPTY:+set}", &v), "");
+        assert_eq!(expand("${EMPTY+set}", &v), "set");
+        assert_eq!(expand("${MISSING:-${NAME}-fallback}", &v), "app-fallback");
+        assert_eq!(expand("\\$NAME", &v), "$NAME");
+        assert_eq!(expand("'$NAME' \"$NAME\"", &v), "'$NAME' \"app\"");
+        assert_eq!(expand("cost: $5 ${", &v), "cost: $5 ${");
+    }
+
+    #[test]
+    fn test_parse_arg_declarations() {
+        let instructions = parse_dockerfile("ARG VERSION=\"1.2\" DEBUG\nFROM alpine");
+        assert_eq!(instructions.len(), 3);
+        match (&instructions[0], &instructions[1]) {
+            (Instruction::Arg(a, Some(default)), Instruction::Arg(b, None)) => {
+                assert_eq!(a, "VERSION");
+                // Kept as written: the quotes go when the DAG expands the default
+                assert_eq!(default, "\"1.2\"");
+                assert_eq!(b, "DEBUG");
+            }
+            other => panic!("Expected two ARGs, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_arg_with_quoted_spaces() {
+        let instructions = parse_dockerfile("ARG MSG=\"hello world\" NAME='a b'\nFROM alpine");
+        let args: Vec<(String, Option<String>)> = instructions
+            .into_iter()
+            .filter_map(|instruction| match instruction {
+                Instruction::Arg(name, default) => Some((name, default)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            args,
+            vec![
+                ("MSG".to_string(), Some("\"hello world\"".to_string())),
+                ("NAME".to_string(), Some("'a b'".to_string())),
+            ]
+        );
+
+        let graph = graph_with(
+            "FROM alpine\nARG MSG=\"hello world\" NAME='a b'\nWORKDIR /$MSG/$NAME",
+            &[],
+        );
+        assert_eq!(
+            graph.nodes.last().unwrap().content,
+            "WORKDIR /hello world/a b"
+        );
+    }
+
+    #[test]
+    fn test_parse_env_pairs() {
+        let env = |dockerfile: &str| -> Vec<(String, String)> {
+            graph_with(dockerfile, &[])
+                .nodes
+                .iter()
+                .filter(|node| matches!(node.kind, NodeKind::Env))
+                .filter_map(|node| node.content.strip_prefix("ENV ")?.split_once('='))
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect()
+        };
+        let pair = |k: &str, v: &str| (k.to_string(), v.to_string());
+        assert_eq!(
+            env("FROM alpine\nENV A=1 B=\"two words\" C='x=y'"),
+            vec![pair("A", "1"), pair("B", "two words"), pair("C", "x=y")]
+        );
+        assert_eq!(
+            env("FROM alpine\nENV GREETING hello world"),
+            vec![pair("GREETING", "hello world")]
+        );
+        assert_eq!(env("FROM alpine\nENV EMPTY="), vec![pair("EMPTY", "")]);
+        assert!(env("FROM alpine\nENV ONLY").is_empty());
+    }
+
+    #[test]
+    fn test_quotes_and_escapes_decide_what_is_expanded() {
+        let dockerfile =
+            "FROM alpine\nENV X=val\nENV A='$X' B=\"$X\" C=\\$X D=$X E=\"a\\\"b\" F=a\\ b\n\
+                          LABEL single='$X' double=\"$X\" escaped=\\$X";
+        let graph = graph_with(dockerfile, &[]);
+        let env_of = |key: &str| {
+            graph
+                .nodes
+                .iter()
+                .find(|node| node.content.starts_with(&format!("ENV {}=", key)))
+                .map(|node| node.env[key].clone())
+        };
+        assert_eq!(env_of("A").as_deref(), Some("$X"));
+        assert_eq!(env_of("B").as_deref(), Some("val"));
+        assert_eq!(env_of("C").as_deref(), Some("$X"));
+        assert_eq!(env_of("D").as_deref(), Some("val"));
+        assert_eq!(env_of("E").as_deref(), Some("a\"b"));
+        assert_eq!(env_of("F").as_deref(), Some("a b"));
+
+        let pair = |k: &str, v: &str| (k.to_string(), v.to_string());
+        match &graph.nodes.last().unwrap().kind {
+            NodeKind::Label { labels } => assert_eq!(
+                labels,
+                &vec![
+                    pair("single", "$X"),
+                    pair("double", "val"),
+                    pair("escaped", "$X")
+                ]
+            ),
+            other => panic!("Expected LABEL, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_global_arg_parameterizes_from() {
+        let dockerfile = "ARG BASE=alpine\nARG TAG=3.20\nFROM ${BASE}:${TAG}\nRUN echo hi";
+        let graph = graph_with(dockerfile, &[]);
+        assert_eq!(graph.nodes.len(), 2);
+        assert_eq!(graph.nodes[0].content, "FROM alpine:3.20");
+        assert!(graph.nodes[1].deps == vec![0]);
+
+        let graph = graph_with(dockerfile, &[("TAG", "3.19")]);
+        assert_eq!(graph.nodes[0].content, "FROM alpine:3.19");
+    }
+
+    #[test]
+    fn test_global_arg_needs_redeclaration_in_stage() {
+        let graph = graph_with("ARG V=1\nFROM alpine\nRUN echo $V", &[]);
+        assert!(!graph.nodes[1].env.contains_key("V"));
+
+        let graph = graph_with("ARG V=1\nFROM alpine\nARG V\nRUN echo $V", &[]);
+        assert!(matches!(graph.nodes[1].kind, NodeKind::Arg));
+        assert_eq!(graph.nodes[2].env.get("V").map(String::as_str), Some("1"));
+        // RUN text is left to the shell
+        assert_eq!(graph.nodes[2].content, "echo $V");
+    }
+
+    #[test]
+    fn test_stage_args_do_not_leak_into_next_stage() {
+        let graph = graph_with(
+            "FROM alpine\nARG MODE=debug\nRUN a\nFROM alpine\nRUN b",
+            &[("MODE", "release")],
+        );
+        assert_eq!(
+            graph.nodes[2].env.get("MODE").map(String::as_str),
+            Some("release")
+        );
+        assert!(graph.nodes[4].env.is_empty());
+    }
+
+    #[test]
+    fn test_env_substituted_into_later_instructions() {
+        let dockerfile = r#"
+FROM alpine AS base
+ARG APP=/opt/app
+ENV APP_HOME=${APP}/current
+WORKDIR $APP_HOME
+COPY config.toml ${APP_HOME}/config.toml
+ENV APP=/override
+RUN run
+FROM base
+WORKDIR ${APP_HOME:-/nowhere}
+"#;
+        let graph =
+            build_graph_from_instructions(parse_dockerfile(dockerfile), std::env::temp_dir());
+        let contents: Vec<&str> = graph.nodes.iter().map(|n| n.content.as_str()).collect();
+        assert!(contents.contains(&"WORKDIR /opt/app/current"));
+        assert!(contents.contains(&"COPY config.toml /opt/app/current/config.toml"));
+
+        // ENV takes precedence over an ARG of the same name
+        let run = graph.nodes.iter().find(|n| n.content == "run").unwrap();
+        assert_eq!(run.env.get("APP").map(String::as_str), Some("/override"));
+
+        // ENV is inherited by stages built on top of this one
+        assert_eq!(
+            graph.nodes.last().unwrap().content,
+            "WORKDIR /opt/app/current"
+        );
+    }
+
+    #[test]
+    fn test_copy_heredoc_expands_unless_quoted() {
+        let graph = graph_with(
+            "FROM alpine\nARG PORT=80\nCOPY <<EOF /a\nport=$PORT\nEOF\nCOPY <<'EOF' /b\nport=$PORT\nEOF",
+            &[],
+        );
+        assert!(graph.nodes[2].content.contains("port=80"));
+        assert!(graph.nodes[3].content.contains("port=$PORT"));
+    }
+
+    #[test]
+    fn test_build_arg_invalidates_only_affected_nodes() {
+        let dockerfile = r#"
+FROM alpine
+WORKDIR /app
+ARG VERSION=1.0
+ARG UNUSED
+COPY config.toml /app/
+RUN echo building $VERSION
+CMD ["run"]
+"#;
+        let keys = |args: &[(&str, &str)]| {
+            let mut graph = graph_with(dockerfile, args);
+            compute_composite_hashes(&mut graph, &EnvFingerprint::default());
+            graph
+                .nodes
+                .iter()
+                .map(|n| n.hash.clone())
+                .collect::<Vec<_>>()
+        };
+
+        let default = keys(&[]);
+        let explicit = keys(&[("VERSION", "1.0")]);
+        let changed = keys(&[("VERSION", "2.0")]);
+        assert_eq!(default, explicit);
+
+        // FROM, WORKDIR, both ARGs and COPY come before the first use of VERSION
+        assert_eq!(default[..5], changed[..5]);
+        assert_ne!(default[5], changed[5]);
+        // CMD only changes image configuration and does not build on the RUN
+        assert_eq!(default[6], changed[6]);
+    }
+
+    #[test]
+    fn test_undeclared_build_args_are_ignored() {
+        let dockerfile = "FROM alpine\nRUN echo $SECRET";
+        let graph = graph_with(dockerfile, &[("SECRET", "x")]);
+        assert!(graph.nodes[1].env.is_empty());
+    }
+}
+
+/// Tests for typed instruction variants, exec/shell form and SHELL
+#[cfg(test)]
+mod instruction_coverage_tests {
+    use memobuild::docker::dag::build_graph_from_instructions;
+    use memobuild::docker::parser::{parse_dockerfile, CommandForm, Instruction};
+    use memobuild::export::config::{image_config, parse_duration_ns};
+    use memobuild::graph::{BuildGraph, NodeKind};
+    use memobuild::sandbox::local::LocalSandbox;
+    use memobuild::sandbox::{command_argv, Sandbox};
+
+    const DOCKERFILE: &str = r#"
+FROM debian:bookworm-slim
+ADD app.tar.gz /opt/
+USER app:app
+EXPOSE 8080 9090/udp
+LABEL org.opencontainers.image.title="My App" version=1.0
+VOLUME ["/data", "/logs"]
+HEALTHCHECK --interval=30s --timeout=5s --retries=3 CMD curl -f http://localhost:8080/ || exit 1
+STOPSIGNAL SIGTERM
+ONBUILD COPY . /src
+WORKDIR /srv
+WORKDIR app
+ENTRYPOINT ["/usr/bin/app"]
+CMD --serve
+"#;
+
+    fn graph(dockerfile: &str) -> BuildGraph {
+        build_graph_from_instructions(parse_dockerfile(dockerfile), std::env::temp_dir())
+    }
+
+    fn shell(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_command_form_parsing() {
+        assert_eq!(
+            CommandForm::parse(r#"["npm", "start"]"#),
+            CommandForm::Exec(shell(&["npm", "start"]))
+        );
+        assert_eq!(
+            CommandForm::parse("npm start"),
+            CommandForm::Shell("npm start".to_string())
+        );
+        // Malformed JSON falls back to shell form, as in Docker
+        assert_eq!(
+            CommandForm::parse("[npm, start]"),
+            CommandForm::Shell("[npm, start]".to_string())
+        );
+        assert_eq!(
+            CommandForm::Shell("ls".into()).argv(&shell(&["/bin/sh", "-c"])),
+            shell(&["/bin/sh", "-c", "ls"])
+        );
+    }
+
+    #[test]
+    fn test_instructions_parse_into_typed_variants() {
+        let instructions = parse_dockerfile(DOCKERFILE);
+        assert!(instructions
+            .iter()
+            .all(|i| !matches!(i, Instruction::Other(_))));
+
+        assert!(
+            matches!(&instructions[1], Instruction::Add(spec) if spec.sources == vec!["app.tar.gz".to_string()] && spec.dst == "/opt/")
+        );
+        assert!(matches!(&instructions[2], Instruction::User(u) if u == "app:app"));
+        match &instructions[4] {
+            Instruction::Label(labels) => assert_eq!(
+                labels,
+                &vec![
+                    (
+                        "org.opencontainers.image.title".to_string(),
+                        "\"My App\"".to_string()
+                    ),
+                    ("version".to_string(), "1.0".to_string()),
+                ]
+            ),
+            other => panic!("Expected LABEL, got {:?}", other),
+        }
+        assert!(
+            matches!(&instructions[5], Instruction::Volume(paths) if paths == &shell(&["/data", "/logs"]))
+        );
+        match &instructions[6] {
+            Instruction::Healthcheck(config) => {
+                assert_eq!(config.interval.as_deref(), Some("30s"));
+                assert_eq!(config.retries, Some(3));
+                assert_eq!(
+                    config.test,
+                    shell(&["CMD-SHELL", "curl -f http://localhost:8080/ || exit 1"])
+                );
+            }
+            other => panic!("Expected HEALTHCHECK, got {:?}", other),
+        }
+        assert!(matches!(&instructions[8], Instruction::Onbuild(t) if t == "COPY . /src"));
+    }
+
+    #[test]
+    fn test_healthcheck_none_and_invalid_shell() {
+        let instructions = parse_dockerfile(
+            "HEALTHCHECK NONE\nSHELL /bin/bash -c\nLABEL legacy value with spaces",
+        );
+        assert!(
+            matches!(&instructions[0], Instruction::Healthcheck(c) if c.test == shell(&["NONE"]))
+        );
+        // SHELL requires the JSON form, so it is reported and left out
+        assert_eq!(instructions.len(), 2);
+        assert!(matches!(
+            &instructions[1],
+            Instruction::Label(l) if l == &vec![("legacy".to_string(), "value with spaces".to_string())]
+        ));
+    }
+
+    #[test]
+    fn test_shell_instruction_changes_command_wrapping() {
+        let graph = graph(
+            "FROM alpine\nRUN echo one\nCMD serve\nSHELL [\"/bin/bash\", \"-euo\", \"pipefail\", \"-c\"]\nRUN echo two\nRUN [\"echo\", \"three\"]\nCMD serve",
+        );
+        let default_shell = shell(&["/bin/sh", "-c"]);
+
+        assert_eq!(
+            command_argv(&graph.nodes[1], &default_shell),
+            Some(shell(&["/bin/sh", "-c", "echo one"]))
+        );
+        assert!(matches!(
+            &graph.nodes[2].kind,
+            NodeKind::Cmd { command } if command == &shell(&["/bin/sh", "-c", "serve"])
+        ));
+        assert_eq!(
+            command_argv(&graph.nodes[4], &default_shell),
+            Some(shell(&["/bin/bash", "-euo", "pipefail", "-c", "echo two"]))
+        );
+        // Exec form ignores SHELL
+        assert_eq!(
+            command_argv(&graph.nodes[5], &default_shell),
+            Some(shell(&["echo", "three"]))
+        );
+        assert!(matches!(
+            &graph.nodes[6].kind,
+            NodeKind::Cmd { command } if command[0] == "/bin/bash" && command[4] == "serve"
+        ));
+    }
+
+    #[test]
+    fn test_shell_is_part_of_the_cache_key() {
+        use memobuild::core::compute_composite_hashes;
+        use memobuild::env::EnvFingerprint;
+
+        let key = |dockerfile: &str| {
+            let mut g = graph(dockerfile);
+            compute_composite_hashes(&mut g, &EnvFingerprint::default());
+            g.nodes.last().unwrap().hash.clone()
+        };
+        assert_ne!(
+            key("FROM alpine\nRUN echo hi"),
+            key("FROM alpine\nSHELL [\"/bin/bash\", \"-c\"]\nRUN echo hi")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_local_sandbox_runs_exec_form_and_shell() {
+        let dir = tempfile::tempdir().unwrap();
+        let sandbox = LocalSandbox::new(dir.path().to_path_buf());
+        let graph = graph(
+            "FROM alpine\nRUN [\"echo\", \"$HOME is literal\"]\nSHELL [\"/bin/sh\", \"-ec\"]\nRUN echo shell form",
+        );
+
+        for (node, expected) in [
+            (&graph.nodes[1], "$HOME is literal\n"),
+            (&graph.nodes[3], "shell form\n"),
+        ] {
+            let env = sandbox.prepare(node).await.unwrap();
+            let result = sandbox.execute(&env, node).await.unwrap();
+            assert_eq!(result.exit_code, 0);
+            assert_eq!(String::from_utf8_lossy(&result.stdout), expected);
+        }
+    }
+
+    #[test]
+    fn test_image_config_export() {
+        let config = image_config(&graph(DOCKERFILE));
+
+        assert_eq!(config.entrypoint, Some(shell(&["/usr/bin/app"])));
+        assert_eq!(config.cmd, Some(shell(&["/bin/sh", "-c", "--serve"])));
+        assert_eq!(config.user.as_deref(), Some("app:app"));
+        assert!(config.exposed_ports.contains_key("8080/tcp"));
+        assert!(config.exposed_ports.contains_key("9090/udp"));
+        assert_eq!(
+            config
+                .labels
+                .get("org.opencontainers.image.title")
+                .map(String::as_str),
+            Some("My App")
+        );
+        assert!(config.volumes.contains_key("/data"));
+        assert_eq!(config.stop_signal.as_deref(), Some("SIGTERM"));
+        assert_eq!(config.on_build, shell(&["COPY . /src"]));
+        assert_eq!(config.working_dir.as_deref(), Some("/srv/app"));
+
+        let healthcheck = config.healthcheck.unwrap();
+        assert_eq!(healthcheck.interval, Some(30_000_000_000));
+        assert_eq!(healthcheck.timeout, Some(5_000_000_000));
+        assert_eq!(healthcheck.retries, Some(3));
+
+        let json =
+            serde_json::to_value(image_config(&graph("FROM alpine\nENV A=1\nENV A=2"))).unwrap();
+        assert_eq!(json["Env"], serde_json::json!(["A=2"]));
+        assert_eq!(json["Cmd"], serde_json::json!(["/bin/sh"]));
+        assert!(json.get("Labels").is_none());
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration_ns("1m30s"), Some(90_000_000_000));
+        assert_eq!(parse_duration_ns("500ms"), Some(500_000_000));
+        assert_eq!(parse_duration_ns("1.5h"), Some(5_400_000_000_000));
+        assert_eq!(parse_duration_ns("10"), None);
+        assert_eq!(parse_duration_ns("abc"), None);
+    }
+}
+
+#[cfg(test)]
+mod copy_flag_tests {
+    use memobuild::core::{detect_changes_against, ContextState};
+    use memobuild::docker::dag::build_graph_from_instructions;
+    use memobuild::docker::parser::{is_url, parse_dockerfile, Instruction};
+    use memobuild::export::files::{layer_entries, parse_chown};
+    use memobuild::export::layer::create_layer_tar;
+    use memobuild::graph::{BuildGraph, Node, NodeKind};
+    use std::path::{Path, PathBuf};
+
+    fn graph(dockerfile: &str) -> BuildGraph {
+        build_graph_from_instructions(parse_dockerfile(dockerfile), PathBuf::from("."))
+    }
+
+    fn graph_in(dockerfile: &str, context: &Path) -> BuildGraph {
+        build_graph_from_instructions(parse_dockerfile(dockerfile), context.to_path_buf())
+    }
+
+    fn source_hash(dockerfile: &str, context: &Path) -> Option<String> {
+        let mut graph = graph_in(dockerfile, context);
+        detect_changes_against(&mut graph, context, &ContextState::default()).unwrap();
+        graph.nodes[1].metadata.source_content_hash.clone()
+    }
+
+    #[test]
+    fn test_copy_flags_are_parsed() {
+        let instructions = parse_dockerfile(
+            "COPY --chown=app:app --chmod=644 --link --exclude=*.md --exclude=tmp a.txt b.txt /dst/",
+        );
+        match &instructions[0] {
+            Instruction::Copy(spec) => {
+                assert_eq!(spec.sources, vec!["a.txt", "b.txt"]);
+                assert_eq!(spec.dst, "/dst/");
+                assert_eq!(spec.options.chown.as_deref(), Some("app:app"));
+                assert_eq!(spec.options.chmod.as_deref(), Some("644"));
+                assert!(spec.options.link);
+                assert_eq!(spec.options.exclude, vec!["*.md", "tmp"]);
+            }
+            other => panic!("Expected COPY, got {:?}", other),
+        }
+
+        match &parse_dockerfile(r#"COPY ["my file.txt", "other.txt", "/app/"]"#)[0] {
+            Instruction::Copy(spec) => {
+                assert_eq!(spec.sources, vec!["my file.txt", "other.txt"]);
+                assert_eq!(spec.dst, "/app/");
+            }
+            other => panic!("Expected COPY, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_add_url_and_checksum() {
+        let instructions =
+            parse_dockerfile("ADD --checksum=sha256:abc https://example.com/tool.tar.gz /opt/");
+        match &instructions[0] {
+            Instruction::Add(spec) => {
+                assert_eq!(spec.checksum.as_deref(), Some("sha256:abc"));
+                assert!(is_url(&spec.sources[0]));
+            }
+            other => panic!("Expected ADD, got {:?}", other),
+        }
+        assert!(!is_url("app.tar.gz"));
+
+        let graph = graph("FROM alpine\nADD --checksum=sha256:abc https://example.com/t.tgz /opt/");
+        let node = &graph.nodes[1];
+        assert!(node.content.contains("--checksum=sha256:abc"));
+        assert!(
+            node.source_path.is_none(),
+            "URL sources are not hashed from the context"
+        );
+        match &node.kind {
+            NodeKind::Add { checksum, .. } => assert_eq!(checksum.as_deref(), Some("sha256:abc")),
+            other => panic!("Expected Add, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_flags_change_node_content() {
+        let plain = graph("FROM alpine\nCOPY a.txt /app/");
+        let owned = graph("FROM alpine\nCOPY --chown=1000 a.txt /app/");
+        assert_ne!(plain.nodes[1].content, owned.nodes[1].content);
+        assert!(owned.nodes[1].content.contains("--chown=1000"));
+    }
+
+    #[test]
+    fn test_destination_resolves_against_workdir() {
+        let graph = graph("FROM alpine\nWORKDIR /srv\nCOPY a.txt b.txt app/\nCOPY c.txt ../c.txt");
+        match &graph.nodes[2].kind {
+            NodeKind::Copy { sources, dst, .. } => {
+                assert_eq!(sources.len(), 2);
+                assert_eq!(dst.to_string_lossy(), "/srv/app/");
+            }
+            other => panic!("Expected Copy, got {:?}", other),
+        }
+        match &graph.nodes[3].kind {
+            NodeKind::Copy { dst, .. } => assert_eq!(dst.to_string_lossy(), "/c.txt"),
+            other => panic!("Expected Copy, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_link_copy_does_not_depend_on_previous_layers() {
+        let graph = graph(
+            "FROM alpine AS build\nRUN make\n\
+             FROM alpine\nRUN apt-get install -y curl\nCOPY --link --from=build /out /app\nRUN ls /app",
+        );
+        let link = graph
+            .nodes
+            .iter()
+            .find(|n| n.content.contains("--link"))
+            .unwrap();
+        let install = graph
+            .nodes
+            .iter()
+            .find(|n| n.content.contains("apt-get"))
+            .unwrap();
+        let last = graph.nodes.last().unwrap();
+
+        assert!(!link.deps.contains(&install.id));
+        assert!(
+            link.deps.contains(&1),
+            "depends on the stage it copies from"
+        );
+        assert!(last.deps.contains(&link.id));
+        assert!(last.deps.contains(&install.id));
+    }
+
+    #[test]
+    fn test_glob_sources_and_excludes_affect_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), "a").unwrap();
+        std::fs::write(dir.path().join("b.txt"), "b").unwrap();
+        std::fs::create_dir(dir.path().join("src")).unwrap();
+        std::fs::write(dir.path().join("src/main.rs"), "fn main() {}").unwrap();
+        std::fs::write(dir.path().join("src/notes.md"), "v1").unwrap();
+
+        let glob = "FROM alpine\nCOPY *.txt /app/";
+        let before = source_hash(glob, dir.path());
+        assert!(before.is_some());
+        std::fs::write(dir.path().join("b.txt"), "changed").unwrap();
+        assert_ne!(before, source_hash(glob, dir.path()));
+
+        let excluded = "FROM alpine\nCOPY --exclude=*.md src /app/src";
+        let before = source_hash(excluded, dir.path());
+        std::fs::write(dir.path().join("src/notes.md"), "v2").unwrap();
+        assert_eq!(before, source_hash(excluded, dir.path()));
+        std::fs::write(dir.path().join("src/main.rs"), "fn main() { }").unwrap();
+        assert_ne!(before, source_hash(excluded, dir.path()));
+    }
+
+    #[test]
+    fn test_parse_chown() {
+        let numeric = parse_chown("1000:2000");
+        assert_eq!((numeric.uid, numeric.gid), (1000, 2000));
+        assert!(numeric.user.is_none());
+
+        let named = parse_chown("app");
+        assert_eq!(named.user.as_deref(), Some("app"));
+        assert_eq!(named.group.as_deref(), Some("app"));
+    }
+
+    fn layer_files(node: &Node, context: &Path) -> Vec<(String, u64, u64, u32)> {
+        let out = tempfile::tempdir().unwrap();
+        let ignore = memobuild::hasher::IgnoreRules::for_context(context, None);
+        let info = create_layer_tar(out.path(), node, Some(context), &ignore).unwrap();
+        let hex = info.digest.trim_start_matches("sha256:");
+        let data = std::fs::read(out.path().join("blobs/sha256").join(hex)).unwrap();
+        let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(&data[..]));
+        archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                let header = entry.header();
+                (
+                    entry.path().unwrap().to_string_lossy().to_string(),
+                    header.uid().unwrap(),
+                    header.gid().unwrap(),
+                    header.mode().unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_layer_applies_chown_and_chmod() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("run.sh"), "#!/bin/sh").unwrap();
+        let graph = graph_in(
+            "FROM alpine\nCOPY --chown=1000:1000 --chmod=755 run.sh /app/",
+            dir.path(),
+        );
+
+        let files = layer_files(&graph.nodes[1], dir.path());
+        let entry = files
+            .iter()
+            .find(|(path, ..)| path == "app/run.sh")
+            .unwrap_or_else(|| panic!("run.sh missing from {:?}", files));
+        assert_eq!((entry.1, entry.2, entry.3), (1000, 1000, 0o755));
+    }
+
+    #[test]
+    fn test_add_extracts_local_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = std::fs::File::create(dir.path().join("bundle.tar.gz")).unwrap();
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+            archive,
+            flate2::Compression::default(),
+        ));
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        header.set_mode(0o640);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "bin/tool", &b"hello"[..])
+            .unwrap();
+        builder.into_inner().unwrap().finish().unwrap();
+
+        let graph = graph_in("FROM alpine\nADD bundle.tar.gz /opt/", dir.path());
+        let files = layer_files(&graph.nodes[1], dir.path());
+        let tool = files
+            .iter()
+            .find(|(path, ..)| path == "opt/bin/tool")
+            .unwrap_or_else(|| panic!("archive not extracted: {:?}", files));
+        assert_eq!(tool.3, 0o640);
+        assert!(!files
+            .iter()
+            .any(|(path, ..)| path.ends_with("bundle.tar.gz")));
+    }
+
+    fn archive_header(kind: tar::EntryType, name: &[u8], size: u64) -> tar::Header {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(kind);
+        // Written raw, as set_path refuses the `..` a hostile archive can contain
+        header.as_old_mut().name[..name.len()].copy_from_slice(name);
+        header.set_size(size);
+        header.set_mode(0o755);
+        header
+    }
+
+    #[test]
+    fn test_add_archive_keeps_links_and_rejects_escapes() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut tool = archive_header(tar::EntryType::Regular, b"bin/tool", 5);
+        tool.set_cksum();
+        builder.append(&tool, &b"hello"[..]).unwrap();
+        let mut symlink = archive_header(tar::EntryType::Symlink, b"bin/sh", 0);
+        symlink.set_link_name("tool").unwrap();
+        symlink.set_cksum();
+        builder.append(&symlink, &b""[..]).unwrap();
+        let mut hardlink = archive_header(tar::EntryType::Link, b"./bin/../bin/again", 0);
+        hardlink.set_link_name("bin/tool").unwrap();
+        hardlink.set_cksum();
+        builder.append(&hardlink, &b""[..]).unwrap();
+        std::fs::write(dir.path().join("links.tar"), builder.into_inner().unwrap()).unwrap();
+
+        let graph = graph_in("FROM alpine\nADD links.tar /opt/", dir.path());
+        let ignore = memobuild::hasher::IgnoreRules::for_context(dir.path(), None);
+        let entries = layer_entries(&graph.nodes[1], dir.path(), &ignore).unwrap();
+        let find = |path: &str| {
+            entries
+                .iter()
+                .find(|entry| entry.path == path)
+                .unwrap_or_else(|| panic!("{} missing from {:?}", path, entries))
+        };
+        assert_eq!(find("opt/bin/sh").link_target.as_deref(), Some("tool"));
+        assert_eq!(find("opt/bin/again").data, b"hello");
+        assert_eq!(find("opt/bin/again").link_target, None);
+
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut evil = archive_header(tar::EntryType::Regular, b"bin/../../etc/passwd", 4);
+        evil.set_cksum();
+        builder.append(&evil, &b"root"[..]).unwrap();
+        std::fs::write(dir.path().join("evil.tar"), builder.into_inner().unwrap()).unwrap();
+        let graph = graph_in("FROM alpine\nADD evil.tar /opt/", dir.path());
+        assert!(layer_entries(&graph.nodes[1], dir.path(), &ignore).is_err());
+    }
+}
+
+#[cfg(test)]
+mod run_mount_tests {
+    use memobuild::core::{detect_changes_against, ContextState};
+    use memobuild::docker::dag::build_graph_from_instructions;
+    use memobuild::docker::parser::{
+        parse_dockerfile, parse_dockerfile_with_diagnostics, Instruction,
+    };
+    use memobuild::graph::{BuildGraph, CacheSharing, RunMount};
+    use memobuild::sandbox::local::LocalSandbox;
+    #[cfg(target_os = "linux")]
+    use memobuild::sandbox::rootless::RootlessSandbox;
+    use memobuild::sandbox::{NetworkPolicy, Sandbox};
+    use memobuild::secrets::EnvSecretProvider;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+
+    fn graph_in(dockerfile: &str, context: &Path) -> BuildGraph {
+        build_graph_from_instructions(parse_dockerfile(dockerfile), context.to_path_buf())
+    }
+
+    fn mounts(graph: &BuildGraph, id: usize) -> Vec<RunMount> {
+        match &graph.nodes[id].kind {
+            memobuild::graph::NodeKind::Run { mounts } => mounts.clone(),
+            other => panic!("Expected Run, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_mount_flags_are_parsed_out_of_the_command() {
+        let instructions = parse_dockerfile(
+            "RUN --mount=type=cache,target=/root/.cargo/registry,sharing=locked \\\n    --mount=type=secret,id=npm_token --network=none cargo build",
+        );
+        assert!(matches!(
+            instructions[0],
+            Instruction::Network(NetworkPolicy::None)
+        ));
+        match &instructions[1] {
+            Instruction::Run(cmd, mounts) => {
+                assert_eq!(cmd, "cargo build");
+                assert_eq!(
+                    mounts[0],
+                    RunMount::Cache {
+                        target: PathBuf::from("/root/.cargo/registry"),
+                        id: "/root/.cargo/registry".to_string(),
+                        sharing: CacheSharing::Locked,
+                        readonly: false,
+                    }
+                );
+                assert_eq!(
+                    mounts[1],
+                    RunMount::Secret {
+                        id: "npm_token".to_string(),
+                        target: Some(PathBuf::from("/run/secrets/npm_token")),
+                        env: None,
+                        required: false,
+                    }
+                );
+            }
+            other => panic!("Expected RUN, got {:?}", other),
+        }
+
+        match &parse_dockerfile("RUN --mount=target=/src,ro make")[0] {
+            Instruction::Run(_, mounts) => assert!(matches!(
+                &mounts[0],
+                RunMount::Bind {
+                    readwrite: false,
+                    source: None,
+                    ..
+                }
+            )),
+            other => panic!("Expected RUN, got {:?}", other),
+        }
+        for invalid in [
+            "FROM alpine\nRUN --mount=type=cache make",
+            "FROM alpine\nRUN --mount=type=nfs,target=/x make",
+        ] {
+            let parsed = parse_dockerfile_with_diagnostics(invalid);
+            assert_eq!(parsed.instructions.len(), 1);
+            assert_eq!(parsed.diagnostics[0].code, "invalid-mount");
+            assert_eq!(parsed.diagnostics[0].span.line, 2);
+        }
+    }
+
+    #[test]
+    fn test_mounts_resolve_against_workdir_and_change_the_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let graph = graph_in(
+            "FROM alpine\nARG CACHE=pip\nWORKDIR /app\nRUN --mount=type=cache,id=$CACHE,target=.cache pip install",
+            dir.path(),
+        );
+        assert_eq!(
+            mounts(&graph, 3),
+            vec![RunMount::Cache {
+                target: PathBuf::from("/app/.cache"),
+                id: "pip".to_string(),
+                sharing: CacheSharing::Shared,
+                readonly: false,
+            }]
+        );
+        assert_eq!(graph.nodes[3].content, "pip install");
+
+        let plain = graph_in("FROM alpine\nRUN pip install", dir.path());
+        let key = |g: &BuildGraph, id: usize| g.nodes[id].compute_node_key(&[], None, None);
+        assert_ne!(key(&graph, 3), key(&plain, 1));
+    }
+
+    #[test]
+    fn test_bind_mounts_add_dependencies_and_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("requirements.txt"), "flask").unwrap();
+        let dockerfile = "FROM alpine AS deps\nRUN make\n\
+             FROM alpine\nRUN --mount=type=bind,from=deps,source=/out,target=/deps ls /deps\n\
+             RUN --mount=type=bind,source=requirements.txt,target=/tmp/req.txt pip install -r /tmp/req.txt";
+        let mut graph = graph_in(dockerfile, dir.path());
+        assert!(graph.nodes[3].deps.contains(&1));
+
+        detect_changes_against(&mut graph, dir.path(), &ContextState::default()).unwrap();
+        let before = graph.nodes[4].metadata.source_content_hash.clone();
+        assert!(before.is_some());
+
+        std::fs::write(dir.path().join("requirements.txt"), "django").unwrap();
+        let mut graph = graph_in(dockerfile, dir.path());
+        detect_changes_against(&mut graph, dir.path(), &ContextState::default()).unwrap();
+        assert_ne!(before, graph.nodes[4].metadata.source_content_hash);
+    }
+
+    async fn run_all(sandbox: &dyn Sandbox, graph: &BuildGraph) -> Vec<String> {
+        let mut outputs = Vec::new();
+        for node in &graph.nodes[1..] {
+            let env = sandbox.prepare(node).await.unwrap();
+            let result = sandbox.execute(&env, node).await.unwrap();
+            sandbox.cleanup(&env).await.unwrap();
+            assert_eq!(
+                result.exit_code,
+                0,
+                "{}",
+                String::from_utf8_lossy(&result.stderr)
+            );
+            outputs.push(String::from_utf8_lossy(&result.stdout).to_string());
+        }
+        outputs
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_cache_mount_persists_between_runs() {
+        if !RootlessSandbox::is_supported() {
+            return;
+        }
+        let context = tempfile::tempdir().unwrap();
+        let work = tempfile::tempdir().unwrap();
+        let caches = tempfile::tempdir().unwrap();
+        let sandbox = RootlessSandbox::new(context.path().to_path_buf(), work.path().to_path_buf())
+            .with_cache_mount_dir(caches.path().to_path_buf());
+        let mut graph = graph_in(
+            "FROM scratch\n\
+             RUN --mount=type=cache,id=deps,target=/cache echo warm > /cache/marker\n\
+             RUN --mount=type=cache,id=deps,target=/var/cache cat /var/cache/marker\n\
+             RUN --mount=type=tmpfs,target=/scratch ls /scratch",
+            context.path(),
+        );
+        graph.assign_input_layers();
+
+        let outputs = run_all(&sandbox, &graph).await;
+        assert_eq!(outputs[1], "warm\n");
+        assert_eq!(outputs[2], "");
+        // Nothing lands in the build context
+        assert_eq!(std::fs::read_dir(context.path()).unwrap().count(), 0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_secret_mounts_stay_out_of_keys_and_output() {
+        if !RootlessSandbox::is_supported() {
+            return;
+        }
+        let context = tempfile::tempdir().unwrap();
+        let work = tempfile::tempdir().unwrap();
+        std::env::set_var("mbmounttest_API_TOKEN", "s3cr3t-value");
+        let sandbox = RootlessSandbox::new(context.path().to_path_buf(), work.path().to_path_buf())
+            .with_secrets(Arc::new(EnvSecretProvider::new("mbmounttest".to_string())));
+        let mut graph = graph_in(
+            "FROM scratch\n\
+             RUN --mount=type=secret,id=api_token,env=TOKEN,target=/run/secrets/api_token cat /run/secrets/api_token; echo \" $TOKEN\"\n\
+             RUN --mount=type=secret,id=api_token cat /run/secrets/api_token",
+            context.path(),
+        );
+        graph.assign_input_layers();
+
+        let outputs = run_all(&sandbox, &graph).await;
+        assert_eq!(outputs, vec!["**** ****\n", "****"]);
+        assert_eq!(std::fs::read_dir(context.path()).unwrap().count(), 0);
+
+        let serialized = serde_json::to_string(&graph).unwrap();
+        assert!(!serialized.contains("s3cr3t-value"));
+        assert!(!graph.nodes[1].env.values().any(|v| v.contains("s3cr3t")));
+
+        let required = graph_in(
+            "FROM scratch\nRUN --mount=type=secret,id=missing,required=true true",
+            context.path(),
+        );
+        let env = sandbox.prepare(&required.nodes[1]).await.unwrap();
+        assert!(sandbox.execute(&env, &required.nodes[1]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_host_sandbox_only_takes_mounts_without_targets() {
+        let workspace = tempfile::tempdir().unwrap();
+        let caches = tempfile::tempdir().unwrap();
+        std::env::set_var("mbhostmount_API_TOKEN", "hunter2-value");
+        let sandbox = LocalSandbox::new(workspace.path().to_path_buf())
+            .with_cache_mount_dir(caches.path().to_path_buf())
+            .with_secrets(Arc::new(EnvSecretProvider::new("mbhostmount".to_string())));
+
+        // Cache and tmpfs mounts are skipped and env secrets still work
+        let graph = graph_in(
+            "FROM alpine\n\
+             RUN --mount=type=cache,target=/root/.cargo/registry --mount=type=tmpfs,target=/scratch echo built\n\
+             RUN --mount=type=secret,id=api_token,env=TOKEN echo \"token $TOKEN\"",
+            workspace.path(),
+        );
+        let outputs = run_all(&sandbox, &graph).await;
+        assert_eq!(outputs, vec!["built\n", "token ****\n"]);
+        assert_eq!(std::fs::read_dir(workspace.path()).unwrap().count(), 0);
+        assert_eq!(std::fs::read_dir(caches.path()).unwrap().count(), 0);
+
+        // Redaction leaves binary output intact
+        let binary = graph_in(
+            "FROM alpine\nRUN --mount=type=secret,id=api_token,env=TOKEN printf '\\377\\376%s' \"$TOKEN\"",
+            workspace.path(),
+        );
+        let env = sandbox.prepare(&binary.nodes[1]).await.unwrap();
+        let result = sandbox.execute(&env, &binary.nodes[1]).await.unwrap();
+        assert_eq!(result.stdout, b"\xff\xfe****");
+
+        // Mounts a command cannot do without are refused rather than placed on the host
+        for refused in [
+            "FROM alpine\nRUN --mount=type=secret,id=api_token cat /run/secrets/api_token",
+            "FROM alpine\nRUN --mount=type=bind,target=/src ls /src",
+        ] {
+            let graph = graph_in(refused, workspace.path());
+            let env = sandbox.prepare(&graph.nodes[1]).await.unwrap();
+            let error = sandbox.execute(&env, &graph.nodes[1]).await.unwrap_err();
+            assert!(
+                error.to_string().contains("--sandbox rootless"),
+                "{}",
+                error
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod diagnostics_tests {
+    use memobuild::docker::lint::lint_dockerfile;
+    use memobuild::docker::parser::{parse_dockerfile_with_diagnostics, Instruction, Severity};
+
+    fn codes(dockerfile: &str) -> Vec<(String, usize)> {
+        lint_dockerfile(dockerfile)
+            .into_iter()
+            .map(|d| (d.code, d.span.line))
+            .collect()
+    }
+
+    #[test]
+    fn test_malformed_instructions_are_reported_with_spans() {
+        let parsed = parse_dockerfile_with_diagnostics(
+            "FROM alpine:3.19\n\n  COPY onlyone\nENV FOO\nRUN echo \\\n    ok\nFROBNICATE x\nWORKDIR",
+        );
+
+        let found: Vec<(&str, usize, usize)> = parsed
+            .diagnostics
+            .iter()
+            .map(|d| (d.code.as_str(), d.span.line, d.span.column))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                ("missing-arguments", 3, 3),
+                ("missing-arguments", 4, 1),
+                ("unknown-instruction", 7, 1),
+                ("missing-arguments", 8, 1),
+            ]
+        );
+        assert!(parsed.has_errors());
+        assert!(parsed.diagnostics[0].message.contains("COPY"));
+
+        // Valid instructions are kept, each with its span
+        assert_eq!(parsed.instructions.len(), parsed.spans.len());
+        let run = parsed
+            .instructions
+            .iter()
+            .position(|i| matches!(i, Instruction::Run(..)))
+            .unwrap();
+        assert_eq!((parsed.spans[run].line, parsed.spans[run].end_line), (5, 6));
+    }
+
+    #[test]
+    fn test_warnings_and_missing_from() {
+        let parsed =
+            parse_dockerfile_with_diagnostics("ARG V=1\nENV A 1\nFROM alpine:3\nMAINTAINER me");
+        let found: Vec<(&str, Severity)> = parsed
+            .diagnostics
+            .iter()
+            .map(|d| (d.code.as_str(), d.severity))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                ("legacy-key-value", Severity::Warning),
+                ("deprecated", Severity::Warning),
+                ("missing-from", Severity::Error),
+            ]
+        );
+        assert_eq!(parsed.diagnostics[2].span.line, 2);
+    }
+
+    #[test]
+    fn test_lint_cache_hostile_patterns() {
+        let found = codes(
+            "FROM node\nWORKDIR /app\nCOPY . .\nRUN npm ci\n\
+             FROM debian:latest\nRUN apt-get update\nRUN apt-get install -y curl\n\
+             ADD https://example.com/tool.tgz /opt/\n\
+             FROM registry.local:5000/base\nFROM ubuntu@sha256:abc\nFROM ${BASE}",
+        );
+        assert_eq!(
+            found,
+            vec![
+                ("unpinned-base-image".to_string(), 1),
+                ("copy-context-before-install".to_string(), 3),
+                ("unpinned-base-image".to_string(), 5),
+                ("apt-get-update-without-install".to_string(), 6),
+                ("add-url-without-checksum".to_string(), 8),
+                ("unpinned-base-image".to_string(), 9),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lint_accepts_cache_friendly_dockerfile() {
+        let found = codes(
+            "FROM node:20-alpine AS deps\nWORKDIR /app\nCOPY package.json package-lock.json ./\n\
+             RUN npm ci\nCOPY . .\nRUN npm run build\n\
+             FROM deps\nRUN apt-get update && apt-get install -y curl\n\
+             ADD --checksum=sha256:abc https://example.com/tool.tgz /opt/",
+        );
+        assert!(found.is_empty(), "unexpected diagnostics: {:?}", found);
+    }
+
+    #[test]
+    fn test_diagnostics_serialize_for_ci() {
+        let diagnostics = lint_dockerfile("FROM alpine\nCOPY x");
+        let json = serde_json::to_value(&diagnostics).unwrap();
+        assert_eq!(json[0]["code"], "unpinned-base-image");
+        assert_eq!(json[1]["severity"], "error");
+        assert_eq!(json[1]["line"], 2);
+        assert_eq!(json[1]["column"], 1);
+    }
+}
+
+/// Tests for data-flow dependencies between instructions
+#[cfg(test)]
+mod data_flow_tests {
+    use memobuild::docker::dag::build_graph_from_instructions;
+    use memobuild::docker::parser::parse_dockerfile;
+    use memobuild::graph::BuildGraph;
+
+    fn graph(dockerfile: &str) -> BuildGraph {
+        build_graph_from_instructions(parse_dockerfile(dockerfile), std::env::temp_dir())
+    }
+
+    #[test]
+    fn test_metadata_instructions_share_one_level() {
+        let graph = graph(
+            "FROM alpine:3.19\nENV A=1\nLABEL team=core\nWORKDIR /app\nUSER app\nEXPOSE 80\n\
+             CMD [\"app\"]",
+        );
+        let levels = graph.levels();
+        assert_eq!(levels.len(), 2);
+        assert_eq!(levels[1], vec![1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn test_consecutive_copies_run_in_parallel() {
+        let graph = graph(
+            "FROM node:20\nWORKDIR /app\nCOPY package.json ./\nCOPY tsconfig.json ./\n\
+             RUN npm ci\nCOPY src ./src\nRUN npm run build",
+        );
+        let levels = graph.levels();
+        assert_eq!(
+            levels,
+            vec![vec![0], vec![1, 2, 3], vec![4], vec![5], vec![6]]
+        );
+        // The install sees both manifests and the WORKDIR it runs in
+        assert_eq!(graph.nodes[4].deps, vec![0, 1, 2, 3]);
+        // A copy after a RUN only needs that RUN
+        assert_eq!(graph.nodes[5].deps, vec![4]);
+        assert_eq!(graph.nodes[6].deps, vec![1, 4, 5]);
+    }
+
+    #[test]
+    fn test_multi_stage_levels() {
+        let graph = graph(
+            r#"
+FROM golang:1.22 AS build
+WORKDIR /src
+COPY go.mod go.sum ./
+RUN go mod download
+COPY . .
+RUN go build -o /out/app
+
+FROM alpine:3.19 AS runtime
+RUN apk add ca-certificates
+LABEL org.opencontainers.image.source=example
+COPY --from=build /out/app /usr/bin/app
+ENTRYPOINT ["/usr/bin/app"]
+"#,
+        );
+        let levels = graph.levels();
+        // Chaining every instruction took 8 levels; now the runtime stage's RUN, LABEL and
+        // ENTRYPOINT overlap with the build stage
+        assert_eq!(levels.len(), 6);
+        assert_eq!(levels[0], vec![0, 6]);
+        assert_eq!(levels[1], vec![1, 2, 7, 8, 10]);
+        assert_eq!(levels[5], vec![9]);
+        // COPY --from waits for the build stage, not for the runtime LABEL
+        assert_eq!(graph.nodes[9].deps, vec![7, 5]);
+    }
+
+    #[test]
+    fn test_run_depends_on_configuration_in_effect() {
+        let graph = graph(
+            "FROM alpine:3.19\nARG VERSION=1\nENV PATH=/opt/bin\nENV UNUSED=1\nENV PATH=/usr/bin\n\
+             SHELL [\"/bin/bash\", \"-c\"]\nUSER app\nRUN make",
+        );
+        // The ENV that was overridden no longer reaches the RUN
+        assert_eq!(graph.nodes[7].deps, vec![0, 1, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn test_from_stage_depends_on_everything_the_stage_produced() {
+        let graph = graph(
+            "FROM alpine:3.19 AS base\nRUN apk add git\nENV GIT=1\nCOPY --link bin /bin\n\
+             FROM base\nRUN git --version",
+        );
+        assert_eq!(graph.nodes[4].deps, vec![1, 2, 3]);
+        assert_eq!(graph.nodes[5].deps, vec![4]);
+        assert_eq!(graph.nodes[5].env.get("GIT").map(String::as_str), Some("1"));
+    }
+
+    #[test]
+    fn test_unknown_instructions_act_as_barriers() {
+        let graph = graph("FROM alpine:3.19\nCOPY a /a\nMAINTAINER someone\nCOPY b /b");
+        assert_eq!(graph.nodes[2].deps, vec![0, 1]);
+        assert_eq!(graph.nodes[3].deps, vec![2]);
+    }
+}
@@ -0,0 +1,222 @@
+/// Conformance tests for `.dockerignore` matching, taken from Docker's documentation and
+/// its pattern matcher's behaviour
+#[cfg(test)]
+mod dockerignore_tests {
+    use memobuild::core::detect_changes_against;
+    use memobuild::core::ContextState;
+    use memobuild::docker::dag::build_graph_from_instructions;
+    use memobuild::docker::parser::parse_dockerfile;
+    use memobuild::hasher::walker::walk_dir;
+    use memobuild::hasher::IgnoreRules;
+    use std::fs;
+    use std::path::Path;
+
+    fn ignored(rules: &str, path: &str) -> bool {
+        IgnoreRules::parse(rules).is_ignored(Path::new(path))
+    }
+
+    #[test]
+    fn test_documented_example() {
+        let rules = "# comment\n*/temp*\n*/*/temp*\ntemp?";
+        assert!(ignored(rules, "somedir/temporary.txt"));
+        assert!(ignored(rules, "somedir/temp"));
+        assert!(ignored(rules, "somedir/temp/file.txt"));
+        assert!(ignored(rules, "somedir/subdir/temporary.txt"));
+        assert!(ignored(rules, "tempa"));
+        assert!(ignored(rules, "tempb"));
+        assert!(!ignored(rules, "temporary.txt"));
+        assert!(!ignored(rules, "temp"));
+        assert!(!ignored(rules, "a/b/c/temporary.txt"));
+        assert!(!ignored(rules, "# comment"));
+    }
+
+    #[test]
+    fn test_double_star_matches_any_number_of_directories() {
+        assert!(ignored("**/*.go", "main.go"));
+        assert!(ignored("**/*.go", "cmd/tool/main.go"));
+        assert!(!ignored("**/*.go", "main.go.txt"));
+        assert!(ignored("docs/**/*.md", "docs/a.md"));
+        assert!(ignored("docs/**/*.md", "docs/x/y/a.md"));
+        assert!(!ignored("docs/**/*.md", "src/docs/a.md"));
+        // Trailing "**" matches everything below
+        assert!(ignored("build/**", "build/out/bin"));
+        assert!(ignored("**", "anything/at/all"));
+    }
+
+    #[test]
+    fn test_single_star_and_question_mark_stay_in_one_directory() {
+        assert!(ignored("*.md", "README.md"));
+        assert!(!ignored("*.md", "docs/README.md"));
+        assert!(ignored("a/?/c", "a/b/c"));
+        assert!(!ignored("a/?/c", "a/bb/c"));
+        assert!(!ignored("a*c", "ab/c"));
+    }
+
+    #[test]
+    fn test_exceptions_in_order() {
+        let rules = "*.md\n!README.md";
+        assert!(ignored(rules, "CHANGELOG.md"));
+        assert!(!ignored(rules, "README.md"));
+
+        // The last matching line wins
+        let rules = "*.md\n!README*.md\nREADME-secret.md";
+        assert!(ignored(rules, "README-secret.md"));
+        assert!(!ignored(rules, "README-public.md"));
+        assert!(ignored(rules, "CHANGELOG.md"));
+
+        let rules = "*.md\nREADME-secret.md\n!README*.md";
+        assert!(!ignored(rules, "README-secret.md"));
+        assert!(!ignored(rules, "README-public.md"));
+    }
+
+    #[test]
+    fn test_excluded_directory_with_reincluded_file() {
+        let rules = "node_modules\n!node_modules/keep/package.json";
+        assert!(ignored(rules, "node_modules"));
+        assert!(ignored(rules, "node_modules/left-pad/index.js"));
+        assert!(ignored(rules, "node_modules/keep/index.js"));
+        assert!(!ignored(rules, "node_modules/keep/package.json"));
+
+        let rules = IgnoreRules::parse(rules);
+        // The walk has to enter the excluded directory to find the exception
+        assert!(!rules.skips_dir(Path::new("node_modules")));
+        assert!(!rules.skips_dir(Path::new("node_modules/keep")));
+        assert!(rules.skips_dir(Path::new("node_modules/left-pad")));
+        assert!(IgnoreRules::parse("node_modules").skips_dir(Path::new("node_modules")));
+        // Exceptions with wildcards could match anywhere, so nothing is skipped
+        assert!(!IgnoreRules::parse("vendor\n!*/LICENSE").skips_dir(Path::new("vendor")));
+    }
+
+    #[test]
+    fn test_patterns_are_cleaned_and_anchored_at_the_root() {
+        assert!(ignored("/foo/bar", "foo/bar"));
+        assert!(ignored("foo/bar", "foo/bar"));
+        assert!(ignored("./foo//bar/", "foo/bar"));
+        assert!(ignored("foo/../bar", "bar"));
+        assert!(!ignored("foo", "src/foo"));
+        assert!(ignored("  spaced.txt  ", "spaced.txt"));
+        // A '#' only starts a comment in the first column
+        assert!(ignored(" #notacomment", "#notacomment"));
+        // Blank lines and a lone "!" are not patterns
+        assert!(!ignored("\n   \n!\n", "anything"));
+        assert!(ignored("\u{feff}bom.txt", "bom.txt"));
+    }
+
+    #[test]
+    fn test_classes_and_escapes() {
+        assert!(ignored("file[0-9].txt", "file7.txt"));
+        assert!(!ignored("file[0-9].txt", "filex.txt"));
+        assert!(ignored("file[!0-9].txt", "filex.txt"));
+        assert!(!ignored("file[!0-9].txt", "file7.txt"));
+        assert!(ignored("file[^0-9].txt", "filex.txt"));
+        assert!(ignored(r"weird\*name", "weird*name"));
+        assert!(!ignored(r"weird\*name", "weirdXname"));
+        // Regex metacharacters are literals
+        assert!(ignored("a+b(c).txt", "a+b(c).txt"));
+        assert!(!ignored("a.txt", "abtxt"));
+        // An unterminated class is an invalid pattern and matches nothing
+        assert!(!ignored("file[0-9", "file[0-9"));
+    }
+
+    fn context() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for path in [
+            "src/main.rs",
+            "src/debug.log",
+            "src/vendor/lib.rs",
+            "src/vendor/LICENSE",
+            "docs/guide.md",
+        ] {
+            let path = dir.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, path.to_string_lossy().as_bytes()).unwrap();
+        }
+        dir
+    }
+
+    fn names(root: &Path, files: Vec<std::path::PathBuf>) -> Vec<String> {
+        files
+            .iter()
+            .map(|f| f.strip_prefix(root).unwrap().to_string_lossy().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_walk_honours_exceptions_under_excluded_directories() {
+        let dir = context();
+        let rules = IgnoreRules::parse("src/vendor\n!src/vendor/LICENSE\n**/*.md");
+        assert_eq!(
+            names(dir.path(), walk_dir(dir.path(), &rules)),
+            vec!["src/debug.log", "src/main.rs", "src/vendor/LICENSE"]
+        );
+    }
+
+    #[test]
+    fn test_rules_apply_relative_to_the_context_root() {
+        let dir = context();
+        let rules = IgnoreRules::parse("src/*.log\nsrc/vendor");
+        let src = dir.path().join("src");
+        assert_eq!(
+            names(&src, walk_dir(&src, &rules.relative_to(Path::new("src")))),
+            vec!["main.rs"]
+        );
+
+        fs::write(dir.path().join(".dockerignore"), "src/*.log\nsrc/vendor").unwrap();
+        let mut graph = build_graph_from_instructions(
+            parse_dockerfile("FROM alpine\nCOPY src /src"),
+            dir.path().to_path_buf(),
+        );
+        let state =
+            detect_changes_against(&mut graph, dir.path(), &ContextState::default()).unwrap();
+        let files: Vec<&String> = state.nodes["COPY src /src"].files.keys().collect();
+        assert_eq!(files, vec!["src/main.rs"]);
+    }
+
+    #[test]
+    fn test_copy_excludes_are_relative_to_the_source() {
+        // "*.log" from .dockerignore only matches at the context root, while the same
+        // pattern in --exclude matches at the top of the copied directory
+        let mut rules = IgnoreRules::parse("*.log\nsrc/vendor").relative_to(Path::new("src"));
+        assert!(!rules.is_ignored(Path::new("debug.log")));
+        assert!(rules.is_ignored(Path::new("vendor/lib.rs")));
+        rules.add_patterns(&["*.log".to_string()]);
+        assert!(rules.is_ignored(Path::new("debug.log")));
+        assert!(rules.skips_dir(Path::new("vendor")));
+    }
+
+    #[test]
+    fn test_dockerfile_specific_ignore_file_takes_precedence() {
+        let dir = context();
+        fs::write(dir.path().join(".dockerignore"), "docs").unwrap();
+        fs::create_dir_all(dir.path().join("build")).unwrap();
+        let dockerfile = dir.path().join("build/app.Dockerfile");
+        fs::write(&dockerfile, "FROM alpine\nCOPY . /app").unwrap();
+        assert!(IgnoreRules::dockerfile_ignore_file(&dockerfile).is_none());
+
+        fs::write(dir.path().join("build/app.Dockerfile.dockerignore"), "src").unwrap();
+        let ignore_file = IgnoreRules::dockerfile_ignore_file(&dockerfile);
+        assert_eq!(
+            ignore_file.as_deref(),
+            Some(
+                dir.path()
+                    .join("build/app.Dockerfile.dockerignore")
+                    .as_path()
+            )
+        );
+
+        let rules = IgnoreRules::for_context(dir.path(), ignore_file.as_deref());
+        assert!(rules.is_ignored(Path::new("src/main.rs")));
+        assert!(!rules.is_ignored(Path::new("docs/guide.md")));
+
+        let mut graph = build_graph_from_instructions(
+            parse_dockerfile("FROM alpine\nCOPY . /app"),
+            dir.path().to_path_buf(),
+        );
+        graph.ignore_file = ignore_file;
+        let state =
+            detect_changes_against(&mut graph, dir.path(), &ContextState::default()).unwrap();
+        let files = &state.nodes["COPY . /app"].files;
+        assert!(files.contains_key("docs/guide.md"));
+        assert!(!files.keys().any(|f| f.starts_with("src/")));
+    }
+}
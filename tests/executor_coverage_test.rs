@@ -1,7 +1,9 @@
+mod common;
+
 /// Comprehensive tests for the executor module
 #[cfg(test)]
 mod executor_tests {
-    use memobuild::graph::{BuildGraph, Node, NodeKind, NodeMetadata};
+    use memobuild::graph::{BuildGraph, GraphIssue, Node, NodeKind, NodeMetadata};
 
     fn create_mock_graph() -> BuildGraph {
         // Create a simple linear DAG: FROM -> COPY -> RUN
@@ -24,8 +26,10 @@ mod executor_tests {
                 id: 1,
                 name: "COPY app".to_string(),
                 kind: NodeKind::Copy {
-                    src: "app".into(),
+                    sources: vec!["app".into()],
                     dst: "/app".into(),
+                    from: None,
+                    options: Default::default(),
                 },
                 content: "COPY app /app".to_string(),
                 hash: "copy_hash".to_string(),
@@ -39,7 +43,7 @@ mod executor_tests {
             Node {
                 id: 2,
                 name: "RUN build".to_string(),
-                kind: NodeKind::Run,
+                kind: NodeKind::Run { mounts: vec![] },
                 content: "RUN npm run build".to_string(),
                 hash: "run_hash".to_string(),
                 deps: vec![1],
@@ -132,12 +136,88 @@ mod executor_tests {
     fn test_circular_dependency_detection() {
         let mut graph = create_mock_graph();
 
-        // Create a potential circular dependency
+        assert!(graph.validate().is_valid());
+
+        // Create a circular dependency
         graph.nodes[0].deps.push(2); // FROM now depends on RUN
 
-        // This test verifies that circular dependencies are present
-        // In a real system, we'd reject this during DAG construction
-        assert!(graph.nodes[0].deps.contains(&2));
+        let validation = graph.validate();
+        assert!(!validation.is_valid());
+        assert_eq!(
+            validation.issues,
+            vec![GraphIssue::Cycle {
+                path: vec![0, 2, 1, 0]
+            }]
+        );
+        assert_eq!(
+            validation.issues[0].to_string(),
+            "dependency cycle: 0 -> 2 -> 1 -> 0"
+        );
+    }
+
+    #[test]
+    fn test_dangling_and_duplicate_ids_are_reported() {
+        let mut graph = create_mock_graph();
+        graph.nodes[2].deps.push(7);
+        graph.nodes[1].id = 0;
+
+        let issues = graph.validate().issues;
+        assert!(issues.contains(&GraphIssue::DanglingDependency { node: 2, dep: 7 }));
+        assert!(issues.contains(&GraphIssue::DuplicateId {
+            id: 0,
+            positions: vec![0, 1]
+        }));
+        assert!(graph.validate().ensure_valid().is_err());
+    }
+
+    #[test]
+    fn test_unreachable_stage_is_a_warning() {
+        let mut graph = create_mock_graph();
+        graph.nodes[2].metadata.stage = 1;
+        graph.nodes[2].deps.clear();
+        graph.nodes[2].metadata.stage_name = Some("final".to_string());
+
+        let validation = graph.validate();
+        assert!(validation.is_valid());
+        assert_eq!(
+            validation.warnings().collect::<Vec<_>>(),
+            vec![&GraphIssue::UnreachableStage {
+                stage: 0,
+                name: None
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_executor_rejects_invalid_graph() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let cache = crate::common::cache(cache_dir.path());
+
+        let mut graph = create_mock_graph();
+        graph.nodes[1].deps.push(2);
+        let mut executor = memobuild::executor::IncrementalExecutor::new(cache);
+        let err = executor.execute(&mut graph).await.unwrap_err();
+        assert!(err.to_string().contains("dependency cycle"));
+    }
+
+    #[tokio::test]
+    async fn test_skip_clean_runs_only_dirty_nodes() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let cache = crate::common::cache(cache_dir.path());
+
+        let mut graph = create_mock_graph();
+        graph.nodes[0].dirty = false;
+        graph.nodes[1].dirty = false;
+        let mut executor = memobuild::executor::IncrementalExecutor::new(cache)
+            .with_dry_run(true)
+            .with_skip_clean(true);
+        let stats = executor.execute(&mut graph).await.unwrap();
+
+        assert_eq!(stats.executed_nodes, 1);
+        assert_eq!(stats.cache_hits, 2);
+        assert!(graph.nodes[0].cache_hit && graph.nodes[1].cache_hit);
+        assert!(graph.nodes[0].metadata.execution_time_ms.is_none());
+        assert!(graph.nodes[2].metadata.execution_time_ms.is_some());
     }
 
     #[test]
@@ -262,7 +342,7 @@ mod cache_behavior_tests {
             Node {
                 id: 0,
                 name: "A".to_string(),
-                kind: NodeKind::Run,
+                kind: NodeKind::Run { mounts: vec![] },
                 content: "A".to_string(),
                 hash: "a_hash".to_string(),
                 deps: vec![],
@@ -275,7 +355,7 @@ mod cache_behavior_tests {
             Node {
                 id: 1,
                 name: "B".to_string(),
-                kind: NodeKind::Run,
+                kind: NodeKind::Run { mounts: vec![] },
                 content: "B".to_string(),
                 hash: "b_hash".to_string(),
                 deps: vec![0],
@@ -288,7 +368,7 @@ mod cache_behavior_tests {
             Node {
                 id: 2,
                 name: "C".to_string(),
-                kind: NodeKind::Run,
+                kind: NodeKind::Run { mounts: vec![] },
                 content: "C".to_string(),
                 hash: "c_hash".to_string(),
                 deps: vec![1],
@@ -312,7 +392,7 @@ mod cache_behavior_tests {
         let node = Node {
             id: 0,
             name: "test".to_string(),
-            kind: NodeKind::Run,
+            kind: NodeKind::Run { mounts: vec![] },
             content: "test".to_string(),
             hash: "test_hash".to_string(),
             deps: vec![],
@@ -0,0 +1,189 @@
+/// Tests for the miss reasons reported by `memobuild explain-cache`
+#[cfg(test)]
+mod explain_cache_tests {
+    use memobuild::cache::CacheLocation;
+    use memobuild::core::{compute_composite_hashes, detect_changes_against, ContextState};
+    use memobuild::diff::{BuildSnapshot, EnvChange, KeyChange};
+    use memobuild::docker::dag::build_graph_from_instructions;
+    use memobuild::docker::parser::parse_dockerfile;
+    use memobuild::env::EnvFingerprint;
+    use memobuild::explain::{explain, Baseline, MissReason};
+    use memobuild::hasher::{hash_path, IgnoreRules};
+    use std::fs;
+    use std::path::Path;
+
+    const DOCKERFILE: &str = "FROM alpine:3.19\nCOPY src /src\nRUN make";
+
+    /// Hash `context` as a build would, returning the snapshot and its source inputs.
+    fn scan(
+        dockerfile: &str,
+        context: &Path,
+        env: EnvFingerprint,
+    ) -> (BuildSnapshot, ContextState) {
+        let mut graph =
+            build_graph_from_instructions(parse_dockerfile(dockerfile), context.to_path_buf());
+        let state = detect_changes_against(&mut graph, context, &ContextState::default()).unwrap();
+        compute_composite_hashes(&mut graph, &env);
+        (
+            BuildSnapshot {
+                graph,
+                env: Some(env),
+            },
+            state,
+        )
+    }
+
+    fn baseline(snapshot: BuildSnapshot, state: ContextState) -> Baseline {
+        Baseline {
+            id: "previous".to_string(),
+            snapshot,
+            state,
+        }
+    }
+
+    fn context() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("src")).unwrap();
+        fs::write(dir.path().join("src/a.rs"), "a").unwrap();
+        fs::write(dir.path().join("src/b.rs"), "b").unwrap();
+        dir
+    }
+
+    const MISSING: [CacheLocation; 3] = [CacheLocation::Missing; 3];
+
+    #[test]
+    fn test_changed_files_are_named() {
+        let dir = context();
+        let (before, before_state) = scan(DOCKERFILE, dir.path(), EnvFingerprint::default());
+        // Per-file hashes do not change the hash of the source as a whole
+        assert_eq!(
+            before_state.nodes["COPY src /src"].sources["src"],
+            hash_path(&dir.path().join("src"), &IgnoreRules::empty()).unwrap()
+        );
+
+        fs::write(dir.path().join("src/b.rs"), "b2").unwrap();
+        fs::write(dir.path().join("src/c.rs"), "c").unwrap();
+        let (after, state) = scan(DOCKERFILE, dir.path(), EnvFingerprint::default());
+        let explained = explain(
+            &after,
+            &state,
+            Some(&baseline(before, before_state)),
+            &[
+                CacheLocation::Local,
+                CacheLocation::Missing,
+                CacheLocation::Missing,
+            ],
+        );
+
+        assert!(explained[0].reasons.is_empty());
+        assert_eq!(
+            explained[1].reasons,
+            vec![MissReason::FilesChanged {
+                added: vec!["src/c.rs".to_string()],
+                removed: vec![],
+                modified: vec!["src/b.rs".to_string()],
+            }]
+        );
+        assert_eq!(
+            explained[2].reasons,
+            vec![MissReason::UpstreamNode {
+                id: 1,
+                label: "COPY src /src".to_string()
+            }]
+        );
+        assert_ne!(
+            explained[2].previous_hash.as_ref(),
+            Some(&explained[2].hash)
+        );
+    }
+
+    #[test]
+    fn test_toolchain_change_is_named_on_root_nodes() {
+        let dir = context();
+        let mut rust_179 = EnvFingerprint::default();
+        rust_179
+            .toolchain
+            .insert("rustc".to_string(), "1.79.0".to_string());
+        let mut rust_180 = rust_179.clone();
+        rust_180
+            .toolchain
+            .insert("rustc".to_string(), "1.80.0".to_string());
+
+        let (before, before_state) = scan(DOCKERFILE, dir.path(), rust_179);
+        let (after, state) = scan(DOCKERFILE, dir.path(), rust_180);
+        let explained = explain(
+            &after,
+            &state,
+            Some(&baseline(before, before_state)),
+            &MISSING,
+        );
+
+        let expected = MissReason::EnvFingerprint {
+            changes: vec![EnvChange {
+                field: "toolchain.rustc".to_string(),
+                before: Some("1.79.0".to_string()),
+                after: Some("1.80.0".to_string()),
+            }],
+        };
+        // Only the FROM has no upstream node; the rest changed through it
+        assert_eq!(explained[0].reasons, vec![expected]);
+        assert!(matches!(
+            explained[1].reasons[..],
+            [MissReason::UpstreamNode { id: 0, .. }]
+        ));
+        assert_eq!(
+            explained[0].reasons[0].to_string(),
+            "environment changed: toolchain.rustc 1.79.0 → 1.80.0"
+        );
+    }
+
+    #[test]
+    fn test_unchanged_key_new_node_and_instruction_edit() {
+        let dir = context();
+        let (before, before_state) = scan(DOCKERFILE, dir.path(), EnvFingerprint::default());
+        let (after, state) = scan(
+            "FROM alpine:3.19\nCOPY src /src\nRUN make -j4\nLABEL team=core",
+            dir.path(),
+            EnvFingerprint::default(),
+        );
+        let explained = explain(
+            &after,
+            &state,
+            Some(&baseline(before, before_state)),
+            &[
+                CacheLocation::Remote,
+                CacheLocation::Missing,
+                CacheLocation::Missing,
+                CacheLocation::Missing,
+            ],
+        );
+
+        assert!(explained[0].reasons.is_empty());
+        assert_eq!(explained[0].cache, CacheLocation::Remote);
+        assert_eq!(explained[1].reasons, vec![MissReason::NotCached]);
+        assert_eq!(
+            explained[2].reasons,
+            vec![MissReason::KeyInput {
+                change: KeyChange::Instruction {
+                    before: "RUN make".to_string(),
+                    after: "RUN make -j4".to_string(),
+                }
+            }]
+        );
+        assert_eq!(explained[3].reasons, vec![MissReason::NewNode]);
+    }
+
+    #[test]
+    fn test_without_previous_build() {
+        let dir = context();
+        let (current, state) = scan(DOCKERFILE, dir.path(), EnvFingerprint::default());
+        let explained = explain(&current, &state, None, &MISSING);
+        assert!(explained
+            .iter()
+            .all(|e| e.reasons == vec![MissReason::NoPreviousBuild] && e.previous_hash.is_none()));
+        assert_eq!(
+            explained[2].reasons[0].to_string(),
+            "no previous build of this context"
+        );
+    }
+}
@@ -0,0 +1,90 @@
+/// Tests for the DOT, Mermaid and JSON renderings of `memobuild graph`
+#[cfg(test)]
+mod graph_export_tests {
+    use memobuild::docker::dag::build_graph_from_instructions;
+    use memobuild::docker::parser::parse_dockerfile;
+    use memobuild::graph::BuildGraph;
+    use memobuild::visualize::{CacheStatus, GraphExport, GraphFormat};
+
+    const DOCKERFILE: &str = r#"
+FROM golang:1.22 AS build
+COPY go.mod ./
+RUN go mod download
+FROM alpine:3.19
+COPY --from=build /out/app /usr/bin/app
+CMD ["app"]
+"#;
+
+    fn graph() -> BuildGraph {
+        let mut graph =
+            build_graph_from_instructions(parse_dockerfile(DOCKERFILE), std::env::temp_dir());
+        graph.nodes[1].cache_hit = true;
+        graph.nodes[2].dirty = true;
+        graph.nodes[2].metadata.execution_time_ms = Some(1500);
+        graph
+    }
+
+    #[test]
+    fn test_export_records_stages_cache_and_durations() {
+        let export = GraphExport::from_graph(&graph());
+        assert_eq!(export.stages.len(), 2);
+        assert_eq!(export.stages[0].name.as_deref(), Some("build"));
+        assert_eq!(export.stages[1].name, None);
+
+        assert_eq!(export.nodes[1].cache, CacheStatus::Cached);
+        assert_eq!(export.nodes[2].cache, CacheStatus::Dirty);
+        assert_eq!(export.nodes[0].cache, CacheStatus::Miss);
+        assert_eq!(export.nodes[2].kind, "RUN");
+        assert_eq!(export.nodes[2].label, "RUN go mod download");
+        assert_eq!(export.nodes[2].duration_ms, Some(1500));
+        assert_eq!(export.nodes[4].deps, vec![3, 2]);
+    }
+
+    #[test]
+    fn test_dot_clusters_stages() {
+        let dot = GraphExport::from_graph(&graph()).to_dot();
+        assert!(dot.starts_with("digraph memobuild {"));
+        assert!(dot.contains("subgraph cluster_stage_0 {"));
+        assert!(dot.contains("label=\"stage 0 (build)\";"));
+        assert!(dot.contains("subgraph cluster_stage_1 {"));
+        assert!(dot.contains("n2 [label=\"RUN go mod download\\ndirty, 1.5s\""));
+        assert!(dot.contains("n2 -> n4;"));
+        assert!(dot.contains("CMD [\\\"app\\\"]"));
+    }
+
+    #[test]
+    fn test_mermaid_escapes_labels_and_styles_cache_status() {
+        let mermaid = GraphExport::from_graph(&graph()).to_mermaid();
+        assert!(mermaid.starts_with("flowchart TD\n"));
+        assert!(mermaid.contains("subgraph stage0[\"stage 0 (build)\"]"));
+        assert!(mermaid.contains("n1[\"COPY go.mod ./<br/>cached\"]:::cached"));
+        assert!(mermaid.contains("CMD [#quot;app#quot;]"));
+        assert!(mermaid.contains("  n3 --> n4\n"));
+        assert!(mermaid.contains("classDef dirty fill:"));
+    }
+
+    #[test]
+    fn test_json_round_trips() {
+        let export = GraphExport::from_graph(&graph());
+        let json = export.render(GraphFormat::Json).unwrap();
+        let parsed: GraphExport = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, export);
+        // Output is stable, so exports can be diffed in CI
+        assert_eq!(
+            json,
+            GraphExport::from_graph(&graph())
+                .render(GraphFormat::Json)
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_format_parsing() {
+        assert_eq!("DOT".parse::<GraphFormat>().unwrap(), GraphFormat::Dot);
+        assert_eq!(
+            "mermaid".parse::<GraphFormat>().unwrap(),
+            GraphFormat::Mermaid
+        );
+        assert!("svg".parse::<GraphFormat>().is_err());
+    }
+}
@@ -0,0 +1,341 @@
+mod common;
+
+/// Tests for network policies: parsing, `# memobuild:network` directives, isolation of the
+/// local and rootless sandboxes, and the allowlist proxy's record of fetched URLs
+#[cfg(all(test, target_os = "linux"))]
+mod network_policy_tests {
+    use crate::common::graph;
+    use memobuild::docker::parser::parse_dockerfile_with_diagnostics;
+    use memobuild::env::EnvFingerprint;
+    use memobuild::graph::BuildGraph;
+    use memobuild::history::{BuildHistory, BuildRecord};
+    use memobuild::sandbox::local::LocalSandbox;
+    use memobuild::sandbox::rootless::RootlessSandbox;
+    use memobuild::sandbox::{ExecResult, FailureReason, FetchedUrl, NetworkPolicy, Sandbox};
+    use sha2::{Digest, Sha256};
+    use std::io::{Read, Write};
+    use std::sync::Arc;
+
+    const BODY: &str = "hello from upstream\n";
+
+    fn has_curl() -> bool {
+        std::process::Command::new("curl")
+            .arg("--version")
+            .output()
+            .is_ok_and(|output| output.status.success())
+    }
+
+    /// HTTP server on the host's loopback that answers every request with [`BODY`]. It runs
+    /// on a thread of its own, since the sandbox may block the test's runtime.
+    fn upstream() -> u16 {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut request = [0u8; 4096];
+                let _ = stream.read(&mut request);
+                let response = format!(
+                    "HTTP/1.0 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                    BODY.len(),
+                    BODY
+                );
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+        port
+    }
+
+    async fn run(sandbox: &dyn Sandbox, graph: &BuildGraph, id: usize) -> ExecResult {
+        let env = sandbox.prepare(&graph.nodes[id]).await.unwrap();
+        let result = sandbox.execute(&env, &graph.nodes[id]).await.unwrap();
+        sandbox.cleanup(&env).await.unwrap();
+        result
+    }
+
+    fn fetch(port: u16) -> String {
+        format!(
+            "FROM alpine\nRUN curl -sSf --max-time 10 http://127.0.0.1:{}/pkg.tar",
+            port
+        )
+    }
+
+    #[test]
+    fn test_parse_and_match_policies() {
+        assert_eq!(NetworkPolicy::parse("none").unwrap(), NetworkPolicy::None);
+        assert_eq!(NetworkPolicy::parse(" HOST ").unwrap(), NetworkPolicy::Host);
+        let allow = NetworkPolicy::parse("allow=example.com, *.pypi.org,localhost:8080").unwrap();
+        assert_eq!(
+            allow.to_string(),
+            "allow=example.com,*.pypi.org,localhost:8080"
+        );
+        assert_eq!(NetworkPolicy::parse(&allow.to_string()).unwrap(), allow);
+        for invalid in ["", "allow=", "allow= , ", "open", "deny=example.com"] {
+            assert!(NetworkPolicy::parse(invalid).is_err(), "{:?}", invalid);
+        }
+
+        assert!(allow.allows("example.com", 443));
+        assert!(allow.allows("EXAMPLE.com", 80));
+        assert!(!allow.allows("www.example.com", 443));
+        assert!(allow.allows("files.pypi.org", 443));
+        assert!(!allow.allows("pypi.org.evil.net", 443));
+        assert!(allow.allows("localhost", 8080));
+        assert!(!allow.allows("localhost", 8081));
+        assert!(NetworkPolicy::Host.allows("anything", 1));
+        assert!(!NetworkPolicy::None.allows("localhost", 80));
+    }
+
+    #[test]
+    fn test_directive_applies_to_the_next_instruction() {
+        let ctx = tempfile::tempdir().unwrap();
+        let graph = graph(
+            "# memobuild:network none\nFROM alpine\n# memobuild:network allow=example.com\nRUN echo a\nRUN echo b",
+            ctx.path(),
+        );
+        assert_eq!(graph.nodes.len(), 3);
+        assert_eq!(graph.nodes[0].metadata.network, Some(NetworkPolicy::None));
+        assert_eq!(
+            graph.nodes[1].metadata.network,
+            Some(NetworkPolicy::Allow(vec!["example.com".to_string()]))
+        );
+        assert_eq!(graph.nodes[2].metadata.network, None);
+
+        let parsed = parse_dockerfile_with_diagnostics(
+            "# memobuild:network none\nFROM alpine\n# memobuild:network everywhere\nRUN true",
+        );
+        let codes: Vec<(&str, usize)> = parsed
+            .diagnostics
+            .iter()
+            .map(|d| (d.code.as_str(), d.span.line))
+            .collect();
+        assert_eq!(codes, vec![("invalid-network", 3)]);
+    }
+
+    #[test]
+    fn test_run_network_flag_sets_the_policy() {
+        let ctx = tempfile::tempdir().unwrap();
+        let graph = graph(
+            "FROM alpine\nRUN --network=none echo a\n# memobuild:network none\nRUN --network=host echo b\nRUN --network=default echo c",
+            ctx.path(),
+        );
+        assert_eq!(graph.nodes.len(), 4);
+        assert_eq!(graph.nodes[1].metadata.network, Some(NetworkPolicy::None));
+        assert_eq!(graph.nodes[2].metadata.network, Some(NetworkPolicy::Host));
+        assert_eq!(graph.nodes[3].metadata.network, None);
+
+        let parsed = parse_dockerfile_with_diagnostics("FROM alpine\nRUN --network=bridge true");
+        let codes: Vec<(&str, usize)> = parsed
+            .diagnostics
+            .iter()
+            .map(|d| (d.code.as_str(), d.span.line))
+            .collect();
+        assert_eq!(codes, vec![("invalid-network", 2)]);
+    }
+
+    #[test]
+    fn test_network_policy_is_part_of_the_cache_key() {
+        let ctx = tempfile::tempdir().unwrap();
+        let key = |dockerfile: &str| graph(dockerfile, ctx.path()).nodes[1].hash.clone();
+        let open = key("FROM alpine\nRUN echo a");
+        let none = key("FROM alpine\nRUN --network=none echo a");
+        let host = key("FROM alpine\n# memobuild:network host\nRUN echo a");
+        assert_ne!(open, none);
+        assert_ne!(open, host);
+        assert_ne!(none, host);
+        assert_eq!(
+            none,
+            key("FROM alpine\n# memobuild:network none\nRUN echo a")
+        );
+
+        // `--network` on the command line covers the commands that set no policy themselves
+        let mut graph = graph(
+            "FROM alpine\nWORKDIR /app\nRUN echo a\nRUN --network=host echo b",
+            ctx.path(),
+        );
+        graph.set_default_network(&NetworkPolicy::None);
+        let policies: Vec<_> = graph
+            .nodes
+            .iter()
+            .map(|n| n.metadata.network.clone())
+            .collect();
+        assert_eq!(
+            policies,
+            vec![
+                None,
+                None,
+                Some(NetworkPolicy::None),
+                Some(NetworkPolicy::Host)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_none_cuts_the_command_off() {
+        if !has_curl() {
+            return;
+        }
+        let port = upstream();
+        let workspace = tempfile::tempdir().unwrap();
+        let graph = graph(&fetch(port), workspace.path());
+
+        let host = LocalSandbox::new(workspace.path().to_path_buf());
+        let result = run(&host, &graph, 1).await;
+        assert_eq!(result.exit_code, 0);
+        assert_eq!(String::from_utf8_lossy(&result.stdout), BODY);
+
+        let isolated =
+            LocalSandbox::new(workspace.path().to_path_buf()).with_network(NetworkPolicy::None);
+        let result = run(&isolated, &graph, 1).await;
+        assert_ne!(result.exit_code, 0);
+        assert_eq!(result.failure, None);
+        assert!(result.fetched.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_allowlist_records_fetched_urls() {
+        if !has_curl() {
+            return;
+        }
+        let port = upstream();
+        let workspace = tempfile::tempdir().unwrap();
+        let graph = graph(&fetch(port), workspace.path());
+        let sandbox = LocalSandbox::new(workspace.path().to_path_buf())
+            .with_network(NetworkPolicy::parse(&format!("allow=127.0.0.1:{}", port)).unwrap());
+
+        let result = run(&sandbox, &graph, 1).await;
+        assert_eq!(
+            result.exit_code,
+            0,
+            "{}",
+            String::from_utf8_lossy(&result.stderr)
+        );
+        assert_eq!(String::from_utf8_lossy(&result.stdout), BODY);
+        assert_eq!(
+            result.fetched,
+            vec![FetchedUrl {
+                url: format!("http://127.0.0.1:{}/pkg.tar", port),
+                digest: Some(format!("sha256:{}", hex::encode(Sha256::digest(BODY)))),
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_blocked_request_is_the_failure_reason() {
+        if !has_curl() {
+            return;
+        }
+        let port = upstream();
+        let workspace = tempfile::tempdir().unwrap();
+        let mut graph = graph(
+            &format!(
+                "FROM alpine\n# memobuild:network allow=example.com\nRUN curl -sSf --max-time 10 http://127.0.0.1:{}/pkg.tar",
+                port
+            ),
+            workspace.path(),
+        );
+        let url = format!("http://127.0.0.1:{}/pkg.tar", port);
+
+        let sandbox = LocalSandbox::new(workspace.path().to_path_buf());
+        let result = run(&sandbox, &graph, 1).await;
+        assert_ne!(result.exit_code, 0);
+        assert_eq!(
+            result.failure,
+            Some(FailureReason::NetworkBlocked { url: url.clone() })
+        );
+        assert!(result.fetched.is_empty());
+
+        let cache_dir = tempfile::tempdir().unwrap();
+        let cache = crate::common::cache(cache_dir.path());
+        let mut executor =
+            memobuild::executor::IncrementalExecutor::new(cache).with_sandbox(Arc::new(sandbox));
+        let error = executor.execute(&mut graph).await.unwrap_err();
+        let message = format!("{:#}", error);
+        assert!(
+            message.contains(&format!(
+                "failed after the network policy refused access to {}",
+                url
+            )),
+            "{}",
+            message
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rootless_allowlist() {
+        if !RootlessSandbox::is_supported() || !has_curl() {
+            return;
+        }
+        let port = upstream();
+        let context = tempfile::tempdir().unwrap();
+        let work = tempfile::tempdir().unwrap();
+        let graph = graph(&fetch(port), context.path());
+
+        // Loopback only by default
+        let sandbox = RootlessSandbox::new(context.path().to_path_buf(), work.path().to_path_buf());
+        let result = run(&sandbox, &graph, 1).await;
+        assert_ne!(result.exit_code, 0);
+
+        let sandbox = RootlessSandbox::new(context.path().to_path_buf(), work.path().to_path_buf())
+            .with_network(NetworkPolicy::parse(&format!("allow=127.0.0.1:{}", port)).unwrap());
+        let result = run(&sandbox, &graph, 1).await;
+        assert_eq!(
+            result.exit_code,
+            0,
+            "{}",
+            String::from_utf8_lossy(&result.stderr)
+        );
+        assert_eq!(result.fetched.len(), 1);
+        assert_eq!(
+            result.fetched[0].url,
+            format!("http://127.0.0.1:{}/pkg.tar", port)
+        );
+    }
+
+    #[test]
+    fn test_fetched_urls_reach_history_and_provenance() {
+        let ctx = tempfile::tempdir().unwrap();
+        let fetched = FetchedUrl {
+            url: "https://example.com".to_string(),
+            digest: None,
+        };
+        let mut ran = graph("FROM alpine\nRUN a\nRUN b", ctx.path());
+        ran.nodes[1].metadata.fetched = vec![fetched.clone()];
+        ran.nodes[2].metadata.fetched = vec![fetched.clone()];
+        assert_eq!(ran.fetched_urls(), vec![fetched.clone()]);
+
+        // A later build that hits the cache still lists what the node fetched when it ran
+        let history_dir = tempfile::tempdir().unwrap();
+        let history = BuildHistory::new(history_dir.path());
+        let mut record = BuildRecord::new("1", ctx.path(), "Dockerfile", EnvFingerprint::default());
+        record.graph = ran.clone();
+        history.save(&record).unwrap();
+        let mut cached = ran.clone();
+        for node in &mut cached.nodes {
+            node.cache_hit = true;
+            node.metadata.fetched.clear();
+        }
+        history.recall_fetched(&mut cached).unwrap();
+        assert_eq!(cached.nodes[1].metadata.fetched, vec![fetched.clone()]);
+        assert!(cached.nodes[0].metadata.fetched.is_empty());
+
+        let generator = memobuild::slsa::ProvenanceGenerator::new("memobuild-builder".to_string());
+        let mut provenance = generator
+            .generate_provenance(
+                "git+file:///src",
+                "sha256:aa",
+                "oci://image",
+                "sha256:bb",
+                &memobuild::slsa::InvocationParams::default(),
+            )
+            .unwrap();
+        provenance.add_resolved_dependency(&fetched.url, "");
+        let json: serde_json::Value =
+            serde_json::from_str(&generator.provenance_to_json(&provenance).unwrap()).unwrap();
+        let uris: Vec<&str> = json["predicate"]["buildDefinition"]["resolvedDependencies"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|material| material["uri"].as_str().unwrap())
+            .collect();
+        assert_eq!(uris, vec!["git+file:///src", "https://example.com"]);
+    }
+}
@@ -22,7 +22,12 @@ async fn test_reproducible_exports_are_identical() {
     let mut graph_1 =
         build_graph_from_instructions(instructions.clone(), std::env::current_dir().unwrap());
 
-    core::detect_changes(&mut graph_1);
+    core::detect_changes_against(
+        &mut graph_1,
+        &std::env::current_dir().unwrap(),
+        &core::ContextState::default(),
+    )
+    .unwrap();
     core::propagate_dirty(&mut graph_1);
     core::compute_composite_hashes(&mut graph_1, &env_fp);
     core::propagate_manifests(&mut graph_1);
@@ -45,7 +50,12 @@ async fn test_reproducible_exports_are_identical() {
 
     let mut graph_2 = build_graph_from_instructions(instructions, std::env::current_dir().unwrap());
 
-    core::detect_changes(&mut graph_2);
+    core::detect_changes_against(
+        &mut graph_2,
+        &std::env::current_dir().unwrap(),
+        &core::ContextState::default(),
+    )
+    .unwrap();
     core::propagate_dirty(&mut graph_2);
     core::compute_composite_hashes(&mut graph_2, &env_fp);
     core::propagate_manifests(&mut graph_2);
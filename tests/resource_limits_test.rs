@@ -0,0 +1,300 @@
+mod common;
+
+/// Tests for resource limits: parsing, `# memobuild:limits` directives, and enforcement of
+/// timeouts, memory and cgroup settings on the commands the local sandbox runs
+#[cfg(all(test, target_os = "linux"))]
+mod resource_limits_tests {
+    use crate::common::graph;
+    use memobuild::docker::parser::parse_dockerfile_with_diagnostics;
+    use memobuild::remote_exec::worker::WorkerNode;
+    use memobuild::remote_exec::{ActionRequest, Digest, RemoteExecutor};
+    use memobuild::sandbox::limits::{cpu_weight, is_delegated, Limiter};
+    use memobuild::sandbox::local::LocalSandbox;
+    use memobuild::sandbox::{
+        ExecResult, FailureReason, NetworkPolicy, ResourceLimits, Sandbox, SandboxEnv,
+    };
+    use std::collections::HashMap;
+    use std::fs;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_parse_limits() {
+        let limits =
+            ResourceLimits::parse("memory=512m, cpus=1.5 cpu-shares=512 pids=64 timeout=2m")
+                .unwrap();
+        assert_eq!(
+            limits,
+            ResourceLimits {
+                cpu_shares: Some(512),
+                memory_mb: Some(512),
+                cpus: Some(1.5),
+                pids: Some(64),
+                timeout_secs: Some(120),
+            }
+        );
+        assert_eq!(
+            ResourceLimits::parse("memory=1g").unwrap().memory_mb,
+            Some(1024)
+        );
+        // Plain numbers are bytes and seconds, rounded up to whole megabytes
+        assert_eq!(
+            ResourceLimits::parse("memory=1000").unwrap().memory_mb,
+            Some(1)
+        );
+        assert_eq!(
+            ResourceLimits::parse("timeout=90").unwrap().timeout_secs,
+            Some(90)
+        );
+        assert!(ResourceLimits::parse("").unwrap().is_empty());
+
+        for invalid in [
+            "memory",
+            "memory=lots",
+            "memory=5x",
+            "cpus=0",
+            "pids=0",
+            "timeout=0",
+            "disk=1g",
+        ] {
+            assert!(ResourceLimits::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_node_limits_override_defaults_per_field() {
+        let node = ResourceLimits::parse("memory=256m").unwrap();
+        let worker = ResourceLimits::parse("memory=1g timeout=60").unwrap();
+        let merged = node.or(&worker);
+        assert_eq!(merged.memory_mb, Some(256));
+        assert_eq!(merged.timeout_secs, Some(60));
+    }
+
+    #[test]
+    fn test_directive_applies_to_the_next_instruction() {
+        let ctx = tempfile::tempdir().unwrap();
+        let graph = graph(
+            "# memobuild:limits memory=256m\nFROM alpine\nRUN echo a\n  # memobuild:limits timeout=30 pids=10\nRUN echo b\nRUN echo c",
+            ctx.path(),
+        );
+        let contents: Vec<&str> = graph.nodes.iter().map(|n| n.content.as_str()).collect();
+        assert_eq!(contents, vec!["FROM alpine", "echo a", "echo b", "echo c"]);
+        assert_eq!(graph.nodes[0].metadata.limits.memory_mb, Some(256));
+        assert!(graph.nodes[1].metadata.limits.is_empty());
+        assert_eq!(graph.nodes[2].metadata.limits.timeout_secs, Some(30));
+        assert_eq!(graph.nodes[2].metadata.limits.pids, Some(10));
+        assert!(graph.nodes[3].metadata.limits.is_empty());
+    }
+
+    #[test]
+    fn test_directive_diagnostics() {
+        let parsed = parse_dockerfile_with_diagnostics(
+            "# memobuild:limits memory=huge\nFROM alpine\n# memobuild:frobnicate x\nRUN true",
+        );
+        let codes: Vec<(&str, usize)> = parsed
+            .diagnostics
+            .iter()
+            .map(|d| (d.code.as_str(), d.span.line))
+            .collect();
+        assert_eq!(codes, vec![("invalid-limits", 1), ("unknown-directive", 3)]);
+
+        // A directive before FROM is not an instruction before FROM
+        let parsed = parse_dockerfile_with_diagnostics(
+            "# memobuild:limits timeout=5\nFROM alpine\nRUN true",
+        );
+        assert!(parsed.diagnostics.is_empty());
+        assert_eq!(parsed.instructions.len(), parsed.spans.len());
+    }
+
+    #[tokio::test]
+    async fn test_timeout_kills_the_command_and_its_children() {
+        let workspace = tempfile::tempdir().unwrap();
+        let sandbox = LocalSandbox::new(workspace.path().to_path_buf());
+        let graph = graph(
+            "FROM alpine\n# memobuild:limits timeout=1\nRUN echo started; sleep 30 & sleep 30",
+            workspace.path(),
+        );
+        let node = &graph.nodes[1];
+
+        let started = Instant::now();
+        let env = sandbox.prepare(node).await.unwrap();
+        let result = sandbox.execute(&env, node).await.unwrap();
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert_ne!(result.exit_code, 0);
+        assert_eq!(
+            result.failure,
+            Some(FailureReason::Timeout { after_secs: 1 })
+        );
+        // Output written before the timeout is kept
+        assert_eq!(String::from_utf8_lossy(&result.stdout), "started\n");
+    }
+
+    #[tokio::test]
+    async fn test_sandbox_limits_apply_to_every_node() {
+        let workspace = tempfile::tempdir().unwrap();
+        let sandbox = LocalSandbox::new(workspace.path().to_path_buf())
+            .with_limits(ResourceLimits::parse("timeout=1").unwrap());
+        let graph = graph(
+            "FROM alpine\nRUN sleep 30\nRUN echo quick",
+            workspace.path(),
+        );
+
+        let env = sandbox.prepare(&graph.nodes[1]).await.unwrap();
+        let slow = sandbox.execute(&env, &graph.nodes[1]).await.unwrap();
+        assert_eq!(slow.failure, Some(FailureReason::Timeout { after_secs: 1 }));
+        let env = sandbox.prepare(&graph.nodes[2]).await.unwrap();
+        let quick = sandbox.execute(&env, &graph.nodes[2]).await.unwrap();
+        assert_eq!(quick.exit_code, 0);
+        assert_eq!(quick.failure, None);
+    }
+
+    #[tokio::test]
+    async fn test_memory_limit_stops_a_hungry_command() {
+        let workspace = tempfile::tempdir().unwrap();
+        let sandbox = LocalSandbox::new(workspace.path().to_path_buf());
+        let graph = graph(
+            "FROM alpine\n# memobuild:limits memory=64m\nRUN x=$(head -c 300000000 /dev/zero | tr '\\0' a); echo ${#x}",
+            workspace.path(),
+        );
+        let node = &graph.nodes[1];
+
+        let env = sandbox.prepare(node).await.unwrap();
+        let result = sandbox.execute(&env, node).await.unwrap();
+        assert_ne!(result.exit_code, 0);
+        // Only a cgroup can tell an OOM kill apart; rlimits make the allocation fail instead
+        match memobuild::sandbox::limits::delegated_cgroup() {
+            Some(_) => assert_eq!(
+                result.failure,
+                Some(FailureReason::OutOfMemory { limit_mb: 64 })
+            ),
+            None => assert_eq!(result.failure, None),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_executor_reports_the_failure_reason() {
+        let workspace = tempfile::tempdir().unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        let cache = crate::common::cache(cache_dir.path());
+        let mut graph = graph(
+            "FROM alpine\n# memobuild:limits timeout=1\nRUN sleep 30",
+            workspace.path(),
+        );
+
+        let mut executor = memobuild::executor::IncrementalExecutor::new(cache)
+            .with_sandbox(Arc::new(LocalSandbox::new(workspace.path().to_path_buf())));
+        let error = executor.execute(&mut graph).await.unwrap_err();
+        assert!(
+            format!("{:#}", error).contains("Command timed out after 1s"),
+            "{:#}",
+            error
+        );
+    }
+
+    /// Sandbox that records the node it is asked to run
+    #[derive(Default)]
+    struct Recording(std::sync::Mutex<Option<memobuild::graph::Node>>);
+
+    #[async_trait::async_trait]
+    impl Sandbox for Recording {
+        async fn prepare(&self, node: &memobuild::graph::Node) -> anyhow::Result<SandboxEnv> {
+            Ok(SandboxEnv {
+                workspace_dir: std::env::temp_dir(),
+                env_vars: node.env.clone(),
+            })
+        }
+
+        async fn execute(
+            &self,
+            _env: &SandboxEnv,
+            node: &memobuild::graph::Node,
+        ) -> anyhow::Result<ExecResult> {
+            *self.0.lock().unwrap() = Some(node.clone());
+            Ok(ExecResult {
+                exit_code: 0,
+                stdout: Vec::new(),
+                stderr: Vec::new(),
+                diff: None,
+                failure: None,
+                fetched: Vec::new(),
+            })
+        }
+
+        async fn cleanup(&self, _env: &SandboxEnv) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_workers_get_the_node_limits_and_network() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let sandbox = Arc::new(Recording::default());
+        let worker = WorkerNode::new(
+            "w1",
+            crate::common::cache(cache_dir.path()),
+            sandbox.clone(),
+        );
+        let action = ActionRequest {
+            command: vec!["/bin/sh".into(), "-c".into(), "true".into()],
+            env: HashMap::new(),
+            input_root_digest: Digest {
+                hash: "0".repeat(64),
+                size_bytes: 0,
+            },
+            timeout: Duration::from_secs(30),
+            platform_properties: HashMap::new(),
+            output_files: Vec::new(),
+            output_directories: Vec::new(),
+            limits: ResourceLimits::parse("memory=64m pids=16").unwrap(),
+            network: Some(NetworkPolicy::Host),
+        };
+        assert_eq!(worker.execute(action).await.unwrap().exit_code, 0);
+
+        let node = sandbox.0.lock().unwrap().take().unwrap();
+        assert_eq!(
+            node.metadata.limits,
+            ResourceLimits::parse("memory=64m pids=16 timeout=30").unwrap()
+        );
+        assert_eq!(node.metadata.network, Some(NetworkPolicy::Host));
+    }
+
+    #[test]
+    fn test_cgroup_files_are_written() {
+        let parent = tempfile::tempdir().unwrap();
+        fs::write(
+            parent.path().join("cgroup.subtree_control"),
+            "cpu memory pids",
+        )
+        .unwrap();
+        let limits = ResourceLimits::parse("memory=64m pids=32 cpus=1.5 cpu-shares=1024").unwrap();
+
+        let limiter = Limiter::with_cgroup_parent(&limits, Some(parent.path())).unwrap();
+        let cgroup = limiter.cgroup().unwrap();
+        assert!(cgroup.starts_with(parent.path()));
+        let read = |file: &str| fs::read_to_string(cgroup.join(file)).unwrap();
+        assert_eq!(read("memory.max"), (64u64 << 20).to_string());
+        assert_eq!(read("pids.max"), "32");
+        assert_eq!(read("cpu.max"), "150000 100000");
+        assert_eq!(read("cpu.weight"), cpu_weight(1024).to_string());
+
+        // Timeouts alone need no cgroup
+        let timeout = ResourceLimits::parse("timeout=5").unwrap();
+        let limiter = Limiter::with_cgroup_parent(&timeout, Some(parent.path())).unwrap();
+        assert!(limiter.cgroup().is_none());
+    }
+
+    #[test]
+    fn test_cgroup_delegation_and_cpu_weight() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(!is_delegated(dir.path()));
+        fs::write(dir.path().join("cgroup.subtree_control"), "cpu pids").unwrap();
+        fs::write(dir.path().join("cgroup.procs"), "").unwrap();
+        assert!(!is_delegated(dir.path()));
+        fs::write(dir.path().join("cgroup.subtree_control"), "memory pids").unwrap();
+        assert!(is_delegated(dir.path()));
+
+        assert_eq!(cpu_weight(2), 1);
+        assert_eq!(cpu_weight(1024), 39);
+        assert_eq!(cpu_weight(262_144), 10_000);
+    }
+}
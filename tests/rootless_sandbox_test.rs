@@ -0,0 +1,179 @@
+mod common;
+
+/// Tests for the rootless namespace sandbox. They pass trivially where the kernel does not
+/// allow unprivileged user namespaces.
+#[cfg(all(test, target_os = "linux"))]
+mod rootless_sandbox_tests {
+    use crate::common::{context, graph, layer_contents};
+    use memobuild::graph::BuildGraph;
+    use memobuild::sandbox::rootless::RootlessSandbox;
+    use memobuild::sandbox::{ExecResult, FailureReason, Sandbox};
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    /// Run every node after FROM in order and return the result of the last one
+    async fn build(sandbox: &RootlessSandbox, graph: &BuildGraph) -> ExecResult {
+        let mut last = None;
+        for node in &graph.nodes[1..] {
+            let env = sandbox.prepare(node).await.unwrap();
+            let result = sandbox.execute(&env, node).await.unwrap();
+            sandbox.cleanup(&env).await.unwrap();
+            last = Some(result);
+        }
+        last.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_run_is_isolated_from_the_host() {
+        if !RootlessSandbox::is_supported() {
+            return;
+        }
+        let ctx = context();
+        let work = tempfile::tempdir().unwrap();
+        let sandbox = RootlessSandbox::new(ctx.path().to_path_buf(), work.path().to_path_buf());
+        let host_file = ctx.path().join("src/x.txt");
+        let dockerfile = format!(
+            "FROM scratch\nCOPY src /app\nRUN {{ {}; }} > /report",
+            [
+                "echo pid=$$",
+                "echo uid=$(id -u)",
+                "echo host=$(cat /proc/sys/kernel/hostname)",
+                &format!("test -e {} || echo host-fs=hidden", host_file.display()),
+                "grep -c : /proc/net/dev | sed s/^/interfaces=/",
+                "touch /usr/memobuild 2>/dev/null || echo usr=read-only",
+                "cat /app/x.txt",
+            ]
+            .join(" && ")
+        );
+        let graph = graph(&dockerfile, ctx.path());
+
+        let result = build(&sandbox, &graph).await;
+        assert_eq!(
+            result.exit_code,
+            0,
+            "{}",
+            String::from_utf8_lossy(&result.stderr)
+        );
+        let diff = layer_contents(&result.diff.unwrap());
+        assert_eq!(
+            diff["report"],
+            "pid=1\nuid=0\nhost=memobuild\nhost-fs=hidden\ninterfaces=1\nusr=read-only\nhello"
+        );
+        // Mount points of the toolchain, /proc and /dev are not part of the diff
+        assert_eq!(diff.keys().collect::<Vec<_>>(), vec!["report"]);
+        assert!(!ctx.path().join("report").exists());
+    }
+
+    #[tokio::test]
+    async fn test_commands_run_in_the_workdir_with_the_node_env() {
+        if !RootlessSandbox::is_supported() {
+            return;
+        }
+        let ctx = context();
+        let work = tempfile::tempdir().unwrap();
+        let sandbox = RootlessSandbox::new(ctx.path().to_path_buf(), work.path().to_path_buf());
+        let graph = graph(
+            "FROM scratch\nENV GREETING=hi\nWORKDIR /srv/app\nRUN pwd > where && echo $GREETING > env",
+            ctx.path(),
+        );
+
+        let diff = layer_contents(&build(&sandbox, &graph).await.diff.unwrap());
+        assert_eq!(diff["srv/app/where"], "/srv/app\n");
+        assert_eq!(diff["srv/app/env"], "hi\n");
+    }
+
+    #[tokio::test]
+    async fn test_cache_mounts_are_bound_and_not_recorded() {
+        if !RootlessSandbox::is_supported() {
+            return;
+        }
+        let ctx = context();
+        let work = tempfile::tempdir().unwrap();
+        let mounts = tempfile::tempdir().unwrap();
+        let sandbox = RootlessSandbox::new(ctx.path().to_path_buf(), work.path().to_path_buf())
+            .with_cache_mount_dir(mounts.path().to_path_buf());
+        let graph = graph(
+            "FROM scratch\nRUN --mount=type=cache,target=/var/cache/pkg echo cached > /var/cache/pkg/index && echo done > /out",
+            ctx.path(),
+        );
+
+        let diff = layer_contents(&build(&sandbox, &graph).await.diff.unwrap());
+        assert_eq!(diff["out"], "done\n");
+        assert!(!diff.keys().any(|path| path.starts_with("var")));
+        let cache_dir = fs::read_dir(mounts.path())
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(cache_dir.path().join("index")).unwrap(),
+            "cached\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_host_tools_stay_visible_next_to_copied_ones() {
+        if !RootlessSandbox::is_supported() {
+            return;
+        }
+        let ctx = context();
+        let tool = ctx.path().join("tool");
+        fs::write(&tool, "#!/bin/sh\necho from-tool\n").unwrap();
+        fs::set_permissions(&tool, fs::Permissions::from_mode(0o755)).unwrap();
+        let work = tempfile::tempdir().unwrap();
+        let sandbox = RootlessSandbox::new(ctx.path().to_path_buf(), work.path().to_path_buf());
+        let graph = graph(
+            "FROM scratch\nCOPY tool /usr/local/bin/tool\nRUN tool > /out && /usr/bin/env true",
+            ctx.path(),
+        );
+
+        let result = build(&sandbox, &graph).await;
+        assert_eq!(
+            result.exit_code,
+            0,
+            "{}",
+            String::from_utf8_lossy(&result.stderr)
+        );
+        let diff = layer_contents(&result.diff.unwrap());
+        assert_eq!(diff.keys().collect::<Vec<_>>(), vec!["out"]);
+        assert_eq!(diff["out"], "from-tool\n");
+    }
+
+    #[tokio::test]
+    async fn test_failing_command_reports_its_exit_code() {
+        if !RootlessSandbox::is_supported() {
+            return;
+        }
+        let ctx = context();
+        let work = tempfile::tempdir().unwrap();
+        let sandbox = RootlessSandbox::new(ctx.path().to_path_buf(), work.path().to_path_buf());
+        let graph = graph("FROM scratch\nRUN echo oops >&2; exit 3", ctx.path());
+
+        let result = build(&sandbox, &graph).await;
+        assert_eq!(result.exit_code, 3);
+        assert_eq!(String::from_utf8_lossy(&result.stderr), "oops\n");
+        assert!(result.diff.is_none());
+        assert_eq!(fs::read_dir(work.path().join("rootfs")).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_limits_apply_inside_the_namespaces() {
+        if !RootlessSandbox::is_supported() {
+            return;
+        }
+        let ctx = context();
+        let work = tempfile::tempdir().unwrap();
+        let sandbox = RootlessSandbox::new(ctx.path().to_path_buf(), work.path().to_path_buf());
+        let graph = graph(
+            "FROM scratch\n# memobuild:limits timeout=1\nRUN sleep 30",
+            ctx.path(),
+        );
+
+        let result = build(&sandbox, &graph).await;
+        assert_eq!(
+            result.failure,
+            Some(FailureReason::Timeout { after_secs: 1 })
+        );
+        assert!(result.diff.is_none());
+    }
+}
@@ -0,0 +1,286 @@
+mod common;
+
+/// Tests for the filesystem snapshot sandbox: private root filesystems, layer diffs with
+/// whiteouts, and cache hits that restore filesystem state
+#[cfg(all(test, unix))]
+mod snapshot_sandbox_tests {
+    use crate::common::{context, graph, layer_contents};
+    use memobuild::graph::BuildGraph;
+    use memobuild::sandbox::snapshot::{apply_layers, is_layer, SnapshotSandbox};
+    use memobuild::sandbox::Sandbox;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    async fn run(sandbox: &SnapshotSandbox, graph: &BuildGraph, id: usize) -> Vec<u8> {
+        let node = &graph.nodes[id];
+        let env = sandbox.prepare(node).await.unwrap();
+        let result = sandbox.execute(&env, node).await.unwrap();
+        sandbox.cleanup(&env).await.unwrap();
+        assert_eq!(
+            result.exit_code,
+            0,
+            "{}",
+            String::from_utf8_lossy(&result.stderr)
+        );
+        result.diff.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_run_changes_become_a_layer_diff_with_whiteouts() {
+        let ctx = context();
+        let work = tempfile::tempdir().unwrap();
+        let sandbox = SnapshotSandbox::new(ctx.path().to_path_buf(), work.path().to_path_buf());
+        let graph = graph(
+            "FROM scratch\nCOPY src /app\nRUN cat app/x.txt > copy.txt && rm app/x.txt && echo v2 > app/keep.txt && chmod 700 app",
+            ctx.path(),
+        );
+
+        let copy = run(&sandbox, &graph, 1).await;
+        assert!(is_layer(&copy));
+        let files = layer_contents(&copy);
+        assert_eq!(files["app/x.txt"], "hello");
+        assert_eq!(files["app/keep.txt"], "keep");
+
+        let diff = layer_contents(&run(&sandbox, &graph, 2).await);
+        assert_eq!(
+            diff.keys().collect::<Vec<_>>(),
+            vec!["app", "app/.wh.x.txt", "app/keep.txt", "copy.txt"]
+        );
+        assert_eq!(diff["copy.txt"], "hello");
+        assert_eq!(diff["app/keep.txt"], "v2\n");
+
+        // The command ran in a private filesystem: the context is untouched
+        assert!(ctx.path().join("src/x.txt").exists());
+        assert!(!ctx.path().join("copy.txt").exists());
+        assert_eq!(fs::read_dir(work.path().join("rootfs")).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_next_node_runs_on_the_applied_layers() {
+        let ctx = context();
+        let work = tempfile::tempdir().unwrap();
+        let sandbox = SnapshotSandbox::new(ctx.path().to_path_buf(), work.path().to_path_buf());
+        let graph = graph(
+            "FROM scratch\nCOPY src /app\nRUN rm app/x.txt && mkdir -p out/empty\nRUN test ! -e app/x.txt && test -d out/empty && cat app/keep.txt > seen",
+            ctx.path(),
+        );
+        assert_eq!(graph.nodes[3].metadata.input_layers.len(), 2);
+
+        for id in 1..=3 {
+            run(&sandbox, &graph, id).await;
+        }
+        let last = layer_contents(&fs::read(sandbox.layer_path(&graph.nodes[3].hash)).unwrap());
+        assert_eq!(last["seen"], "keep");
+    }
+
+    #[tokio::test]
+    async fn test_run_starts_in_the_workdir() {
+        let ctx = context();
+        let work = tempfile::tempdir().unwrap();
+        let sandbox = SnapshotSandbox::new(ctx.path().to_path_buf(), work.path().to_path_buf());
+        let graph = graph(
+            "FROM scratch\nCOPY src /app\nWORKDIR /app\nRUN cat keep.txt > seen\nWORKDIR /srv/data\nRUN echo made > here",
+            ctx.path(),
+        );
+
+        run(&sandbox, &graph, 1).await;
+        let seen = layer_contents(&run(&sandbox, &graph, 3).await);
+        assert_eq!(seen["app/seen"], "keep");
+        let made = layer_contents(&run(&sandbox, &graph, 5).await);
+        // The missing WORKDIR is created as part of the node's changes
+        assert_eq!(
+            made.keys().collect::<Vec<_>>(),
+            vec!["srv", "srv/data", "srv/data/here"]
+        );
+    }
+
+    #[test]
+    fn test_input_layers_follow_from_but_not_copy_from() {
+        let ctx = context();
+        let graph = graph(
+            "FROM scratch AS base\nRUN echo a > a\nFROM base\nRUN echo b > b\nFROM scratch\nCOPY --from=base a /a\nLABEL x=y\nRUN cat a",
+            ctx.path(),
+        );
+        let layers = |id: usize| -> Vec<&str> {
+            graph.nodes[id]
+                .metadata
+                .input_layers
+                .iter()
+                .map(|hash| {
+                    let node = graph.nodes.iter().find(|n| &n.hash == hash).unwrap();
+                    node.content.as_str()
+                })
+                .collect()
+        };
+        assert_eq!(layers(3), vec!["echo a > a"]);
+        assert_eq!(layers(7), vec!["COPY --from=base a /a"]);
+    }
+
+    #[tokio::test]
+    async fn test_copy_from_reads_the_source_stage() {
+        let ctx = context();
+        let work = tempfile::tempdir().unwrap();
+        let sandbox = SnapshotSandbox::new(ctx.path().to_path_buf(), work.path().to_path_buf());
+        let graph = graph(
+            "FROM scratch AS base\nRUN echo a > a\nFROM base\nRUN echo b > b\nFROM scratch\nCOPY --from=base a /a\nLABEL x=y\nRUN cat a",
+            ctx.path(),
+        );
+        assert_eq!(
+            graph.nodes[5]
+                .metadata
+                .source_layers
+                .as_ref()
+                .unwrap()
+                .len(),
+            1
+        );
+
+        run(&sandbox, &graph, 1).await;
+        let copy = layer_contents(&run(&sandbox, &graph, 5).await);
+        assert_eq!(copy.keys().collect::<Vec<_>>(), vec!["a"]);
+        assert_eq!(copy["a"], "a\n");
+
+        let node = &graph.nodes[7];
+        let env = sandbox.prepare(node).await.unwrap();
+        let result = sandbox.execute(&env, node).await.unwrap();
+        sandbox.cleanup(&env).await.unwrap();
+        assert_eq!(result.exit_code, 0);
+        assert_eq!(result.stdout, b"a\n");
+    }
+
+    #[tokio::test]
+    async fn test_copy_from_an_image_or_a_missing_path_fails() {
+        let ctx = context();
+        let work = tempfile::tempdir().unwrap();
+        let sandbox = SnapshotSandbox::new(ctx.path().to_path_buf(), work.path().to_path_buf());
+        let graph = graph(
+            "FROM scratch AS base\nRUN echo a > a\nFROM scratch\nCOPY --from=alpine /etc/alpine-release /r\nCOPY --from=base missing /m",
+            ctx.path(),
+        );
+        assert!(graph.nodes[3].metadata.source_layers.is_none());
+        run(&sandbox, &graph, 1).await;
+
+        for id in [3, 4] {
+            let env = sandbox.prepare(&graph.nodes[id]).await.unwrap();
+            assert!(sandbox.execute(&env, &graph.nodes[id]).await.is_err());
+            sandbox.cleanup(&env).await.unwrap();
+            assert!(!sandbox.layer_path(&graph.nodes[id].hash).exists());
+        }
+    }
+
+    #[test]
+    fn test_apply_layers_honours_whiteouts_and_modes() {
+        fn layer(entries: &[(&str, tar::EntryType, u32, &str)]) -> Vec<u8> {
+            let mut tar = tar::Builder::new(Vec::new());
+            for (path, kind, mode, data) in entries {
+                let mut header = tar::Header::new_gnu();
+                header.set_entry_type(*kind);
+                header.set_mode(*mode);
+                header.set_size(data.len() as u64);
+                tar.append_data(&mut header, path, data.as_bytes()).unwrap();
+            }
+            tar.into_inner().unwrap()
+        }
+        use tar::EntryType::{Directory, Regular};
+
+        let root = tempfile::tempdir().unwrap();
+        let lower = layer(&[
+            ("etc/", Directory, 0o755, ""),
+            ("etc/a.conf", Regular, 0o644, "a"),
+            ("etc/b.conf", Regular, 0o644, "b"),
+            ("var/cache/x", Regular, 0o644, "x"),
+            ("bin/tool", Regular, 0o755, "#!"),
+        ]);
+        let upper = layer(&[
+            ("etc/.wh.a.conf", Regular, 0o644, ""),
+            ("var/cache/.wh..wh..opq", Regular, 0o644, ""),
+            ("var/cache/y", Regular, 0o644, "y"),
+            ("ro/", Directory, 0o555, ""),
+            ("ro/file", Regular, 0o444, "r"),
+        ]);
+        apply_layers(root.path(), &[lower, upper]).unwrap();
+
+        let r = root.path();
+        assert!(!r.join("etc/a.conf").exists());
+        assert_eq!(fs::read_to_string(r.join("etc/b.conf")).unwrap(), "b");
+        assert!(!r.join("var/cache/x").exists());
+        assert_eq!(fs::read_to_string(r.join("var/cache/y")).unwrap(), "y");
+        let mode = |p: &str| fs::metadata(r.join(p)).unwrap().permissions().mode() & 0o7777;
+        assert_eq!(mode("bin/tool"), 0o755);
+        assert_eq!(mode("ro"), 0o555);
+        assert_eq!(fs::read_to_string(r.join("ro/file")).unwrap(), "r");
+
+        // Entries may not escape the root
+        let mut header = tar::Header::new_gnu();
+        header.as_gnu_mut().unwrap().name[..10].copy_from_slice(b"../outside");
+        header.set_size(0);
+        header.set_cksum();
+        let mut escape = tar::Builder::new(Vec::new());
+        escape.append(&header, &[][..]).unwrap();
+        let escape = escape.into_inner().unwrap();
+        assert!(apply_layers(root.path(), &[escape]).is_err());
+        assert!(!r.parent().unwrap().join("outside").exists());
+        fs::set_permissions(r.join("ro"), fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cache_hits_restore_filesystem_state() {
+        let ctx = context();
+        let cache_dir = tempfile::tempdir().unwrap();
+        let cache = crate::common::cache(cache_dir.path());
+
+        let build = |dockerfile: &'static str, work: &Path| {
+            let cache = cache.clone();
+            let sandbox = SnapshotSandbox::new(ctx.path().to_path_buf(), work.to_path_buf());
+            let mut graph = graph(dockerfile, ctx.path());
+            async move {
+                let mut executor = memobuild::executor::IncrementalExecutor::new(cache)
+                    .with_sandbox(Arc::new(sandbox));
+                let stats = executor.execute(&mut graph).await.unwrap();
+                (stats, graph)
+            }
+        };
+
+        let first = tempfile::tempdir().unwrap();
+        let (stats, _) = build(
+            "FROM scratch\nCOPY src /app\nRUN cp app/x.txt built.txt\nRUN cat built.txt",
+            first.path(),
+        )
+        .await;
+        assert_eq!(stats.cache_hits, 0);
+        assert!(!ctx.path().join("built.txt").exists());
+
+        // A fresh sandbox only gets the earlier layers from the cache, and the changed last
+        // step still sees the file the cached step created
+        let second = tempfile::tempdir().unwrap();
+        let (stats, graph) = build(
+            "FROM scratch\nCOPY src /app\nRUN cp app/x.txt built.txt\nRUN cat built.txt > again.txt",
+            second.path(),
+        )
+        .await;
+        assert_eq!(stats.cache_hits, 3);
+        assert_eq!(stats.executed_nodes, 1);
+        let sandbox = SnapshotSandbox::new(ctx.path().to_path_buf(), second.path().to_path_buf());
+        let last = layer_contents(&fs::read(sandbox.layer_path(&graph.nodes[3].hash)).unwrap());
+        assert_eq!(last["again.txt"], "hello");
+    }
+
+    #[tokio::test]
+    async fn test_artifacts_of_other_sandboxes_are_not_restored() {
+        let ctx = context();
+        let work = tempfile::tempdir().unwrap();
+        let sandbox = SnapshotSandbox::new(ctx.path().to_path_buf(), work.path().to_path_buf());
+        let graph = graph("FROM scratch\nRUN echo hi", ctx.path());
+
+        assert!(sandbox
+            .restore(&graph.nodes[1], b"Artifact for RUN")
+            .await
+            .is_err());
+        assert!(sandbox.restore(&graph.nodes[1], &[]).await.is_err());
+        let empty = tar::Builder::new(Vec::new()).into_inner().unwrap();
+        sandbox.restore(&graph.nodes[1], &empty).await.unwrap();
+        assert!(sandbox.layer_path(&graph.nodes[1].hash).exists());
+    }
+}
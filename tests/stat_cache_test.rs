@@ -0,0 +1,114 @@
+/// Tests for the stat-based file hash cache used by change detection
+#[cfg(test)]
+mod stat_cache_tests {
+    use memobuild::core::{detect_changes_against, detect_changes_with, ContextState};
+    use memobuild::docker::dag::build_graph_from_instructions;
+    use memobuild::docker::parser::parse_dockerfile;
+    use memobuild::hasher::file_hasher::{hash_dir, hash_dir_files_with};
+    use memobuild::hasher::{IgnoreRules, StatCache};
+    use std::fs;
+    use std::path::Path;
+    use std::time::{Duration, SystemTime};
+
+    fn set_mtime(path: &Path, time: SystemTime) {
+        fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(time)
+            .unwrap();
+    }
+
+    /// Context whose files were last modified an hour ago, so cached hashes are trusted
+    fn context() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("src")).unwrap();
+        for name in ["src/a.rs", "src/b.rs", "src/c.rs"] {
+            fs::write(dir.path().join(name), name).unwrap();
+            set_mtime(
+                &dir.path().join(name),
+                SystemTime::now() - Duration::from_secs(3600),
+            );
+        }
+        dir
+    }
+
+    #[test]
+    fn test_unchanged_files_are_not_rehashed_across_loads() {
+        let dir = context();
+        let cache_file = dir.path().join("cache/stat-cache.json");
+        let ignore = IgnoreRules::parse("cache");
+
+        let cache = StatCache::load_from(&cache_file).unwrap();
+        let first = hash_dir_files_with(dir.path(), &ignore, Some(&cache)).unwrap();
+        assert_eq!((cache.hits(), cache.misses()), (0, 3));
+        cache.save().unwrap();
+
+        let cache = StatCache::load_from(&cache_file).unwrap();
+        let second = hash_dir_files_with(dir.path(), &ignore, Some(&cache)).unwrap();
+        assert_eq!((cache.hits(), cache.misses()), (3, 0));
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn test_changed_stat_is_rehashed() {
+        let dir = context();
+        let cache = StatCache::in_memory();
+        let ignore = IgnoreRules::empty();
+        hash_dir_files_with(dir.path(), &ignore, Some(&cache)).unwrap();
+
+        // Same size, older mtime: only the stat data tells the files apart
+        let b = dir.path().join("src/b.rs");
+        fs::write(&b, "src/B.rs").unwrap();
+        set_mtime(&b, SystemTime::now() - Duration::from_secs(7200));
+
+        let files = hash_dir_files_with(dir.path(), &ignore, Some(&cache)).unwrap();
+        assert_eq!((cache.hits(), cache.misses()), (2, 4));
+        assert_eq!(
+            memobuild::hasher::file_hasher::combine_file_hashes(&files),
+            hash_dir(dir.path(), &ignore).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_racily_clean_files_are_always_rehashed() {
+        let dir = context();
+        let a = dir.path().join("src/a.rs");
+        // Written in the same second it is hashed
+        fs::write(&a, "fresh").unwrap();
+
+        let cache = StatCache::in_memory();
+        cache.hash_file(&a).unwrap();
+        cache.hash_file(&a).unwrap();
+        assert_eq!((cache.hits(), cache.misses()), (0, 2));
+
+        // A same-size write that keeps the mtime, as on filesystems with coarse
+        // timestamps, is still seen
+        let mtime = fs::metadata(&a).unwrap().modified().unwrap();
+        fs::write(&a, "FRESH").unwrap();
+        set_mtime(&a, mtime);
+        assert_eq!(
+            cache.hash_file(&a).unwrap(),
+            memobuild::hasher::file_hasher::hash_file(&a).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_detect_changes_with_matches_full_hashing() {
+        let dir = context();
+        let dockerfile = "FROM alpine\nCOPY src /src\nCOPY src/a.rs /a.rs\nRUN make";
+        let graph = || {
+            build_graph_from_instructions(parse_dockerfile(dockerfile), dir.path().to_path_buf())
+        };
+        let cache = StatCache::in_memory();
+        let previous = ContextState::default();
+
+        let mut cached_graph = graph();
+        let warm = detect_changes_with(&mut cached_graph, dir.path(), &previous, &cache).unwrap();
+        let hot = detect_changes_with(&mut cached_graph, dir.path(), &previous, &cache).unwrap();
+        let full = detect_changes_against(&mut graph(), dir.path(), &previous).unwrap();
+        assert!(cache.hits() >= 4);
+        assert_eq!(hot.nodes, full.nodes);
+        assert_eq!(warm.nodes, full.nodes);
+    }
+}
@@ -0,0 +1,119 @@
+/// Tests for `memobuild build --watch`: change batching and incremental rebuild planning
+#[cfg(test)]
+mod watch_tests {
+    use memobuild::core::ContextState;
+    use memobuild::docker::dag::build_graph_from_instructions;
+    use memobuild::docker::parser::parse_dockerfile;
+    use memobuild::env::EnvFingerprint;
+    use memobuild::watch::{Changes, ContextWatcher, WatchSession};
+    use std::collections::BTreeSet;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    const DOCKERFILE: &str =
+        "FROM alpine:3.19\nCOPY src /src\nCOPY docs /docs\nRUN make\nLABEL team=core";
+
+    fn context() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("src")).unwrap();
+        fs::create_dir_all(dir.path().join("docs")).unwrap();
+        fs::write(dir.path().join("src/main.c"), "int main() {}").unwrap();
+        fs::write(dir.path().join("docs/README"), "docs").unwrap();
+        fs::write(dir.path().join(".dockerignore"), "*.log\n").unwrap();
+        dir
+    }
+
+    async fn next(watcher: &mut ContextWatcher) -> Changes {
+        tokio::time::timeout(
+            Duration::from_secs(10),
+            watcher.next_batch(Duration::from_millis(700)),
+        )
+        .await
+        .expect("no change reported")
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_changes_are_debounced_and_ignored_paths_dropped() {
+        let dir = context();
+        let mut watcher = ContextWatcher::new(dir.path(), &dir.path().join("Dockerfile")).unwrap();
+
+        fs::write(dir.path().join("build.log"), "noise").unwrap();
+        fs::write(dir.path().join("src/main.c"), "int main() { return 1; }").unwrap();
+        fs::create_dir_all(dir.path().join("src/lib")).unwrap();
+        fs::write(dir.path().join("src/lib/util.c"), "void f() {}").unwrap();
+
+        let batch = next(&mut watcher).await;
+        assert!(batch.paths.contains(&PathBuf::from("src/main.c")));
+        assert!(batch.paths.contains(&PathBuf::from("src/lib/util.c")));
+        assert!(!batch.paths.contains(&PathBuf::from("build.log")));
+        assert!(!batch.reload);
+    }
+
+    #[tokio::test]
+    async fn test_dockerfile_and_ignore_file_changes_reload_the_rules() {
+        let dir = context();
+        let dockerfile = dir.path().join("Dockerfile");
+        fs::write(&dockerfile, DOCKERFILE).unwrap();
+        fs::write(
+            dir.path().join(".dockerignore"),
+            "*.log\nDockerfile\n.dockerignore\n",
+        )
+        .unwrap();
+        let mut watcher = ContextWatcher::new(dir.path(), &dockerfile).unwrap();
+
+        fs::write(&dockerfile, format!("{}\nLABEL v=2", DOCKERFILE)).unwrap();
+        let batch = next(&mut watcher).await;
+        assert!(batch.paths.contains(&PathBuf::from("Dockerfile")));
+        assert!(batch.reload);
+
+        fs::write(dir.path().join(".dockerignore"), "Dockerfile\n").unwrap();
+        let batch = next(&mut watcher).await;
+        assert!(batch.paths.contains(&PathBuf::from(".dockerignore")));
+        assert!(batch.reload);
+
+        // Logs are no longer ignored
+        fs::write(dir.path().join("build.log"), "kept").unwrap();
+        let batch = next(&mut watcher).await;
+        assert!(batch.paths.contains(&PathBuf::from("build.log")));
+        assert!(!batch.reload);
+    }
+
+    #[test]
+    fn test_plan_marks_only_the_affected_subgraph() {
+        let dir = context();
+        let template =
+            build_graph_from_instructions(parse_dockerfile(DOCKERFILE), dir.path().to_path_buf());
+        let mut session = WatchSession::new(
+            template,
+            dir.path(),
+            EnvFingerprint::default(),
+            ContextState::default(),
+        );
+
+        let first = session.plan().unwrap();
+        assert_eq!(first.dirty.len(), first.graph.nodes.len());
+        session.commit(first.state.clone());
+        assert!(session.plan().unwrap().dirty.is_empty());
+
+        fs::write(dir.path().join("docs/README"), "more docs").unwrap();
+        let rebuild = session.plan().unwrap();
+        let dirty: BTreeSet<&str> = rebuild
+            .dirty
+            .iter()
+            .map(|&id| rebuild.graph.nodes[id].content.as_str())
+            .collect();
+        assert!(dirty.contains("COPY docs /docs"));
+        assert!(!dirty.contains("COPY src /src"));
+        assert!(!dirty.contains("FROM alpine:3.19"));
+        for &id in &rebuild.dirty {
+            assert_ne!(rebuild.graph.nodes[id].hash, first.graph.nodes[id].hash);
+        }
+
+        // Until the rebuild is committed, the same change is planned again
+        assert_eq!(session.plan().unwrap().dirty, rebuild.dirty);
+        session.commit(rebuild.state);
+        assert!(session.plan().unwrap().dirty.is_empty());
+    }
+}